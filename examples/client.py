@@ -29,6 +29,8 @@ def exchange(addr, A):
     with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as s:
         s.connect(addr)
         send_array(s, A)
+        # close our side so the server ends the connection after responding
+        s.shutdown(socket.SHUT_WR)
         result = recv_array(s)
     return result
 
@@ -1,10 +1,11 @@
 //! Example of a simple server that runs a neural network.
 //!
-//! Run with `cargo run --example mlp`.
+//! Run with `cargo run --example mlp`, adding `-- --dashboard` to watch the
+//! server's requests live.
 use std::sync::Arc;
 
 use candle_core::{DType, Device, Result, Tensor};
-use socket_nn::server::run_server;
+use socket_nn::server::{dashboard, run_server, Server};
 
 struct Linear {
     weight: Tensor,
@@ -48,6 +49,16 @@ fn forward(model: &Model, input_data: Tensor) -> Result<Tensor> {
 
 #[tokio::main]
 async fn main() {
+    if std::env::args().any(|arg| arg == "--dashboard") {
+        let handle = Server::new(load_model(), forward)
+            .bind("127.0.0.1:8080")
+            .await
+            .unwrap();
+        dashboard::run(&handle).await.unwrap();
+        handle.shutdown();
+        handle.join().await.unwrap();
+        return;
+    }
     println!("Running server on localhost 8080...");
     run_server("127.0.0.1:8080", load_model(), forward)
         .await
@@ -1,115 +1,686 @@
 /// Module to read and write `numpy` arrays to the stream.
 /// Based on `candle_core::npy`.
-use candle_core::{DType, Device, Error, Result, Shape, Tensor};
+use bytemuck::Pod;
+use candle_core::{DType, Device, Error, Result, Shape, Storage, Tensor, WithDType};
 use half::{bf16, f16};
 use std::collections::HashMap;
+use std::future::Future;
+use std::io::IoSlice;
 use std::marker::Unpin;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::Instant;
+
+use self::codec::read_length_prefixed;
+use self::pool::BufferPool;
+
+#[cfg(feature = "arrow")]
+pub mod arrow;
+pub mod codec;
+pub mod compression;
+pub mod envelope;
+pub mod error;
+pub mod frame;
+pub mod handshake;
+pub mod json;
+pub mod mmap;
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
+pub mod named;
+#[cfg(feature = "onnx")]
+pub mod onnx;
+pub mod pool;
+pub mod quantized;
+pub mod safetensors;
+pub mod sparse;
+pub mod stream;
+pub mod text;
 
 const NPY_MAGIC_STRING: &[u8] = b"\x93NUMPY";
+/// Size in bytes of the chunks the array body is written in.
+const WRITE_CHUNK_SIZE: usize = 64 * 1024;
 
 /// Read a `numpy` array from the stream and conver to a `Tensor`.
-pub async fn read_numpy<T>(mut reader: T) -> Result<Tensor>
+///
+/// Arrays in fortran (column-major) order are transposed into a contiguous
+/// row-major tensor of the same shape.
+pub async fn read_numpy<T>(reader: T) -> Result<Tensor>
+where
+    T: AsyncReadExt + Unpin,
+{
+    read_numpy_to_device(reader, &Device::Cpu).await
+}
+
+/// Read a `numpy` array from the stream into a `Tensor` on the given device.
+pub async fn read_numpy_to_device<T>(reader: T, device: &Device) -> Result<Tensor>
 where
     T: AsyncReadExt + Unpin,
 {
-    let header = read_header(&mut reader).await?;
-    let header = Header::parse(&header)?;
+    read_numpy_with_limit(reader, device, usize::MAX).await
+}
+
+/// Read a `numpy` array from the stream into a `Tensor` on the given device,
+/// rejecting arrays whose body is larger than `max_bytes` before allocating.
+#[cfg_attr(
+    feature = "trace",
+    tracing::instrument(level = "trace", skip_all, fields(shape))
+)]
+pub async fn read_numpy_with_limit<T>(
+    mut reader: T,
+    device: &Device,
+    max_bytes: usize,
+) -> Result<Tensor>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let header = read_npy_header(&mut reader).await?;
+    #[cfg(feature = "trace")]
+    tracing::Span::current().record("shape", tracing::field::debug(&header.shape));
+    let nbytes = header
+        .shape
+        .iter()
+        .try_fold(header.descr.size_in_bytes(), |acc, &d| acc.checked_mul(d));
+    match nbytes {
+        Some(nbytes) if nbytes <= max_bytes => {}
+        _ => {
+            return Err(LimitExceeded {
+                what: format!("array of shape {:?}", header.shape),
+                max_bytes,
+            }
+            .into())
+        }
+    }
+    // column-major data is laid out as a row-major array with reversed dims
+    let shape = if header.fortran_order {
+        Shape::from(header.shape.iter().rev().copied().collect::<Vec<_>>())
+    } else {
+        header.shape()
+    };
+
+    // read the whole body straight into the buffer backing the tensor
+    let tensor = match header.descr {
+        DType::BF16 => read_values::<bf16, _>(&mut reader, &header, shape, device).await,
+        DType::F16 => read_values::<f16, _>(&mut reader, &header, shape, device).await,
+        DType::F32 => read_values::<f32, _>(&mut reader, &header, shape, device).await,
+        DType::F64 => read_values::<f64, _>(&mut reader, &header, shape, device).await,
+        DType::U8 => read_values::<u8, _>(&mut reader, &header, shape, device).await,
+        DType::U32 => read_values::<u32, _>(&mut reader, &header, shape, device).await,
+        DType::I64 if header.itemsize < 8 => {
+            read_narrow_ints(&mut reader, &header, shape, device).await
+        }
+        DType::I64 => read_values::<i64, _>(&mut reader, &header, shape, device).await,
+    }?;
+
     if header.fortran_order {
-        return Err(Error::Npy("fortran order not supported".to_string()));
+        reverse_dims(&tensor)?.contiguous()
+    } else {
+        Ok(tensor)
     }
-    let shape = header.shape();
+}
 
-    match header.descr {
-        DType::BF16 => {
-            let mut arr = vec![];
-            let mut data = [0u8; std::mem::size_of::<bf16>()];
-            for _ in 0..shape.elem_count() {
-                reader.read_exact(&mut data).await?;
-                arr.push(bf16::from_le_bytes(data));
-            }
-            Tensor::from_vec(arr, shape, &Device::Cpu)
-        }
-        DType::F16 => {
-            let mut arr = vec![];
-            let mut data = [0u8; std::mem::size_of::<f16>()];
-            for _ in 0..shape.elem_count() {
-                reader.read_exact(&mut data).await?;
-                arr.push(f16::from_le_bytes(data));
-            }
-            Tensor::from_vec(arr, shape, &Device::Cpu)
-        }
-        DType::F32 => {
-            let mut arr = vec![];
-            let mut data = [0u8; std::mem::size_of::<f32>()];
-            for _ in 0..shape.elem_count() {
-                reader.read_exact(&mut data).await?;
-                arr.push(f32::from_le_bytes(data));
+/// Reverse the order of all dimensions of a tensor.
+fn reverse_dims(tensor: &Tensor) -> Result<Tensor> {
+    let rank = tensor.rank();
+    let mut tensor = tensor.clone();
+    for i in 0..rank / 2 {
+        tensor = tensor.transpose(i, rank - 1 - i)?;
+    }
+    Ok(tensor)
+}
+
+/// Write a `Tensor` to the stream in `numpy` array format.
+#[cfg_attr(
+    feature = "trace",
+    tracing::instrument(level = "trace", skip_all, fields(shape = ?tensor.dims()))
+)]
+pub async fn write_numpy<T>(tensor: &Tensor, f: &mut T) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    write_array(tensor, &Header::for_tensor(tensor), f).await
+}
+
+/// Read a `numpy` array from the stream, failing with a `TimedOut` IO error
+/// if it has not been read by `deadline`.
+///
+/// Reading is not resumable: on timeout the partially read array is dropped
+/// and the position in the stream is unknown, so the stream should be closed.
+pub async fn read_numpy_with_deadline<T>(reader: T, deadline: Instant) -> Result<Tensor>
+where
+    T: AsyncReadExt + Unpin,
+{
+    with_deadline(deadline, read_numpy(reader)).await
+}
+
+/// Write a `Tensor` to the stream in `numpy` array format, failing with a
+/// `TimedOut` IO error if it has not been written by `deadline`.
+///
+/// On timeout only part of the array may have been written, so the stream
+/// should be closed.
+pub async fn write_numpy_with_deadline<T>(
+    tensor: &Tensor,
+    f: &mut T,
+    deadline: Instant,
+) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    with_deadline(deadline, write_numpy(tensor, f)).await
+}
+
+/// Run an IO future, converting an elapsed deadline into a `TimedOut` error.
+pub(crate) async fn with_deadline<F, V>(deadline: Instant, future: F) -> Result<V>
+where
+    F: Future<Output = Result<V>>,
+{
+    match tokio::time::timeout_at(deadline, future).await {
+        Ok(result) => result,
+        Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "deadline elapsed").into()),
+    }
+}
+
+/// Write a `Tensor` to the stream in big-endian `numpy` array format.
+pub async fn write_numpy_big_endian<T>(tensor: &Tensor, f: &mut T) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    let header = Header {
+        big_endian: true,
+        ..Header::for_tensor(tensor)
+    };
+    write_array(tensor, &header, f).await
+}
+
+/// Write a `u8` `Tensor` to the stream as a `numpy` boolean (`|b1`) array.
+/// Any non-zero value is written as `True`.
+pub async fn write_numpy_bool<T>(tensor: &Tensor, f: &mut T) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    if tensor.dtype() != DType::U8 {
+        return Err(Error::Npy(format!(
+            "boolean arrays must be u8, got {:?}",
+            tensor.dtype()
+        )));
+    }
+    let header = Header {
+        boolean: true,
+        ..Header::for_tensor(tensor)
+    };
+    write_array(tensor, &header, f).await
+}
+
+async fn write_array<T>(tensor: &Tensor, header: &Header, f: &mut T) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    let boolean = header.boolean;
+    let big_endian = header.big_endian;
+    let header = header.to_bytes()?;
+
+    let vs = flatten_values(tensor)?;
+    match vs.dtype() {
+        DType::BF16 => write_values::<bf16, _>(&vs, &header, big_endian, boolean, f).await,
+        DType::F16 => write_values::<f16, _>(&vs, &header, big_endian, boolean, f).await,
+        DType::F32 => write_values::<f32, _>(&vs, &header, big_endian, boolean, f).await,
+        DType::F64 => write_values::<f64, _>(&vs, &header, big_endian, boolean, f).await,
+        DType::U8 => write_values::<u8, _>(&vs, &header, big_endian, boolean, f).await,
+        DType::U32 => write_values::<u32, _>(&vs, &header, big_endian, boolean, f).await,
+        DType::I64 => write_values::<i64, _>(&vs, &header, big_endian, boolean, f).await,
+    }
+}
+
+/// Write the values of a 1d tensor after `header`.
+///
+/// The values are copied out in chunks through a pooled buffer and streamed
+/// rather than buffering the whole body, the header goes out in the same
+/// vectored write as the first chunk.
+async fn write_values<V, T>(
+    vs: &Tensor,
+    header: &[u8],
+    big_endian: bool,
+    boolean: bool,
+    f: &mut T,
+) -> Result<()>
+where
+    V: Pod + WithDType,
+    T: AsyncWriteExt + Unpin,
+{
+    let elem_size = std::mem::size_of::<V>();
+    let chunk_len = (WRITE_CHUNK_SIZE / elem_size).max(1);
+    let mut chunk = BufferPool::global().get();
+    let mut prefix = header;
+    let mut start = 0;
+    loop {
+        let len = chunk_len.min(vs.elem_count() - start);
+        copy_values::<V>(vs, start, len, &mut chunk)?;
+        let data: &mut [u8] = &mut chunk;
+        if big_endian != cfg!(target_endian = "big") {
+            swap_bytes(data, elem_size);
+        }
+        if boolean {
+            for v in data.iter_mut() {
+                *v = (*v != 0) as u8;
             }
-            Tensor::from_vec(arr, shape, &Device::Cpu)
-        }
-        DType::F64 => {
-            let mut arr = vec![];
-            let mut data = [0u8; std::mem::size_of::<f64>()];
-            for _ in 0..shape.elem_count() {
-                reader.read_exact(&mut data).await?;
-                arr.push(f64::from_le_bytes(data));
+        }
+        write_all_vectored(f, &[prefix, data]).await?;
+        prefix = &[];
+        start += len;
+        if start >= vs.elem_count() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy the bytes of `len` values from `start` of a 1d tensor into `chunk`.
+/// Contiguous tensors in CPU memory are copied straight out of their storage
+/// while it is locked, others through a narrowed copy.
+fn copy_values<V: Pod + WithDType>(
+    vs: &Tensor,
+    start: usize,
+    len: usize,
+    chunk: &mut Vec<u8>,
+) -> Result<()> {
+    chunk.clear();
+    if len == 0 {
+        return Ok(());
+    }
+    {
+        let (storage, layout) = vs.storage_and_layout();
+        if let (Some((offset, _)), Storage::Cpu(cpu)) = (layout.contiguous_offsets(), &*storage) {
+            let values = &cpu.as_slice::<V>()?[offset + start..offset + start + len];
+            chunk.extend_from_slice(bytemuck::cast_slice(values));
+            return Ok(());
+        }
+    }
+    let values = vs.narrow(0, start, len)?.to_vec1::<V>()?;
+    chunk.extend_from_slice(bytemuck::cast_slice(&values));
+    Ok(())
+}
+
+/// Write all of `bufs` to the stream using vectored writes, so they are sent
+/// without first being concatenated.
+pub(crate) async fn write_all_vectored<T>(f: &mut T, bufs: &[&[u8]]) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    let mut bufs: Vec<&[u8]> = bufs.iter().copied().filter(|b| !b.is_empty()).collect();
+    while !bufs.is_empty() {
+        let slices: Vec<IoSlice> = bufs.iter().map(|b| IoSlice::new(b)).collect();
+        let mut n = f.write_vectored(&slices).await?;
+        if n == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into());
+        }
+        while n > 0 {
+            if n >= bufs[0].len() {
+                n -= bufs[0].len();
+                bufs.remove(0);
+            } else {
+                bufs[0] = &bufs[0][n..];
+                n = 0;
             }
-            Tensor::from_vec(arr, shape, &Device::Cpu)
-        }
-        DType::U8 => {
-            let mut arr = vec![];
-            let mut data = [0u8; std::mem::size_of::<u8>()];
-            for _ in 0..shape.elem_count() {
-                reader.read_exact(&mut data).await?;
-                arr.push(u8::from_le_bytes(data));
+        }
+    }
+    Ok(())
+}
+
+/// Flatten a `Tensor` of any rank, including 0-dimensional scalars, into a 1d
+/// tensor of its values in row-major order. Contiguous tensors are reshaped
+/// without copying their storage.
+pub(crate) fn flatten_values(tensor: &Tensor) -> Result<Tensor> {
+    tensor.reshape(tensor.elem_count())
+}
+
+/// Convert the values of a `Tensor` into little-endian bytes in row-major order.
+pub(crate) fn tensor_to_le_bytes(tensor: &Tensor) -> Result<Vec<u8>> {
+    let mut value_bytes = Vec::new();
+    extend_le_bytes(tensor, &mut value_bytes)?;
+    Ok(value_bytes)
+}
+
+/// Append the values of a `Tensor` to `value_bytes` as little-endian bytes in
+/// row-major order.
+fn extend_le_bytes(tensor: &Tensor, value_bytes: &mut Vec<u8>) -> Result<()> {
+    let vs = flatten_values(tensor)?;
+    match vs.dtype() {
+        DType::BF16 => extend_values::<bf16>(&vs, value_bytes),
+        DType::F16 => extend_values::<f16>(&vs, value_bytes),
+        DType::F32 => extend_values::<f32>(&vs, value_bytes),
+        DType::F64 => extend_values::<f64>(&vs, value_bytes),
+        DType::U8 => extend_values::<u8>(&vs, value_bytes),
+        DType::U32 => extend_values::<u32>(&vs, value_bytes),
+        DType::I64 => extend_values::<i64>(&vs, value_bytes),
+    }
+}
+
+/// Append the values of a 1d tensor as little-endian bytes in one copy.
+fn extend_values<V: Pod + WithDType>(vs: &Tensor, value_bytes: &mut Vec<u8>) -> Result<()> {
+    let mut values = vs.to_vec1::<V>()?;
+    let data: &mut [u8] = bytemuck::cast_slice_mut(&mut values);
+    if cfg!(target_endian = "big") {
+        swap_bytes(data, std::mem::size_of::<V>());
+    }
+    value_bytes.extend_from_slice(data);
+    Ok(())
+}
+
+/// Build a `Tensor` of the given dtype and shape from little-endian bytes.
+pub(crate) fn tensor_from_le_bytes(data: &[u8], dtype: DType, shape: Shape) -> Result<Tensor> {
+    if data.len() != shape.elem_count() * dtype.size_in_bytes() {
+        return Err(Error::Npy(format!(
+            "expected {} bytes for {shape:?} {dtype:?}, got {}",
+            shape.elem_count() * dtype.size_in_bytes(),
+            data.len()
+        )));
+    }
+    match dtype {
+        DType::BF16 => tensor_from_le_slice::<bf16>(data, shape),
+        DType::F16 => tensor_from_le_slice::<f16>(data, shape),
+        DType::F32 => tensor_from_le_slice::<f32>(data, shape),
+        DType::F64 => tensor_from_le_slice::<f64>(data, shape),
+        DType::U8 => tensor_from_le_slice::<u8>(data, shape),
+        DType::U32 => tensor_from_le_slice::<u32>(data, shape),
+        DType::I64 => tensor_from_le_slice::<i64>(data, shape),
+    }
+}
+
+fn tensor_from_le_slice<V: Pod + WithDType>(data: &[u8], shape: Shape) -> Result<Tensor> {
+    // aligned little-endian bytes can be viewed as values without converting
+    if cfg!(target_endian = "little") {
+        if let Ok(values) = bytemuck::try_cast_slice::<u8, V>(data) {
+            return Tensor::from_slice(values, shape, &Device::Cpu);
+        }
+    }
+    let mut values = bytemuck::pod_collect_to_vec::<u8, V>(data);
+    if cfg!(target_endian = "big") {
+        swap_bytes(
+            bytemuck::cast_slice_mut(&mut values),
+            std::mem::size_of::<V>(),
+        );
+    }
+    Tensor::from_vec(values, shape, &Device::Cpu)
+}
+
+/// Read `shape.elem_count()` values of the header dtype directly into a new
+/// tensor's buffer.
+async fn read_values<V, T>(
+    reader: &mut T,
+    header: &Header,
+    shape: Shape,
+    device: &Device,
+) -> Result<Tensor>
+where
+    V: Pod + WithDType,
+    T: AsyncReadExt + Unpin,
+{
+    let mut values = vec![V::zeroed(); shape.elem_count()];
+    let data: &mut [u8] = bytemuck::cast_slice_mut(&mut values);
+    reader.read_exact(data).await?;
+    if header.big_endian != cfg!(target_endian = "big") {
+        swap_bytes(data, std::mem::size_of::<V>());
+    }
+    if header.boolean {
+        for v in data.iter_mut() {
+            *v = (*v != 0) as u8;
+        }
+    }
+    Tensor::from_vec(values, shape, device)
+}
+
+/// Read signed integers narrower than 8 bytes and widen them into an `I64`
+/// tensor, as candle has no narrower signed integer dtypes.
+async fn read_narrow_ints<T>(
+    reader: &mut T,
+    header: &Header,
+    shape: Shape,
+    device: &Device,
+) -> Result<Tensor>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let mut data = BufferPool::global().get_zeroed(shape.elem_count() * header.itemsize);
+    reader.read_exact(&mut data).await?;
+    let values = widen_ints(&data, header.itemsize, header.big_endian)?;
+    Tensor::from_vec(values, shape, device)
+}
+
+/// Sign extend `itemsize` byte integers to `i64`.
+pub(crate) fn widen_ints(data: &[u8], itemsize: usize, big_endian: bool) -> Result<Vec<i64>> {
+    let widen: fn(&[u8]) -> i64 = match (itemsize, big_endian) {
+        (1, _) => |v| v[0] as i8 as i64,
+        (2, false) => |v| i16::from_le_bytes([v[0], v[1]]) as i64,
+        (2, true) => |v| i16::from_be_bytes([v[0], v[1]]) as i64,
+        (4, false) => |v| i32::from_le_bytes([v[0], v[1], v[2], v[3]]) as i64,
+        (4, true) => |v| i32::from_be_bytes([v[0], v[1], v[2], v[3]]) as i64,
+        _ => return Err(Error::Npy(format!("cannot widen {itemsize} byte integers"))),
+    };
+    Ok(data.chunks_exact(itemsize).map(widen).collect())
+}
+
+/// Reverse the byte order of each `size` byte value.
+fn swap_bytes(data: &mut [u8], size: usize) {
+    for v in data.chunks_exact_mut(size) {
+        v.reverse();
+    }
+}
+
+/// Read bytes up to a newline, which is consumed but not returned. The last
+/// line of the stream may omit the newline. Lines longer than `max_bytes`
+/// are rejected once they pass the limit.
+pub(crate) async fn read_line<T>(reader: &mut T, max_bytes: usize) -> Result<Vec<u8>>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let mut line = Vec::new();
+    loop {
+        match reader.read_u8().await {
+            Ok(b'\n') => break,
+            Ok(_) if line.len() == max_bytes => {
+                return Err(LimitExceeded {
+                    what: format!("line of more than {max_bytes} bytes"),
+                    max_bytes,
+                }
+                .into())
             }
-            Tensor::from_vec(arr, shape, &Device::Cpu)
-        }
-        DType::U32 => {
-            let mut arr = vec![];
-            let mut data = [0u8; std::mem::size_of::<u32>()];
-            for _ in 0..shape.elem_count() {
-                reader.read_exact(&mut data).await?;
-                arr.push(u32::from_le_bytes(data));
+            Ok(byte) => line.push(byte),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && !line.is_empty() => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(line)
+}
+
+/// Short name of a dtype as used by the self-describing codecs.
+pub(crate) fn dtype_name(dtype: DType) -> &'static str {
+    match dtype {
+        DType::BF16 => "bf16",
+        DType::F16 => "f16",
+        DType::F32 => "f32",
+        DType::F64 => "f64",
+        DType::U8 => "u8",
+        DType::U32 => "u32",
+        DType::I64 => "i64",
+    }
+}
+
+/// Parse a dtype from its short name, see [`dtype_name`].
+pub(crate) fn dtype_from_name(name: &str) -> Result<DType> {
+    match name {
+        "bf16" => Ok(DType::BF16),
+        "f16" => Ok(DType::F16),
+        "f32" => Ok(DType::F32),
+        "f64" => Ok(DType::F64),
+        "u8" | "bool" => Ok(DType::U8),
+        "u32" => Ok(DType::U32),
+        "i64" => Ok(DType::I64),
+        name => Err(Error::Msg(format!("unrecognized dtype {name}"))),
+    }
+}
+
+/// Read a `numpy` `.npz` archive of named arrays from the stream.
+///
+/// The archive must be prefixed by its length in bytes as a little-endian `u64`
+/// since the zip central directory is stored at the end of the archive.
+pub async fn read_npz<T>(reader: T) -> Result<HashMap<String, Tensor>>
+where
+    T: AsyncReadExt + Unpin,
+{
+    read_npz_with_limit(reader, usize::MAX).await
+}
+
+/// Read a `numpy` `.npz` archive of named arrays from the stream, rejecting
+/// archives longer than `max_bytes`, or whose arrays unpack to more than
+/// `max_bytes` in total, before allocating.
+pub async fn read_npz_with_limit<T>(
+    mut reader: T,
+    max_bytes: usize,
+) -> Result<HashMap<String, Tensor>>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let bytes = read_length_prefixed(&mut reader, max_bytes).await?;
+
+    // unpack the archive before decoding as zip entries are not `Send`
+    let mut entries = vec![];
+    {
+        let mut zip = zip::ZipArchive::new(std::io::Cursor::new(bytes))?;
+        let mut remaining = max_bytes as u64;
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i)?;
+            let name = file.name();
+            let name = name.strip_suffix(".npy").unwrap_or(name).to_string();
+            // the sizes in the archive can't be trusted, so read at most one
+            // byte past what's left of the limit
+            let mut data = Vec::new();
+            let mut member = std::io::Read::take(&mut file, remaining.saturating_add(1));
+            std::io::Read::read_to_end(&mut member, &mut data)?;
+            if data.len() as u64 > remaining {
+                return Err(LimitExceeded {
+                    what: format!("npz archive member {name}"),
+                    max_bytes,
+                }
+                .into());
             }
-            Tensor::from_vec(arr, shape, &Device::Cpu)
+            remaining -= data.len() as u64;
+            entries.push((name, data));
         }
     }
+
+    let mut tensors = HashMap::new();
+    for (name, data) in entries {
+        let tensor = read_numpy_with_limit(data.as_slice(), &Device::Cpu, max_bytes).await?;
+        tensors.insert(name, tensor);
+    }
+    Ok(tensors)
 }
 
-/// Write a `Tensor` to the stream in `numpy` array format.
-pub async fn write_numpy<T>(tensor: &Tensor, f: &mut T) -> Result<()>
+/// Write named `Tensor`s to the stream as a `numpy` `.npz` archive prefixed by
+/// its length in bytes as a little-endian `u64`.
+pub async fn write_npz<T>(tensors: &[(&str, &Tensor)], f: &mut T) -> Result<()>
 where
     T: AsyncWriteExt + Unpin,
 {
-    let header = Header {
-        descr: tensor.dtype(),
-        fortran_order: false,
-        shape: tensor.dims().to_vec(),
+    let mut arrays = vec![];
+    for (name, tensor) in tensors {
+        let mut data = Vec::new();
+        write_numpy(tensor, &mut data).await?;
+        arrays.push((format!("{name}.npy"), data));
+    }
+
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options =
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    for (name, data) in arrays {
+        zip.start_file(name, options)?;
+        std::io::Write::write_all(&mut zip, &data)?;
+    }
+    let bytes = zip.finish()?.into_inner();
+
+    f.write_u64_le(bytes.len() as u64).await?;
+    f.write_all(&bytes).await?;
+    Ok(())
+}
+
+/// The `DType` of a numpy type descriptor such as `<f4` or `b1`. The byte
+/// order prefix is optional, numpy booleans map to `U8` and all signed
+/// integers to `I64`.
+pub fn dtype_from_descr(descr: &str) -> Result<DType> {
+    Ok(parse_descr(descr)?.0)
+}
+
+/// The `DType` of a numpy type descriptor and the size in bytes of each
+/// stored value.
+fn parse_descr(descr: &str) -> Result<(DType, usize)> {
+    // the only supported types in tensor are:
+    //     float64, float32, float16,
+    //     complex64, complex128,
+    //     int64, uint32, uint8, and bool.
+    // narrower signed integers are widened to int64
+    let dtype = match descr.trim_start_matches(['=', '<', '>', '|']) {
+        "e" | "f2" => (DType::F16, 2),
+        "f" | "f4" => (DType::F32, 4),
+        "d" | "f8" => (DType::F64, 8),
+        "q" | "l" | "i8" => (DType::I64, 8),
+        "i" | "i4" => (DType::I64, 4),
+        "h" | "i2" => (DType::I64, 2),
+        "b" | "i1" => (DType::I64, 1),
+        "B" | "u1" => (DType::U8, 1),
+        "I" | "u4" => (DType::U32, 4),
+        "?" | "b1" => (DType::U8, 1),
+        // "F" | "F4" => DType::C64,
+        // "D" | "F8" => DType::C128,
+        _ => return Err(HeaderError::UnrecognizedDescr(descr.to_string()).into()),
+    };
+    Ok(dtype)
+}
+
+/// The numpy type descriptor of a `DType` including its byte order, e.g.
+/// `<f4`. `U8` values are described as booleans if `boolean` is set.
+pub fn dtype_descr(dtype: DType, boolean: bool, big_endian: bool) -> Result<String> {
+    let descr = match dtype {
+        DType::BF16 => Err(Error::Npy("bf16 is not supported".into()))?,
+        DType::F16 => "f2",
+        DType::F32 => "f4",
+        DType::F64 => "f8",
+        DType::U32 => "u4",
+        DType::I64 => "i8",
+        DType::U8 if boolean => "b1",
+        DType::U8 => "u1",
+    };
+    let byte_order = match dtype {
+        DType::U8 => '|',
+        _ if big_endian => '>',
+        _ => '<',
     };
-    let mut header = header.to_string()?;
-    let pad = 16 - (NPY_MAGIC_STRING.len() + 5 + header.len()) % 16;
+    Ok(format!("{byte_order}{descr}"))
+}
+
+/// Pad the header with spaces and a newline so the data is 16 byte aligned.
+fn pad_header(mut header: String, header_len_len: usize) -> String {
+    let pad = 16 - (NPY_MAGIC_STRING.len() + 3 + header_len_len + header.len()) % 16;
     for _ in 0..pad % 16 {
         header.push(' ')
     }
     header.push('\n');
+    header
+}
 
-    let mut payload = Vec::new();
-    payload.extend_from_slice(NPY_MAGIC_STRING);
-    payload.extend_from_slice(&[1u8, 0u8]);
-    payload.extend_from_slice(&[(header.len() % 256) as u8, (header.len() / 256) as u8]);
-    payload.extend_from_slice(header.as_bytes());
-
-    let mut value_bytes = Vec::new();
-    let vs = tensor.flatten_all()?;
-    for v in vs.to_vec1::<f64>()? {
-        value_bytes.extend_from_slice(&v.to_le_bytes());
-    }
-    payload.extend_from_slice(&value_bytes);
-
-    f.write_all(&payload).await?;
+/// Read and parse a `numpy` array header, leaving the stream at the start of
+/// the array body.
+pub async fn read_npy_header<T>(reader: &mut T) -> Result<Header>
+where
+    T: AsyncReadExt + Unpin,
+{
+    Header::parse(&read_header(reader).await?)
+}
 
+/// Write a `numpy` array header, the array body should follow.
+pub async fn write_npy_header<T>(header: &Header, f: &mut T) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    f.write_all(&header.to_bytes()?).await?;
     Ok(())
 }
 
@@ -117,42 +688,119 @@ async fn read_header<T>(reader: &mut T) -> Result<String>
 where
     T: AsyncReadExt + Unpin,
 {
-    let mut magic_string = vec![0u8; NPY_MAGIC_STRING.len()];
-    reader.read_exact(&mut magic_string).await?;
-    if magic_string != NPY_MAGIC_STRING {
-        return Err(Error::Npy("magic string mismatch".to_string()));
-    }
-    let mut version = [0u8; 2];
-    reader.read_exact(&mut version).await?;
-    let header_len_len = match version[0] {
+    let mut header = BufferPool::global().get();
+    let start = read_header_into(reader, &mut header).await?;
+    match std::str::from_utf8(&header[start..]) {
+        Ok(header) => Ok(header.to_string()),
+        Err(_) => Err(HeaderError::NotText.into()),
+    }
+}
+
+/// Read the raw bytes of a `numpy` array header, from the magic string to
+/// the end of the header text, leaving the stream at the start of the array
+/// body.
+pub(crate) async fn read_npy_header_bytes<T>(reader: &mut T) -> Result<Vec<u8>>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let mut header = Vec::new();
+    read_header_into(reader, &mut header).await?;
+    Ok(header)
+}
+
+/// Read a `numpy` array header into `buf`, returning the offset of the
+/// header text.
+async fn read_header_into<T>(reader: &mut T, buf: &mut Vec<u8>) -> Result<usize>
+where
+    T: AsyncReadExt + Unpin,
+{
+    buf.resize(NPY_MAGIC_STRING.len() + 2, 0);
+    reader.read_exact(buf).await?;
+    if &buf[..NPY_MAGIC_STRING.len()] != NPY_MAGIC_STRING {
+        return Err(HeaderError::MagicMismatch.into());
+    }
+    let header_len_len = match buf[NPY_MAGIC_STRING.len()] {
         1 => 2,
-        2 => 4,
-        otherwise => return Err(Error::Npy(format!("unsupported version {otherwise}"))),
+        2 | 3 => 4,
+        otherwise => return Err(HeaderError::UnsupportedVersion(otherwise).into()),
     };
     let mut header_len = vec![0u8; header_len_len];
     reader.read_exact(&mut header_len).await?;
+    buf.extend_from_slice(&header_len);
     let header_len = header_len
         .iter()
         .rev()
         .fold(0_usize, |acc, &v| 256 * acc + v as usize);
-    let mut header = vec![0u8; header_len];
-    reader.read_exact(&mut header).await?;
-    Ok(String::from_utf8_lossy(&header).to_string())
+    if header_len > MAX_HEADER_LEN {
+        return Err(HeaderError::TooLong(header_len).into());
+    }
+    let start = buf.len();
+    buf.resize(start + header_len, 0);
+    reader.read_exact(&mut buf[start..]).await?;
+    Ok(start)
 }
 
-#[derive(Debug, PartialEq)]
-struct Header {
-    descr: DType,
-    fortran_order: bool,
-    shape: Vec<usize>,
+/// The header of a `numpy` array.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// The dtype of the array values.
+    pub descr: DType,
+    /// Whether the array holds numpy booleans, stored as `U8`.
+    pub boolean: bool,
+    /// Size in bytes of each stored value. Narrower signed integers are
+    /// stored in fewer bytes than their `I64` dtype.
+    pub itemsize: usize,
+    /// Whether multi-byte values are stored big-endian.
+    pub big_endian: bool,
+    /// Whether the array is stored in column-major order.
+    pub fortran_order: bool,
+    /// The dimensions of the array, empty for a scalar.
+    pub shape: Vec<usize>,
 }
 
 impl Header {
-    fn shape(&self) -> Shape {
+    /// The header of a row-major little-endian array holding `tensor`.
+    pub fn for_tensor(tensor: &Tensor) -> Header {
+        Header {
+            descr: tensor.dtype(),
+            boolean: false,
+            itemsize: tensor.dtype().size_in_bytes(),
+            big_endian: false,
+            fortran_order: false,
+            shape: tensor.dims().to_vec(),
+        }
+    }
+
+    /// The shape of the array.
+    pub fn shape(&self) -> Shape {
         Shape::from(self.shape.as_slice())
     }
 
-    fn to_string(&self) -> Result<String> {
+    /// The serialized header: magic string, version, header length and the
+    /// header dictionary padded so the array body is 16 byte aligned.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let header = self.to_string()?;
+        // version 1 stores the header length in 2 bytes, fall back to version 2
+        // with a 4 byte length for headers that do not fit
+        let (version, header) = match pad_header(header.clone(), 2) {
+            padded if padded.len() <= u16::MAX as usize => (1u8, padded),
+            _ => (2u8, pad_header(header, 4)),
+        };
+        let mut bytes = Vec::with_capacity(NPY_MAGIC_STRING.len() + 6 + header.len());
+        bytes.extend_from_slice(NPY_MAGIC_STRING);
+        bytes.extend_from_slice(&[version, 0u8]);
+        if version == 1 {
+            bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        } else {
+            bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        }
+        bytes.extend_from_slice(header.as_bytes());
+        Ok(bytes)
+    }
+
+    /// The header dictionary, e.g.
+    /// `{'descr': '<f8', 'fortran_order': False, 'shape': (128,), }`.
+    pub fn to_string(&self) -> Result<String> {
         let fortran_order = if self.fortran_order { "True" } else { "False" };
         let mut shape = self
             .shape
@@ -160,119 +808,336 @@ impl Header {
             .map(|x| x.to_string())
             .collect::<Vec<_>>()
             .join(",");
-        let descr = match self.descr {
-            DType::BF16 => Err(Error::Npy("bf16 is not supported".into()))?,
-            DType::F16 => "f2",
-            DType::F32 => "f4",
-            DType::F64 => "f8",
-            DType::U32 => "u4",
-            DType::U8 => "u1",
-        };
+        if self.itemsize != self.descr.size_in_bytes() {
+            return Err(Error::Npy(format!(
+                "cannot write {:?} values as {} bytes",
+                self.descr, self.itemsize
+            )));
+        }
+        let descr = dtype_descr(self.descr, self.boolean, self.big_endian)?;
         if !shape.is_empty() {
             shape.push(',')
         }
         Ok(format!(
-            "{{'descr': '<{descr}', 'fortran_order': {fortran_order}, 'shape': ({shape}), }}"
+            "{{'descr': '{descr}', 'fortran_order': {fortran_order}, 'shape': ({shape}), }}"
         ))
     }
 
-    // Hacky parser for the npy header, a typical example would be:
-    // {'descr': '<f8', 'fortran_order': False, 'shape': (128,), }
-    fn parse(header: &str) -> Result<Header> {
-        let header =
-            header.trim_matches(|c: char| c == '{' || c == '}' || c == ',' || c.is_whitespace());
-
-        let mut parts: Vec<String> = vec![];
-        let mut start_index = 0usize;
-        let mut cnt_parenthesis = 0i64;
-        for (index, c) in header.chars().enumerate() {
-            match c {
-                '(' => cnt_parenthesis += 1,
-                ')' => cnt_parenthesis -= 1,
-                ',' => {
-                    if cnt_parenthesis == 0 {
-                        parts.push(header[start_index..index].to_owned());
-                        start_index = index + 1;
-                    }
-                }
-                _ => {}
-            }
-        }
-        parts.push(header[start_index..].to_owned());
-        let mut part_map: HashMap<String, String> = HashMap::new();
-        for part in parts.iter() {
-            let part = part.trim();
-            if !part.is_empty() {
-                match part.split(':').collect::<Vec<_>>().as_slice() {
-                    [key, value] => {
-                        let key = key.trim_matches(|c: char| c == '\'' || c.is_whitespace());
-                        let value = value.trim_matches(|c: char| c == '\'' || c.is_whitespace());
-                        let _ = part_map.insert(key.to_owned(), value.to_owned());
-                    }
-                    _ => return Err(Error::Npy(format!("unable to parse header {header}"))),
-                }
+    /// Parse the header dictionary, see [`Header::to_string`].
+    ///
+    /// The dictionary must hold a `descr` and a `shape` and may hold a
+    /// `fortran_order`, with nothing but padding after it.
+    pub fn parse(header: &str) -> Result<Header> {
+        let mut parser = HeaderParser {
+            input: header,
+            pos: 0,
+        };
+        let mut descr = None;
+        let mut fortran_order = None;
+        let mut shape = None;
+        for (key, value) in parser.dict()? {
+            let slot = match key.as_str() {
+                "descr" => &mut descr,
+                "fortran_order" => &mut fortran_order,
+                "shape" => &mut shape,
+                _ => return Err(HeaderError::UnknownKey(key).into()),
+            };
+            if slot.replace(value).is_some() {
+                return Err(HeaderError::DuplicateKey(key).into());
             }
         }
-        let fortran_order = match part_map.get("fortran_order") {
-            None => false,
-            Some(fortran_order) => match fortran_order.as_ref() {
-                "False" => false,
-                "True" => true,
-                _ => return Err(Error::Npy(format!("unknown fortran_order {fortran_order}"))),
-            },
+
+        let descr = match descr {
+            Some(HeaderValue::Str(descr)) => descr,
+            Some(value) => return Err(HeaderError::invalid("descr", value).into()),
+            None => return Err(HeaderError::MissingKey("descr").into()),
         };
-        let descr = match part_map.get("descr") {
-            None => return Err(Error::Npy("no descr in header".to_string())),
-            Some(descr) => {
-                if descr.is_empty() {
-                    return Err(Error::Npy("empty descr".to_string()));
-                }
-                if descr.starts_with('>') {
-                    return Err(Error::Npy(format!("little-endian descr {descr}")));
-                }
-                // the only supported types in tensor are:
-                //     float64, float32, float16,
-                //     complex64, complex128,
-                //     int64, int32, int16, int8,
-                //     uint8, and bool.
-                match descr.trim_matches(|c: char| c == '=' || c == '<' || c == '|') {
-                    "e" | "f2" => DType::F16,
-                    "f" | "f4" => DType::F32,
-                    "d" | "f8" => DType::F64,
-                    // "i" | "i4" => DType::S32,
-                    // "h" | "i2" => DType::S16,
-                    // "b" | "i1" => DType::S8,
-                    "B" | "u1" => DType::U8,
-                    "I" | "u4" => DType::U32,
-                    "?" | "b1" => DType::U8,
-                    // "F" | "F4" => DType::C64,
-                    // "D" | "F8" => DType::C128,
-                    descr => return Err(Error::Npy(format!("unrecognized descr {descr}"))),
-                }
-            }
+        let fortran_order = match fortran_order {
+            Some(HeaderValue::Bool(fortran_order)) => fortran_order,
+            Some(value) => return Err(HeaderError::invalid("fortran_order", value).into()),
+            None => false,
         };
-        let shape = match part_map.get("shape") {
-            None => return Err(Error::Npy("no shape in header".to_string())),
-            Some(shape) => {
-                let shape = shape.trim_matches(|c: char| c == '(' || c == ')' || c == ',');
-                if shape.is_empty() {
-                    vec![]
-                } else {
-                    shape
-                        .split(',')
-                        .map(|v| v.trim().parse::<usize>())
-                        .collect::<std::result::Result<Vec<_>, _>>()?
-                }
-            }
+        let shape = match shape {
+            Some(HeaderValue::Tuple(shape)) => shape,
+            Some(value) => return Err(HeaderError::invalid("shape", value).into()),
+            None => return Err(HeaderError::MissingKey("shape").into()),
         };
+
+        let (dtype, itemsize) = parse_descr(&descr)?;
+        let boolean = matches!(descr.trim_start_matches(['=', '<', '|']), "?" | "b1");
+        let big_endian =
+            descr.starts_with('>') || (descr.starts_with('=') && cfg!(target_endian = "big"));
+        // the body size must be representable so it can be checked against limits
+        if shape
+            .iter()
+            .try_fold(dtype.size_in_bytes(), |acc, &d| acc.checked_mul(d))
+            .is_none()
+        {
+            return Err(HeaderError::TooLarge(shape).into());
+        }
         Ok(Header {
-            descr,
+            descr: dtype,
+            boolean,
+            itemsize,
+            big_endian,
             fortran_order,
             shape,
         })
     }
 }
 
+/// Longest accepted header dictionary in bytes.
+pub const MAX_HEADER_LEN: usize = 1024 * 1024;
+
+/// Why a `numpy` array header could not be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The stream does not start with the npy magic string.
+    MagicMismatch,
+    /// The npy format version is not 1, 2 or 3.
+    UnsupportedVersion(u8),
+    /// The declared header length exceeds [`MAX_HEADER_LEN`].
+    TooLong(usize),
+    /// The header is not valid text.
+    NotText,
+    /// The header dictionary is malformed at the given byte offset.
+    Syntax {
+        offset: usize,
+        expected: &'static str,
+    },
+    /// A key appears more than once in the header dictionary.
+    DuplicateKey(String),
+    /// The header dictionary has a key the npy format doesn't define.
+    UnknownKey(String),
+    /// A required key is missing from the header dictionary.
+    MissingKey(&'static str),
+    /// A key holds a value of the wrong kind.
+    InvalidValue { key: &'static str, value: String },
+    /// The `descr` names a dtype that can't be read.
+    UnrecognizedDescr(String),
+    /// The array size in bytes overflows a `usize`.
+    TooLarge(Vec<usize>),
+}
+
+impl HeaderError {
+    fn invalid(key: &'static str, value: HeaderValue) -> HeaderError {
+        HeaderError::InvalidValue {
+            key,
+            value: format!("{value:?}"),
+        }
+    }
+}
+
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeaderError::MagicMismatch => write!(f, "magic string mismatch"),
+            HeaderError::UnsupportedVersion(version) => {
+                write!(f, "unsupported version {version}")
+            }
+            HeaderError::TooLong(len) => write!(
+                f,
+                "header of {len} bytes exceeds the limit of {MAX_HEADER_LEN} bytes"
+            ),
+            HeaderError::NotText => write!(f, "header is not valid text"),
+            HeaderError::Syntax { offset, expected } => {
+                write!(f, "malformed header at byte {offset}, expected {expected}")
+            }
+            HeaderError::DuplicateKey(key) => write!(f, "duplicate key {key} in header"),
+            HeaderError::UnknownKey(key) => write!(f, "unknown key {key} in header"),
+            HeaderError::MissingKey(key) => write!(f, "no {key} in header"),
+            HeaderError::InvalidValue { key, value } => write!(f, "invalid {key} {value}"),
+            HeaderError::UnrecognizedDescr(descr) => write!(f, "unrecognized descr {descr}"),
+            HeaderError::TooLarge(shape) => write!(f, "array of shape {shape:?} is too large"),
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+impl From<HeaderError> for Error {
+    fn from(err: HeaderError) -> Error {
+        Error::wrap(err)
+    }
+}
+
+/// A payload rejected for being larger than a limit, before it was read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitExceeded {
+    /// What was rejected, such as `array of shape [2, 3]`.
+    pub what: String,
+    pub max_bytes: usize,
+}
+
+impl LimitExceeded {
+    /// Whether `err` is a [`LimitExceeded`].
+    pub fn is(err: &Error) -> bool {
+        match err {
+            Error::Wrapped(err) => err.is::<LimitExceeded>(),
+            Error::WithBacktrace { inner, .. } => LimitExceeded::is(inner),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} exceeds the limit of {} bytes",
+            self.what, self.max_bytes
+        )
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+impl From<LimitExceeded> for Error {
+    fn from(err: LimitExceeded) -> Error {
+        Error::wrap(err)
+    }
+}
+
+/// A value in the header dictionary.
+#[derive(Debug)]
+enum HeaderValue {
+    Str(String),
+    Bool(bool),
+    Tuple(Vec<usize>),
+}
+
+/// Parser for the subset of python literals used in npy headers, a typical
+/// example would be:
+/// `{'descr': '<f8', 'fortran_order': False, 'shape': (128,), }`
+struct HeaderParser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl HeaderParser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek().filter(|c| c.is_whitespace()) {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    /// Consume `c` after any whitespace if it is next.
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char, expected: &'static str) -> std::result::Result<(), HeaderError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(expected))
+        }
+    }
+
+    fn error(&self, expected: &'static str) -> HeaderError {
+        HeaderError::Syntax {
+            offset: self.pos,
+            expected,
+        }
+    }
+
+    /// The whole header: a dictionary followed only by padding.
+    fn dict(&mut self) -> std::result::Result<Vec<(String, HeaderValue)>, HeaderError> {
+        self.expect('{', "'{'")?;
+        let mut entries = vec![];
+        while !self.eat('}') {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(':', "':'")?;
+            entries.push((key, self.value()?));
+            if !self.eat(',') {
+                self.expect('}', "',' or '}'")?;
+                break;
+            }
+        }
+        self.skip_whitespace();
+        if self.pos != self.input.len() {
+            return Err(self.error("end of header"));
+        }
+        Ok(entries)
+    }
+
+    fn value(&mut self) -> std::result::Result<HeaderValue, HeaderError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('\'' | '"') => Ok(HeaderValue::Str(self.string()?)),
+            Some('(') => Ok(HeaderValue::Tuple(self.tuple()?)),
+            _ if self.keyword("True") => Ok(HeaderValue::Bool(true)),
+            _ if self.keyword("False") => Ok(HeaderValue::Bool(false)),
+            _ => Err(self.error("a string, tuple or boolean")),
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        if self.input[self.pos..].starts_with(keyword) {
+            self.pos += keyword.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// A quoted string without escapes.
+    fn string(&mut self) -> std::result::Result<String, HeaderError> {
+        let quote = match self.peek() {
+            Some(quote @ ('\'' | '"')) => quote,
+            _ => return Err(self.error("a quoted string")),
+        };
+        let start = self.pos + 1;
+        match self.input[start..].find([quote, '\\']) {
+            Some(len) if self.input[start + len..].starts_with(quote) => {
+                self.pos = start + len + 1;
+                Ok(self.input[start..start + len].to_string())
+            }
+            _ => Err(self.error("a closing quote")),
+        }
+    }
+
+    /// A tuple of non-negative integers, e.g. `()`, `(3,)` or `(2, 3)`.
+    fn tuple(&mut self) -> std::result::Result<Vec<usize>, HeaderError> {
+        self.expect('(', "'('")?;
+        let mut values = vec![];
+        while !self.eat(')') {
+            values.push(self.integer()?);
+            if !self.eat(',') {
+                self.expect(')', "',' or ')'")?;
+                break;
+            }
+        }
+        Ok(values)
+    }
+
+    fn integer(&mut self) -> std::result::Result<usize, HeaderError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let digits = self.input[start..]
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.input.len() - start);
+        let value = self.input[start..start + digits]
+            .parse::<usize>()
+            .map_err(|_| self.error("a dimension"))?;
+        self.pos += digits;
+        // python 2 wrote dimensions as longs, e.g. `(3L,)`
+        self.keyword("L");
+        Ok(value)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -313,4 +1178,419 @@ mod tests {
             ]
         );
     }
+
+    #[tokio::test]
+    async fn test_read_numpy_u8() {
+        let mut f = File::open("tests/eye2_u8.npy").await.unwrap();
+        let tensor = read_numpy(&mut f).await.unwrap();
+        assert_eq!(tensor.dtype(), DType::U8);
+        assert_eq!(tensor.dims(), &[2, 2]);
+        let v = tensor.to_vec2::<u8>().unwrap();
+        assert_eq!(v, vec![vec![1u8, 0u8], vec![0u8, 1u8]]);
+    }
+
+    #[tokio::test]
+    async fn test_read_numpy_u32() {
+        let mut f = File::open("tests/eye2_u32.npy").await.unwrap();
+        let tensor = read_numpy(&mut f).await.unwrap();
+        assert_eq!(tensor.dtype(), DType::U32);
+        assert_eq!(tensor.dims(), &[2, 2]);
+        let v = tensor.to_vec2::<u32>().unwrap();
+        assert_eq!(v, vec![vec![1u32, 0u32], vec![0u32, 1u32]]);
+    }
+
+    #[tokio::test]
+    async fn test_read_numpy_signed_ints() {
+        let mut f = File::open("tests/ints_i8.npy").await.unwrap();
+        let tensor = read_numpy(&mut f).await.unwrap();
+        assert_eq!(tensor.dtype(), DType::I64);
+        assert_eq!(tensor.to_vec1::<i64>().unwrap(), vec![-1, 0, 1 << 40]);
+
+        // narrower integers are widened to i64
+        for path in [
+            "tests/ints_i4.npy",
+            "tests/ints_i2_be.npy",
+            "tests/ints_i1.npy",
+        ] {
+            let mut f = File::open(path).await.unwrap();
+            let tensor = read_numpy(&mut f).await.unwrap();
+            assert_eq!(tensor.dtype(), DType::I64);
+            assert_eq!(tensor.to_vec1::<i64>().unwrap(), vec![-1, 0, 7], "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_numpy_i64() {
+        let tensor = Tensor::new(&[-3i64, 1 << 40], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_numpy(&tensor, &mut buf).await.unwrap();
+        let header = read_npy_header(&mut buf.as_slice()).await.unwrap();
+        assert_eq!(
+            header.to_string().unwrap().get(..15),
+            Some("{'descr': '<i8'")
+        );
+        let roundtrip = read_numpy(buf.as_slice()).await.unwrap();
+        assert_eq!(roundtrip.to_vec1::<i64>().unwrap(), vec![-3, 1 << 40]);
+    }
+
+    #[tokio::test]
+    async fn test_write_numpy_native_dtype() {
+        for (path, dtype) in [
+            ("tests/eye2_f16.npy", DType::F16),
+            ("tests/eye2_f32.npy", DType::F32),
+            ("tests/eye2_f64.npy", DType::F64),
+            ("tests/eye2_u8.npy", DType::U8),
+            ("tests/eye2_u32.npy", DType::U32),
+        ] {
+            let mut f = File::open(path).await.unwrap();
+            let tensor = read_numpy(&mut f).await.unwrap();
+            let mut buf = Vec::new();
+            write_numpy(&tensor, &mut buf).await.unwrap();
+            let roundtrip = read_numpy(buf.as_slice()).await.unwrap();
+            assert_eq!(roundtrip.dtype(), dtype);
+            assert_eq!(roundtrip.dims(), &[2, 2]);
+            let v = roundtrip
+                .to_dtype(DType::F64)
+                .unwrap()
+                .to_vec2::<f64>()
+                .unwrap();
+            assert_eq!(v, vec![vec![1.0, 0.0], vec![0.0, 1.0]]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_numpy_bool() {
+        let mut f = File::open("tests/eye2_bool.npy").await.unwrap();
+        let tensor = read_numpy(&mut f).await.unwrap();
+        assert_eq!(tensor.dtype(), DType::U8);
+        assert_eq!(tensor.dims(), &[2, 2]);
+        let v = tensor.to_vec2::<u8>().unwrap();
+        assert_eq!(v, vec![vec![1u8, 0u8], vec![0u8, 1u8]]);
+    }
+
+    #[tokio::test]
+    async fn test_write_numpy_bool() {
+        let tensor = Tensor::new(&[[3u8, 0u8], [0u8, 1u8]], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_numpy_bool(&tensor, &mut buf).await.unwrap();
+        let header = read_npy_header(&mut buf.as_slice()).await.unwrap();
+        assert!(header.boolean);
+        let roundtrip = read_numpy(buf.as_slice()).await.unwrap();
+        let v = roundtrip.to_vec2::<u8>().unwrap();
+        assert_eq!(v, vec![vec![1u8, 0u8], vec![0u8, 1u8]]);
+
+        let tensor = Tensor::new(&[1f32, 0f32], &Device::Cpu).unwrap();
+        assert!(write_numpy_bool(&tensor, &mut Vec::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_numpy_fortran_order() {
+        let mut f = File::open("tests/arange6_fortran_f32.npy").await.unwrap();
+        let tensor = read_numpy(&mut f).await.unwrap();
+        assert_eq!(tensor.dtype(), DType::F32);
+        assert_eq!(tensor.dims(), &[2, 3]);
+        let v = tensor.to_vec2::<f32>().unwrap();
+        assert_eq!(v, vec![vec![0f32, 1f32, 2f32], vec![3f32, 4f32, 5f32]]);
+    }
+
+    #[tokio::test]
+    async fn test_write_numpy_long_header() {
+        let tensor = Tensor::zeros(vec![1usize; 40_000], DType::F32, &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_numpy(&tensor, &mut buf).await.unwrap();
+        assert_eq!(buf[6], 2);
+        let roundtrip = read_numpy(buf.as_slice()).await.unwrap();
+        assert_eq!(roundtrip.dims(), tensor.dims());
+
+        // version 3 only differs by allowing utf8 in the header
+        buf[6] = 3;
+        let roundtrip = read_numpy(buf.as_slice()).await.unwrap();
+        assert_eq!(roundtrip.dims(), tensor.dims());
+
+        let tensor = Tensor::zeros((2, 2), DType::F32, &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_numpy(&tensor, &mut buf).await.unwrap();
+        assert_eq!(buf[6], 1);
+    }
+
+    #[tokio::test]
+    async fn test_read_numpy_big_endian() {
+        let mut f = File::open("tests/eye2_f64_be.npy").await.unwrap();
+        let tensor = read_numpy(&mut f).await.unwrap();
+        assert_eq!(tensor.dtype(), DType::F64);
+        let v = tensor.to_vec2::<f64>().unwrap();
+        assert_eq!(v, vec![vec![1.0, 0.0], vec![0.0, 1.0]]);
+    }
+
+    #[tokio::test]
+    async fn test_write_numpy_big_endian() {
+        let tensor = Tensor::new(&[1f32, 2f32, 3f32], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_numpy_big_endian(&tensor, &mut buf).await.unwrap();
+        assert_eq!(&buf[buf.len() - 4..], &3f32.to_be_bytes());
+        let roundtrip = read_numpy(buf.as_slice()).await.unwrap();
+        assert_eq!(roundtrip.to_vec1::<f32>().unwrap(), vec![1f32, 2f32, 3f32]);
+    }
+
+    #[tokio::test]
+    async fn test_npz_roundtrip() {
+        let ids = Tensor::new(&[[1u32, 2u32, 3u32]], &Device::Cpu).unwrap();
+        let mask = Tensor::new(&[[1f32, 1f32, 0f32]], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_npz(&[("input_ids", &ids), ("attention_mask", &mask)], &mut buf)
+            .await
+            .unwrap();
+        let tensors = read_npz(buf.as_slice()).await.unwrap();
+        assert_eq!(tensors.len(), 2);
+        assert_eq!(
+            tensors["input_ids"].to_vec2::<u32>().unwrap(),
+            vec![vec![1u32, 2u32, 3u32]]
+        );
+        assert_eq!(
+            tensors["attention_mask"].to_vec2::<f32>().unwrap(),
+            vec![vec![1f32, 1f32, 0f32]]
+        );
+
+        // only a single `.npy` suffix is stripped
+        let mut buf = Vec::new();
+        write_npz(&[("a.npy", &mask)], &mut buf).await.unwrap();
+        let tensors = read_npz(buf.as_slice()).await.unwrap();
+        assert!(tensors.contains_key("a.npy"));
+    }
+
+    #[tokio::test]
+    async fn test_read_npz_with_limit() {
+        let x = Tensor::zeros(1024, DType::U8, &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_npz(&[("x", &x)], &mut buf).await.unwrap();
+        assert!(read_npz_with_limit(buf.as_slice(), 4096).await.is_ok());
+        let err = read_npz_with_limit(buf.as_slice(), 1000).await.unwrap_err();
+        assert!(LimitExceeded::is(&err), "{err}");
+
+        // a forged archive length is rejected before allocating
+        let err = read_npz_with_limit(u64::MAX.to_le_bytes().as_slice(), 4096)
+            .await
+            .unwrap_err();
+        assert!(LimitExceeded::is(&err), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_read_numpy_with_limit() {
+        let mut buf = Vec::new();
+        let tensor = Tensor::new(&[1f32, 2f32], &Device::Cpu).unwrap();
+        write_numpy(&tensor, &mut buf).await.unwrap();
+        let read = read_numpy_with_limit(buf.as_slice(), &Device::Cpu, 8)
+            .await
+            .unwrap();
+        assert_eq!(read.to_vec1::<f32>().unwrap(), vec![1f32, 2f32]);
+        let err = read_numpy_with_limit(buf.as_slice(), &Device::Cpu, 7)
+            .await
+            .unwrap_err();
+        assert!(LimitExceeded::is(&err));
+
+        // a huge declared shape is rejected without allocating or overflowing
+        let header = Header {
+            descr: DType::F64,
+            boolean: false,
+            itemsize: 8,
+            big_endian: false,
+            fortran_order: false,
+            shape: vec![1 << 40, 1 << 40],
+        };
+        let mut buf = Vec::new();
+        write_array(
+            &Tensor::zeros(0, DType::F64, &Device::Cpu).unwrap(),
+            &header,
+            &mut buf,
+        )
+        .await
+        .unwrap();
+        assert!(read_numpy_with_limit(buf.as_slice(), &Device::Cpu, 1 << 30)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_numpy_with_deadline() {
+        // the peer never sends anything
+        let (_writer, reader) = tokio::io::duplex(64);
+        let deadline = Instant::now() + std::time::Duration::from_millis(10);
+        let err = read_numpy_with_deadline(reader, deadline)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Io(e) if e.kind() == std::io::ErrorKind::TimedOut));
+
+        let mut buf = Vec::new();
+        let tensor = Tensor::new(&[1f32, 2f32], &Device::Cpu).unwrap();
+        let deadline = Instant::now() + std::time::Duration::from_secs(1);
+        write_numpy_with_deadline(&tensor, &mut buf, deadline)
+            .await
+            .unwrap();
+        let read = read_numpy_with_deadline(buf.as_slice(), deadline)
+            .await
+            .unwrap();
+        assert_eq!(read.to_vec1::<f32>().unwrap(), vec![1f32, 2f32]);
+    }
+
+    #[tokio::test]
+    async fn test_read_numpy_scalar() {
+        // np.save(f, np.float64(3.0))
+        let mut f = File::open("tests/scalar_f64.npy").await.unwrap();
+        let tensor = read_numpy(&mut f).await.unwrap();
+        assert_eq!(tensor.dims(), &[] as &[usize]);
+        assert_eq!(tensor.to_scalar::<f64>().unwrap(), 3f64);
+    }
+
+    #[tokio::test]
+    async fn test_write_numpy_scalar() {
+        let tensor = Tensor::new(3f32, &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_numpy(&tensor, &mut buf).await.unwrap();
+        let header = String::from_utf8_lossy(&buf[10..buf.len() - 4]).to_string();
+        assert!(header.contains("'shape': ()"));
+        let roundtrip = read_numpy(buf.as_slice()).await.unwrap();
+        assert_eq!(roundtrip.dims(), &[] as &[usize]);
+        assert_eq!(roundtrip.to_scalar::<f32>().unwrap(), 3f32);
+    }
+
+    #[tokio::test]
+    async fn test_npy_header_roundtrip() {
+        let header = Header {
+            descr: DType::F32,
+            boolean: false,
+            itemsize: 4,
+            big_endian: true,
+            fortran_order: true,
+            shape: vec![2, 3],
+        };
+        assert_eq!(
+            header.to_string().unwrap(),
+            "{'descr': '>f4', 'fortran_order': True, 'shape': (2,3,), }"
+        );
+        let mut buf = Vec::new();
+        write_npy_header(&header, &mut buf).await.unwrap();
+        assert_eq!(buf.len() % 16, 0);
+        assert_eq!(read_npy_header(&mut buf.as_slice()).await.unwrap(), header);
+
+        assert_eq!(dtype_from_descr("<u4").unwrap(), DType::U32);
+        assert_eq!(dtype_from_descr("?").unwrap(), DType::U8);
+        assert!(dtype_from_descr("<c8").is_err());
+        assert_eq!(dtype_descr(DType::U8, true, false).unwrap(), "|b1");
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let header = Header::parse(
+            "{'descr': '<f8', 'fortran_order': False, 'shape': (3, 4), }          \n",
+        )
+        .unwrap();
+        assert_eq!(header.descr, DType::F64);
+        assert_eq!(header.shape, vec![3, 4]);
+
+        // double quotes, python 2 longs and a missing trailing comma
+        let header = Header::parse("{\"descr\": \"|b1\", \"shape\": (3L,)}").unwrap();
+        assert!(header.boolean);
+        assert_eq!(header.shape, vec![3]);
+
+        for header in [
+            "",
+            "{",
+            "{'descr': '<f8', 'shape': (3,), } trailing",
+            "{'descr': '<f8', 'shape': (3,), 'shape': (3,)}",
+            "{'descr': '<f8', 'shape': (3,), 'extra': True}",
+            "{'descr': '<f8'}",
+            "{'descr': '<f8', 'shape': (-1,)}",
+            "{'descr': '<f8', 'shape': (3 4)}",
+            "{'descr': '<f8', 'shape': '3'}",
+            "{'descr': '<f8, 'shape': (3,)}",
+            "{'descr': '<f8', 'shape': (99999999999999999999999,)}",
+            "{'descr': '<f8', 'shape': (4294967296, 4294967296)}",
+            "{'descr': '<c8', 'shape': (3,)}",
+            "{'descr': '<f8', 'fortran_order': 'no', 'shape': (3,)}",
+        ] {
+            assert!(Header::parse(header).is_err(), "{header}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_header_too_long() {
+        // a declared length of 4GiB is rejected before allocating
+        let mut buf = NPY_MAGIC_STRING.to_vec();
+        buf.extend_from_slice(&[2, 0]);
+        buf.extend_from_slice(&u32::MAX.to_le_bytes());
+        let err = read_numpy(buf.as_slice()).await.unwrap_err();
+        assert!(err.to_string().contains("exceeds the limit"));
+    }
+
+    #[tokio::test]
+    async fn test_write_numpy_non_contiguous() {
+        // contiguous tensors are copied out of their storage, others are
+        // made contiguous first
+        let tensor = Tensor::new(&[[1f32, 2f32, 3f32], [4f32, 5f32, 6f32]], &Device::Cpu)
+            .unwrap()
+            .t()
+            .unwrap();
+        let mut buf = Vec::new();
+        write_numpy(&tensor, &mut buf).await.unwrap();
+        let roundtrip = read_numpy(buf.as_slice()).await.unwrap();
+        assert_eq!(
+            roundtrip.to_vec2::<f32>().unwrap(),
+            vec![vec![1f32, 4f32], vec![2f32, 5f32], vec![3f32, 6f32]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_numpy_contiguous() {
+        // a contiguous view with an offset into its storage
+        let tensor = Tensor::new(&[[1f32, 2f32], [3f32, 4f32], [5f32, 6f32]], &Device::Cpu)
+            .unwrap()
+            .narrow(0, 1, 2)
+            .unwrap();
+        let mut buf = Vec::new();
+        write_numpy(&tensor, &mut buf).await.unwrap();
+        let header = Header::for_tensor(&tensor).to_bytes().unwrap();
+        let body: Vec<u8> = [3f32, 4f32, 5f32, 6f32]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        assert_eq!(buf, [header, body].concat());
+    }
+
+    #[tokio::test]
+    async fn test_write_numpy_chunked() {
+        // spans several write chunks with a partial final chunk
+        let n = 3 * WRITE_CHUNK_SIZE / 4 + 5;
+        let tensor = Tensor::arange(0u32, n as u32, &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_numpy(&tensor, &mut buf).await.unwrap();
+        let roundtrip = read_numpy(buf.as_slice()).await.unwrap();
+        assert_eq!(
+            roundtrip.to_vec1::<u32>().unwrap(),
+            tensor.to_vec1::<u32>().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_all_vectored_partial_writes() {
+        // a tiny pipe forces writes to be split across the buffers
+        let (mut writer, mut reader) = tokio::io::duplex(5);
+        let write = tokio::spawn(async move {
+            write_all_vectored(&mut writer, &[b"abc", b"", b"defghij", b"k"])
+                .await
+                .unwrap();
+        });
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).await.unwrap();
+        write.await.unwrap();
+        assert_eq!(out, b"abcdefghijk");
+    }
+
+    #[tokio::test]
+    async fn test_write_numpy_empty() {
+        let tensor = Tensor::zeros((0, 3), DType::F32, &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_numpy(&tensor, &mut buf).await.unwrap();
+        let roundtrip = read_numpy(buf.as_slice()).await.unwrap();
+        assert_eq!(roundtrip.dims(), &[0, 3]);
+    }
 }
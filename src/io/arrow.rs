@@ -0,0 +1,196 @@
+//! Module to read and write Arrow IPC streams to the stream.
+//!
+//! A record batch maps onto a 2d `Tensor` with one column per feature, so every
+//! column must share the same primitive type and contain no nulls. On the wire
+//! the IPC stream is prefixed by its length in bytes as a little-endian `u64`.
+use std::marker::Unpin;
+use std::sync::Arc;
+
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Float16Type, Float32Type, Float64Type, Int64Type, UInt32Type, UInt8Type};
+use arrow_array::{
+    Array, ArrayRef, Float16Array, Float32Array, Float64Array, Int64Array, RecordBatch,
+    UInt32Array, UInt8Array,
+};
+use arrow_ipc::reader::StreamReader;
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{DataType, Field, Schema};
+use candle_core::{DType, Device, Error, Result, Tensor};
+use half::f16;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::codec::{check_limit, read_length_prefixed, TensorCodec};
+
+/// Read an Arrow IPC stream from the stream and convert the record batches
+/// into a `(rows, columns)` `Tensor`.
+pub async fn read_arrow<T>(reader: T) -> Result<Tensor>
+where
+    T: AsyncReadExt + Unpin,
+{
+    read_arrow_with_limit(reader, usize::MAX).await
+}
+
+/// Read an Arrow IPC stream from the stream into a `Tensor`, rejecting
+/// streams longer than `max_bytes` before allocating.
+pub async fn read_arrow_with_limit<T>(mut reader: T, max_bytes: usize) -> Result<Tensor>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let bytes = read_length_prefixed(&mut reader, max_bytes).await?;
+
+    let stream = StreamReader::try_new(std::io::Cursor::new(bytes), None).map_err(Error::wrap)?;
+    let mut batches = vec![];
+    for batch in stream {
+        let batch = batch.map_err(Error::wrap)?;
+        let columns = batch
+            .columns()
+            .iter()
+            .map(column_to_tensor)
+            .collect::<Result<Vec<_>>>()?;
+        batches.push(Tensor::stack(&columns, 1)?);
+    }
+    if batches.is_empty() {
+        return Err(Error::Msg("arrow stream contains no batches".to_string()));
+    }
+    Tensor::cat(&batches, 0)
+}
+
+/// Write a 1d or 2d `Tensor` to the stream as an Arrow IPC stream with one
+/// column per feature.
+pub async fn write_arrow<T>(tensor: &Tensor, f: &mut T) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    let tensor = match tensor.rank() {
+        1 => tensor.unsqueeze(1)?,
+        2 => tensor.clone(),
+        rank => {
+            return Err(Error::Msg(format!(
+                "arrow payloads must be 1d or 2d, got rank {rank}"
+            )))
+        }
+    };
+    let columns = tensor.t()?.contiguous()?;
+    let mut fields = vec![];
+    let mut arrays = vec![];
+    for i in 0..columns.dim(0)? {
+        let array = tensor_to_column(&columns.get(i)?)?;
+        fields.push(Field::new(i.to_string(), array.data_type().clone(), false));
+        arrays.push(array);
+    }
+    let schema = Arc::new(Schema::new(fields));
+    let batch = RecordBatch::try_new(schema.clone(), arrays).map_err(Error::wrap)?;
+
+    let mut bytes = Vec::new();
+    {
+        let mut writer = StreamWriter::try_new(&mut bytes, &schema).map_err(Error::wrap)?;
+        writer.write(&batch).map_err(Error::wrap)?;
+        writer.finish().map_err(Error::wrap)?;
+    }
+
+    f.write_u64_le(bytes.len() as u64).await?;
+    f.write_all(&bytes).await?;
+    Ok(())
+}
+
+/// The Arrow IPC stream format.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ArrowCodec;
+
+impl TensorCodec for ArrowCodec {
+    fn name(&self) -> String {
+        "arrow".to_string()
+    }
+
+    async fn decode<R>(&self, reader: &mut R) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        read_arrow(reader).await
+    }
+
+    async fn decode_with_limit<R>(
+        &self,
+        reader: &mut R,
+        device: &Device,
+        max_bytes: usize,
+    ) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let tensor = read_arrow_with_limit(reader, max_bytes).await?;
+        check_limit(tensor.to_device(device)?, max_bytes)
+    }
+
+    async fn encode<W>(&self, tensor: &Tensor, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        write_arrow(tensor, writer).await
+    }
+}
+
+fn column_to_tensor(column: &ArrayRef) -> Result<Tensor> {
+    if column.null_count() > 0 {
+        return Err(Error::Msg(
+            "arrow columns must not contain nulls".to_string(),
+        ));
+    }
+    let device = &Device::Cpu;
+    match column.data_type() {
+        DataType::Float16 => Tensor::new(
+            column.as_primitive::<Float16Type>().values().as_ref(),
+            device,
+        ),
+        DataType::Float32 => Tensor::new(
+            column.as_primitive::<Float32Type>().values().as_ref(),
+            device,
+        ),
+        DataType::Float64 => Tensor::new(
+            column.as_primitive::<Float64Type>().values().as_ref(),
+            device,
+        ),
+        DataType::UInt8 => {
+            Tensor::new(column.as_primitive::<UInt8Type>().values().as_ref(), device)
+        }
+        DataType::UInt32 => Tensor::new(
+            column.as_primitive::<UInt32Type>().values().as_ref(),
+            device,
+        ),
+        DataType::Int64 => {
+            Tensor::new(column.as_primitive::<Int64Type>().values().as_ref(), device)
+        }
+        dtype => Err(Error::Msg(format!("unsupported arrow type {dtype}"))),
+    }
+}
+
+fn tensor_to_column(tensor: &Tensor) -> Result<ArrayRef> {
+    let array: ArrayRef = match tensor.dtype() {
+        DType::F16 => Arc::new(Float16Array::from(tensor.to_vec1::<f16>()?)),
+        DType::F32 => Arc::new(Float32Array::from(tensor.to_vec1::<f32>()?)),
+        DType::F64 => Arc::new(Float64Array::from(tensor.to_vec1::<f64>()?)),
+        DType::U8 => Arc::new(UInt8Array::from(tensor.to_vec1::<u8>()?)),
+        DType::U32 => Arc::new(UInt32Array::from(tensor.to_vec1::<u32>()?)),
+        DType::I64 => Arc::new(Int64Array::from(tensor.to_vec1::<i64>()?)),
+        DType::BF16 => return Err(Error::Msg("bf16 is not supported by arrow".to_string())),
+    };
+    Ok(array)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_arrow_roundtrip() {
+        let x = Tensor::new(&[[1f32, 2f32], [3f32, 4f32], [5f32, 6f32]], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_arrow(&x, &mut buf).await.unwrap();
+        let roundtrip = read_arrow(buf.as_slice()).await.unwrap();
+        assert_eq!(roundtrip.dtype(), DType::F32);
+        assert_eq!(
+            roundtrip.to_vec2::<f32>().unwrap(),
+            vec![vec![1f32, 2f32], vec![3f32, 4f32], vec![5f32, 6f32]]
+        );
+    }
+}
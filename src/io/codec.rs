@@ -0,0 +1,279 @@
+//! Pluggable encodings of tensors on the wire.
+//!
+//! A server reads each request with [`TensorCodec::decode`] and writes the
+//! result with [`TensorCodec::encode`], so custom formats only need to
+//! implement this trait.
+use std::collections::HashMap;
+use std::future::Future;
+use std::marker::Unpin;
+
+use candle_core::{Device, Error, Result, Tensor};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+use super::safetensors::{read_safetensors, read_safetensors_with_limit, write_safetensors};
+use super::{
+    read_npy_header_bytes, read_numpy, read_numpy_to_device, read_numpy_with_limit, write_numpy,
+    LimitExceeded,
+};
+
+/// Reads and writes tensors in a particular wire format.
+pub trait TensorCodec: Send + Sync + 'static {
+    /// Name of the wire format advertised in the connection hello.
+    fn name(&self) -> String {
+        "custom".to_string()
+    }
+
+    /// Read a `Tensor` from the stream.
+    fn decode<R>(&self, reader: &mut R) -> impl Future<Output = Result<Tensor>> + Send
+    where
+        R: AsyncRead + Unpin + Send;
+
+    /// Read a `Tensor` from the stream onto the given device. Codecs that can
+    /// allocate on the device directly should override this.
+    fn decode_to_device<R>(
+        &self,
+        reader: &mut R,
+        device: &Device,
+    ) -> impl Future<Output = Result<Tensor>> + Send
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        async move { self.decode(reader).await?.to_device(device) }
+    }
+
+    /// Read a `Tensor` from the stream onto the given device, rejecting
+    /// payloads larger than `max_bytes`. The default checks the decoded
+    /// tensor, so codecs reading a length from the peer must override this
+    /// and reject it before allocating, see [`read_length_prefixed`].
+    fn decode_with_limit<R>(
+        &self,
+        reader: &mut R,
+        device: &Device,
+        max_bytes: usize,
+    ) -> impl Future<Output = Result<Tensor>> + Send
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        async move { check_limit(self.decode_to_device(reader, device).await?, max_bytes) }
+    }
+
+    /// Read the header that starts an encoded tensor and return its bytes,
+    /// which are then decoded along with the rest of the payload. Servers
+    /// read it within their header read timeout, so codecs with a header of
+    /// their own should override this. The default reads nothing.
+    fn read_header<R>(&self, _reader: &mut R) -> impl Future<Output = Result<Vec<u8>>> + Send
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        async { Ok(Vec::new()) }
+    }
+
+    /// Write a `Tensor` to the stream.
+    fn encode<W>(&self, tensor: &Tensor, writer: &mut W) -> impl Future<Output = Result<()>> + Send
+    where
+        W: AsyncWrite + Unpin + Send;
+}
+
+/// Read a payload prefixed by its length in bytes as a little-endian `u64`,
+/// rejecting lengths over `max_bytes` before allocating. The buffer only grows
+/// as bytes arrive, so a forged length can't reserve memory that's never sent.
+pub async fn read_length_prefixed<R>(reader: &mut R, max_bytes: usize) -> Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let len = reader.read_u64_le().await?;
+    if len > max_bytes as u64 {
+        return Err(LimitExceeded {
+            what: format!("payload of {len} bytes"),
+            max_bytes,
+        }
+        .into());
+    }
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes).await?;
+    if bytes.len() as u64 != len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(bytes)
+}
+
+/// Reject a decoded `tensor` larger than `max_bytes`.
+pub(super) fn check_limit(tensor: Tensor, max_bytes: usize) -> Result<Tensor> {
+    let nbytes = tensor.elem_count() * tensor.dtype().size_in_bytes();
+    if nbytes > max_bytes {
+        return Err(LimitExceeded {
+            what: format!("tensor of {nbytes} bytes"),
+            max_bytes,
+        }
+        .into());
+    }
+    Ok(tensor)
+}
+
+/// The `numpy` array format.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NpyCodec;
+
+impl TensorCodec for NpyCodec {
+    fn name(&self) -> String {
+        "npy".to_string()
+    }
+
+    async fn decode<R>(&self, reader: &mut R) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        read_numpy(reader).await
+    }
+
+    async fn decode_to_device<R>(&self, reader: &mut R, device: &Device) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        read_numpy_to_device(reader, device).await
+    }
+
+    async fn decode_with_limit<R>(
+        &self,
+        reader: &mut R,
+        device: &Device,
+        max_bytes: usize,
+    ) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        read_numpy_with_limit(reader, device, max_bytes).await
+    }
+
+    async fn read_header<R>(&self, reader: &mut R) -> Result<Vec<u8>>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        read_npy_header_bytes(reader).await
+    }
+
+    async fn encode<W>(&self, tensor: &Tensor, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        write_numpy(tensor, writer).await
+    }
+}
+
+/// The `safetensors` format holding a single tensor. Responses are written
+/// under the name `"tensor"`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SafeTensorsCodec;
+
+impl TensorCodec for SafeTensorsCodec {
+    fn name(&self) -> String {
+        "safetensors".to_string()
+    }
+
+    async fn decode<R>(&self, reader: &mut R) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        single_tensor(read_safetensors(reader).await?)
+    }
+
+    async fn decode_with_limit<R>(
+        &self,
+        reader: &mut R,
+        device: &Device,
+        max_bytes: usize,
+    ) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let tensors = read_safetensors_with_limit(reader, max_bytes).await?;
+        check_limit(single_tensor(tensors)?.to_device(device)?, max_bytes)
+    }
+
+    async fn encode<W>(&self, tensor: &Tensor, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        write_safetensors(&[("tensor", tensor)], writer).await
+    }
+}
+
+fn single_tensor(mut tensors: HashMap<String, Tensor>) -> Result<Tensor> {
+    if tensors.len() != 1 {
+        return Err(Error::Msg(format!(
+            "expected a single tensor, got {}",
+            tensors.len()
+        )));
+    }
+    let name = tensors.keys().next().cloned().unwrap_or_default();
+    tensors
+        .remove(&name)
+        .ok_or_else(|| Error::Msg("missing tensor".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    async fn roundtrip<C: TensorCodec>(codec: C) {
+        let x = Tensor::new(&[[1f32, 2f32], [3f32, 4f32]], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        codec.encode(&x, &mut buf).await.unwrap();
+        let y = codec.decode(&mut buf.as_slice()).await.unwrap();
+        assert_eq!(y.to_vec2::<f32>().unwrap(), x.to_vec2::<f32>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_npy_codec() {
+        roundtrip(NpyCodec).await;
+    }
+
+    #[tokio::test]
+    async fn test_npy_codec_read_header() {
+        let x = Tensor::new(&[1f32, 2f32, 3f32], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        NpyCodec.encode(&x, &mut buf).await.unwrap();
+        let mut reader = buf.as_slice();
+        let header = NpyCodec.read_header(&mut reader).await.unwrap();
+        assert_eq!(header.len() + 12, buf.len());
+        let y = NpyCodec
+            .decode(&mut header.as_slice().chain(reader))
+            .await
+            .unwrap();
+        assert_eq!(y.to_vec1::<f32>().unwrap(), vec![1f32, 2f32, 3f32]);
+    }
+
+    #[tokio::test]
+    async fn test_safetensors_codec() {
+        roundtrip(SafeTensorsCodec).await;
+    }
+
+    async fn rejects_length_prefix<C: TensorCodec>(codec: C) {
+        let payload = u64::MAX.to_le_bytes();
+        let err = codec
+            .decode_with_limit(&mut payload.as_slice(), &Device::Cpu, 1 << 20)
+            .await
+            .unwrap_err();
+        assert!(LimitExceeded::is(&err), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_decode_with_limit_rejects_length_prefix() {
+        rejects_length_prefix(SafeTensorsCodec).await;
+        #[cfg(feature = "arrow")]
+        rejects_length_prefix(super::super::arrow::ArrowCodec).await;
+        #[cfg(feature = "msgpack")]
+        rejects_length_prefix(super::super::msgpack::MsgPackCodec).await;
+        #[cfg(feature = "onnx")]
+        rejects_length_prefix(super::super::onnx::OnnxCodec).await;
+
+        // a length within the limit that's never sent is a short read
+        let payload = 64u64.to_le_bytes();
+        let err = SafeTensorsCodec
+            .decode_with_limit(&mut payload.as_slice(), &Device::Cpu, 1 << 20)
+            .await
+            .unwrap_err();
+        assert!(!LimitExceeded::is(&err), "{err}");
+    }
+}
@@ -0,0 +1,186 @@
+//! Compression of frame payloads.
+//!
+//! The algorithm used for a payload is stored in the low bits of the frame
+//! flags, see [`Compression::from_flags`]. The next bits advertise which
+//! algorithms the sender is able to decompress, so the peer can pick one for
+//! its reply, see [`Compression::negotiate`].
+use candle_core::{Error, Result};
+use tokio::io::AsyncRead;
+#[cfg(any(feature = "gzip", feature = "lz4", feature = "zstd"))]
+use tokio::io::AsyncWriteExt;
+
+/// Bits of the frame flags holding the compression algorithm.
+pub const COMPRESSION_MASK: u8 = 0b0000_0111;
+/// Bits of the frame flags advertising the accepted compression algorithms.
+pub const ACCEPT_MASK: u8 = 0b0011_1000;
+
+/// Compression algorithm applied to a frame payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Gzip,
+    Lz4,
+}
+
+impl Compression {
+    /// Every compression algorithm, including those not enabled in this build.
+    pub const ALL: [Compression; 4] = [
+        Compression::None,
+        Compression::Zstd,
+        Compression::Gzip,
+        Compression::Lz4,
+    ];
+
+    /// Read the compression algorithm from the frame flags.
+    pub fn from_flags(flags: u8) -> Result<Compression> {
+        match flags & COMPRESSION_MASK {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zstd),
+            2 => Ok(Compression::Gzip),
+            3 => Ok(Compression::Lz4),
+            id => Err(Error::Msg(format!("unknown compression {id}"))),
+        }
+    }
+
+    /// The frame flag bits for this compression algorithm.
+    pub fn flags(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Gzip => 2,
+            Compression::Lz4 => 3,
+        }
+    }
+
+    /// The frame flag bit advertising that this algorithm is accepted.
+    /// Uncompressed payloads are always accepted.
+    pub fn accept_flag(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1 << 3,
+            Compression::Gzip => 1 << 4,
+            Compression::Lz4 => 1 << 5,
+        }
+    }
+
+    /// Whether support for this algorithm is compiled in.
+    pub fn is_enabled(&self) -> bool {
+        match self {
+            Compression::None => true,
+            Compression::Zstd => cfg!(feature = "zstd"),
+            Compression::Gzip => cfg!(feature = "gzip"),
+            Compression::Lz4 => cfg!(feature = "lz4"),
+        }
+    }
+
+    /// Pick the first enabled algorithm from `preferences` that the peer
+    /// accepts according to its frame flags, falling back to no compression.
+    pub fn negotiate(preferences: &[Compression], peer_flags: u8) -> Compression {
+        preferences
+            .iter()
+            .copied()
+            .find(|c| {
+                c.is_enabled() && (*c == Compression::None || peer_flags & c.accept_flag() != 0)
+            })
+            .unwrap_or(Compression::None)
+    }
+
+    /// Compress a payload.
+    pub async fn compress(&self, payload: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(payload),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                let mut encoder = async_compression::tokio::write::ZstdEncoder::new(Vec::new());
+                encoder.write_all(&payload).await?;
+                encoder.shutdown().await?;
+                Ok(encoder.into_inner())
+            }
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                let mut encoder = async_compression::tokio::write::GzipEncoder::new(Vec::new());
+                encoder.write_all(&payload).await?;
+                encoder.shutdown().await?;
+                Ok(encoder.into_inner())
+            }
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                let mut encoder = async_compression::tokio::write::Lz4Encoder::new(Vec::new());
+                encoder.write_all(&payload).await?;
+                encoder.shutdown().await?;
+                Ok(encoder.into_inner())
+            }
+            #[allow(unreachable_patterns)]
+            compression => Err(compression.unsupported()),
+        }
+    }
+
+    /// Wrap a compressed payload in a reader that decompresses it as it is read.
+    pub fn decompress<'a>(
+        &self,
+        payload: &'a [u8],
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send + 'a>> {
+        match self {
+            Compression::None => Ok(Box::new(payload)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(Box::new(
+                async_compression::tokio::bufread::ZstdDecoder::new(payload),
+            )),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Ok(Box::new(
+                async_compression::tokio::bufread::GzipDecoder::new(payload),
+            )),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(Box::new(
+                async_compression::tokio::bufread::Lz4Decoder::new(payload),
+            )),
+            #[allow(unreachable_patterns)]
+            compression => Err(compression.unsupported()),
+        }
+    }
+
+    fn unsupported(&self) -> Error {
+        Error::Msg(format!("{self:?} compression is not enabled"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_compression_roundtrip() {
+        let payload = vec![0u8; 4096];
+        for compression in Compression::ALL.into_iter().filter(|c| c.is_enabled()) {
+            let compressed = compression.compress(payload.clone()).await.unwrap();
+            let mut decompressed = Vec::new();
+            compression
+                .decompress(&compressed)
+                .unwrap()
+                .read_to_end(&mut decompressed)
+                .await
+                .unwrap();
+            assert_eq!(decompressed, payload);
+            assert_eq!(
+                Compression::from_flags(compression.flags()).unwrap(),
+                compression
+            );
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let preferences = [Compression::Zstd, Compression::Gzip, Compression::None];
+        assert_eq!(Compression::negotiate(&preferences, 0), Compression::None);
+        let expected = if cfg!(feature = "gzip") {
+            Compression::Gzip
+        } else {
+            Compression::None
+        };
+        let peer = Compression::Gzip.accept_flag() | Compression::Lz4.accept_flag();
+        assert_eq!(Compression::negotiate(&preferences, peer), expected);
+    }
+}
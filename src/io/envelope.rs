@@ -0,0 +1,456 @@
+//! Request envelopes carrying per-request metadata.
+//!
+//! A request may be preceded by an envelope holding a client chosen request
+//! ID, flags and the name of the model to run. The server echoes the envelope
+//! in front of the response so clients can match responses to requests.
+//!
+//! | field      | size  | description                            |
+//! |------------|-------|----------------------------------------|
+//! | magic      | 4     | `b"SNNE"`                              |
+//! | request_id | 8     | little-endian u64                      |
+//! | flags      | 1     | bit set of `FLAG_*` constants          |
+//! | model      | 1+n   | length-prefixed model name             |
+//! | version    | 0/1+v | length-prefixed version, if flagged    |
+//! | priority   | 0/1   | u8 priority class, if flagged          |
+//! | token      | 0/2+t | u16 length-prefixed token, if flagged  |
+//! | metadata   | 0/4+m | u32 length-prefixed JSON, if flagged   |
+//! | session    | 0/1+s | length-prefixed session ID, if flagged |
+//! | sampling   | 0/1+p | sampling parameters, if flagged        |
+//!
+//! The metadata is a small JSON sidecar for non-tensor context such as client
+//! IDs or preprocessing hints. The server replaces it with the metadata
+//! returned by the forward pass when echoing the envelope.
+//!
+//! Requests with a higher priority class run first when the server's
+//! inference queue is busy. Requests without one have priority 0.
+//!
+//! A request may carry an API key or bearer token for servers that require
+//! one. The server never echoes the token.
+//!
+//! A server serving several models runs the one named, see
+//! [`Server::register`](crate::server::Server::register). Requests without a
+//! name run the server's own model. A request may pin a version of the
+//! model, otherwise it runs the latest, and the server echoes the version
+//! that ran.
+//!
+//! Requests naming a session run on the state the server keeps for it
+//! between requests, such as a transformer's KV cache, see
+//! [`Server::stateful`](crate::server::Server::stateful).
+//!
+//! Requests to generative models may carry [`SamplingParams`], passed to the
+//! forward pass of [`Server::generate`](crate::server::Server::generate).
+//! They're sent as a byte with the `SAMPLE_*` bit of each parameter given,
+//! followed by the parameters given in the order of the bits, as
+//! little-endian `f32` temperature and top-p, `u32` top-k and max tokens and
+//! `u64` seed. The server never echoes them.
+use std::marker::Unpin;
+
+use candle_core::{Error, Result};
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+pub const ENVELOPE_MAGIC: &[u8; 4] = b"SNNE";
+/// The response is a stream of tensors, see [`crate::io::stream`]. Set on
+/// the echoed envelope exactly when the response is streamed.
+pub const FLAG_STREAMING: u8 = 1 << 0;
+/// The payload that follows is compressed.
+pub const FLAG_COMPRESSED: u8 = 1 << 1;
+/// The token, if any, is followed by a JSON metadata sidecar. Set and
+/// cleared on write depending on whether [`Envelope::metadata`] is present.
+const FLAG_METADATA: u8 = 1 << 2;
+/// The version, if any, is followed by a priority class. Set and cleared on
+/// write depending on whether [`Envelope::priority`] is non-zero.
+const FLAG_PRIORITY: u8 = 1 << 3;
+/// The priority is followed by a token. Set and cleared on write depending on
+/// whether [`Envelope::token`] is present.
+const FLAG_TOKEN: u8 = 1 << 4;
+/// The model name is followed by a model version. Set and cleared on write
+/// depending on whether [`Envelope::version`] is present.
+const FLAG_VERSION: u8 = 1 << 5;
+/// The metadata is followed by a session ID. Set and cleared on write
+/// depending on whether [`Envelope::session`] is present.
+const FLAG_SESSION: u8 = 1 << 6;
+/// The session ID is followed by sampling parameters. Set and cleared on
+/// write depending on whether [`Envelope::sampling`] is present.
+const FLAG_SAMPLING: u8 = 1 << 7;
+/// Flags set and cleared on write depending on the fields present.
+const FIELD_FLAGS: u8 =
+    FLAG_METADATA | FLAG_PRIORITY | FLAG_TOKEN | FLAG_VERSION | FLAG_SESSION | FLAG_SAMPLING;
+const SAMPLE_TEMPERATURE: u8 = 1 << 0;
+const SAMPLE_TOP_K: u8 = 1 << 1;
+const SAMPLE_TOP_P: u8 = 1 << 2;
+const SAMPLE_MAX_TOKENS: u8 = 1 << 3;
+const SAMPLE_SEED: u8 = 1 << 4;
+/// Longest accepted metadata sidecar in bytes.
+pub const MAX_METADATA_LEN: usize = 64 * 1024;
+
+/// Metadata sent in front of a request and echoed in front of its response.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Envelope {
+    pub request_id: u64,
+    pub flags: u8,
+    pub model: Option<String>,
+    pub version: Option<String>,
+    pub priority: u8,
+    pub token: Option<String>,
+    pub metadata: Option<Value>,
+    pub session: Option<String>,
+    pub sampling: Option<SamplingParams>,
+}
+
+/// How a generative model samples its output. Parameters not given are left
+/// to the model's defaults.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SamplingParams {
+    /// Softmax temperature, 0 for greedy decoding.
+    pub temperature: Option<f32>,
+    /// Sample from only the `top_k` most likely tokens.
+    pub top_k: Option<u32>,
+    /// Sample from only the most likely tokens whose probabilities add up
+    /// to `top_p`, between 0 and 1.
+    pub top_p: Option<f32>,
+    /// The most tokens generated.
+    pub max_tokens: Option<u32>,
+    /// Seed of the random number generator, for reproducible samples.
+    pub seed: Option<u64>,
+}
+
+impl SamplingParams {
+    /// Fails if the temperature is negative or the top-p isn't between 0
+    /// and 1.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(temperature) = self.temperature {
+            if !(temperature >= 0. && temperature.is_finite()) {
+                return Err(Error::Msg(format!("invalid temperature {temperature}")));
+            }
+        }
+        if let Some(top_p) = self.top_p {
+            if !(0. ..=1.).contains(&top_p) {
+                return Err(Error::Msg(format!("invalid top-p {top_p}")));
+            }
+        }
+        Ok(())
+    }
+
+    /// The `SAMPLE_*` bits of the parameters given.
+    fn mask(&self) -> u8 {
+        let mut mask = 0;
+        for (given, bit) in [
+            (self.temperature.is_some(), SAMPLE_TEMPERATURE),
+            (self.top_k.is_some(), SAMPLE_TOP_K),
+            (self.top_p.is_some(), SAMPLE_TOP_P),
+            (self.max_tokens.is_some(), SAMPLE_MAX_TOKENS),
+            (self.seed.is_some(), SAMPLE_SEED),
+        ] {
+            if given {
+                mask |= bit;
+            }
+        }
+        mask
+    }
+}
+
+/// Compared bitwise, so envelopes carrying them are `Eq`.
+impl PartialEq for SamplingParams {
+    fn eq(&self, other: &Self) -> bool {
+        self.temperature.map(f32::to_bits) == other.temperature.map(f32::to_bits)
+            && self.top_k == other.top_k
+            && self.top_p.map(f32::to_bits) == other.top_p.map(f32::to_bits)
+            && self.max_tokens == other.max_tokens
+            && self.seed == other.seed
+    }
+}
+
+impl Eq for SamplingParams {}
+
+impl Envelope {
+    pub fn new(request_id: u64) -> Envelope {
+        Envelope {
+            request_id,
+            ..Default::default()
+        }
+    }
+
+    /// Whether every bit of `flag` is set.
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag == flag
+    }
+}
+
+/// Read an envelope from the stream.
+pub async fn read_envelope<T>(reader: &mut T) -> Result<Envelope>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).await?;
+    if &magic != ENVELOPE_MAGIC {
+        return Err(Error::Msg("envelope magic mismatch".to_string()));
+    }
+    read_envelope_body(reader).await
+}
+
+/// Read the rest of an envelope once the magic has been consumed.
+pub async fn read_envelope_body<T>(reader: &mut T) -> Result<Envelope>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let request_id = reader.read_u64_le().await?;
+    let flags = reader.read_u8().await?;
+    let len = reader.read_u8().await? as usize;
+    let mut model = vec![0u8; len];
+    reader.read_exact(&mut model).await?;
+    let model = String::from_utf8(model).map_err(Error::wrap)?;
+    let version = if flags & FLAG_VERSION != 0 {
+        let len = reader.read_u8().await? as usize;
+        let mut version = vec![0u8; len];
+        reader.read_exact(&mut version).await?;
+        Some(String::from_utf8(version).map_err(Error::wrap)?)
+    } else {
+        None
+    };
+    let priority = match flags & FLAG_PRIORITY {
+        0 => 0,
+        _ => reader.read_u8().await?,
+    };
+    let token = if flags & FLAG_TOKEN != 0 {
+        let len = reader.read_u16_le().await? as usize;
+        let mut token = vec![0u8; len];
+        reader.read_exact(&mut token).await?;
+        Some(String::from_utf8(token).map_err(Error::wrap)?)
+    } else {
+        None
+    };
+    let metadata = if flags & FLAG_METADATA != 0 {
+        let len = reader.read_u32_le().await? as usize;
+        if len > MAX_METADATA_LEN {
+            return Err(Error::Msg(format!(
+                "metadata of {len} bytes exceeds the limit of {MAX_METADATA_LEN} bytes"
+            )));
+        }
+        let mut metadata = vec![0u8; len];
+        reader.read_exact(&mut metadata).await?;
+        Some(serde_json::from_slice(&metadata).map_err(Error::wrap)?)
+    } else {
+        None
+    };
+    let session = if flags & FLAG_SESSION != 0 {
+        let len = reader.read_u8().await? as usize;
+        let mut session = vec![0u8; len];
+        reader.read_exact(&mut session).await?;
+        Some(String::from_utf8(session).map_err(Error::wrap)?)
+    } else {
+        None
+    };
+    let sampling = match flags & FLAG_SAMPLING {
+        0 => None,
+        _ => Some(read_sampling(reader).await?),
+    };
+    Ok(Envelope {
+        request_id,
+        flags: flags & !FIELD_FLAGS,
+        model: (!model.is_empty()).then_some(model),
+        version,
+        priority,
+        token,
+        metadata,
+        session,
+        sampling,
+    })
+}
+
+async fn read_sampling<T>(reader: &mut T) -> Result<SamplingParams>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let mask = reader.read_u8().await?;
+    let given = |bit| mask & bit != 0;
+    let sampling = SamplingParams {
+        temperature: match given(SAMPLE_TEMPERATURE) {
+            true => Some(reader.read_f32_le().await?),
+            false => None,
+        },
+        top_k: match given(SAMPLE_TOP_K) {
+            true => Some(reader.read_u32_le().await?),
+            false => None,
+        },
+        top_p: match given(SAMPLE_TOP_P) {
+            true => Some(reader.read_f32_le().await?),
+            false => None,
+        },
+        max_tokens: match given(SAMPLE_MAX_TOKENS) {
+            true => Some(reader.read_u32_le().await?),
+            false => None,
+        },
+        seed: match given(SAMPLE_SEED) {
+            true => Some(reader.read_u64_le().await?),
+            false => None,
+        },
+    };
+    sampling.validate()?;
+    Ok(sampling)
+}
+
+/// Write an envelope to the stream.
+pub async fn write_envelope<T>(envelope: &Envelope, f: &mut T) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    let model = envelope.model.as_deref().unwrap_or_default().as_bytes();
+    if model.len() > u8::MAX as usize {
+        return Err(Error::Msg(format!(
+            "model name too long: {}",
+            envelope.model.as_deref().unwrap_or_default()
+        )));
+    }
+    let version = envelope.version.as_deref().unwrap_or_default().as_bytes();
+    if version.len() > u8::MAX as usize {
+        return Err(Error::Msg(format!(
+            "model version too long: {}",
+            envelope.version.as_deref().unwrap_or_default()
+        )));
+    }
+    let metadata = match &envelope.metadata {
+        Some(metadata) => serde_json::to_vec(metadata).map_err(Error::wrap)?,
+        None => vec![],
+    };
+    if metadata.len() > MAX_METADATA_LEN {
+        return Err(Error::Msg(format!(
+            "metadata of {} bytes exceeds the limit of {MAX_METADATA_LEN} bytes",
+            metadata.len()
+        )));
+    }
+    let token = envelope.token.as_deref().unwrap_or_default().as_bytes();
+    if token.len() > u16::MAX as usize {
+        return Err(Error::Msg(format!(
+            "token of {} bytes is too long",
+            token.len()
+        )));
+    }
+    let session = envelope.session.as_deref().unwrap_or_default().as_bytes();
+    if session.len() > u8::MAX as usize {
+        return Err(Error::Msg(format!(
+            "session ID of {} bytes is too long",
+            session.len()
+        )));
+    }
+    let mut flags = envelope.flags & !FIELD_FLAGS;
+    if envelope.version.is_some() {
+        flags |= FLAG_VERSION;
+    }
+    if envelope.metadata.is_some() {
+        flags |= FLAG_METADATA;
+    }
+    if envelope.priority != 0 {
+        flags |= FLAG_PRIORITY;
+    }
+    if envelope.token.is_some() {
+        flags |= FLAG_TOKEN;
+    }
+    if envelope.session.is_some() {
+        flags |= FLAG_SESSION;
+    }
+    if envelope.sampling.is_some() {
+        flags |= FLAG_SAMPLING;
+    }
+    let capacity = 48 + model.len() + version.len() + token.len() + metadata.len() + session.len();
+    let mut bytes = Vec::with_capacity(capacity);
+    bytes.extend_from_slice(ENVELOPE_MAGIC);
+    bytes.extend_from_slice(&envelope.request_id.to_le_bytes());
+    bytes.push(flags);
+    bytes.push(model.len() as u8);
+    bytes.extend_from_slice(model);
+    if envelope.version.is_some() {
+        bytes.push(version.len() as u8);
+        bytes.extend_from_slice(version);
+    }
+    if envelope.priority != 0 {
+        bytes.push(envelope.priority);
+    }
+    if envelope.token.is_some() {
+        bytes.extend_from_slice(&(token.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(token);
+    }
+    if envelope.metadata.is_some() {
+        bytes.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&metadata);
+    }
+    if envelope.session.is_some() {
+        bytes.push(session.len() as u8);
+        bytes.extend_from_slice(session);
+    }
+    if let Some(sampling) = &envelope.sampling {
+        bytes.push(sampling.mask());
+        if let Some(temperature) = sampling.temperature {
+            bytes.extend_from_slice(&temperature.to_le_bytes());
+        }
+        if let Some(top_k) = sampling.top_k {
+            bytes.extend_from_slice(&top_k.to_le_bytes());
+        }
+        if let Some(top_p) = sampling.top_p {
+            bytes.extend_from_slice(&top_p.to_le_bytes());
+        }
+        if let Some(max_tokens) = sampling.max_tokens {
+            bytes.extend_from_slice(&max_tokens.to_le_bytes());
+        }
+        if let Some(seed) = sampling.seed {
+            bytes.extend_from_slice(&seed.to_le_bytes());
+        }
+    }
+    f.write_all(&bytes).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_envelope_roundtrip() {
+        for envelope in [
+            Envelope::new(7),
+            Envelope {
+                request_id: u64::MAX,
+                flags: FLAG_STREAMING | FLAG_COMPRESSED,
+                model: Some("resnet".to_string()),
+                version: Some("2".to_string()),
+                priority: 0,
+                token: Some("secret".to_string()),
+                metadata: None,
+                session: None,
+                sampling: Some(SamplingParams {
+                    temperature: Some(0.7),
+                    top_p: Some(0.9),
+                    seed: Some(42),
+                    ..Default::default()
+                }),
+            },
+            Envelope {
+                request_id: 1,
+                flags: FLAG_STREAMING,
+                model: None,
+                version: None,
+                priority: 3,
+                token: None,
+                metadata: Some(serde_json::json!({"client": "a", "labels": [1, 2]})),
+                session: Some("chat-42".to_string()),
+                sampling: None,
+            },
+        ] {
+            let mut buf = Vec::new();
+            write_envelope(&envelope, &mut buf).await.unwrap();
+            assert_eq!(read_envelope(&mut buf.as_slice()).await.unwrap(), envelope);
+        }
+
+        let envelope = Envelope {
+            sampling: Some(SamplingParams {
+                top_p: Some(1.5),
+                ..Default::default()
+            }),
+            ..Envelope::new(1)
+        };
+        let mut buf = Vec::new();
+        write_envelope(&envelope, &mut buf).await.unwrap();
+        assert!(read_envelope(&mut buf.as_slice()).await.is_err());
+    }
+}
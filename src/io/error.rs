@@ -0,0 +1,149 @@
+//! Error responses sent in place of a tensor.
+//!
+//! When a request fails the server replies with an error frame instead of the
+//! response tensor, after the echoed envelope if the request had one.
+//!
+//! | field   | size | description                        |
+//! |---------|------|------------------------------------|
+//! | magic   | 4    | `b"SNNX"`                          |
+//! | code    | 2    | little-endian u16, see [`codes`]   |
+//! | message | 4+n  | u32 length-prefixed UTF-8 message  |
+use std::fmt;
+use std::marker::Unpin;
+
+use candle_core::{Error, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+pub const ERROR_MAGIC: &[u8; 4] = b"SNNX";
+/// Longest message sent in an error frame in bytes, longer messages are
+/// truncated.
+pub const MAX_ERROR_MESSAGE_LEN: usize = 64 * 1024;
+
+/// Error codes sent in an error frame.
+pub mod codes {
+    /// The request could not be read. The server closes the connection as
+    /// the rest of the request can't be skipped.
+    pub const BAD_REQUEST: u16 = 1;
+    /// The forward pass failed.
+    pub const FORWARD_FAILED: u16 = 2;
+    /// The request wasn't served within the server's request timeout. The
+    /// server closes the connection.
+    pub const TIMED_OUT: u16 = 3;
+    /// The server's inference queue is full. The connection stays open and
+    /// the request may be retried later.
+    pub const SERVER_BUSY: u16 = 4;
+    /// The request's token was missing or rejected. The server closes the
+    /// connection.
+    pub const UNAUTHORIZED: u16 = 5;
+    /// The client exceeded the server's rate limit. The connection stays
+    /// open and the request may be retried later.
+    pub const THROTTLED: u16 = 6;
+    /// The request is larger than the server accepts. The server closes the
+    /// connection as the rest of the request can't be skipped.
+    pub const REQUEST_TOO_LARGE: u16 = 7;
+    /// The request was read but its tensors were rejected before the forward
+    /// pass. The connection stays open.
+    pub const INVALID_INPUT: u16 = 8;
+    /// The request's API key exceeded its quota. The connection stays open.
+    pub const QUOTA_EXCEEDED: u16 = 9;
+    /// The request named a model the server doesn't serve. The connection
+    /// stays open.
+    pub const MODEL_NOT_FOUND: u16 = 10;
+}
+
+/// An error reported to the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorFrame {
+    pub code: u16,
+    pub message: String,
+}
+
+impl ErrorFrame {
+    pub fn new(code: u16, message: impl Into<String>) -> ErrorFrame {
+        ErrorFrame {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ErrorFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ErrorFrame {}
+
+/// Read an error frame from the stream.
+pub async fn read_error_frame<T>(reader: &mut T) -> Result<ErrorFrame>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).await?;
+    if &magic != ERROR_MAGIC {
+        return Err(Error::Msg("error frame magic mismatch".to_string()));
+    }
+    read_error_frame_body(reader).await
+}
+
+/// Read the rest of an error frame once the magic has been consumed.
+pub async fn read_error_frame_body<T>(reader: &mut T) -> Result<ErrorFrame>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let code = reader.read_u16_le().await?;
+    let len = reader.read_u32_le().await? as usize;
+    if len > MAX_ERROR_MESSAGE_LEN {
+        return Err(Error::Msg(format!(
+            "error message of {len} bytes exceeds the limit of {MAX_ERROR_MESSAGE_LEN} bytes"
+        )));
+    }
+    let mut message = vec![0u8; len];
+    reader.read_exact(&mut message).await?;
+    let message = String::from_utf8(message).map_err(Error::wrap)?;
+    Ok(ErrorFrame { code, message })
+}
+
+/// Write an error frame to the stream.
+pub async fn write_error_frame<T>(frame: &ErrorFrame, f: &mut T) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    let mut len = frame.message.len().min(MAX_ERROR_MESSAGE_LEN);
+    while !frame.message.is_char_boundary(len) {
+        len -= 1;
+    }
+    let message = &frame.message.as_bytes()[..len];
+    let mut bytes = Vec::with_capacity(10 + message.len());
+    bytes.extend_from_slice(ERROR_MAGIC);
+    bytes.extend_from_slice(&frame.code.to_le_bytes());
+    bytes.extend_from_slice(&(message.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(message);
+    f.write_all(&bytes).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_error_frame_roundtrip() {
+        let frame = ErrorFrame::new(codes::FORWARD_FAILED, "shape mismatch");
+        let mut buf = Vec::new();
+        write_error_frame(&frame, &mut buf).await.unwrap();
+        assert_eq!(read_error_frame(&mut buf.as_slice()).await.unwrap(), frame);
+    }
+
+    #[tokio::test]
+    async fn test_error_frame_truncated() {
+        let frame = ErrorFrame::new(codes::BAD_REQUEST, "é".repeat(MAX_ERROR_MESSAGE_LEN));
+        let mut buf = Vec::new();
+        write_error_frame(&frame, &mut buf).await.unwrap();
+        let read = read_error_frame(&mut buf.as_slice()).await.unwrap();
+        assert_eq!(read.message.len(), MAX_ERROR_MESSAGE_LEN);
+        assert!(frame.message.starts_with(&read.message));
+    }
+}
@@ -0,0 +1,546 @@
+//! Length-prefixed framing of payloads on the stream.
+//!
+//! Each frame is laid out as
+//!
+//! | field   | size | description                         |
+//! |---------|------|-------------------------------------|
+//! | magic   | 4    | `b"SNNF"`                           |
+//! | version | 1    | frame protocol version, currently 1 |
+//! | flags   | 1    | bit flags, see below                |
+//! | length  | 8    | payload length as little-endian u64 |
+//! | payload | n    | the encoded tensor                  |
+//! | crc32   | 0/4  | optional little-endian payload CRC  |
+//!
+//! so a reader always knows how many bytes belong to a message regardless of
+//! the payload encoding. The low three bits of the flags select the
+//! [`Compression`] of the payload and the next three bits advertise which
+//! compression algorithms the sender accepts in reply. When [`FLAG_CRC32`] is
+//! set the payload is followed by its CRC32 checksum, which is verified on read.
+//!
+//! Large payloads can be sent in chunked mode: a sequence of frames with
+//! [`FLAG_CHUNKED`] set, each carrying the next (separately compressed) chunk,
+//! terminated by an empty chunk frame. The receiver decodes the chunks as they
+//! arrive so neither side has to hold the whole payload in memory.
+use std::marker::Unpin;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use candle_core::{Device, Error, Result, Tensor};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::codec::{NpyCodec, TensorCodec};
+use super::compression::{Compression, ACCEPT_MASK};
+use super::LimitExceeded;
+
+pub const FRAME_MAGIC: &[u8; 4] = b"SNNF";
+pub const FRAME_VERSION: u8 = 1;
+/// Size in bytes of the magic, version, flags and length starting a frame.
+const FRAME_HEADER_LEN: usize = 14;
+/// Capacity of the in-memory pipe between the chunk frames and the inner codec.
+const CHUNK_PIPE_SIZE: usize = 64 * 1024;
+/// Frame flag marking that the payload is followed by a CRC32 checksum.
+pub const FLAG_CRC32: u8 = 1 << 6;
+/// Frame flag marking that the payload is one chunk of a chunked message.
+pub const FLAG_CHUNKED: u8 = 1 << 7;
+
+/// A single frame read from or written to the stream.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Frame {
+    pub flags: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(payload: Vec<u8>) -> Frame {
+        Frame { flags: 0, payload }
+    }
+}
+
+/// Read a frame from the stream.
+pub async fn read_frame<T>(reader: &mut T) -> Result<Frame>
+where
+    T: AsyncReadExt + Unpin,
+{
+    read_frame_with_limit(reader, usize::MAX).await
+}
+
+/// Read a frame from the stream, rejecting payloads longer than `max_len`
+/// before allocating them.
+#[cfg_attr(
+    feature = "trace",
+    tracing::instrument(level = "trace", skip_all, fields(len))
+)]
+pub async fn read_frame_with_limit<T>(reader: &mut T, max_len: usize) -> Result<Frame>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).await?;
+    if &magic != FRAME_MAGIC {
+        return Err(Error::Msg("frame magic mismatch".to_string()));
+    }
+    let version = reader.read_u8().await?;
+    if version != FRAME_VERSION {
+        return Err(Error::Msg(format!("unsupported frame version {version}")));
+    }
+    let flags = reader.read_u8().await?;
+    let len = reader.read_u64_le().await?;
+    #[cfg(feature = "trace")]
+    tracing::Span::current().record("len", len);
+    let len = match usize::try_from(len) {
+        Ok(len) if len <= max_len => len,
+        _ => {
+            return Err(LimitExceeded {
+                what: format!("frame of {len} bytes"),
+                max_bytes: max_len,
+            }
+            .into())
+        }
+    };
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    if flags & FLAG_CRC32 != 0 {
+        let expected = reader.read_u32_le().await?;
+        let actual = crc32fast::hash(&payload);
+        if actual != expected {
+            return Err(Error::Msg(format!(
+                "frame checksum mismatch, expected {expected:#010x} got {actual:#010x}"
+            )));
+        }
+    }
+    Ok(Frame { flags, payload })
+}
+
+/// Write a frame to the stream.
+pub async fn write_frame<T>(frame: &Frame, f: &mut T) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    let mut header = Vec::with_capacity(14);
+    header.extend_from_slice(FRAME_MAGIC);
+    header.push(FRAME_VERSION);
+    header.push(frame.flags);
+    header.extend_from_slice(&(frame.payload.len() as u64).to_le_bytes());
+    f.write_all(&header).await?;
+    f.write_all(&frame.payload).await?;
+    if frame.flags & FLAG_CRC32 != 0 {
+        f.write_u32_le(crc32fast::hash(&frame.payload)).await?;
+    }
+    Ok(())
+}
+
+/// Wraps another codec so each tensor is sent in its own frame.
+///
+/// Every written frame advertises the configured compression algorithms and
+/// is compressed with the algorithm negotiated from the last frame read, so a
+/// codec should be cloned for each connection. Chunked messages are always
+/// accepted on read, whether they are sent depends on [`FramedCodec::with_chunk_size`].
+#[derive(Debug, Default)]
+pub struct FramedCodec<C = NpyCodec> {
+    inner: C,
+    preferences: Vec<Compression>,
+    negotiated: AtomicU8,
+    checksum: bool,
+    chunk_size: Option<usize>,
+}
+
+impl<C: TensorCodec> FramedCodec<C> {
+    pub fn new(inner: C) -> FramedCodec<C> {
+        FramedCodec {
+            inner,
+            preferences: vec![],
+            negotiated: AtomicU8::new(Compression::None.flags()),
+            checksum: false,
+            chunk_size: None,
+        }
+    }
+
+    /// Compression algorithms to accept and offer, most preferred first.
+    pub fn with_compression(mut self, preferences: &[Compression]) -> FramedCodec<C> {
+        self.preferences = preferences.to_vec();
+        self
+    }
+
+    /// Append a CRC32 checksum to written frames.
+    pub fn with_checksum(mut self, checksum: bool) -> FramedCodec<C> {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Send payloads in chunked mode, in frames of at most `chunk_size` bytes.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> FramedCodec<C> {
+        self.chunk_size = Some(chunk_size.max(1));
+        self
+    }
+
+    /// The compression algorithm used for the next written frame.
+    pub fn compression(&self) -> Compression {
+        Compression::from_flags(self.negotiated.load(Ordering::Relaxed)).unwrap_or_default()
+    }
+}
+
+impl<C: Clone> Clone for FramedCodec<C> {
+    fn clone(&self) -> Self {
+        FramedCodec {
+            inner: self.inner.clone(),
+            preferences: self.preferences.clone(),
+            negotiated: AtomicU8::new(self.negotiated.load(Ordering::Relaxed)),
+            checksum: self.checksum,
+            chunk_size: self.chunk_size,
+        }
+    }
+}
+
+impl<C: TensorCodec> TensorCodec for FramedCodec<C> {
+    fn name(&self) -> String {
+        format!("framed+{}", self.inner.name())
+    }
+
+    async fn decode<R>(&self, reader: &mut R) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        self.decode_frames(reader, &Device::Cpu, usize::MAX).await
+    }
+
+    async fn decode_to_device<R>(&self, reader: &mut R, device: &Device) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        self.decode_frames(reader, device, usize::MAX).await
+    }
+
+    async fn decode_with_limit<R>(
+        &self,
+        reader: &mut R,
+        device: &Device,
+        max_bytes: usize,
+    ) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        self.decode_frames(reader, device, max_bytes).await
+    }
+
+    async fn read_header<R>(&self, reader: &mut R) -> Result<Vec<u8>>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut header = vec![0u8; FRAME_HEADER_LEN];
+        reader.read_exact(&mut header).await?;
+        Ok(header)
+    }
+
+    async fn encode<W>(&self, tensor: &Tensor, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        let accept = self
+            .preferences
+            .iter()
+            .filter(|c| c.is_enabled())
+            .fold(0, |flags, c| flags | c.accept_flag());
+        let compression = self.compression();
+        let checksum = if self.checksum { FLAG_CRC32 } else { 0 };
+        let flags = (accept & ACCEPT_MASK) | checksum;
+        if let Some(chunk_size) = self.chunk_size {
+            return self
+                .encode_chunked(tensor, writer, flags, compression, chunk_size)
+                .await;
+        }
+        let mut payload = Vec::new();
+        self.inner.encode(tensor, &mut payload).await?;
+        let frame = Frame {
+            flags: compression.flags() | flags,
+            payload: compression.compress(payload).await?,
+        };
+        write_frame(&frame, writer).await
+    }
+}
+
+impl<C: TensorCodec> FramedCodec<C> {
+    /// Read a framed message, plain or chunked, and decode it with the inner
+    /// codec. Frames and the decoded payload are limited to `max_bytes`.
+    async fn decode_frames<R>(
+        &self,
+        reader: &mut R,
+        device: &Device,
+        max_bytes: usize,
+    ) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let frame = read_frame_with_limit(reader, max_bytes).await?;
+        let compression = Compression::from_flags(frame.flags)?;
+        let negotiated = Compression::negotiate(&self.preferences, frame.flags);
+        self.negotiated.store(negotiated.flags(), Ordering::Relaxed);
+        if frame.flags & FLAG_CHUNKED != 0 {
+            return self.decode_chunked(frame, reader, device, max_bytes).await;
+        }
+        let mut payload = compression.decompress(&frame.payload)?;
+        self.inner
+            .decode_with_limit(&mut payload, device, max_bytes)
+            .await
+    }
+
+    /// Decode a chunked message starting with `first`, feeding the inner codec
+    /// through a pipe as the chunks arrive.
+    async fn decode_chunked<R>(
+        &self,
+        first: Frame,
+        reader: &mut R,
+        device: &Device,
+        max_bytes: usize,
+    ) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let (mut sink, mut source) = tokio::io::duplex(CHUNK_PIPE_SIZE);
+        let decode = async move {
+            self.inner
+                .decode_with_limit(&mut source, device, max_bytes)
+                .await
+        };
+        let pump = async move {
+            let mut frame = first;
+            // keep reading up to the terminating frame even if the inner
+            // codec stops early so the stream stays in sync
+            let mut open = true;
+            let mut decoded = 0;
+            let mut buf = vec![0u8; CHUNK_PIPE_SIZE];
+            while !frame.payload.is_empty() {
+                if frame.flags & FLAG_CHUNKED == 0 {
+                    return Err(Error::Msg("expected a chunk frame".to_string()));
+                }
+                let compression = Compression::from_flags(frame.flags)?;
+                let mut chunk = compression.decompress(&frame.payload)?;
+                // stream each chunk into the pipe as it's decompressed, as a
+                // small chunk can expand to any size
+                while open {
+                    let n = chunk.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    decoded += n;
+                    if decoded > max_bytes {
+                        return Err(LimitExceeded {
+                            what: "decompressed chunked message".to_string(),
+                            max_bytes,
+                        }
+                        .into());
+                    }
+                    open = sink.write_all(&buf[..n]).await.is_ok();
+                }
+                drop(chunk);
+                frame = read_frame_with_limit(reader, max_bytes).await?;
+            }
+            Ok(())
+        };
+        let (tensor, pumped) = tokio::join!(decode, pump);
+        pumped?;
+        tensor
+    }
+
+    /// Encode through a pipe, writing the bytes produced by the inner codec
+    /// as chunk frames of at most `chunk_size` bytes.
+    async fn encode_chunked<W>(
+        &self,
+        tensor: &Tensor,
+        writer: &mut W,
+        flags: u8,
+        compression: Compression,
+        chunk_size: usize,
+    ) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        let flags = flags | FLAG_CHUNKED;
+        let (mut sink, mut source) = tokio::io::duplex(chunk_size.min(CHUNK_PIPE_SIZE));
+        let encode = async move { self.inner.encode(tensor, &mut sink).await };
+        let pump = async move {
+            loop {
+                let mut chunk = Vec::with_capacity(chunk_size);
+                (&mut source)
+                    .take(chunk_size as u64)
+                    .read_to_end(&mut chunk)
+                    .await?;
+                if chunk.is_empty() {
+                    break;
+                }
+                let frame = Frame {
+                    flags: compression.flags() | flags,
+                    payload: compression.compress(chunk).await?,
+                };
+                write_frame(&frame, writer).await?;
+            }
+            write_frame(
+                &Frame {
+                    flags,
+                    payload: vec![],
+                },
+                writer,
+            )
+            .await
+        };
+        let (encoded, pumped) = tokio::join!(encode, pump);
+        pumped?;
+        encoded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() {
+        let frame = Frame {
+            flags: 3,
+            payload: vec![1, 2, 3],
+        };
+        let mut buf = Vec::new();
+        write_frame(&frame, &mut buf).await.unwrap();
+        assert_eq!(buf.len(), 14 + 3);
+        assert_eq!(read_frame(&mut buf.as_slice()).await.unwrap(), frame);
+
+        assert!(read_frame_with_limit(&mut buf.as_slice(), 2).await.is_err());
+
+        buf[0] = b'X';
+        assert!(read_frame(&mut buf.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_frame_checksum() {
+        let frame = Frame {
+            flags: FLAG_CRC32,
+            payload: vec![1, 2, 3],
+        };
+        let mut buf = Vec::new();
+        write_frame(&frame, &mut buf).await.unwrap();
+        assert_eq!(buf.len(), 14 + 3 + 4);
+        assert_eq!(read_frame(&mut buf.as_slice()).await.unwrap(), frame);
+
+        // corrupt the payload
+        buf[15] ^= 0xff;
+        assert!(read_frame(&mut buf.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_framed_codec() {
+        let codec = FramedCodec::new(NpyCodec);
+        let x = Tensor::new(&[1f32, 2f32], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        codec.encode(&x, &mut buf).await.unwrap();
+        codec.encode(&x, &mut buf).await.unwrap();
+        let mut reader = buf.as_slice();
+        for _ in 0..2 {
+            let y = codec.decode(&mut reader).await.unwrap();
+            assert_eq!(y.to_vec1::<f32>().unwrap(), vec![1f32, 2f32]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_framed_codec_negotiates_compression() {
+        let client = FramedCodec::new(NpyCodec).with_compression(&[Compression::Gzip]);
+        let server = FramedCodec::new(NpyCodec).with_compression(&[Compression::Gzip]);
+        let x = Tensor::new(&[1f32, 2f32], &Device::Cpu).unwrap();
+
+        // requests are sent uncompressed until the peer's support is known
+        let mut buf = Vec::new();
+        client.encode(&x, &mut buf).await.unwrap();
+        assert_eq!(Compression::from_flags(buf[5]).unwrap(), Compression::None);
+        server.decode(&mut buf.as_slice()).await.unwrap();
+        let expected = if Compression::Gzip.is_enabled() {
+            Compression::Gzip
+        } else {
+            Compression::None
+        };
+        assert_eq!(server.compression(), expected);
+
+        let mut buf = Vec::new();
+        server.encode(&x, &mut buf).await.unwrap();
+        assert_eq!(Compression::from_flags(buf[5]).unwrap(), expected);
+        let y = client.decode(&mut buf.as_slice()).await.unwrap();
+        assert_eq!(y.to_vec1::<f32>().unwrap(), vec![1f32, 2f32]);
+    }
+
+    #[tokio::test]
+    async fn test_framed_codec_chunked() {
+        let sender = FramedCodec::new(NpyCodec)
+            .with_chunk_size(16)
+            .with_checksum(true);
+        let receiver = FramedCodec::new(NpyCodec);
+        let x = Tensor::arange(0f32, 100f32, &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        sender.encode(&x, &mut buf).await.unwrap();
+        sender.encode(&x, &mut buf).await.unwrap();
+
+        // the first message is split into several chunk frames
+        let mut reader = buf.as_slice();
+        let first = read_frame(&mut reader).await.unwrap();
+        assert_ne!(first.flags & FLAG_CHUNKED, 0);
+        assert_eq!(first.payload.len(), 16);
+
+        let mut reader = buf.as_slice();
+        for _ in 0..2 {
+            let y = receiver.decode(&mut reader).await.unwrap();
+            assert_eq!(y.to_vec1::<f32>().unwrap(), x.to_vec1::<f32>().unwrap());
+        }
+        assert!(reader.is_empty());
+    }
+
+    /// Reads the whole payload, however long.
+    #[cfg(feature = "gzip")]
+    struct Drain;
+
+    #[cfg(feature = "gzip")]
+    impl TensorCodec for Drain {
+        async fn decode<R>(&self, reader: &mut R) -> Result<Tensor>
+        where
+            R: AsyncRead + Unpin + Send,
+        {
+            let n = tokio::io::copy(reader, &mut tokio::io::sink()).await?;
+            Tensor::new(n as u32, &Device::Cpu)
+        }
+
+        async fn encode<W>(&self, _: &Tensor, _: &mut W) -> Result<()>
+        where
+            W: AsyncWrite + Unpin + Send,
+        {
+            Err(Error::Msg("the drain codec only decodes".to_string()))
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    #[tokio::test]
+    async fn test_framed_codec_chunked_zip_bomb() {
+        // a small chunk expanding to 1MiB
+        let payload = Compression::Gzip
+            .compress(vec![0u8; 1 << 20])
+            .await
+            .unwrap();
+        assert!(payload.len() < 4096);
+        let flags = FLAG_CHUNKED | Compression::Gzip.flags();
+        let mut buf = Vec::new();
+        write_frame(&Frame { flags, payload }, &mut buf)
+            .await
+            .unwrap();
+        let end = Frame {
+            flags: FLAG_CHUNKED,
+            payload: vec![],
+        };
+        write_frame(&end, &mut buf).await.unwrap();
+
+        let codec = FramedCodec::new(Drain);
+        let err = codec
+            .decode_with_limit(&mut buf.as_slice(), &Device::Cpu, 64 * 1024)
+            .await
+            .unwrap_err();
+        assert!(LimitExceeded::is(&err), "{err}");
+        let y = codec
+            .decode_with_limit(&mut buf.as_slice(), &Device::Cpu, 1 << 20)
+            .await
+            .unwrap();
+        assert_eq!(y.to_scalar::<u32>().unwrap(), 1 << 20);
+    }
+}
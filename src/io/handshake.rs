@@ -0,0 +1,170 @@
+//! Hello messages exchanged when a connection is opened.
+//!
+//! A client may start a connection with a hello advertising its protocol
+//! version, supported features and codec; the server replies with its own
+//! hello. Clients that skip the hello are served with the default protocol, so
+//! the wire format can evolve without breaking them.
+//!
+//! | field    | size | description                           |
+//! |----------|------|---------------------------------------|
+//! | magic    | 4    | `b"SNNH"`                             |
+//! | version  | 1    | highest supported protocol version    |
+//! | features | 4    | little-endian bit set of [`features`] |
+//! | codec    | 1+n  | length-prefixed codec name            |
+use std::marker::Unpin;
+
+use candle_core::{Error, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::compression::Compression;
+
+pub const HELLO_MAGIC: &[u8; 4] = b"SNNH";
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Feature bits advertised in a hello.
+pub mod features {
+    /// Payloads are wrapped in frames, see [`crate::io::frame`].
+    pub const FRAMES: u32 = 1 << 0;
+    /// Frames may carry a CRC32 trailer.
+    pub const CRC32: u32 = 1 << 1;
+    pub const ZSTD: u32 = 1 << 2;
+    pub const GZIP: u32 = 1 << 3;
+    pub const LZ4: u32 = 1 << 4;
+    /// Several requests may be in flight on one connection.
+    pub const MULTIPLEX: u32 = 1 << 5;
+}
+
+/// A hello message advertising what a peer supports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u8,
+    pub features: u32,
+    pub codec: String,
+}
+
+impl Hello {
+    /// A hello for this build of the crate using the named codec.
+    pub fn new(codec: impl Into<String>) -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            features: Hello::supported_features(),
+            codec: codec.into(),
+        }
+    }
+
+    /// The features supported by this build of the crate.
+    pub fn supported_features() -> u32 {
+        let mut supported = features::FRAMES | features::CRC32;
+        for (compression, feature) in [
+            (Compression::Zstd, features::ZSTD),
+            (Compression::Gzip, features::GZIP),
+            (Compression::Lz4, features::LZ4),
+        ] {
+            if compression.is_enabled() {
+                supported |= feature;
+            }
+        }
+        supported
+    }
+
+    /// The protocol version and features shared with the peer, keeping this
+    /// side's codec.
+    pub fn negotiate(&self, peer: &Hello) -> Hello {
+        Hello {
+            version: self.version.min(peer.version),
+            features: self.features & peer.features,
+            codec: self.codec.clone(),
+        }
+    }
+
+    /// Whether every bit of `feature` is set.
+    pub fn supports(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+}
+
+/// Read a hello message from the stream.
+pub async fn read_hello<T>(reader: &mut T) -> Result<Hello>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).await?;
+    if &magic != HELLO_MAGIC {
+        return Err(Error::Msg("hello magic mismatch".to_string()));
+    }
+    read_hello_body(reader).await
+}
+
+/// Read the rest of a hello message once the magic has been consumed.
+pub async fn read_hello_body<T>(reader: &mut T) -> Result<Hello>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let version = reader.read_u8().await?;
+    if version == 0 {
+        return Err(Error::Msg("invalid protocol version 0".to_string()));
+    }
+    let features = reader.read_u32_le().await?;
+    let len = reader.read_u8().await? as usize;
+    let mut codec = vec![0u8; len];
+    reader.read_exact(&mut codec).await?;
+    let codec = String::from_utf8(codec).map_err(Error::wrap)?;
+    Ok(Hello {
+        version,
+        features,
+        codec,
+    })
+}
+
+/// Write a hello message to the stream.
+pub async fn write_hello<T>(hello: &Hello, f: &mut T) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    let codec = hello.codec.as_bytes();
+    if codec.len() > u8::MAX as usize {
+        return Err(Error::Msg(format!("codec name too long: {}", hello.codec)));
+    }
+    let mut bytes = Vec::with_capacity(10 + codec.len());
+    bytes.extend_from_slice(HELLO_MAGIC);
+    bytes.push(hello.version);
+    bytes.extend_from_slice(&hello.features.to_le_bytes());
+    bytes.push(codec.len() as u8);
+    bytes.extend_from_slice(codec);
+    f.write_all(&bytes).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hello_roundtrip() {
+        let hello = Hello::new("npy");
+        let mut buf = Vec::new();
+        write_hello(&hello, &mut buf).await.unwrap();
+        assert_eq!(read_hello(&mut buf.as_slice()).await.unwrap(), hello);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let server = Hello {
+            version: 2,
+            features: features::FRAMES | features::CRC32,
+            codec: "npy".to_string(),
+        };
+        let client = Hello {
+            version: 1,
+            features: features::FRAMES | features::MULTIPLEX,
+            codec: "json".to_string(),
+        };
+        let agreed = server.negotiate(&client);
+        assert_eq!(agreed.version, 1);
+        assert!(agreed.supports(features::FRAMES));
+        assert!(!agreed.supports(features::CRC32));
+        assert!(!agreed.supports(features::MULTIPLEX));
+        assert_eq!(agreed.codec, "npy");
+    }
+}
@@ -0,0 +1,144 @@
+//! Module to read and write JSON encoded tensors to the stream.
+//!
+//! Each tensor is a single line of JSON such as
+//! `{"shape": [2, 2], "dtype": "f32", "data": [1, 0, 0, 1]}` with the values in
+//! row-major order, so a server can be exercised by hand with `nc` while
+//! developing. The `dtype` defaults to `"f64"` when omitted.
+use std::marker::Unpin;
+
+use candle_core::{DType, Device, Error, Result, Shape, Tensor};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::codec::{check_limit, TensorCodec};
+use super::{dtype_from_name, dtype_name, flatten_values, read_line};
+
+#[derive(Serialize, Deserialize)]
+struct JsonTensor {
+    shape: Vec<usize>,
+    #[serde(default = "default_dtype")]
+    dtype: String,
+    data: Vec<f64>,
+}
+
+fn default_dtype() -> String {
+    dtype_name(DType::F64).to_string()
+}
+
+/// Read a newline terminated JSON tensor from the stream.
+pub async fn read_json<T>(reader: T) -> Result<Tensor>
+where
+    T: AsyncReadExt + Unpin,
+{
+    read_json_with_limit(reader, usize::MAX).await
+}
+
+/// Read a newline terminated JSON tensor from the stream, rejecting lines
+/// longer than `max_bytes` before reading the rest of them.
+pub async fn read_json_with_limit<T>(mut reader: T, max_bytes: usize) -> Result<Tensor>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let line = read_line(&mut reader, max_bytes).await?;
+    let tensor: JsonTensor = serde_json::from_slice(&line).map_err(Error::wrap)?;
+    let shape = Shape::from(tensor.shape);
+    let dtype = dtype_from_name(&tensor.dtype)?;
+    Tensor::from_vec(tensor.data, shape, &Device::Cpu)?.to_dtype(dtype)
+}
+
+/// Write a `Tensor` to the stream as a newline terminated JSON object.
+pub async fn write_json<T>(tensor: &Tensor, f: &mut T) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    let encoded = JsonTensor {
+        shape: tensor.dims().to_vec(),
+        dtype: dtype_name(tensor.dtype()).to_string(),
+        data: flatten_values(tensor)?
+            .to_dtype(DType::F64)?
+            .to_vec1::<f64>()?,
+    };
+    let mut bytes = serde_json::to_vec(&encoded).map_err(Error::wrap)?;
+    bytes.push(b'\n');
+    f.write_all(&bytes).await?;
+    Ok(())
+}
+
+/// A human readable JSON format for debugging.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl TensorCodec for JsonCodec {
+    fn name(&self) -> String {
+        "json".to_string()
+    }
+
+    async fn decode<R>(&self, reader: &mut R) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        read_json(reader).await
+    }
+
+    async fn decode_with_limit<R>(
+        &self,
+        reader: &mut R,
+        device: &Device,
+        max_bytes: usize,
+    ) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let tensor = read_json_with_limit(reader, max_bytes).await?;
+        check_limit(tensor.to_device(device)?, max_bytes)
+    }
+
+    async fn encode<W>(&self, tensor: &Tensor, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        write_json(tensor, writer).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::LimitExceeded;
+
+    #[tokio::test]
+    async fn test_read_json() {
+        let line = b"{\"shape\": [2, 2], \"dtype\": \"f32\", \"data\": [1, 0, 0, 1]}\n";
+        let tensor = read_json(&line[..]).await.unwrap();
+        assert_eq!(tensor.dtype(), DType::F32);
+        assert_eq!(
+            tensor.to_vec2::<f32>().unwrap(),
+            vec![vec![1f32, 0f32], vec![0f32, 1f32]]
+        );
+
+        let line = b"{\"shape\": [2], \"data\": [0.5, 1.5]}";
+        let tensor = read_json(&line[..]).await.unwrap();
+        assert_eq!(tensor.dtype(), DType::F64);
+    }
+
+    #[tokio::test]
+    async fn test_read_json_with_limit() {
+        // a line that never ends
+        let err = JsonCodec
+            .decode_with_limit(&mut tokio::io::repeat(b'1'), &Device::Cpu, 1024)
+            .await
+            .unwrap_err();
+        assert!(LimitExceeded::is(&err), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_write_json() {
+        let x = Tensor::new(&[1u8, 2u8], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_json(&x, &mut buf).await.unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "{\"shape\":[2],\"dtype\":\"u8\",\"data\":[1.0,2.0]}\n"
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! Synchronous helpers to load `.npy` and `.npz` files from disk, e.g. model
+//! weights or evaluation inputs during setup.
+//!
+//! Files are memory mapped so array bodies are copied straight from the page
+//! cache into the tensors without an intermediate read buffer.
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+use candle_core::{Device, Error, Result, Shape, Tensor};
+use memmap2::Mmap;
+
+use super::{
+    reverse_dims, swap_bytes, tensor_from_le_bytes, widen_ints, Header, HeaderError,
+    MAX_HEADER_LEN, NPY_MAGIC_STRING,
+};
+
+/// Load a `numpy` array from a `.npy` file.
+pub fn load_npy<P: AsRef<Path>>(path: P) -> Result<Tensor> {
+    let mmap = map(path.as_ref())?;
+    npy_from_bytes(&mmap)
+}
+
+/// Load the named arrays of a `numpy` `.npz` archive, stored or compressed.
+pub fn load_npz<P: AsRef<Path>>(path: P) -> Result<HashMap<String, Tensor>> {
+    let mmap = map(path.as_ref())?;
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(&mmap[..]))?;
+    let mut tensors = HashMap::new();
+    for i in 0..zip.len() {
+        let mut file = zip.by_index(i)?;
+        let name = file.name();
+        let name = name.strip_suffix(".npy").unwrap_or(name).to_string();
+        let mut data = Vec::with_capacity(file.size() as usize);
+        std::io::Read::read_to_end(&mut file, &mut data)?;
+        tensors.insert(name, npy_from_bytes(&data)?);
+    }
+    Ok(tensors)
+}
+
+fn map(path: &Path) -> Result<Mmap> {
+    let file = File::open(path)?;
+    // SAFETY: the mapping only lives until the arrays have been copied out of
+    // it, callers must not truncate the file while it is being loaded
+    Ok(unsafe { Mmap::map(&file)? })
+}
+
+/// Parse a whole `.npy` file held in memory.
+fn npy_from_bytes(bytes: &[u8]) -> Result<Tensor> {
+    let (header, body) = split_header(bytes)?;
+    let nbytes = header.shape().elem_count() * header.itemsize;
+    let data = body.get(..nbytes).ok_or_else(|| {
+        Error::Npy(format!(
+            "expected {nbytes} bytes of data, got {}",
+            body.len()
+        ))
+    })?;
+    // column-major data is laid out as a row-major array with reversed dims
+    let shape = if header.fortran_order {
+        Shape::from(header.shape.iter().rev().copied().collect::<Vec<_>>())
+    } else {
+        header.shape()
+    };
+
+    let tensor = if header.itemsize != header.descr.size_in_bytes() {
+        let values = widen_ints(data, header.itemsize, header.big_endian)?;
+        Tensor::from_vec(values, shape, &Device::Cpu)?
+    } else if header.big_endian || header.boolean {
+        let mut data = data.to_vec();
+        if header.big_endian {
+            swap_bytes(&mut data, header.descr.size_in_bytes());
+        }
+        if header.boolean {
+            for v in data.iter_mut() {
+                *v = (*v != 0) as u8;
+            }
+        }
+        tensor_from_le_bytes(&data, header.descr, shape)?
+    } else {
+        tensor_from_le_bytes(data, header.descr, shape)?
+    };
+
+    if header.fortran_order {
+        reverse_dims(&tensor)?.contiguous()
+    } else {
+        Ok(tensor)
+    }
+}
+
+/// Split a `.npy` file into its parsed header and the array body.
+fn split_header(bytes: &[u8]) -> Result<(Header, &[u8])> {
+    let truncated = || Error::Npy("truncated npy file".to_string());
+    if !bytes.starts_with(NPY_MAGIC_STRING) {
+        return Err(HeaderError::MagicMismatch.into());
+    }
+    let rest = &bytes[NPY_MAGIC_STRING.len()..];
+    let (header_len, rest) = match rest {
+        [1, _, a, b, rest @ ..] => (u16::from_le_bytes([*a, *b]) as usize, rest),
+        [2 | 3, _, a, b, c, d, rest @ ..] => (u32::from_le_bytes([*a, *b, *c, *d]) as usize, rest),
+        [version, ..] => return Err(HeaderError::UnsupportedVersion(*version).into()),
+        [] => return Err(truncated()),
+    };
+    if header_len > MAX_HEADER_LEN {
+        return Err(HeaderError::TooLong(header_len).into());
+    }
+    if rest.len() < header_len {
+        return Err(truncated());
+    }
+    let (header, body) = rest.split_at(header_len);
+    let header = std::str::from_utf8(header).map_err(|_| HeaderError::NotText)?;
+    Ok((Header::parse(header)?, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::write_npz;
+    use candle_core::DType;
+
+    #[test]
+    fn test_load_npy() {
+        let tensor = load_npy("tests/eye2_f64.npy").unwrap();
+        assert_eq!(tensor.dtype(), DType::F64);
+        let v = tensor.to_vec2::<f64>().unwrap();
+        assert_eq!(v, vec![vec![1.0, 0.0], vec![0.0, 1.0]]);
+
+        let tensor = load_npy("tests/eye2_f64_be.npy").unwrap();
+        let v = tensor.to_vec2::<f64>().unwrap();
+        assert_eq!(v, vec![vec![1.0, 0.0], vec![0.0, 1.0]]);
+
+        let tensor = load_npy("tests/arange6_fortran_f32.npy").unwrap();
+        let v = tensor.to_vec2::<f32>().unwrap();
+        assert_eq!(v, vec![vec![0f32, 1f32, 2f32], vec![3f32, 4f32, 5f32]]);
+
+        assert!(load_npy("tests/missing.npy").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_load_npz() {
+        let x = Tensor::new(&[1u32, 2u32], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_npz(&[("x", &x)], &mut buf).await.unwrap();
+        // files on disk are not length prefixed
+        let path = std::env::temp_dir().join(format!("socket-nn-{}.npz", std::process::id()));
+        std::fs::write(&path, &buf[8..]).unwrap();
+        let tensors = load_npz(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            tensors.unwrap()["x"].to_vec1::<u32>().unwrap(),
+            vec![1u32, 2u32]
+        );
+    }
+
+    #[test]
+    fn test_split_header_truncated() {
+        let bytes = std::fs::read("tests/eye2_f64.npy").unwrap();
+        for len in [0, 6, 9, 20] {
+            assert!(split_header(&bytes[..len]).is_err());
+        }
+        assert!(npy_from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+//! Module to read and write MessagePack encoded tensors to the stream.
+//!
+//! A tensor is encoded as a map with `shape`, `dtype` (e.g. `"f32"`) and
+//! `data` (little-endian bytes in row-major order) fields. On the wire the
+//! encoded map is prefixed by its length in bytes as a little-endian `u64`.
+use std::marker::Unpin;
+
+use candle_core::{Device, Error, Result, Shape, Tensor};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::codec::{check_limit, read_length_prefixed, TensorCodec};
+use super::{dtype_from_name, dtype_name, tensor_from_le_bytes, tensor_to_le_bytes};
+
+#[derive(Serialize, Deserialize)]
+struct MsgPackTensor {
+    shape: Vec<usize>,
+    dtype: String,
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+}
+
+/// Read a MessagePack encoded tensor from the stream.
+pub async fn read_msgpack<T>(reader: T) -> Result<Tensor>
+where
+    T: AsyncReadExt + Unpin,
+{
+    read_msgpack_with_limit(reader, usize::MAX).await
+}
+
+/// Read a MessagePack encoded tensor from the stream, rejecting maps longer
+/// than `max_bytes` before allocating.
+pub async fn read_msgpack_with_limit<T>(mut reader: T, max_bytes: usize) -> Result<Tensor>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let bytes = read_length_prefixed(&mut reader, max_bytes).await?;
+    let tensor: MsgPackTensor = rmp_serde::from_slice(&bytes).map_err(Error::wrap)?;
+    tensor_from_le_bytes(
+        &tensor.data,
+        dtype_from_name(&tensor.dtype)?,
+        Shape::from(tensor.shape),
+    )
+}
+
+/// Write a `Tensor` to the stream as a MessagePack map.
+pub async fn write_msgpack<T>(tensor: &Tensor, f: &mut T) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    let encoded = MsgPackTensor {
+        shape: tensor.dims().to_vec(),
+        dtype: dtype_name(tensor.dtype()).to_string(),
+        data: tensor_to_le_bytes(tensor)?,
+    };
+    let bytes = rmp_serde::to_vec_named(&encoded).map_err(Error::wrap)?;
+
+    f.write_u64_le(bytes.len() as u64).await?;
+    f.write_all(&bytes).await?;
+    Ok(())
+}
+
+/// The MessagePack format.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsgPackCodec;
+
+impl TensorCodec for MsgPackCodec {
+    fn name(&self) -> String {
+        "msgpack".to_string()
+    }
+
+    async fn decode<R>(&self, reader: &mut R) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        read_msgpack(reader).await
+    }
+
+    async fn decode_with_limit<R>(
+        &self,
+        reader: &mut R,
+        device: &Device,
+        max_bytes: usize,
+    ) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let tensor = read_msgpack_with_limit(reader, max_bytes).await?;
+        check_limit(tensor.to_device(device)?, max_bytes)
+    }
+
+    async fn encode<W>(&self, tensor: &Tensor, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        write_msgpack(tensor, writer).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::{DType, Device};
+
+    #[tokio::test]
+    async fn test_msgpack_roundtrip() {
+        let x = Tensor::new(&[[1u32, 2u32], [3u32, 4u32]], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_msgpack(&x, &mut buf).await.unwrap();
+        let roundtrip = read_msgpack(buf.as_slice()).await.unwrap();
+        assert_eq!(roundtrip.dtype(), DType::U32);
+        assert_eq!(
+            roundtrip.to_vec2::<u32>().unwrap(),
+            vec![vec![1u32, 2u32], vec![3u32, 4u32]]
+        );
+    }
+}
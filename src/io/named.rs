@@ -0,0 +1,126 @@
+//! Payloads of several named tensors, e.g. a model's ids and attention mask.
+//!
+//! | field   | size | description                              |
+//! |---------|------|------------------------------------------|
+//! | count   | 4    | number of tensors as little-endian u32   |
+//!
+//! followed by `count` entries of
+//!
+//! | field   | size | description                              |
+//! |---------|------|------------------------------------------|
+//! | name    | 2+n  | little-endian u16 length prefixed name   |
+//! | tensor  | m    | the tensor encoded with the codec in use |
+use std::collections::HashMap;
+use std::marker::Unpin;
+
+use candle_core::{Device, Error, Result, Tensor};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::codec::{NpyCodec, TensorCodec};
+
+/// Read named `numpy` arrays from the stream.
+pub async fn read_named<R>(reader: &mut R) -> Result<HashMap<String, Tensor>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    read_named_with(&NpyCodec, reader, &Device::Cpu, usize::MAX).await
+}
+
+/// Write named `Tensor`s to the stream as `numpy` arrays.
+pub async fn write_named<W>(tensors: &[(&str, &Tensor)], writer: &mut W) -> Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    write_named_with(&NpyCodec, tensors, writer).await
+}
+
+/// Read named tensors decoded with `codec` onto the given device. The tensors
+/// may hold at most `max_bytes` in total.
+pub async fn read_named_with<C, R>(
+    codec: &C,
+    reader: &mut R,
+    device: &Device,
+    max_bytes: usize,
+) -> Result<HashMap<String, Tensor>>
+where
+    C: TensorCodec,
+    R: AsyncRead + Unpin + Send,
+{
+    let count = reader.read_u32_le().await?;
+    let mut remaining = max_bytes;
+    let mut tensors = HashMap::new();
+    for _ in 0..count {
+        let len = reader.read_u16_le().await? as usize;
+        let mut name = vec![0u8; len];
+        reader.read_exact(&mut name).await?;
+        let name = String::from_utf8(name).map_err(Error::wrap)?;
+        let tensor = codec.decode_with_limit(reader, device, remaining).await?;
+        remaining -= tensor.elem_count() * tensor.dtype().size_in_bytes();
+        if tensors.insert(name.clone(), tensor).is_some() {
+            return Err(Error::Msg(format!("duplicate tensor name {name}")));
+        }
+    }
+    Ok(tensors)
+}
+
+/// Write named `Tensor`s to the stream encoded with `codec`.
+pub async fn write_named_with<C, W>(
+    codec: &C,
+    tensors: &[(&str, &Tensor)],
+    writer: &mut W,
+) -> Result<()>
+where
+    C: TensorCodec,
+    W: AsyncWrite + Unpin + Send,
+{
+    let count = u32::try_from(tensors.len())
+        .map_err(|_| Error::Msg(format!("too many tensors: {}", tensors.len())))?;
+    writer.write_u32_le(count).await?;
+    for (name, tensor) in tensors {
+        let len = u16::try_from(name.len())
+            .map_err(|_| Error::Msg(format!("tensor name too long: {name}")))?;
+        writer.write_u16_le(len).await?;
+        writer.write_all(name.as_bytes()).await?;
+        codec.encode(tensor, writer).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_named_roundtrip() {
+        let ids = Tensor::new(&[[1u32, 2u32, 3u32]], &Device::Cpu).unwrap();
+        let mask = Tensor::new(&[[1f32, 1f32, 0f32]], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_named(&[("input_ids", &ids), ("attention_mask", &mask)], &mut buf)
+            .await
+            .unwrap();
+        let tensors = read_named(&mut buf.as_slice()).await.unwrap();
+        assert_eq!(tensors.len(), 2);
+        assert_eq!(
+            tensors["input_ids"].to_vec2::<u32>().unwrap(),
+            vec![vec![1u32, 2u32, 3u32]]
+        );
+        assert_eq!(
+            tensors["attention_mask"].to_vec2::<f32>().unwrap(),
+            vec![vec![1f32, 1f32, 0f32]]
+        );
+
+        // the limit covers all tensors together
+        let limited = read_named_with(&NpyCodec, &mut buf.as_slice(), &Device::Cpu, 20).await;
+        assert!(limited.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_named_duplicate_names() {
+        let x = Tensor::new(&[1f32], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_named(&[("x", &x), ("x", &x)], &mut buf)
+            .await
+            .unwrap();
+        assert!(read_named(&mut buf.as_slice()).await.is_err());
+    }
+}
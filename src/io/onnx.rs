@@ -0,0 +1,226 @@
+//! Module to read and write ONNX `TensorProto` messages to the stream.
+//!
+//! Only the fields needed to describe a dense tensor are decoded. On the wire
+//! the encoded message is prefixed by its length in bytes as a little-endian
+//! `u64`.
+use std::marker::Unpin;
+
+use candle_core::{DType, Device, Error, Result, Shape, Tensor};
+use half::{bf16, f16};
+use prost::Message;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::codec::{check_limit, read_length_prefixed, TensorCodec};
+use super::{tensor_from_le_bytes, tensor_to_le_bytes};
+
+// `TensorProto.DataType` values from `onnx.proto`.
+const FLOAT: i32 = 1;
+const UINT8: i32 = 2;
+const INT64: i32 = 7;
+const BOOL: i32 = 9;
+const FLOAT16: i32 = 10;
+const DOUBLE: i32 = 11;
+const UINT32: i32 = 12;
+const BFLOAT16: i32 = 16;
+
+/// Subset of the ONNX `TensorProto` message describing a dense tensor.
+#[derive(Clone, PartialEq, Message)]
+pub struct TensorProto {
+    #[prost(int64, repeated, tag = "1")]
+    pub dims: Vec<i64>,
+    #[prost(int32, tag = "2")]
+    pub data_type: i32,
+    #[prost(float, repeated, tag = "4")]
+    pub float_data: Vec<f32>,
+    #[prost(int32, repeated, tag = "5")]
+    pub int32_data: Vec<i32>,
+    #[prost(int64, repeated, tag = "7")]
+    pub int64_data: Vec<i64>,
+    #[prost(string, tag = "8")]
+    pub name: String,
+    #[prost(bytes = "vec", tag = "9")]
+    pub raw_data: Vec<u8>,
+    #[prost(double, repeated, tag = "10")]
+    pub double_data: Vec<f64>,
+    #[prost(uint64, repeated, tag = "11")]
+    pub uint64_data: Vec<u64>,
+}
+
+/// Read an ONNX `TensorProto` from the stream and convert to a `Tensor`.
+pub async fn read_onnx<T>(reader: T) -> Result<Tensor>
+where
+    T: AsyncReadExt + Unpin,
+{
+    read_onnx_with_limit(reader, usize::MAX).await
+}
+
+/// Read an ONNX `TensorProto` from the stream into a `Tensor`, rejecting
+/// messages longer than `max_bytes` before allocating.
+pub async fn read_onnx_with_limit<T>(mut reader: T, max_bytes: usize) -> Result<Tensor>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let bytes = read_length_prefixed(&mut reader, max_bytes).await?;
+    let proto = TensorProto::decode(bytes.as_slice()).map_err(Error::wrap)?;
+    proto_to_tensor(&proto)
+}
+
+/// Write a `Tensor` to the stream as an ONNX `TensorProto` using `raw_data`.
+pub async fn write_onnx<T>(tensor: &Tensor, f: &mut T) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    let data_type = match tensor.dtype() {
+        DType::BF16 => BFLOAT16,
+        DType::F16 => FLOAT16,
+        DType::F32 => FLOAT,
+        DType::F64 => DOUBLE,
+        DType::U8 => UINT8,
+        DType::U32 => UINT32,
+        DType::I64 => INT64,
+    };
+    let proto = TensorProto {
+        dims: tensor.dims().iter().map(|&d| d as i64).collect(),
+        data_type,
+        raw_data: tensor_to_le_bytes(tensor)?,
+        ..Default::default()
+    };
+    let bytes = proto.encode_to_vec();
+
+    f.write_u64_le(bytes.len() as u64).await?;
+    f.write_all(&bytes).await?;
+    Ok(())
+}
+
+/// The ONNX `TensorProto` format.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OnnxCodec;
+
+impl TensorCodec for OnnxCodec {
+    fn name(&self) -> String {
+        "onnx".to_string()
+    }
+
+    async fn decode<R>(&self, reader: &mut R) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        read_onnx(reader).await
+    }
+
+    async fn decode_with_limit<R>(
+        &self,
+        reader: &mut R,
+        device: &Device,
+        max_bytes: usize,
+    ) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let tensor = read_onnx_with_limit(reader, max_bytes).await?;
+        check_limit(tensor.to_device(device)?, max_bytes)
+    }
+
+    async fn encode<W>(&self, tensor: &Tensor, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        write_onnx(tensor, writer).await
+    }
+}
+
+fn proto_to_tensor(proto: &TensorProto) -> Result<Tensor> {
+    let dims = proto
+        .dims
+        .iter()
+        .map(|&d| usize::try_from(d).map_err(|_| Error::Msg(format!("negative dim {d}"))))
+        .collect::<Result<Vec<_>>>()?;
+    let shape = Shape::from(dims);
+    let dtype = match proto.data_type {
+        FLOAT => DType::F32,
+        UINT8 | BOOL => DType::U8,
+        FLOAT16 => DType::F16,
+        DOUBLE => DType::F64,
+        UINT32 => DType::U32,
+        INT64 => DType::I64,
+        BFLOAT16 => DType::BF16,
+        data_type => {
+            return Err(Error::Msg(format!(
+                "unsupported onnx data_type {data_type}"
+            )))
+        }
+    };
+    if !proto.raw_data.is_empty() {
+        return tensor_from_le_bytes(&proto.raw_data, dtype, shape);
+    }
+
+    // otherwise the values are stored in the typed field for the data type
+    let device = &Device::Cpu;
+    match dtype {
+        DType::F32 => Tensor::from_vec(proto.float_data.clone(), shape, device),
+        DType::F64 => Tensor::from_vec(proto.double_data.clone(), shape, device),
+        DType::I64 => Tensor::from_vec(proto.int64_data.clone(), shape, device),
+        DType::U8 => {
+            let data = proto
+                .int32_data
+                .iter()
+                .map(|&v| v as u8)
+                .collect::<Vec<_>>();
+            Tensor::from_vec(data, shape, device)
+        }
+        DType::F16 => {
+            let data = proto
+                .int32_data
+                .iter()
+                .map(|&v| f16::from_bits(v as u16))
+                .collect::<Vec<_>>();
+            Tensor::from_vec(data, shape, device)
+        }
+        DType::BF16 => {
+            let data = proto
+                .int32_data
+                .iter()
+                .map(|&v| bf16::from_bits(v as u16))
+                .collect::<Vec<_>>();
+            Tensor::from_vec(data, shape, device)
+        }
+        DType::U32 => {
+            let data = proto
+                .uint64_data
+                .iter()
+                .map(|&v| v as u32)
+                .collect::<Vec<_>>();
+            Tensor::from_vec(data, shape, device)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_onnx_roundtrip() {
+        let x = Tensor::new(&[[1f64, 2f64], [3f64, 4f64]], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_onnx(&x, &mut buf).await.unwrap();
+        let roundtrip = read_onnx(buf.as_slice()).await.unwrap();
+        assert_eq!(roundtrip.dtype(), DType::F64);
+        assert_eq!(
+            roundtrip.to_vec2::<f64>().unwrap(),
+            vec![vec![1f64, 2f64], vec![3f64, 4f64]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_onnx_typed_fields() {
+        let proto = TensorProto {
+            dims: vec![3],
+            data_type: FLOAT,
+            float_data: vec![1f32, 2f32, 3f32],
+            ..Default::default()
+        };
+        let tensor = proto_to_tensor(&proto).unwrap();
+        assert_eq!(tensor.to_vec1::<f32>().unwrap(), vec![1f32, 2f32, 3f32]);
+    }
+}
@@ -0,0 +1,130 @@
+//! A pool of reusable byte buffers.
+//!
+//! Reading and writing a tensor needs scratch buffers for headers, chunks of
+//! the body being written and narrow integers being widened. Drawing them
+//! from a pool avoids an allocation per buffer under high request rates.
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, OnceLock};
+
+/// Number of idle buffers kept by the global pool.
+const GLOBAL_POOL_SIZE: usize = 64;
+/// Buffers that grew beyond this capacity are freed instead of pooled so one
+/// large request does not pin its memory forever.
+const MAX_POOLED_CAPACITY: usize = 4 * 1024 * 1024;
+
+/// A pool of byte buffers.
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    /// A pool keeping at most `max_buffers` idle buffers.
+    pub fn new(max_buffers: usize) -> BufferPool {
+        BufferPool {
+            buffers: Mutex::new(Vec::with_capacity(max_buffers)),
+            max_buffers,
+        }
+    }
+
+    /// The pool shared by the functions in [`crate::io`].
+    pub fn global() -> &'static BufferPool {
+        static POOL: OnceLock<BufferPool> = OnceLock::new();
+        POOL.get_or_init(|| BufferPool::new(GLOBAL_POOL_SIZE))
+    }
+
+    /// Take an empty buffer from the pool, it is returned when dropped.
+    pub fn get(&self) -> PooledBuffer<'_> {
+        let buf = self
+            .buffers
+            .lock()
+            .ok()
+            .and_then(|mut buffers| buffers.pop())
+            .unwrap_or_default();
+        PooledBuffer { buf, pool: self }
+    }
+
+    /// Take a zeroed buffer of `len` bytes from the pool.
+    pub fn get_zeroed(&self, len: usize) -> PooledBuffer<'_> {
+        let mut buf = self.get();
+        buf.resize(len, 0);
+        buf
+    }
+
+    /// Number of idle buffers in the pool.
+    pub fn idle(&self) -> usize {
+        self.buffers.lock().map(|b| b.len()).unwrap_or(0)
+    }
+
+    fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() > MAX_POOLED_CAPACITY {
+            return;
+        }
+        buf.clear();
+        if let Ok(mut buffers) = self.buffers.lock() {
+            if buffers.len() < self.max_buffers {
+                buffers.push(buf);
+            }
+        }
+    }
+}
+
+/// A buffer borrowed from a [`BufferPool`].
+#[derive(Debug)]
+pub struct PooledBuffer<'a> {
+    buf: Vec<u8>,
+    pool: &'a BufferPool,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buf));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffers_are_reused() {
+        let pool = BufferPool::new(1);
+        {
+            let mut buf = pool.get_zeroed(16);
+            assert_eq!(buf.as_slice(), &[0u8; 16]);
+            buf[0] = 1;
+        }
+        assert_eq!(pool.idle(), 1);
+        let buf = pool.get();
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= 16);
+        assert_eq!(pool.idle(), 0);
+
+        // the pool only keeps `max_buffers` idle buffers
+        let (a, b) = (pool.get(), pool.get());
+        drop((a, b, buf));
+        assert_eq!(pool.idle(), 1);
+    }
+
+    #[test]
+    fn test_large_buffers_are_freed() {
+        let pool = BufferPool::new(1);
+        drop(pool.get_zeroed(MAX_POOLED_CAPACITY + 1));
+        assert_eq!(pool.idle(), 0);
+    }
+}
@@ -0,0 +1,246 @@
+//! Module to read and write tensors quantized to 8 bit integers.
+//!
+//! Values are mapped affinely to `i8` with a per-tensor scale and zero point,
+//! cutting the payload to a quarter of `f32` and an eighth of `f64`. Tensors
+//! are dequantized to `f32` on read.
+//!
+//! | field      | size   | description                             |
+//! |------------|--------|-----------------------------------------|
+//! | length     | 8      | length of the rest as little-endian u64 |
+//! | scale      | 4      | little-endian f32                       |
+//! | zero_point | 1      | i8                                      |
+//! | rank       | 1      | number of dimensions                    |
+//! | shape      | 8*rank | little-endian u64 dimensions            |
+//! | data       | n      | one i8 per value in row-major order     |
+use std::marker::Unpin;
+
+use candle_core::{DType, Device, Error, Result, Tensor};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::codec::{read_length_prefixed, TensorCodec};
+use super::{flatten_values, LimitExceeded};
+
+/// The affine mapping `value = (q - zero_point) * scale` between values and
+/// their quantized `i8` representation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quantization {
+    pub scale: f32,
+    pub zero_point: i8,
+}
+
+impl Quantization {
+    /// The mapping spreading the range of `values` over all 256 levels. The
+    /// range always includes zero so zeros are represented exactly.
+    pub fn for_values(values: &[f32]) -> Quantization {
+        let min = values.iter().copied().fold(0f32, f32::min);
+        let max = values.iter().copied().fold(0f32, f32::max);
+        let scale = match (max - min) / 255. {
+            scale if scale > 0. && scale.is_finite() => scale,
+            _ => 1.,
+        };
+        let zero_point = (-128. - min / scale).round().clamp(-128., 127.) as i8;
+        Quantization { scale, zero_point }
+    }
+
+    pub fn quantize(&self, value: f32) -> i8 {
+        ((value / self.scale).round() + self.zero_point as f32).clamp(-128., 127.) as i8
+    }
+
+    pub fn dequantize(&self, q: i8) -> f32 {
+        (q as f32 - self.zero_point as f32) * self.scale
+    }
+}
+
+/// Read a quantized tensor from the stream and dequantize it to `f32`.
+pub async fn read_quantized<T>(reader: T) -> Result<Tensor>
+where
+    T: AsyncReadExt + Unpin,
+{
+    read_quantized_with_limit(reader, usize::MAX).await
+}
+
+/// Read a quantized tensor from the stream and dequantize it to `f32`,
+/// rejecting tensors whose dequantized form is larger than `max_bytes` before
+/// allocating.
+pub async fn read_quantized_with_limit<T>(mut reader: T, max_bytes: usize) -> Result<Tensor>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let bytes = read_length_prefixed(&mut reader, max_bytes).await?;
+
+    let truncated = || Error::Msg("truncated quantized payload".to_string());
+    if bytes.len() < 6 {
+        return Err(truncated());
+    }
+    let (prefix, rest) = bytes.split_at(6);
+    let quantization = Quantization {
+        scale: f32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]),
+        zero_point: prefix[4] as i8,
+    };
+    let rank = prefix[5] as usize;
+    if rest.len() < 8 * rank {
+        return Err(truncated());
+    }
+    let (shape, data) = rest.split_at(8 * rank);
+    let shape = shape
+        .chunks_exact(8)
+        .map(|d| usize::try_from(u64::from_le_bytes(d.try_into().unwrap_or_default())))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(Error::wrap)?;
+    if shape.iter().try_fold(1usize, |acc, &d| acc.checked_mul(d)) != Some(data.len()) {
+        return Err(Error::Msg(format!(
+            "quantized payload of {} values does not match shape {shape:?}",
+            data.len()
+        )));
+    }
+    match data.len().checked_mul(DType::F32.size_in_bytes()) {
+        Some(nbytes) if nbytes <= max_bytes => {}
+        _ => {
+            return Err(LimitExceeded {
+                what: format!("quantized tensor of shape {shape:?}"),
+                max_bytes,
+            }
+            .into())
+        }
+    }
+    let values = data
+        .iter()
+        .map(|&q| quantization.dequantize(q as i8))
+        .collect::<Vec<_>>();
+    Tensor::from_vec(values, shape, &Device::Cpu)
+}
+
+/// Quantize a `Tensor` and write it to the stream.
+pub async fn write_quantized<T>(tensor: &Tensor, f: &mut T) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    let values = flatten_values(tensor)?
+        .to_dtype(DType::F32)?
+        .to_vec1::<f32>()?;
+    let quantization = Quantization::for_values(&values);
+    let rank = u8::try_from(tensor.rank())
+        .map_err(|_| Error::Msg(format!("rank {} is too large", tensor.rank())))?;
+
+    let mut bytes = Vec::with_capacity(14 + 8 * tensor.rank() + values.len());
+    bytes.extend_from_slice(&[0u8; 8]);
+    bytes.extend_from_slice(&quantization.scale.to_le_bytes());
+    bytes.push(quantization.zero_point as u8);
+    bytes.push(rank);
+    for &d in tensor.dims() {
+        bytes.extend_from_slice(&(d as u64).to_le_bytes());
+    }
+    bytes.extend(values.iter().map(|&v| quantization.quantize(v) as u8));
+    let len = (bytes.len() - 8) as u64;
+    bytes[..8].copy_from_slice(&len.to_le_bytes());
+    f.write_all(&bytes).await?;
+    Ok(())
+}
+
+/// Tensors quantized to 8 bit integers, read back as `f32`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuantizedCodec;
+
+impl TensorCodec for QuantizedCodec {
+    fn name(&self) -> String {
+        "quantized-i8".to_string()
+    }
+
+    async fn decode<R>(&self, reader: &mut R) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        read_quantized(reader).await
+    }
+
+    async fn decode_with_limit<R>(
+        &self,
+        reader: &mut R,
+        device: &Device,
+        max_bytes: usize,
+    ) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        read_quantized_with_limit(reader, max_bytes)
+            .await?
+            .to_device(device)
+    }
+
+    async fn encode<W>(&self, tensor: &Tensor, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        write_quantized(tensor, writer).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantization() {
+        let values = [-1f32, -0.5, 0., 0.25, 3.];
+        let quantization = Quantization::for_values(&values);
+        assert_eq!(quantization.dequantize(quantization.quantize(0.)), 0.);
+        for v in values {
+            let q = quantization.dequantize(quantization.quantize(v));
+            assert!((q - v).abs() <= quantization.scale / 2., "{v} {q}");
+        }
+
+        // constant and empty inputs get a usable scale
+        for values in [&[2f32, 2.][..], &[]] {
+            let quantization = Quantization::for_values(values);
+            assert!(quantization.scale > 0.);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quantized_roundtrip() {
+        let x = Tensor::new(&[[1f64, -2f64], [0.5f64, 0f64]], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_quantized(&x, &mut buf).await.unwrap();
+        assert_eq!(buf.len(), 8 + 6 + 16 + 4);
+        let y = read_quantized(buf.as_slice()).await.unwrap();
+        assert_eq!(y.dtype(), DType::F32);
+        assert_eq!(y.dims(), &[2, 2]);
+        let y = y.to_vec2::<f32>().unwrap();
+        assert_eq!(y[1][1], 0.);
+        assert!((y[0][1] + 2.).abs() < 0.01);
+    }
+
+    #[tokio::test]
+    async fn test_read_quantized_rejects_mismatched_shape() {
+        let mut payload = vec![0u8; 8];
+        payload.extend_from_slice(&1f32.to_le_bytes());
+        payload.extend_from_slice(&[0, 1]);
+        payload.extend_from_slice(&3u64.to_le_bytes());
+        payload.extend_from_slice(&[1, 2]);
+        let len = (payload.len() - 8) as u64;
+        payload[..8].copy_from_slice(&len.to_le_bytes());
+        assert!(read_quantized(payload.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_quantized_with_limit() {
+        // a forged length is rejected before allocating
+        let payload = u64::MAX.to_le_bytes();
+        let err = QuantizedCodec
+            .decode_with_limit(&mut payload.as_slice(), &Device::Cpu, 1 << 20)
+            .await
+            .unwrap_err();
+        assert!(LimitExceeded::is(&err), "{err}");
+
+        // the limit applies to the dequantized values
+        let x = Tensor::arange(0f32, 8f32, &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_quantized(&x, &mut buf).await.unwrap();
+        assert_eq!(buf.len(), 8 + 6 + 8 + 8);
+        assert!(read_quantized_with_limit(buf.as_slice(), 32).await.is_ok());
+        let err = read_quantized_with_limit(buf.as_slice(), 31)
+            .await
+            .unwrap_err();
+        assert!(LimitExceeded::is(&err), "{err}");
+    }
+}
@@ -0,0 +1,144 @@
+//! Module to read and write `safetensors` payloads to the stream.
+//!
+//! A `safetensors` buffer carries the name, dtype and shape of each tensor so
+//! a single payload can hold several named tensors. On the wire the buffer is
+//! prefixed by its length in bytes as a little-endian `u64`.
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::marker::Unpin;
+
+use ::safetensors::tensor::{Dtype, SafeTensors, View};
+use candle_core::{DType, Error, Result, Shape, Tensor};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::codec::read_length_prefixed;
+use super::{tensor_from_le_bytes, tensor_to_le_bytes};
+
+/// Read a `safetensors` buffer of named tensors from the stream.
+pub async fn read_safetensors<T>(reader: T) -> Result<HashMap<String, Tensor>>
+where
+    T: AsyncReadExt + Unpin,
+{
+    read_safetensors_with_limit(reader, usize::MAX).await
+}
+
+/// Read a `safetensors` buffer of named tensors from the stream, rejecting
+/// buffers longer than `max_bytes` before allocating.
+pub async fn read_safetensors_with_limit<T>(
+    mut reader: T,
+    max_bytes: usize,
+) -> Result<HashMap<String, Tensor>>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let bytes = read_length_prefixed(&mut reader, max_bytes).await?;
+
+    let safetensors = SafeTensors::deserialize(&bytes)?;
+    let mut tensors = HashMap::new();
+    for (name, view) in safetensors.tensors() {
+        let dtype = match view.dtype() {
+            Dtype::BF16 => DType::BF16,
+            Dtype::F16 => DType::F16,
+            Dtype::F32 => DType::F32,
+            Dtype::F64 => DType::F64,
+            Dtype::U8 | Dtype::BOOL => DType::U8,
+            Dtype::U32 => DType::U32,
+            Dtype::I64 => DType::I64,
+            dtype => {
+                return Err(Error::Msg(format!(
+                    "unsupported safetensors dtype {dtype:?}"
+                )))
+            }
+        };
+        let shape = Shape::from(view.shape());
+        tensors.insert(name, tensor_from_le_bytes(view.data(), dtype, shape)?);
+    }
+    Ok(tensors)
+}
+
+/// Write named `Tensor`s to the stream as a `safetensors` buffer.
+pub async fn write_safetensors<T>(tensors: &[(&str, &Tensor)], f: &mut T) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    let mut views = vec![];
+    for (name, tensor) in tensors {
+        views.push((name.to_string(), RawView::new(tensor)?));
+    }
+    let bytes = ::safetensors::serialize(views.iter().map(|(n, v)| (n, v)), &None)?;
+
+    f.write_u64_le(bytes.len() as u64).await?;
+    f.write_all(&bytes).await?;
+    Ok(())
+}
+
+/// Little-endian tensor data in the layout expected by `safetensors`.
+struct RawView {
+    dtype: Dtype,
+    shape: Vec<usize>,
+    data: Vec<u8>,
+}
+
+impl RawView {
+    fn new(tensor: &Tensor) -> Result<RawView> {
+        let dtype = match tensor.dtype() {
+            DType::BF16 => Dtype::BF16,
+            DType::F16 => Dtype::F16,
+            DType::F32 => Dtype::F32,
+            DType::F64 => Dtype::F64,
+            DType::U8 => Dtype::U8,
+            DType::U32 => Dtype::U32,
+            DType::I64 => Dtype::I64,
+        };
+        Ok(RawView {
+            dtype,
+            shape: tensor.dims().to_vec(),
+            data: tensor_to_le_bytes(tensor)?,
+        })
+    }
+}
+
+impl View for &RawView {
+    fn dtype(&self) -> Dtype {
+        self.dtype
+    }
+
+    fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    fn data(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.data)
+    }
+
+    fn data_len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[tokio::test]
+    async fn test_safetensors_roundtrip() {
+        let ids = Tensor::new(&[[1u32, 2u32, 3u32]], &Device::Cpu).unwrap();
+        let x = Tensor::new(&[[0.5f32], [1.5f32]], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_safetensors(&[("input_ids", &ids), ("x", &x)], &mut buf)
+            .await
+            .unwrap();
+        let tensors = read_safetensors(buf.as_slice()).await.unwrap();
+        assert_eq!(tensors.len(), 2);
+        assert_eq!(tensors["input_ids"].dtype(), DType::U32);
+        assert_eq!(
+            tensors["input_ids"].to_vec2::<u32>().unwrap(),
+            vec![vec![1u32, 2u32, 3u32]]
+        );
+        assert_eq!(
+            tensors["x"].to_vec2::<f32>().unwrap(),
+            vec![vec![0.5f32], vec![1.5f32]]
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! Module to read and write tensors in sparse coordinate (COO) format.
+//!
+//! Only the non-zero values are sent along with their coordinates, so mostly
+//! zero tensors such as bag-of-words features are cheap to transfer. Tensors
+//! are densified on read.
+//!
+//! | field   | size   | description                                |
+//! |---------|--------|--------------------------------------------|
+//! | rank    | 1      | number of dimensions of the dense tensor   |
+//! | shape   | 8*rank | little-endian u64 dimensions               |
+//! | indices | npy    | `u32` array of shape `(nnz, rank)`         |
+//! | values  | npy    | array of shape `(nnz,)`                    |
+//!
+//! Values at repeated coordinates are summed.
+use std::marker::Unpin;
+
+use candle_core::{DType, Device, Error, Result, Tensor, WithDType};
+use half::{bf16, f16};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::codec::TensorCodec;
+use super::{flatten_values, read_numpy_with_limit, write_numpy, LimitExceeded};
+
+/// Read a sparse tensor from the stream into a dense `Tensor`.
+pub async fn read_sparse<T>(reader: T) -> Result<Tensor>
+where
+    T: AsyncReadExt + Unpin,
+{
+    read_sparse_with_limit(reader, usize::MAX).await
+}
+
+/// Read a sparse tensor from the stream into a dense `Tensor`, rejecting
+/// tensors whose dense form is larger than `max_bytes` before allocating.
+pub async fn read_sparse_with_limit<T>(mut reader: T, max_bytes: usize) -> Result<Tensor>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let rank = reader.read_u8().await? as usize;
+    let mut shape = Vec::with_capacity(rank);
+    for _ in 0..rank {
+        let d = reader.read_u64_le().await?;
+        shape.push(usize::try_from(d).map_err(Error::wrap)?);
+    }
+    let indices = read_numpy_with_limit(&mut reader, &Device::Cpu, max_bytes).await?;
+    let values = read_numpy_with_limit(&mut reader, &Device::Cpu, max_bytes).await?;
+    let nnz = values.elem_count();
+    if indices.dtype() != DType::U32 || indices.dims() != [nnz, rank] {
+        return Err(Error::Msg(format!(
+            "expected u32 indices of shape ({nnz}, {rank}), got {:?} {:?}",
+            indices.dtype(),
+            indices.dims()
+        )));
+    }
+
+    let elem_count = shape.iter().try_fold(1usize, |acc, &d| acc.checked_mul(d));
+    let elem_count = match elem_count {
+        Some(n) if n.saturating_mul(values.dtype().size_in_bytes()) <= max_bytes => n,
+        _ => {
+            return Err(LimitExceeded {
+                what: format!("sparse tensor of shape {shape:?}"),
+                max_bytes,
+            }
+            .into())
+        }
+    };
+    let indices = flatten_values(&indices)?.to_vec1::<u32>()?;
+    // densify in the values' own dtype so integers are summed exactly
+    match values.dtype() {
+        DType::BF16 => densify::<bf16>(&indices, &values, shape, elem_count),
+        DType::F16 => densify::<f16>(&indices, &values, shape, elem_count),
+        DType::F32 => densify::<f32>(&indices, &values, shape, elem_count),
+        DType::F64 => densify::<f64>(&indices, &values, shape, elem_count),
+        DType::U8 => densify::<u8>(&indices, &values, shape, elem_count),
+        DType::U32 => densify::<u32>(&indices, &values, shape, elem_count),
+        DType::I64 => densify::<i64>(&indices, &values, shape, elem_count),
+    }
+}
+
+/// Scatter `values` at the row-major coordinates `indices` into a zeroed
+/// tensor of `shape`, summing values at repeated coordinates.
+fn densify<V: WithDType>(
+    indices: &[u32],
+    values: &Tensor,
+    shape: Vec<usize>,
+    elem_count: usize,
+) -> Result<Tensor> {
+    let rank = shape.len();
+    let mut dense = vec![V::from_f64(0.); elem_count];
+    let values = flatten_values(values)?.to_vec1::<V>()?;
+    for (k, &value) in values.iter().enumerate() {
+        let coords = &indices[k * rank..(k + 1) * rank];
+        let mut offset = 0;
+        for (&i, &d) in coords.iter().zip(shape.iter()) {
+            if i as usize >= d {
+                return Err(Error::Msg(format!(
+                    "index {coords:?} out of bounds for shape {shape:?}"
+                )));
+            }
+            offset = offset * d + i as usize;
+        }
+        dense[offset] += value;
+    }
+    Tensor::from_vec(dense, shape, &Device::Cpu)
+}
+
+/// Write a `Tensor` to the stream in sparse format, sending only its non-zero
+/// values.
+pub async fn write_sparse<T>(tensor: &Tensor, f: &mut T) -> Result<()>
+where
+    T: AsyncWriteExt + Unpin,
+{
+    let rank = u8::try_from(tensor.rank())
+        .map_err(|_| Error::Msg(format!("rank {} is too large", tensor.rank())))?;
+    let dims = tensor.dims();
+    let dense = flatten_values(tensor)?
+        .to_dtype(DType::F64)?
+        .to_vec1::<f64>()?;
+
+    let mut indices = vec![];
+    let mut values = vec![];
+    for (offset, &value) in dense.iter().enumerate() {
+        if value == 0. {
+            continue;
+        }
+        // unravel the row-major offset into coordinates
+        let start = indices.len();
+        let mut rest = offset;
+        for &d in dims.iter().rev() {
+            let i = u32::try_from(rest % d)
+                .map_err(|_| Error::Msg(format!("dimension {d} is too large")))?;
+            indices.push(i);
+            rest /= d;
+        }
+        indices[start..].reverse();
+        values.push(value);
+    }
+
+    let nnz = values.len();
+    let indices = Tensor::from_vec(indices, (nnz, dims.len()), &Device::Cpu)?;
+    let values = Tensor::from_vec(values, nnz, &Device::Cpu)?.to_dtype(tensor.dtype())?;
+    f.write_u8(rank).await?;
+    for &d in dims {
+        f.write_u64_le(d as u64).await?;
+    }
+    write_numpy(&indices, f).await?;
+    write_numpy(&values, f).await
+}
+
+/// Sparse tensors, densified on read.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SparseCodec;
+
+impl TensorCodec for SparseCodec {
+    fn name(&self) -> String {
+        "sparse".to_string()
+    }
+
+    async fn decode<R>(&self, reader: &mut R) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        read_sparse(reader).await
+    }
+
+    async fn decode_with_limit<R>(
+        &self,
+        reader: &mut R,
+        device: &Device,
+        max_bytes: usize,
+    ) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        read_sparse_with_limit(reader, max_bytes)
+            .await?
+            .to_device(device)
+    }
+
+    async fn encode<W>(&self, tensor: &Tensor, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        write_sparse(tensor, writer).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sparse_roundtrip() {
+        let x = Tensor::new(&[[0u32, 0, 3], [0, 0, 0], [1, 0, 0]], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_sparse(&x, &mut buf).await.unwrap();
+        let y = read_sparse(buf.as_slice()).await.unwrap();
+        assert_eq!(y.dtype(), DType::U32);
+        assert_eq!(y.to_vec2::<u32>().unwrap(), x.to_vec2::<u32>().unwrap());
+
+        let x = Tensor::new(2f32, &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        write_sparse(&x, &mut buf).await.unwrap();
+        let y = read_sparse(buf.as_slice()).await.unwrap();
+        assert_eq!(y.to_scalar::<f32>().unwrap(), 2f32);
+    }
+
+    #[tokio::test]
+    async fn test_read_sparse_checks_bounds() {
+        let indices = Tensor::new(&[[0u32, 5u32]], &Device::Cpu).unwrap();
+        let values = Tensor::new(&[1f32], &Device::Cpu).unwrap();
+        let mut buf = vec![2u8];
+        buf.extend_from_slice(&2u64.to_le_bytes());
+        buf.extend_from_slice(&2u64.to_le_bytes());
+        write_numpy(&indices, &mut buf).await.unwrap();
+        write_numpy(&values, &mut buf).await.unwrap();
+        assert!(read_sparse(buf.as_slice()).await.is_err());
+
+        // a huge dense shape is rejected before allocating
+        buf[1..9].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        assert!(read_sparse_with_limit(buf.as_slice(), 1 << 30)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_sparse_native_dtype() {
+        // i64 values beyond the precision of f64 are kept exactly
+        let big = (1i64 << 53) + 1;
+        let indices = Tensor::new(&[[1u32], [1u32]], &Device::Cpu).unwrap();
+        let values = Tensor::new(&[big, 1i64], &Device::Cpu).unwrap();
+        let mut buf = vec![1u8];
+        buf.extend_from_slice(&3u64.to_le_bytes());
+        write_numpy(&indices, &mut buf).await.unwrap();
+        write_numpy(&values, &mut buf).await.unwrap();
+        let y = read_sparse(buf.as_slice()).await.unwrap();
+        assert_eq!(y.to_vec1::<i64>().unwrap(), vec![0, big + 1, 0]);
+
+        // the limit is checked against the values' own dtype
+        let indices = Tensor::new(&[[0u32]], &Device::Cpu).unwrap();
+        let values = Tensor::new(&[1u8], &Device::Cpu).unwrap();
+        let mut buf = vec![1u8];
+        buf.extend_from_slice(&16u64.to_le_bytes());
+        write_numpy(&indices, &mut buf).await.unwrap();
+        write_numpy(&values, &mut buf).await.unwrap();
+        let y = read_sparse_with_limit(buf.as_slice(), 16).await.unwrap();
+        assert_eq!(y.dtype(), DType::U8);
+        assert!(read_sparse_with_limit(buf.as_slice(), 15).await.is_err());
+    }
+}
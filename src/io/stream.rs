@@ -0,0 +1,179 @@
+//! Reading a sequence of arrays from a long-lived stream, and streamed
+//! responses.
+//!
+//! A server whose forward pass yields several tensors, such as per-layer
+//! activations or progressive refinements, writes each as soon as it's
+//! produced, see [`Server::streaming`](crate::server::Server::streaming),
+//! and a server generating tokens writes each chunk as soon as it's
+//! generated, see [`Server::generate`](crate::server::Server::generate).
+//! Each tensor is preceded by `b"SNNS"` and encoded with the server's codec,
+//! and the response ends with `b"SNNZ"`, or with an error frame if the
+//! forward pass fails part way, see [`crate::io::error`]. The echoed
+//! envelope of a streamed response has
+//! [`FLAG_STREAMING`](crate::io::envelope::FLAG_STREAMING) set.
+use std::marker::Unpin;
+
+use candle_core::{Device, Error, Result, Tensor};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use super::codec::TensorCodec;
+use super::error::{read_error_frame_body, ERROR_MAGIC};
+use super::read_numpy_to_device;
+
+/// Precedes each tensor of a streamed response.
+pub const STREAM_ITEM_MAGIC: &[u8; 4] = b"SNNS";
+/// Ends a streamed response.
+pub const STREAM_END_MAGIC: &[u8; 4] = b"SNNZ";
+
+/// Yields successive `numpy` arrays read from one stream.
+///
+/// ```no_run
+/// # use socket_nn::io::stream::NpyStream;
+/// # async fn consume(socket: tokio::net::TcpStream) -> candle_core::Result<()> {
+/// let mut stream = NpyStream::new(socket);
+/// while let Some(tensor) = stream.next().await {
+///     println!("{:?}", tensor?.shape());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct NpyStream<R> {
+    reader: R,
+    device: Device,
+}
+
+impl<R: AsyncRead + Unpin> NpyStream<R> {
+    /// A stream reading arrays onto the CPU.
+    pub fn new(reader: R) -> NpyStream<R> {
+        NpyStream {
+            reader,
+            device: Device::Cpu,
+        }
+    }
+
+    /// Read arrays onto the given device.
+    pub fn with_device(mut self, device: Device) -> NpyStream<R> {
+        self.device = device;
+        self
+    }
+
+    /// Read the next array, or `None` if the stream ended cleanly between
+    /// arrays. A stream ending part way through an array is an error.
+    pub async fn next(&mut self) -> Option<Result<Tensor>> {
+        let mut first = [0u8; 1];
+        match self.reader.read(&mut first).await {
+            Ok(0) => None,
+            Ok(_) => {
+                let reader = first.as_slice().chain(&mut self.reader);
+                Some(read_numpy_to_device(reader, &self.device).await)
+            }
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+
+    /// The underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Write one tensor of a streamed response.
+pub async fn write_stream_item<C, W>(codec: &C, tensor: &Tensor, f: &mut W) -> Result<()>
+where
+    C: TensorCodec,
+    W: AsyncWriteExt + Unpin + Send,
+{
+    f.write_all(STREAM_ITEM_MAGIC).await?;
+    codec.encode(tensor, f).await
+}
+
+/// Write the end of a streamed response.
+pub async fn write_stream_end<W>(f: &mut W) -> Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    f.write_all(STREAM_END_MAGIC).await?;
+    Ok(())
+}
+
+/// Read the next tensor of a streamed response onto the given device, or
+/// `None` once it has ended. An error frame ending the response is returned
+/// as an error wrapping the [`ErrorFrame`](super::error::ErrorFrame).
+pub async fn read_stream_item<C, R>(
+    codec: &C,
+    reader: &mut R,
+    device: &Device,
+) -> Result<Option<Tensor>>
+where
+    C: TensorCodec,
+    R: AsyncRead + Unpin + Send,
+{
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).await?;
+    match &magic {
+        STREAM_ITEM_MAGIC => codec.decode_to_device(reader, device).await.map(Some),
+        STREAM_END_MAGIC => Ok(None),
+        ERROR_MAGIC => Err(Error::wrap(read_error_frame_body(reader).await?)),
+        _ => Err(Error::Msg("stream item magic mismatch".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::codec::NpyCodec;
+    use crate::io::error::{write_error_frame, ErrorFrame};
+    use crate::io::write_numpy;
+
+    #[tokio::test]
+    async fn test_npy_stream() {
+        let mut buf = Vec::new();
+        for i in 0..3 {
+            let x = Tensor::new(&[i as f32, 1f32], &Device::Cpu).unwrap();
+            write_numpy(&x, &mut buf).await.unwrap();
+        }
+        let mut stream = NpyStream::new(buf.as_slice());
+        for i in 0..3 {
+            let y = stream.next().await.unwrap().unwrap();
+            assert_eq!(y.to_vec1::<f32>().unwrap(), vec![i as f32, 1f32]);
+        }
+        assert!(stream.next().await.is_none());
+
+        // a truncated array is an error rather than the end of the stream
+        let mut stream = NpyStream::new(&buf[..buf.len() - 1]);
+        for _ in 0..2 {
+            stream.next().await.unwrap().unwrap();
+        }
+        assert!(stream.next().await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_streamed_response() {
+        let mut buf = Vec::new();
+        for i in 0..2 {
+            let x = Tensor::new(&[i as f32], &Device::Cpu).unwrap();
+            write_stream_item(&NpyCodec, &x, &mut buf).await.unwrap();
+        }
+        write_stream_end(&mut buf).await.unwrap();
+        let mut reader = buf.as_slice();
+        for i in 0..2 {
+            let y = read_stream_item(&NpyCodec, &mut reader, &Device::Cpu)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(y.to_vec1::<f32>().unwrap(), vec![i as f32]);
+        }
+        let end = read_stream_item(&NpyCodec, &mut reader, &Device::Cpu).await;
+        assert!(end.unwrap().is_none());
+
+        let mut buf = Vec::new();
+        write_error_frame(&ErrorFrame::new(2, "diverged"), &mut buf)
+            .await
+            .unwrap();
+        let e = read_stream_item(&NpyCodec, &mut buf.as_slice(), &Device::Cpu)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("diverged"));
+    }
+}
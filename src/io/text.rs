@@ -0,0 +1,112 @@
+//! Text transport for exercising a server by hand.
+//!
+//! Each payload is base64 encoded and terminated by a newline, so requests can
+//! be sent from `nc` or shell scripts, e.g.
+//! `python -c "..." | base64 -w0; echo` piped into `nc localhost 8080`.
+use std::marker::Unpin;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use candle_core::{Device, Error, Result, Tensor};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use super::codec::{NpyCodec, TensorCodec};
+use super::read_line;
+
+/// Wraps another codec so each payload is sent as a line of base64 text.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Base64Codec<C = NpyCodec> {
+    inner: C,
+}
+
+impl<C: TensorCodec> Base64Codec<C> {
+    pub fn new(inner: C) -> Base64Codec<C> {
+        Base64Codec { inner }
+    }
+}
+
+impl<C: TensorCodec> TensorCodec for Base64Codec<C> {
+    fn name(&self) -> String {
+        format!("base64+{}", self.inner.name())
+    }
+
+    async fn decode<R>(&self, reader: &mut R) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let payload = read_payload(reader, usize::MAX).await?;
+        self.inner.decode(&mut payload.as_slice()).await
+    }
+
+    async fn decode_with_limit<R>(
+        &self,
+        reader: &mut R,
+        device: &Device,
+        max_bytes: usize,
+    ) -> Result<Tensor>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let payload = read_payload(reader, max_bytes).await?;
+        self.inner
+            .decode_with_limit(&mut payload.as_slice(), device, max_bytes)
+            .await
+    }
+
+    async fn encode<W>(&self, tensor: &Tensor, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        let mut payload = Vec::new();
+        self.inner.encode(tensor, &mut payload).await?;
+        let mut line = STANDARD.encode(payload).into_bytes();
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+        Ok(())
+    }
+}
+
+/// Read a line of base64 text and decode it, rejecting lines encoding more
+/// than `max_bytes` before reading the rest of them.
+async fn read_payload<R>(reader: &mut R, max_bytes: usize) -> Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    // four characters per three bytes, and a carriage return
+    let max_line = (max_bytes / 3 + 1).saturating_mul(4).saturating_add(1);
+    let line = read_line(reader, max_line).await?;
+    // tolerate the carriage return sent by telnet
+    let line = line.strip_suffix(b"\r").unwrap_or(&line);
+    STANDARD.decode(line).map_err(Error::wrap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::LimitExceeded;
+
+    #[tokio::test]
+    async fn test_base64_codec() {
+        let codec = Base64Codec::new(NpyCodec);
+        let x = Tensor::new(&[1f32, 2f32], &Device::Cpu).unwrap();
+        let mut buf = Vec::new();
+        codec.encode(&x, &mut buf).await.unwrap();
+        assert_eq!(buf.last(), Some(&b'\n'));
+        assert!(buf[..buf.len() - 1].iter().all(|b| b.is_ascii_graphic()));
+
+        // as typed into telnet
+        buf.insert(buf.len() - 1, b'\r');
+        let y = codec.decode(&mut buf.as_slice()).await.unwrap();
+        assert_eq!(y.to_vec1::<f32>().unwrap(), vec![1f32, 2f32]);
+    }
+
+    #[tokio::test]
+    async fn test_base64_codec_limit() {
+        // a line that never ends
+        let err = Base64Codec::new(NpyCodec)
+            .decode_with_limit(&mut tokio::io::repeat(b'A'), &Device::Cpu, 1024)
+            .await
+            .unwrap_err();
+        assert!(LimitExceeded::is(&err), "{err}");
+    }
+}
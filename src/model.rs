@@ -0,0 +1,47 @@
+//! Models served without a separate forward function.
+use candle_core::{Module, Result, Tensor};
+
+use crate::server::batch::BatchConfig;
+use crate::server::validate::Schema;
+
+/// A model that runs its forward pass on a request tensor.
+///
+/// Implemented for every [`Module`], which `candle_nn` re-exports, so layers
+/// and models built with `candle_nn` can be served directly.
+pub trait ServeModel: Send + Sync + 'static {
+    fn forward(&self, x: Tensor) -> Result<Tensor>;
+
+    /// Limits on the batches run through this model, replacing the server's
+    /// defaults when served with [`Server::from_model`] and batching enabled.
+    /// Limits set on the server builder still apply on top.
+    ///
+    /// [`Server::from_model`]: crate::server::Server::from_model
+    fn batch_config(&self) -> Option<BatchConfig> {
+        None
+    }
+
+    /// The input this model expects, checked before the forward pass when
+    /// served with [`Server::from_model`].
+    ///
+    /// [`Server::from_model`]: crate::server::Server::from_model
+    fn input_schema(&self) -> Option<Schema> {
+        None
+    }
+
+    /// The output this model returns, checked after the forward pass when
+    /// served with [`Server::from_model`].
+    ///
+    /// [`Server::from_model`]: crate::server::Server::from_model
+    fn output_schema(&self) -> Option<Schema> {
+        None
+    }
+}
+
+impl<T> ServeModel for T
+where
+    T: Module + Send + Sync + 'static,
+{
+    fn forward(&self, x: Tensor) -> Result<Tensor> {
+        Module::forward(self, &x)
+    }
+}
@@ -1,9 +1,181 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(any(feature = "tls", feature = "quic"))]
+use std::path::Path;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use candle_core::{Error, Tensor};
+use candle_core::{Device, Error, Tensor};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::TcpListener;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{sleep, timeout};
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
 
-use crate::io::{read_numpy, write_numpy};
+use crate::io::codec::{NpyCodec, TensorCodec};
+use crate::io::envelope::{
+    read_envelope_body, write_envelope, Envelope, SamplingParams, ENVELOPE_MAGIC, FLAG_STREAMING,
+};
+use crate::io::error::{codes, write_error_frame, ErrorFrame};
+use crate::io::handshake::{read_hello_body, write_hello, Hello, HELLO_MAGIC};
+use crate::io::named::{read_named_with, write_named_with};
+use crate::io::stream::{write_stream_end, write_stream_item};
+use crate::io::LimitExceeded;
+use crate::model::ServeModel;
+
+use self::access_log::{AccessLogFormat, AccessRecord, Outcome};
+use self::admin::{Admin, LoadFn, SetLogLevelFn};
+use self::batch::{BatchConfig, Batcher};
+use self::executor::Executor;
+use self::generate::{TokenSender, TokenStream};
+use self::limit::{ConnectionLimiter, ConnectionSlot, RateLimiter, RequestReader};
+use self::listener::Listener;
+use self::metrics::{CountingWriter, Metrics, Phase, PhaseTimer, ServerStats};
+use self::pipeline::Pipeline;
+use self::queue::{InferenceQueue, Turn};
+use self::quota::{QuotaSlot, Quotas};
+use self::session::{SessionState, Sessions, State};
+use self::shadow::{Shadow, ShadowTarget};
+use self::split::TrafficSplit;
+use self::statsd::{Statsd, StatsdConfig};
+use self::validate::{check_finite, check_input, check_output, Schema};
+
+pub mod access_log;
+mod admin;
+pub mod batch;
+pub mod dashboard;
+mod executor;
+pub mod generate;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "http")]
+mod http;
+mod limit;
+pub mod listener;
+pub mod metrics;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod pipeline;
+mod queue;
+#[cfg(feature = "quic")]
+mod quic;
+pub mod quota;
+#[cfg(feature = "remote")]
+pub mod remote;
+pub mod session;
+mod shadow;
+mod split;
+mod statsd;
+#[cfg(any(feature = "tls", feature = "quic"))]
+mod tls;
+pub mod validate;
+#[cfg(feature = "verify")]
+pub mod verify;
+#[cfg(feature = "watch")]
+pub mod watch;
+#[cfg(feature = "websocket")]
+mod websocket;
+#[cfg(feature = "zmq")]
+mod zmq;
+
+/// Initial and longest pause after failing to accept a connection.
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The model requests are run on, replaced by a [`ModelReloader`].
+pub(crate) type SharedModel<M> = Arc<tokio::sync::watch::Sender<Arc<M>>>;
+
+/// The function that runs the forward pass of a model on a tensor input.
+pub type ForwardFn<M> = dyn Fn(&M, Tensor) -> Result<Tensor, Error> + Send + Sync;
+
+/// The forward pass of a model registered by name, see [`Server::register`].
+type RegisteredForwardFn = dyn Fn(Tensor) -> Result<Tensor, Error> + Send + Sync;
+
+/// A version of a model registered by name.
+struct RegisteredModel {
+    version: Option<String>,
+    forward: Arc<RegisteredForwardFn>,
+}
+
+/// Where a request naming a model runs.
+struct Route {
+    /// The registered model run, or `None` for the server's own.
+    forward: Option<Arc<RegisteredForwardFn>>,
+    /// The version run, if versioned.
+    version: Option<String>,
+}
+
+/// The function that runs the forward pass of a model on named tensor inputs
+/// and returns named outputs.
+pub type NamedForwardFn<M> =
+    dyn Fn(&M, HashMap<String, Tensor>) -> Result<HashMap<String, Tensor>, Error> + Send + Sync;
+
+/// The function that runs the forward pass of a model on a tensor input and
+/// the request's JSON metadata, returning the output and response metadata.
+pub type MetadataForwardFn<M> =
+    dyn Fn(&M, Tensor, Option<Value>) -> Result<(Tensor, Option<Value>), Error> + Send + Sync;
+
+/// The function that runs the forward pass of a model on a tensor input
+/// asynchronously.
+pub type AsyncForwardFn<M> = dyn Fn(Arc<M>, Tensor) -> Pin<Box<dyn Future<Output = Result<Tensor, Error>> + Send>>
+    + Send
+    + Sync;
+
+/// The tensors a streaming forward pass yields, see [`Server::streaming`].
+pub type TensorStream = Box<dyn Iterator<Item = Result<Tensor, Error>> + Send>;
+
+/// The function that runs the forward pass of a model on a tensor input,
+/// yielding the output as a stream of tensors.
+pub type StreamForwardFn<M> = dyn Fn(Arc<M>, Tensor) -> Result<TensorStream, Error> + Send + Sync;
+
+/// The function that runs autoregressive generation on a prompt with the
+/// request's sampling parameters, sending tokens as they're generated, see
+/// [`Server::generate`].
+pub type GenerateFn<M> =
+    dyn Fn(&M, Tensor, &SamplingParams, &TokenSender) -> Result<(), Error> + Send + Sync;
+
+/// The forward pass of a stateful server on a tensor input and the state of
+/// its session, returning the output and the bytes the state holds.
+type SessionForwardFn<M> =
+    dyn Fn(&M, Tensor, &mut State) -> (Result<Tensor, Error>, usize) + Send + Sync;
+
+/// Runs a newly loaded model on the warmup inputs, see [`Server::with_warmup`].
+type WarmupFn<M> = dyn Fn(&Arc<M>) -> Result<(), Error> + Send + Sync;
+
+/// A callback invoked with every error raised while serving a connection.
+pub type ErrorHandler = Arc<dyn Fn(&Error) + Send + Sync>;
+
+/// A callback deciding whether a request's API key or bearer token is valid.
+pub type TokenVerifier = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// The forward pass served for each request.
+enum Forward<M> {
+    Tensor(Arc<ForwardFn<M>>),
+    Named(Arc<NamedForwardFn<M>>),
+    Metadata(Arc<MetadataForwardFn<M>>),
+    Async(Arc<AsyncForwardFn<M>>),
+    Stream(Arc<StreamForwardFn<M>>),
+    Generate(Arc<GenerateFn<M>>),
+    Session(Arc<SessionForwardFn<M>>),
+}
+
+/// The protocol spoken on accepted connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    /// Requests written directly to the stream.
+    Stream,
+    #[cfg(feature = "websocket")]
+    WebSocket,
+    #[cfg(feature = "http")]
+    Http,
+}
 
 /// Runs a server that accepts numpy arrays and returns the result of a forward pass.
 ///
@@ -11,40 +183,2583 @@ use crate::io::{read_numpy, write_numpy};
 ///
 /// * `addr` - The address to bind to.
 /// * `model` - The model to run as an Arc.
-/// * `net_forward` - The function that runs the forward pass. This should accept
-/// a reference to the model and a tensor input and should return a tensor.
-pub async fn run_server<M>(
+/// * `net_forward` - The function or closure that runs the forward pass. This
+///   should accept a reference to the model and a tensor input and should
+///   return a tensor.
+pub async fn run_server<M, F>(addr: &str, model: Arc<M>, net_forward: F) -> Result<(), Error>
+where
+    M: Sync + Send + 'static,
+    F: Fn(&M, Tensor) -> Result<Tensor, Error> + Send + Sync + 'static,
+{
+    Server::new(model, net_forward).run(addr).await
+}
+
+/// Runs a server that accepts numpy arrays and returns the output of the model.
+///
+/// # Arguments
+///
+/// * `addr` - The address to bind to.
+/// * `model` - The model to run as an Arc, such as any `candle_nn` module.
+pub async fn run_model_server<M>(addr: &str, model: Arc<M>) -> Result<(), Error>
+where
+    M: ServeModel,
+{
+    Server::from_model(model).run(addr).await
+}
+
+/// Runs a server that accepts numpy arrays and returns the result of an
+/// asynchronous forward pass.
+///
+/// # Arguments
+///
+/// * `addr` - The address to bind to.
+/// * `model` - The model to run as an Arc.
+/// * `net_forward` - The async function or closure that runs the forward pass.
+///   This should accept the model and a tensor input and should return a
+///   future resolving to a tensor.
+pub async fn run_server_async<M, F, Fut>(
     addr: &str,
     model: Arc<M>,
-    net_forward: fn(&M, Tensor) -> Result<Tensor, Error>,
+    net_forward: F,
 ) -> Result<(), Error>
 where
     M: Sync + Send + 'static,
+    F: Fn(Arc<M>, Tensor) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Tensor, Error>> + Send + 'static,
 {
-    let listener = TcpListener::bind(addr).await.expect("Failed to bind.");
+    Server::new_async(model, net_forward).run(addr).await
+}
+
+/// Runs a server that reads and writes tensors with the given codec and returns
+/// the result of a forward pass.
+///
+/// # Arguments
+///
+/// * `addr` - The address to bind to.
+/// * `model` - The model to run as an Arc.
+/// * `net_forward` - The function or closure that runs the forward pass. This
+///   should accept a reference to the model and a tensor input and should
+///   return a tensor.
+/// * `codec` - The wire format of the request and response tensors.
+pub async fn run_server_with_codec<M, F, C>(
+    addr: &str,
+    model: Arc<M>,
+    net_forward: F,
+    codec: C,
+) -> Result<(), Error>
+where
+    M: Sync + Send + 'static,
+    F: Fn(&M, Tensor) -> Result<Tensor, Error> + Send + Sync + 'static,
+    C: TensorCodec + Clone,
+{
+    Server::new(model, net_forward)
+        .with_codec(codec)
+        .run(addr)
+        .await
+}
+
+/// A server running the forward pass of a model on each request.
+///
+/// ```no_run
+/// # use std::sync::Arc;
+/// # use candle_core::{Device, Result, Tensor};
+/// # use socket_nn::server::Server;
+/// # fn forward(model: &(), x: Tensor) -> Result<Tensor> { Ok(x) }
+/// # async fn serve() -> Result<()> {
+/// Server::new(Arc::new(()), forward)
+///     .with_device(Device::Cpu)
+///     .run("127.0.0.1:8080")
+///     .await
+/// # }
+/// ```
+pub struct Server<M, C = NpyCodec> {
+    model: SharedModel<M>,
+    forward: Forward<M>,
+    models: HashMap<String, Vec<RegisteredModel>>,
+    splits: HashMap<String, TrafficSplit>,
+    shadow: Option<ShadowTarget>,
+    sessions: Sessions,
+    codec: C,
+    device: Device,
+    max_payload_bytes: usize,
+    max_request_bytes: usize,
+    check_finite: bool,
+    input_schema: Option<Schema>,
+    output_schema: Option<Schema>,
+    preprocess: Pipeline,
+    postprocess: Pipeline,
+    max_requests_per_connection: Option<usize>,
+    idle_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    header_read_timeout: Option<Duration>,
+    drain_timeout: Option<Duration>,
+    error_handler: Option<ErrorHandler>,
+    token_verifier: Option<TokenVerifier>,
+    batch_config: BatchConfig,
+    batcher: Option<Batcher<M>>,
+    executor: Executor,
+    queue: Option<InferenceQueue>,
+    shed_load: bool,
+    rate_limiter: Option<RateLimiter>,
+    quotas: Option<Quotas>,
+    connection_limiter: Option<ConnectionLimiter>,
+    metrics: Arc<Metrics>,
+    metrics_addr: Option<String>,
+    admin_addr: Option<String>,
+    admin_reload: Option<Arc<LoadFn<M>>>,
+    admin_log_level: Option<Arc<SetLogLevelFn>>,
+    statsd: Option<StatsdConfig>,
+    access_log: Option<AccessLogFormat>,
+    slow_request_threshold: Option<Duration>,
+    model_name: Option<String>,
+    model_version: Option<String>,
+    warmup_inputs: Vec<Tensor>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsAcceptor>,
+    protocol: Protocol,
+}
+
+impl<M> Server<M>
+where
+    M: Sync + Send + 'static,
+{
+    /// A server reading and writing numpy arrays on the CPU.
+    pub fn new<F>(model: Arc<M>, net_forward: F) -> Server<M>
+    where
+        F: Fn(&M, Tensor) -> Result<Tensor, Error> + Send + Sync + 'static,
+    {
+        Server::with_forward(model, Forward::Tensor(Arc::new(net_forward)))
+    }
+
+    /// A server running the model's own forward pass, see [`ServeModel`].
+    /// Batches are limited by the model's [`ServeModel::batch_config`] if it
+    /// has one, and inputs and outputs are checked against its
+    /// [`ServeModel::input_schema`] and [`ServeModel::output_schema`].
+    pub fn from_model(model: Arc<M>) -> Server<M>
+    where
+        M: ServeModel,
+    {
+        let batch_config = model.batch_config().unwrap_or_default();
+        let input_schema = model.input_schema();
+        let output_schema = model.output_schema();
+        let mut server = Server::new(model, |model: &M, x| model.forward(x));
+        server.batch_config = batch_config;
+        server.input_schema = input_schema;
+        server.output_schema = output_schema;
+        server
+    }
+
+    /// A server awaiting the forward pass, for handlers that call other
+    /// services or wait on shared resources.
+    pub fn new_async<F, Fut>(model: Arc<M>, net_forward: F) -> Server<M>
+    where
+        F: Fn(Arc<M>, Tensor) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Tensor, Error>> + Send + 'static,
+    {
+        let net_forward: Arc<AsyncForwardFn<M>> =
+            Arc::new(move |model, x| Box::pin(net_forward(model, x)));
+        Server::with_forward(model, Forward::Async(net_forward))
+    }
+
+    /// A server whose requests and responses are payloads of named tensors,
+    /// see [`crate::io::named`].
+    pub fn named<F>(model: Arc<M>, net_forward: F) -> Server<M>
+    where
+        F: Fn(&M, HashMap<String, Tensor>) -> Result<HashMap<String, Tensor>, Error>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Server::with_forward(model, Forward::Named(Arc::new(net_forward)))
+    }
+
+    /// A server passing the JSON metadata sent in the request envelope to the
+    /// forward pass and echoing the metadata it returns, see
+    /// [`crate::io::envelope`].
+    pub fn metadata<F>(model: Arc<M>, net_forward: F) -> Server<M>
+    where
+        F: Fn(&M, Tensor, Option<Value>) -> Result<(Tensor, Option<Value>), Error>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Server::with_forward(model, Forward::Metadata(Arc::new(net_forward)))
+    }
+
+    /// A server whose forward pass yields its output as a stream of tensors,
+    /// such as per-layer activations or progressive refinements, each
+    /// written to the client as soon as it's produced, see
+    /// [`crate::io::stream`]. The stream is advanced on the server's
+    /// executor, and requests over HTTP or gRPC fail.
+    pub fn streaming<F, I>(model: Arc<M>, net_forward: F) -> Server<M>
+    where
+        F: Fn(Arc<M>, Tensor) -> Result<I, Error> + Send + Sync + 'static,
+        I: IntoIterator<Item = Result<Tensor, Error>>,
+        I::IntoIter: Send + 'static,
+    {
+        let net_forward: Arc<StreamForwardFn<M>> = Arc::new(move |model, x| {
+            let stream: TensorStream = Box::new(net_forward(model, x)?.into_iter());
+            Ok(stream)
+        });
+        Server::with_forward(model, Forward::Stream(net_forward))
+    }
+
+    /// A server running autoregressive generation on each request, writing
+    /// each token to the client as soon as it's sent, see [`generate`]. The
+    /// forward pass is given the sampling parameters sent in the request's
+    /// envelope, or the default if it sent none, see [`crate::io::envelope`].
+    /// Generation runs on the server's executor, and requests over HTTP or
+    /// gRPC fail.
+    pub fn generate<F>(model: Arc<M>, net_forward: F) -> Server<M>
+    where
+        F: Fn(&M, Tensor, &SamplingParams, &TokenSender) -> Result<(), Error>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Server::with_forward(model, Forward::Generate(Arc::new(net_forward)))
+    }
+
+    /// A server keeping state between the requests of a session, such as a
+    /// transformer's KV cache for incremental decoding, see [`session`].
+    /// Sessions are kept for [`session::DEFAULT_SESSION_TTL`] after their last
+    /// request, up to [`session::DEFAULT_MAX_SESSIONS`] of them and up to
+    /// [`session::DEFAULT_MAX_SESSION_BYTES`] unless set otherwise.
+    pub fn stateful<F, S>(model: Arc<M>, net_forward: F) -> Server<M>
+    where
+        F: Fn(&M, Tensor, &mut S) -> Result<Tensor, Error> + Send + Sync + 'static,
+        S: SessionState,
+    {
+        let net_forward: Arc<SessionForwardFn<M>> = Arc::new(move |model, x, state| {
+            session::with_state(state, |state: &mut S| net_forward(model, x, state))
+        });
+        Server::with_forward(model, Forward::Session(net_forward))
+    }
+
+    fn with_forward(model: Arc<M>, forward: Forward<M>) -> Server<M> {
+        Server {
+            model: Arc::new(tokio::sync::watch::channel(model).0),
+            forward,
+            models: HashMap::new(),
+            splits: HashMap::new(),
+            shadow: None,
+            sessions: Sessions::default(),
+            codec: NpyCodec,
+            device: Device::Cpu,
+            max_payload_bytes: usize::MAX,
+            max_request_bytes: usize::MAX,
+            check_finite: false,
+            input_schema: None,
+            output_schema: None,
+            preprocess: Pipeline::default(),
+            postprocess: Pipeline::default(),
+            max_requests_per_connection: None,
+            idle_timeout: None,
+            request_timeout: None,
+            header_read_timeout: None,
+            drain_timeout: None,
+            error_handler: None,
+            token_verifier: None,
+            batch_config: BatchConfig::default(),
+            batcher: None,
+            executor: Executor::default(),
+            queue: None,
+            shed_load: false,
+            rate_limiter: None,
+            quotas: None,
+            connection_limiter: None,
+            metrics: Arc::default(),
+            metrics_addr: None,
+            admin_addr: None,
+            admin_reload: None,
+            admin_log_level: None,
+            statsd: None,
+            access_log: None,
+            slow_request_threshold: None,
+            model_name: None,
+            model_version: None,
+            warmup_inputs: Vec::new(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            protocol: Protocol::Stream,
+        }
+    }
+}
+
+impl<M, C> Server<M, C>
+where
+    M: Sync + Send + 'static,
+    C: TensorCodec + Clone,
+{
+    /// Read and write tensors with the given codec.
+    pub fn with_codec<D: TensorCodec + Clone>(self, codec: D) -> Server<M, D> {
+        Server {
+            model: self.model,
+            forward: self.forward,
+            models: self.models,
+            splits: self.splits,
+            shadow: self.shadow,
+            sessions: self.sessions,
+            codec,
+            device: self.device,
+            max_payload_bytes: self.max_payload_bytes,
+            max_request_bytes: self.max_request_bytes,
+            check_finite: self.check_finite,
+            input_schema: self.input_schema,
+            output_schema: self.output_schema,
+            preprocess: self.preprocess,
+            postprocess: self.postprocess,
+            max_requests_per_connection: self.max_requests_per_connection,
+            idle_timeout: self.idle_timeout,
+            request_timeout: self.request_timeout,
+            header_read_timeout: self.header_read_timeout,
+            drain_timeout: self.drain_timeout,
+            error_handler: self.error_handler,
+            token_verifier: self.token_verifier,
+            batch_config: self.batch_config,
+            batcher: self.batcher,
+            executor: self.executor,
+            queue: self.queue,
+            shed_load: self.shed_load,
+            rate_limiter: self.rate_limiter,
+            quotas: self.quotas,
+            connection_limiter: self.connection_limiter,
+            metrics: self.metrics,
+            metrics_addr: self.metrics_addr,
+            admin_addr: self.admin_addr,
+            admin_reload: self.admin_reload,
+            admin_log_level: self.admin_log_level,
+            statsd: self.statsd,
+            access_log: self.access_log,
+            slow_request_threshold: self.slow_request_threshold,
+            model_name: self.model_name,
+            model_version: self.model_version,
+            warmup_inputs: self.warmup_inputs,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
+            protocol: self.protocol,
+        }
+    }
+
+    /// Materialize incoming tensors on the given device.
+    pub fn with_device(mut self, device: Device) -> Server<M, C> {
+        self.device = device;
+        self
+    }
+
+    /// Reject requests whose tensor is larger than `max_payload_bytes`. By
+    /// default requests are not limited.
+    pub fn with_max_payload_bytes(mut self, max_payload_bytes: usize) -> Server<M, C> {
+        self.max_payload_bytes = max_payload_bytes;
+        self
+    }
+
+    /// Reject inputs that don't match `schema` with an [`INVALID_INPUT`]
+    /// error describing the mismatch, instead of failing inside the forward
+    /// pass. By default inputs aren't checked.
+    ///
+    /// [`INVALID_INPUT`]: crate::io::error::codes::INVALID_INPUT
+    pub fn with_input_schema(mut self, schema: Schema) -> Server<M, C> {
+        self.input_schema = Some(schema);
+        self
+    }
+
+    /// Fail requests whose forward pass returns outputs that don't match
+    /// `schema` with a [`FORWARD_FAILED`] error describing the mismatch,
+    /// instead of sending them to the client. By default outputs aren't
+    /// checked.
+    ///
+    /// [`FORWARD_FAILED`]: crate::io::error::codes::FORWARD_FAILED
+    pub fn with_output_schema(mut self, schema: Schema) -> Server<M, C> {
+        self.output_schema = Some(schema);
+        self
+    }
+
+    /// Run `stage` on inputs before the forward pass, after the stages added
+    /// before it, such as to normalize or cast them, see
+    /// [`pipeline`](self::pipeline). Inputs failing a stage are rejected with
+    /// an [`INVALID_INPUT`] error.
+    ///
+    /// [`INVALID_INPUT`]: crate::io::error::codes::INVALID_INPUT
+    pub fn with_preprocess<F>(mut self, stage: F) -> Server<M, C>
+    where
+        F: Fn(Tensor) -> Result<Tensor, Error> + Send + Sync + 'static,
+    {
+        self.preprocess.push(Arc::new(stage));
+        self
+    }
+
+    /// Run `stage` on outputs after the forward pass, after the stages added
+    /// before it, such as to clip them or take their softmax, see
+    /// [`pipeline`](self::pipeline). Outputs failing a stage are answered
+    /// with a [`FORWARD_FAILED`] error.
+    ///
+    /// [`FORWARD_FAILED`]: crate::io::error::codes::FORWARD_FAILED
+    pub fn with_postprocess<F>(mut self, stage: F) -> Server<M, C>
+    where
+        F: Fn(Tensor) -> Result<Tensor, Error> + Send + Sync + 'static,
+    {
+        self.postprocess.push(Arc::new(stage));
+        self
+    }
+
+    /// Reject floating point inputs holding NaN or infinite values with an
+    /// [`INVALID_INPUT`] error instead of running the forward pass on them.
+    /// Every value of every input is checked. By default inputs aren't
+    /// checked.
+    ///
+    /// [`INVALID_INPUT`]: crate::io::error::codes::INVALID_INPUT
+    pub fn with_finite_inputs(mut self) -> Server<M, C> {
+        self.check_finite = true;
+        self
+    }
+
+    /// Reject requests larger than `max_request_bytes` on the wire with a
+    /// [`REQUEST_TOO_LARGE`] error frame and close their connection. Tensors
+    /// are rejected by their declared size before they are allocated. HTTP
+    /// requests are answered with a 413 status, gRPC requests with an
+    /// out of range status and WebSocket connections are closed. By default
+    /// requests are not limited.
+    ///
+    /// [`REQUEST_TOO_LARGE`]: crate::io::error::codes::REQUEST_TOO_LARGE
+    pub fn with_max_request_bytes(mut self, max_request_bytes: usize) -> Server<M, C> {
+        self.max_request_bytes = max_request_bytes;
+        self
+    }
+
+    /// Close a connection after serving `max_requests` requests on it. By
+    /// default clients may send any number of requests over one connection.
+    pub fn with_max_requests_per_connection(mut self, max_requests: usize) -> Server<M, C> {
+        self.max_requests_per_connection = Some(max_requests);
+        self
+    }
+
+    /// Close a connection when no request has started within `idle_timeout`
+    /// of the previous response, so leaked connections don't accumulate. This
+    /// applies to plain, WebSocket, HTTP keep-alive and QUIC connections. By
+    /// default idle connections are kept open.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Server<M, C> {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Fail a request that isn't read, run and written within
+    /// `request_timeout` with an error frame and close its connection. HTTP
+    /// requests are answered with a 503 status and gRPC requests are
+    /// cancelled. A forward pass already running on the executor still runs
+    /// to completion. By default requests may take any time.
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Server<M, C> {
+        self.request_timeout = Some(request_timeout);
+        self
+    }
+
+    /// Close a connection when the header of a request isn't read within
+    /// `header_read_timeout` of its first byte, so clients trickling bytes
+    /// can't hold connections open. The header is the TLS and WebSocket
+    /// handshakes, HTTP request headers, and on plain connections the hello,
+    /// the envelope and the codec's header, see [`TensorCodec::read_header`].
+    /// Plain connections are sent an error frame first. By default headers
+    /// may take any time.
+    pub fn with_header_read_timeout(mut self, header_read_timeout: Duration) -> Server<M, C> {
+        self.header_read_timeout = Some(header_read_timeout);
+        self
+    }
+
+    /// Call `handler` with every failed request and connection error, in
+    /// addition to logging them.
+    pub fn with_error_handler<F>(mut self, handler: F) -> Server<M, C>
+    where
+        F: Fn(&Error) + Send + Sync + 'static,
+    {
+        self.error_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Only serve requests carrying a token accepted by `verifier`. The token
+    /// is sent in the request envelope, see [`crate::io::envelope`], as an
+    /// `Authorization: Bearer` header over HTTP and as `authorization`
+    /// metadata over gRPC. Other requests fail with an unauthorized error
+    /// frame. By default requests aren't authenticated.
+    pub fn with_token_auth<F>(mut self, verifier: F) -> Server<M, C>
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.token_verifier = Some(Arc::new(verifier));
+        self
+    }
+
+    /// Queue requests and run them through the forward pass in batches,
+    /// concatenated along their first dimension, see [`batch`]. Only servers
+    /// of single tensors with no metadata are batched; others are unaffected.
+    pub fn with_batching(mut self) -> Server<M, C> {
+        if let Forward::Tensor(net_forward) = &self.forward {
+            self.batcher = Some(Batcher::new(
+                Arc::clone(&self.model),
+                Arc::clone(net_forward),
+                self.batch_config,
+            ));
+        }
+        self
+    }
+
+    /// Run at most `max_batch_size` rows in one batched forward pass. By
+    /// default batches hold up to 32 rows.
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Server<M, C> {
+        self.set_batch_config(BatchConfig {
+            max_batch_size,
+            ..self.batch_config
+        });
+        self
+    }
+
+    /// Wait at most `max_wait` after a request arrives for others to join its
+    /// batch. By default requests wait up to 2ms.
+    pub fn with_max_batch_wait(mut self, max_wait: Duration) -> Server<M, C> {
+        self.set_batch_config(BatchConfig {
+            max_wait,
+            ..self.batch_config
+        });
+        self
+    }
+
+    fn set_batch_config(&mut self, config: BatchConfig) {
+        self.batch_config = config;
+        if let Some(batcher) = &mut self.batcher {
+            batcher.set_config(config);
+        }
+    }
+
+    /// Run forward passes on a dedicated pool of `threads` threads instead of
+    /// tokio's blocking thread pool. With `pin_cores` each thread is pinned to
+    /// its own core, which fails on platforms that don't support pinning.
+    #[cfg(feature = "pool")]
+    pub fn with_inference_pool(
+        mut self,
+        threads: usize,
+        pin_cores: bool,
+    ) -> Result<Server<M, C>, Error> {
+        self.executor = Executor::pool(threads, pin_cores)?;
+        Ok(self)
+    }
+
+    /// Run at most `max_concurrency` forward passes at once, with at most
+    /// `capacity` more requests waiting for their turn. A request arriving
+    /// when the queue is full waits for a place, which stops its connection
+    /// from reading further requests. Waiting requests run in order of the
+    /// priority class in their envelope, see [`crate::io::envelope`]. By
+    /// default every request runs as soon as it's read.
+    pub fn with_inference_queue(mut self, max_concurrency: usize, capacity: usize) -> Server<M, C> {
+        self.queue = Some(InferenceQueue::new(max_concurrency, capacity));
+        self
+    }
+
+    /// Fail requests arriving when the inference queue is full with a server
+    /// busy error frame instead of waiting for a place.
+    pub fn with_load_shedding(mut self) -> Server<M, C> {
+        self.shed_load = true;
+        self
+    }
+
+    /// Limit each client to `requests_per_second` requests on average and
+    /// `burst` requests at once, failing further requests with a throttled
+    /// error frame. Clients are told apart by IP address, so clients without
+    /// one, such as over Unix sockets, aren't limited. By default requests
+    /// aren't limited.
+    pub fn with_rate_limit(mut self, requests_per_second: f64, burst: u32) -> Server<M, C> {
+        self.rate_limiter = Some(RateLimiter::new(requests_per_second, burst));
+        self
+    }
+
+    /// Limit what each API key may use, rejecting requests over their key's
+    /// quota with a [`QUOTA_EXCEEDED`] error. Keep a clone of `quotas` to
+    /// query usage while the server runs. See [`quota`] for how keys are
+    /// sent.
+    ///
+    /// [`QUOTA_EXCEEDED`]: crate::io::error::codes::QUOTA_EXCEEDED
+    pub fn with_quotas(mut self, quotas: Quotas) -> Server<M, C> {
+        self.quotas = Some(quotas);
+        self
+    }
+
+    /// Close new connections from a client already holding
+    /// `max_connections` open connections, so one client can't hold every
+    /// connection. Clients are told apart by IP address. By default clients
+    /// may open any number of connections.
+    pub fn with_max_connections_per_peer(mut self, max_connections: usize) -> Server<M, C> {
+        self.connection_limiter = Some(ConnectionLimiter::new(max_connections));
+        self
+    }
+
+    /// Serve request metrics in the Prometheus text format as
+    /// `GET /metrics`, and statistics as JSON as `GET /stats`, on `addr`
+    /// apart from the inference port, see [`metrics`].
+    pub fn with_metrics_endpoint(mut self, addr: &str) -> Server<M, C> {
+        self.metrics_addr = Some(addr.to_string());
+        self
+    }
+
+    /// Answer admin commands such as `stats`, `reload` and `drain` on `addr`,
+    /// a loopback `host:port` or, on Unix, a socket path such as
+    /// `"unix:/run/socket-nn/admin.sock"`, see [`admin`].
+    pub fn with_admin_endpoint(mut self, addr: &str) -> Server<M, C> {
+        self.admin_addr = Some(addr.to_string());
+        self
+    }
 
-    while let Ok((mut socket, _)) = listener.accept().await {
-        // get a cloned reference of the weights
-        let model_clone = Arc::clone(&model);
+    /// Load the model with `load` and serve it on the admin `reload`
+    /// command, such as from the checkpoint last written. Without it the
+    /// command fails.
+    pub fn with_admin_reload<F>(mut self, load: F) -> Server<M, C>
+    where
+        F: Fn() -> Result<M, Error> + Send + Sync + 'static,
+    {
+        self.admin_reload = Some(Arc::new(load));
+        self
+    }
+
+    /// Pass the level given to the admin `set-log-level` command to
+    /// `set_log_level`, such as a function reloading a `tracing_subscriber`
+    /// filter. Without it the command fails.
+    pub fn with_admin_log_level<F>(mut self, set_log_level: F) -> Server<M, C>
+    where
+        F: Fn(&str) -> Result<(), Error> + Send + Sync + 'static,
+    {
+        self.admin_log_level = Some(Arc::new(set_log_level));
+        self
+    }
+
+    /// Send metrics to the statsd server at `addr`, such as
+    /// `"127.0.0.1:8125"`, with names starting with `prefix`. Counters and
+    /// timings are sent as they happen and gauges every ten seconds.
+    pub fn with_statsd(mut self, addr: &str, prefix: &str) -> Server<M, C> {
+        self.statsd = Some(StatsdConfig {
+            addr: addr.to_string(),
+            prefix: prefix.to_string(),
+            dogstatsd: false,
+        });
+        self
+    }
+
+    /// Send metrics to the DogStatsD agent at `addr`, with names starting
+    /// with `prefix` and errors, phases and the model tagged.
+    pub fn with_dogstatsd(mut self, addr: &str, prefix: &str) -> Server<M, C> {
+        self.statsd = Some(StatsdConfig {
+            addr: addr.to_string(),
+            prefix: prefix.to_string(),
+            dogstatsd: true,
+        });
+        self
+    }
+
+    /// Name the model served, labelling its metrics and access log records.
+    pub fn with_model_name(mut self, name: &str) -> Server<M, C> {
+        self.model_name = Some(name.to_string());
+        self
+    }
+
+    /// Serve another model under `name`, run on requests naming it in their
+    /// envelope, see [`crate::io::envelope`], as `model_name` over gRPC and
+    /// as `POST /models/{name}/infer` over HTTP. Other requests run the
+    /// server's own model. Registered models share the server's transports,
+    /// limits, queue and metrics but not its schemas, batching or reloading.
+    ///
+    /// Once a model is registered, requests naming any other model than
+    /// these or the server's [`with_model_name`](Server::with_model_name)
+    /// fail with a [`MODEL_NOT_FOUND`] error. See
+    /// [`register_version`](Server::register_version) to serve several
+    /// versions of a model.
+    ///
+    /// ```no_run
+    /// # use std::sync::Arc;
+    /// # use candle_core::{Result, Tensor};
+    /// # use socket_nn::server::Server;
+    /// # fn forward(model: &(), x: Tensor) -> Result<Tensor> { Ok(x) }
+    /// # async fn serve() -> Result<()> {
+    /// Server::new(Arc::new(()), forward)
+    ///     .with_model_name("mlp")
+    ///     .register("resnet", Arc::new(()), forward)
+    ///     .run("127.0.0.1:8080")
+    ///     .await
+    /// # }
+    /// ```
+    ///
+    /// [`MODEL_NOT_FOUND`]: crate::io::error::codes::MODEL_NOT_FOUND
+    pub fn register<N, F>(mut self, name: &str, model: Arc<N>, net_forward: F) -> Server<M, C>
+    where
+        N: Send + Sync + 'static,
+        F: Fn(&N, Tensor) -> Result<Tensor, Error> + Send + Sync + 'static,
+    {
+        self.add_model(name, None, model, net_forward);
+        self
+    }
 
+    /// Serve `version` of the model `name`, see [`register`](Server::register).
+    /// Requests may pin a version, in their envelope, as `model_version`
+    /// over gRPC and as `POST /models/{name}/versions/{version}/infer` over
+    /// HTTP, and otherwise run the latest version registered or `"latest"`.
+    /// The server's own model counts as the first version of its name, so
+    /// registering a new version under [`with_model_name`](Server::with_model_name)
+    /// rolls requests for it over while pinned requests keep their version.
+    /// Requests pinning a version not served fail with a
+    /// [`MODEL_NOT_FOUND`] error. Registering a version again replaces it.
+    ///
+    /// [`MODEL_NOT_FOUND`]: crate::io::error::codes::MODEL_NOT_FOUND
+    pub fn register_version<N, F>(
+        mut self,
+        name: &str,
+        version: &str,
+        model: Arc<N>,
+        net_forward: F,
+    ) -> Server<M, C>
+    where
+        N: Send + Sync + 'static,
+        F: Fn(&N, Tensor) -> Result<Tensor, Error> + Send + Sync + 'static,
+    {
+        self.add_model(name, Some(version), model, net_forward);
+        self
+    }
+
+    /// Run `percent` of the requests for the model `name` that don't pin a
+    /// version on its `candidate` version and the rest on its `control`
+    /// version, for online experiments, see
+    /// [`register_version`](Server::register_version). Requests for `name`
+    /// are counted by the version run in the metrics and access log, and
+    /// the version is echoed in the envelope, as `model_version` over gRPC
+    /// and as an `X-Model-Version` header over HTTP. Both versions must be
+    /// served, or requests sent to a missing one fail with a
+    /// [`MODEL_NOT_FOUND`] error.
+    ///
+    /// ```no_run
+    /// # use std::sync::Arc;
+    /// # use candle_core::{Result, Tensor};
+    /// # use socket_nn::server::Server;
+    /// # fn forward(model: &(), x: Tensor) -> Result<Tensor> { Ok(x) }
+    /// # async fn serve() -> Result<()> {
+    /// Server::new(Arc::new(()), forward)
+    ///     .with_model_name("mlp")
+    ///     .with_model_version("1")
+    ///     .register_version("mlp", "2", Arc::new(()), forward)
+    ///     .with_traffic_split("mlp", "1", "2", 10)
+    ///     .run("127.0.0.1:8080")
+    ///     .await
+    /// # }
+    /// ```
+    ///
+    /// [`MODEL_NOT_FOUND`]: crate::io::error::codes::MODEL_NOT_FOUND
+    pub fn with_traffic_split(
+        mut self,
+        name: &str,
+        control: &str,
+        candidate: &str,
+        percent: u8,
+    ) -> Server<M, C> {
+        let split = TrafficSplit::new(control, candidate, percent);
+        self.splits.insert(name.to_string(), split);
+        self
+    }
+
+    /// Mirror the requests for the model `name`, or for the server's own
+    /// model if it's `name`, to its `version`, without returning the
+    /// candidate's output, see [`shadow`](self::shadow). The version must be
+    /// registered with [`register_version`](Server::register_version), and
+    /// requests it serves itself aren't mirrored. Only single tensor
+    /// requests are mirrored. The candidate runs on the server's executor
+    /// while the answer is written but outside the inference queue, and at
+    /// most [`MAX_SHADOWS_IN_FLIGHT`](shadow::MAX_SHADOWS_IN_FLIGHT) mirrors
+    /// run at once.
+    pub fn with_shadow(mut self, name: &str, version: &str) -> Server<M, C> {
+        self.shadow = Some(ShadowTarget::new(name, version));
+        self
+    }
+
+    fn add_model<N, F>(&mut self, name: &str, version: Option<&str>, model: Arc<N>, net_forward: F)
+    where
+        N: Send + Sync + 'static,
+        F: Fn(&N, Tensor) -> Result<Tensor, Error> + Send + Sync + 'static,
+    {
+        let registered = RegisteredModel {
+            version: version.map(str::to_string),
+            forward: Arc::new(move |x| net_forward(&model, x)),
+        };
+        let versions = self.models.entry(name.to_string()).or_default();
+        match versions
+            .iter_mut()
+            .find(|v| v.version == registered.version)
+        {
+            Some(existing) => *existing = registered,
+            None => versions.push(registered),
+        }
+    }
+
+    /// A handle replacing the model while the server runs, such as with
+    /// weights from a new checkpoint, see [`ModelReloader`].
+    pub fn model_reloader(&self) -> ModelReloader<M> {
+        ModelReloader {
+            model: Arc::clone(&self.model),
+            warmup: self.warmup(),
+        }
+    }
+
+    /// Run each of `inputs` through models loaded by a [`ModelReloader`]
+    /// before serving them, so the first requests on new weights don't pay
+    /// for lazy initialization, and keep serving the previous model if any
+    /// fails. Set before taking a [`Server::model_reloader`]. Servers of
+    /// named tensors aren't warmed up.
+    pub fn with_warmup(mut self, inputs: Vec<Tensor>) -> Server<M, C> {
+        self.warmup_inputs = inputs;
+        self
+    }
+
+    fn warmup(&self) -> Option<Arc<WarmupFn<M>>> {
+        if self.warmup_inputs.is_empty() {
+            return None;
+        }
+        let inputs = self.warmup_inputs.clone();
+        let warmup: Arc<WarmupFn<M>> = match &self.forward {
+            Forward::Tensor(net_forward) => {
+                let net_forward = Arc::clone(net_forward);
+                Arc::new(move |model| {
+                    for x in &inputs {
+                        net_forward(model, x.clone())?;
+                    }
+                    Ok(())
+                })
+            }
+            Forward::Metadata(net_forward) => {
+                let net_forward = Arc::clone(net_forward);
+                Arc::new(move |model| {
+                    for x in &inputs {
+                        net_forward(model, x.clone(), None)?;
+                    }
+                    Ok(())
+                })
+            }
+            Forward::Async(net_forward) => {
+                let net_forward = Arc::clone(net_forward);
+                // runs on a blocking thread, within the runtime
+                Arc::new(move |model| {
+                    let runtime = tokio::runtime::Handle::current();
+                    for x in &inputs {
+                        runtime.block_on(net_forward(Arc::clone(model), x.clone()))?;
+                    }
+                    Ok(())
+                })
+            }
+            Forward::Stream(net_forward) => {
+                let net_forward = Arc::clone(net_forward);
+                Arc::new(move |model| {
+                    for x in &inputs {
+                        for y in net_forward(Arc::clone(model), x.clone())? {
+                            y?;
+                        }
+                    }
+                    Ok(())
+                })
+            }
+            Forward::Generate(net_forward) => {
+                let net_forward = Arc::clone(net_forward);
+                Arc::new(move |model| {
+                    for x in &inputs {
+                        let sampling = SamplingParams::default();
+                        net_forward(model, x.clone(), &sampling, &TokenSender::discard())?;
+                    }
+                    Ok(())
+                })
+            }
+            Forward::Session(net_forward) => {
+                let net_forward = Arc::clone(net_forward);
+                Arc::new(move |model| {
+                    for x in &inputs {
+                        net_forward(model, x.clone(), &mut None).0?;
+                    }
+                    Ok(())
+                })
+            }
+            Forward::Named(_) => return None,
+        };
+        Some(warmup)
+    }
+
+    /// Drop the state of sessions unused for longer than `ttl`, see
+    /// [`Server::stateful`].
+    pub fn with_session_ttl(mut self, ttl: Duration) -> Server<M, C> {
+        self.sessions.ttl = ttl;
+        self
+    }
+
+    /// Keep at most `max_bytes` of session state, dropping the sessions used
+    /// least recently beyond it, see [`Server::stateful`].
+    pub fn with_max_session_bytes(mut self, max_bytes: usize) -> Server<M, C> {
+        self.sessions.max_bytes = max_bytes;
+        self
+    }
+
+    /// Keep at most `max_sessions` sessions, dropping the sessions used least
+    /// recently to start new ones beyond it, see [`Server::stateful`].
+    pub fn with_max_sessions(mut self, max_sessions: usize) -> Server<M, C> {
+        self.sessions.max_sessions = max_sessions;
+        self
+    }
+
+    /// Version the model served, labelling its metrics and access log
+    /// records.
+    pub fn with_model_version(mut self, version: &str) -> Server<M, C> {
+        self.model_version = Some(version.to_string());
+        self
+    }
+
+    /// Log a record of every request in `format`, see [`access_log`]. By
+    /// default requests aren't logged.
+    pub fn with_access_log(mut self, format: AccessLogFormat) -> Server<M, C> {
+        self.access_log = Some(format);
+        self
+    }
+
+    /// Log requests taking at least `threshold` at the warn level with the
+    /// target `socket_nn::slow`, with their shapes, peer and the time spent
+    /// in each phase, in the access log's format or logfmt. By default slow
+    /// requests aren't logged.
+    pub fn with_slow_request_log(mut self, threshold: Duration) -> Server<M, C> {
+        self.slow_request_threshold = Some(threshold);
+        self
+    }
+
+    /// Terminate TLS on every connection with the PEM encoded certificate
+    /// chain and private key at the given paths.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(
+        mut self,
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> Result<Server<M, C>, Error> {
+        self.tls = Some(tls::load_acceptor(cert_path.as_ref(), key_path.as_ref())?);
+        Ok(self)
+    }
+
+    /// Speak WebSocket on every connection, sending each request and response
+    /// as a binary message.
+    #[cfg(feature = "websocket")]
+    pub fn with_websocket(mut self) -> Server<M, C> {
+        self.protocol = Protocol::WebSocket;
+        self
+    }
+
+    /// Speak HTTP on every connection, serving `POST /infer` requests, see
+    /// [`http`].
+    #[cfg(feature = "http")]
+    pub fn with_http(mut self) -> Server<M, C> {
+        self.protocol = Protocol::Http;
+        self
+    }
+
+    /// On shutdown, wait at most `drain_timeout` for in-flight requests
+    /// before closing their connections. By default in-flight requests are
+    /// always completed.
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Server<M, C> {
+        self.drain_timeout = Some(drain_timeout);
+        self
+    }
+
+    /// Bind to `addr` and serve connections.
+    pub async fn run(self, addr: &str) -> Result<(), Error> {
+        self.run_with_shutdown(addr, CancellationToken::new()).await
+    }
+
+    /// Bind to `addr` and serve connections until ctrl-c is pressed.
+    pub async fn run_until_ctrl_c(self, addr: &str) -> Result<(), Error> {
+        let shutdown = CancellationToken::new();
+        let token = shutdown.clone();
         tokio::spawn(async move {
-            let (mut reader, mut writer) = socket.split();
-            let buf_reader = tokio::io::BufReader::new(&mut reader);
+            if tokio::signal::ctrl_c().await.is_ok() {
+                token.cancel();
+            }
+        });
+        self.run_with_shutdown(addr, shutdown).await
+    }
 
-            // read array from the stream
-            let input_data = read_numpy(buf_reader)
-                .await
-                .expect("error reading numpy array");
+    /// Bind to `addr` and serve connections until `shutdown` is cancelled.
+    /// The server then stops accepting connections, lets in-flight requests
+    /// finish and closes idle connections before returning.
+    pub async fn run_with_shutdown(
+        self,
+        addr: &str,
+        shutdown: CancellationToken,
+    ) -> Result<(), Error> {
+        let listener = TcpListener::bind(addr).await?;
+        self.serve(listener, shutdown).await
+    }
 
-            // forward pass
-            let x = net_forward(&*model_clone, input_data).expect("error making forward pass");
+    /// Bind to `addr` and serve connections in the background.
+    ///
+    /// ```no_run
+    /// # use std::sync::Arc;
+    /// # use candle_core::{Result, Tensor};
+    /// # use socket_nn::server::Server;
+    /// # fn forward(model: &(), x: Tensor) -> Result<Tensor> { Ok(x) }
+    /// # async fn serve() -> Result<()> {
+    /// let handle = Server::new(Arc::new(()), forward)
+    ///     .bind("127.0.0.1:0")
+    ///     .await?;
+    /// println!("listening on {}", handle.local_addr());
+    /// handle.shutdown();
+    /// handle.join().await
+    /// # }
+    /// ```
+    pub async fn bind(self, addr: &str) -> Result<ServerHandle, Error> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let shutdown = CancellationToken::new();
+        let metrics = Arc::clone(&self.metrics);
+        let task = tokio::spawn(self.serve(listener, shutdown.clone()));
+        Ok(ServerHandle {
+            local_addr,
+            shutdown,
+            metrics,
+            task,
+        })
+    }
 
-            // write array to the stream
-            write_numpy(&x, &mut writer)
-                .await
-                .expect("error writing numpy array");
+    /// The KServe v2 gRPC inference service running this server's forward
+    /// pass, to be added to a `tonic` server alongside other services.
+    #[cfg(feature = "grpc")]
+    pub fn into_grpc_service(self) -> grpc::GrpcInferenceService<M, C> {
+        grpc::GrpcInferenceService::new(self)
+    }
+
+    /// Serve the gRPC inference service on `addr` until `shutdown` is
+    /// cancelled, see [`grpc`].
+    #[cfg(feature = "grpc")]
+    pub async fn run_grpc(self, addr: &str, shutdown: CancellationToken) -> Result<(), Error> {
+        let addr: SocketAddr = addr.parse().map_err(Error::wrap)?;
+        self.start_services(&shutdown).await?;
+        let mut builder = tonic::transport::Server::builder();
+        if let Some(request_timeout) = self.request_timeout {
+            builder = builder.timeout(request_timeout);
+        }
+        builder
+            .add_service(self.into_grpc_service())
+            .serve_with_shutdown(addr, shutdown.cancelled())
+            .await
+            .map_err(Error::wrap)
+    }
+
+    /// Serve requests over QUIC on `addr` with the PEM encoded certificate
+    /// chain and private key at the given paths until `shutdown` is
+    /// cancelled, see [`quic`].
+    #[cfg(feature = "quic")]
+    pub async fn run_quic(
+        self,
+        addr: &str,
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+        shutdown: CancellationToken,
+    ) -> Result<(), Error> {
+        let addr: SocketAddr = addr.parse().map_err(Error::wrap)?;
+        let endpoint = quic::bind(
+            addr,
+            cert_path.as_ref(),
+            key_path.as_ref(),
+            self.idle_timeout,
+        )?;
+        self.start_services(&shutdown).await?;
+        quic::serve(Arc::new(self), endpoint, shutdown).await
+    }
+
+    /// Serve requests on a ZeroMQ REP socket bound to `endpoint`, such as
+    /// `"tcp://127.0.0.1:5555"`, until `shutdown` is cancelled, see [`zmq`].
+    #[cfg(feature = "zmq")]
+    pub async fn run_zmq(self, endpoint: &str, shutdown: CancellationToken) -> Result<(), Error> {
+        self.start_services(&shutdown).await?;
+        zmq::serve(&self, endpoint, shutdown).await
+    }
+
+    /// Serve requests read from stdin with responses written to stdout until
+    /// stdin is closed, for running as a subprocess or under inetd style
+    /// socket activation. Logs must not be written to stdout.
+    pub async fn run_stdio(self) -> Result<(), Error> {
+        let shutdown = CancellationToken::new();
+        serve_requests(
+            tokio::io::stdin(),
+            tokio::io::stdout(),
+            None,
+            &self,
+            &shutdown,
+        )
+        .await
+    }
+
+    /// Serve connections from `listener` until `shutdown` is cancelled, see
+    /// [`Server::run_with_shutdown`].
+    pub async fn serve<L: Listener>(
+        self,
+        mut listener: L,
+        shutdown: CancellationToken,
+    ) -> Result<(), Error> {
+        self.start_services(&shutdown).await?;
+        let server = Arc::new(self);
+        let mut connections = JoinSet::new();
+        let mut backoff = MIN_ACCEPT_BACKOFF;
+
+        loop {
+            let accepted = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                // reap finished connections
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                accepted = listener.accept() => accepted,
+            };
+            let (socket, peer) = match accepted {
+                Ok(accepted) => {
+                    backoff = MIN_ACCEPT_BACKOFF;
+                    accepted
+                }
+                // the peer gave up before the connection was accepted
+                Err(e) if is_connection_error(&e) => {
+                    debug!("failed to accept connection: {e}");
+                    continue;
+                }
+                // running out of resources such as file descriptors, back
+                // off to give connections time to close
+                Err(e) => {
+                    let e = Error::from(e);
+                    warn!("failed to accept connection, retrying in {backoff:?}: {e}");
+                    server.report_error(&e);
+                    tokio::select! {
+                        _ = shutdown.cancelled() => break,
+                        _ = sleep(backoff) => {}
+                    }
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    continue;
+                }
+            };
+            let server = Arc::clone(&server);
+            let shutdown = shutdown.clone();
+            let peer_ip = L::peer_ip(&peer);
+            #[cfg(feature = "trace")]
+            let span = tracing::info_span!("connection", ?peer, model = server.model_name);
+            let connection = async move {
+                let Some(_slot) = server.connection_slot(peer_ip) else {
+                    debug!(?peer, "closing connection over the per-peer limit");
+                    return;
+                };
+                let _open = server.metrics.open_connection();
+                if let Err(e) = accept_connection(socket, peer_ip, &server, &shutdown).await {
+                    warn!(?peer, "connection failed: {e}");
+                    server.report_error(&e);
+                }
+            };
+            #[cfg(feature = "trace")]
+            let connection = tracing::Instrument::instrument(connection, span);
+            connections.spawn(connection);
+        }
+        drop(listener);
+
+        let drain = async { while connections.join_next().await.is_some() {} };
+        match server.drain_timeout {
+            Some(drain_timeout) => {
+                if timeout(drain_timeout, drain).await.is_err() {
+                    warn!(
+                        "closing {} connections still in flight after shutdown",
+                        connections.len()
+                    );
+                }
+            }
+            None => drain.await,
+        }
+
+        Ok(())
+    }
+}
+
+/// Replaces the model a server runs without restarting it, see
+/// [`Server::model_reloader`].
+///
+/// Each request runs on the model current when its forward pass starts, so
+/// requests already running finish on the previous weights. Models loaded
+/// with [`ModelReloader::reload_with`] are warmed up before they're served,
+/// see [`Server::with_warmup`].
+///
+/// ```no_run
+/// # use std::sync::Arc;
+/// # use candle_core::{Result, Tensor};
+/// # use socket_nn::server::Server;
+/// # fn forward(model: &Vec<f32>, x: Tensor) -> Result<Tensor> { Ok(x) }
+/// # fn load_checkpoint(path: &str) -> Result<Vec<f32>> { Ok(vec![]) }
+/// # async fn serve() -> Result<()> {
+/// let server = Server::new(Arc::new(load_checkpoint("v1")?), forward);
+/// let reloader = server.model_reloader();
+/// let handle = server.bind("127.0.0.1:8080").await?;
+/// reloader.reload_with(|| load_checkpoint("v2")).await?;
+/// # handle.join().await
+/// # }
+/// ```
+pub struct ModelReloader<M> {
+    model: SharedModel<M>,
+    warmup: Option<Arc<WarmupFn<M>>>,
+}
+
+impl<M> Clone for ModelReloader<M> {
+    fn clone(&self) -> Self {
+        Self {
+            model: Arc::clone(&self.model),
+            warmup: self.warmup.clone(),
+        }
+    }
+}
+
+impl<M> ModelReloader<M> {
+    /// The model requests start on now.
+    pub fn current(&self) -> Arc<M> {
+        self.model.borrow().clone()
+    }
+
+    /// Runs requests on `model` from now on, returning the previous model.
+    pub fn reload(&self, model: Arc<M>) -> Arc<M> {
+        let previous = self.model.send_replace(model);
+        info!("model reloaded");
+        previous
+    }
+}
+
+impl<M> ModelReloader<M>
+where
+    M: Send + Sync + 'static,
+{
+    /// Loads a model with `load` and warms it up on a blocking thread, so
+    /// serving carries on meanwhile, then runs requests on it. The model
+    /// served is unchanged if `load` or the warmup fails.
+    pub async fn reload_with<F>(&self, load: F) -> Result<Arc<M>, Error>
+    where
+        F: FnOnce() -> Result<M, Error> + Send + 'static,
+    {
+        let warmup = self.warmup.clone();
+        let model = tokio::task::spawn_blocking(move || {
+            let model = Arc::new(load()?);
+            if let Some(warmup) = warmup {
+                let started = Instant::now();
+                warmup(&model).map_err(|e| Error::Msg(format!("warmup failed: {e}")))?;
+                info!(elapsed = ?started.elapsed(), "model warmed up");
+            }
+            Ok::<_, Error>(model)
+        })
+        .await
+        .map_err(Error::wrap)??;
+        Ok(self.reload(model))
+    }
+}
+
+/// A server running in the background, see [`Server::bind`].
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown: CancellationToken,
+    metrics: Arc<Metrics>,
+    task: JoinHandle<Result<(), Error>>,
+}
+
+impl ServerHandle {
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Uptime, connections, requests, errors and queue depth so far.
+    pub fn stats(&self) -> ServerStats {
+        self.metrics.stats()
+    }
+
+    /// Stop accepting connections and close them once their in-flight
+    /// requests finish.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Wait for the server to stop.
+    pub async fn join(self) -> Result<(), Error> {
+        self.task.await.map_err(Error::wrap)?
+    }
+}
+
+impl<M, C> Server<M, C> {
+    fn report_error(&self, e: &Error) {
+        if let Some(handler) = &self.error_handler {
+            handler(e);
+        }
+    }
+
+    /// Logs a finished request to the access log and, if it was slow, to
+    /// the slow request log.
+    fn log_access(&self, record: &AccessRecord) {
+        let model = self.model_name.as_deref();
+        let version = self.model_version.as_deref();
+        if let Some(format) = self.access_log {
+            let record = record.render(format, model, version, false);
+            info!(target: "socket_nn::access", "{record}");
+        }
+        if let Some(threshold) = self.slow_request_threshold {
+            if record.elapsed() >= threshold {
+                let format = self.access_log.unwrap_or(AccessLogFormat::Logfmt);
+                let record = record.render(format, model, version, true);
+                warn!(target: "socket_nn::slow", "{record}");
+            }
+        }
+    }
+
+    /// Starts the uptime, connects to statsd and binds the metrics and admin
+    /// endpoints, if any, serving them in the background until `shutdown` is
+    /// cancelled.
+    async fn start_services(&self, shutdown: &CancellationToken) -> Result<(), Error>
+    where
+        M: Send + Sync + 'static,
+        C: TensorCodec + Clone,
+    {
+        self.metrics.start();
+        if let Some(config) = &self.statsd {
+            let model = self.model_name.as_deref();
+            let version = self.model_version.as_deref();
+            let statsd = Statsd::connect(config, model, version).await?;
+            self.metrics.set_statsd(statsd);
+            tokio::spawn(statsd::send_gauges(
+                Arc::clone(&self.metrics),
+                shutdown.clone(),
+            ));
+        }
+        if let Some(addr) = &self.metrics_addr {
+            let listener = TcpListener::bind(addr).await?;
+            let labels =
+                metrics::model_labels(self.model_name.as_deref(), self.model_version.as_deref());
+            tokio::spawn(metrics::serve(
+                listener,
+                Arc::clone(&self.metrics),
+                labels,
+                shutdown.clone(),
+            ));
+        }
+        if let Some(addr) = &self.admin_addr {
+            let admin = Admin {
+                metrics: Arc::clone(&self.metrics),
+                reloader: self.model_reloader(),
+                load: self.admin_reload.clone(),
+                set_log_level: self.admin_log_level.clone(),
+                shutdown: shutdown.clone(),
+            };
+            admin::start(addr, admin).await?;
+        }
+        Ok(())
+    }
+
+    /// Runs `read` within the header read timeout, returning `None` if it
+    /// times out.
+    async fn read_header<F: Future>(&self, read: F) -> Option<F::Output> {
+        match self.header_read_timeout {
+            Some(header_read_timeout) => timeout(header_read_timeout, read).await.ok(),
+            None => Some(read.await),
+        }
+    }
+
+    /// Counts a connection from `peer` against the per-peer limit, returning
+    /// `None` if it's over the limit. The inner slot is `None` when the
+    /// connection isn't limited.
+    fn connection_slot(&self, peer: Option<IpAddr>) -> Option<Option<ConnectionSlot<'_>>> {
+        match (&self.connection_limiter, peer) {
+            (Some(limiter), Some(peer)) => limiter.acquire(peer).map(Some),
+            _ => Some(None),
+        }
+    }
+
+    /// Where a request naming `model`, and pinning `version` if any, runs,
+    /// or `None` if it runs the server's own model unrouted.
+    fn route(
+        &self,
+        model: Option<&str>,
+        version: Option<&str>,
+    ) -> Result<Option<Route>, (u16, Error)> {
+        let Some(model) = model.filter(|_| !self.models.is_empty()) else {
+            return Ok(None);
+        };
+        // the server's own model is the first version of its name
+        let own = (self.model_name.as_deref() == Some(model)).then(|| Route {
+            forward: None,
+            version: self.model_version.clone(),
         });
+        let registered = self
+            .models
+            .get(model)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let route = |registered: &RegisteredModel| Route {
+            forward: Some(Arc::clone(&registered.forward)),
+            version: registered.version.clone(),
+        };
+        let not_found = |e: String| (codes::MODEL_NOT_FOUND, Error::Msg(e));
+        let version = version.filter(|version| *version != "latest");
+        let version = version.or_else(|| Some(self.splits.get(model)?.pick()));
+        match version {
+            None => match registered.last() {
+                Some(registered) => Ok(Some(route(registered))),
+                None => own
+                    .map(Some)
+                    .ok_or_else(|| not_found(format!("no model named {model}"))),
+            },
+            Some(version) => {
+                let pinned = registered
+                    .iter()
+                    .find(|registered| registered.version.as_deref() == Some(version));
+                match (pinned, own) {
+                    (Some(registered), _) => Ok(Some(route(registered))),
+                    (None, Some(own)) if own.version.as_deref() == Some(version) => Ok(Some(own)),
+                    _ => Err(not_found(format!("no version {version} of model {model}"))),
+                }
+            }
+        }
     }
 
+    /// The shadow model and the forward pass mirroring a request for
+    /// `model`, or for the server's own model if `None`, that ran `version`.
+    fn shadow_for(
+        &self,
+        model: Option<&str>,
+        version: Option<&str>,
+    ) -> Option<(&ShadowTarget, Arc<RegisteredForwardFn>)> {
+        let target = self.shadow.as_ref()?;
+        let (name, candidate) = (&target.model, &target.version);
+        let model = model.or(self.model_name.as_deref())?;
+        if model != name || version == Some(candidate.as_str()) {
+            return None;
+        }
+        let registered = self
+            .models
+            .get(name)?
+            .iter()
+            .find(|registered| registered.version.as_ref() == Some(candidate))?;
+        Some((target, Arc::clone(&registered.forward)))
+    }
+
+    /// Checks the request's token if the server requires one.
+    fn authorize(&self, token: Option<&str>) -> Result<(), (u16, Error)> {
+        let Some(verifier) = &self.token_verifier else {
+            return Ok(());
+        };
+        match token {
+            Some(token) if verifier(token) => Ok(()),
+            Some(_) => Err((codes::UNAUTHORIZED, Error::Msg("invalid token".to_string()))),
+            None => Err((codes::UNAUTHORIZED, Error::Msg("missing token".to_string()))),
+        }
+    }
+}
+
+/// Whether an accept error only affects the connection being accepted.
+fn is_connection_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionRefused | ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset
+    )
+}
+
+/// Completes the TLS handshake if enabled and serves the connection.
+async fn accept_connection<M, C, S>(
+    socket: S,
+    peer: Option<IpAddr>,
+    server: &Arc<Server<M, C>>,
+    shutdown: &CancellationToken,
+) -> Result<(), Error>
+where
+    M: Sync + Send + 'static,
+    C: TensorCodec + Clone,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    #[cfg(feature = "tls")]
+    if let Some(acceptor) = &server.tls {
+        let stream = server
+            .read_header(acceptor.accept(socket))
+            .await
+            .ok_or_else(|| Error::Msg("timed out in the TLS handshake".to_string()))??;
+        return serve_connection(stream, peer, server, shutdown).await;
+    }
+    serve_connection(socket, peer, server, shutdown).await
+}
+
+/// Serves the connection with the server's protocol.
+async fn serve_connection<M, C, S>(
+    stream: S,
+    peer: Option<IpAddr>,
+    server: &Arc<Server<M, C>>,
+    shutdown: &CancellationToken,
+) -> Result<(), Error>
+where
+    M: Sync + Send + 'static,
+    C: TensorCodec + Clone,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match server.protocol {
+        Protocol::Stream => handle_connection(stream, peer, server, shutdown).await,
+        #[cfg(feature = "websocket")]
+        Protocol::WebSocket => websocket::handle_connection(stream, peer, server, shutdown).await,
+        #[cfg(feature = "http")]
+        Protocol::Http => http::handle_connection(stream, peer, Arc::clone(server), shutdown).await,
+    }
+}
+
+/// Serves requests on an accepted connection until the client closes it, the
+/// connection idles, it reaches the request limit or the server shuts down.
+async fn handle_connection<M, C, S>(
+    stream: S,
+    peer: Option<IpAddr>,
+    server: &Server<M, C>,
+    shutdown: &CancellationToken,
+) -> Result<(), Error>
+where
+    M: Sync + Send + 'static,
+    C: TensorCodec + Clone,
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    serve_requests(reader, writer, peer, server, shutdown).await
+}
+
+/// Serves requests read from `reader` with responses written to `writer`, see
+/// [`handle_connection`].
+async fn serve_requests<M, C, R, W>(
+    reader: R,
+    mut writer: W,
+    peer: Option<IpAddr>,
+    server: &Server<M, C>,
+    shutdown: &CancellationToken,
+) -> Result<(), Error>
+where
+    M: Sync + Send + 'static,
+    C: TensorCodec + Clone,
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    // codecs may hold per-connection state such as negotiated compression
+    let codec = server.codec.clone();
+    let mut buf_reader = BufReader::new(reader);
+
+    let mut served = 0;
+    while server.max_requests_per_connection != Some(served) {
+        // wait for the first byte of the next request
+        let next = async {
+            match server.idle_timeout {
+                Some(idle_timeout) => timeout(idle_timeout, buf_reader.fill_buf()).await.ok(),
+                None => Some(buf_reader.fill_buf().await),
+            }
+        };
+        let available = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            available = next => match available {
+                Some(available) => available?,
+                None => {
+                    debug!("closing idle connection");
+                    break;
+                }
+            },
+        };
+        if available.is_empty() {
+            break;
+        }
+
+        served += 1;
+        let keep_open = serve_request(server, &codec, peer, &mut buf_reader, &mut writer).await?;
+        writer.flush().await?;
+        if !keep_open {
+            break;
+        }
+    }
     Ok(())
 }
+
+/// What is known about a request besides its tensors.
+#[derive(Debug, Default)]
+struct RequestContext {
+    /// The JSON metadata sent in the envelope.
+    metadata: Option<Value>,
+    /// The priority class sent in the envelope.
+    priority: u8,
+    /// The IP address of the client, if known.
+    peer: Option<IpAddr>,
+    /// The token sent with the request, which quotas are keyed by.
+    api_key: Option<String>,
+    /// The name of the model to run, if the request named one.
+    model: Option<String>,
+    /// The version of the model to run, if the request pinned one.
+    version: Option<String>,
+    /// The session whose state the request runs on, if it named one.
+    session: Option<String>,
+    /// The sampling parameters sent in the envelope.
+    sampling: SamplingParams,
+}
+
+/// A request decoded from the client.
+enum Input {
+    Tensor(Tensor),
+    Named(HashMap<String, Tensor>),
+}
+
+/// The result of a forward pass written back to the client.
+enum Output<'a> {
+    Tensor(Tensor),
+    Named(HashMap<String, Tensor>),
+    /// Tensors written as they're produced, checked against the output
+    /// schema one at a time. They're produced as the stream is advanced, so
+    /// the forward pass runs until it's been written.
+    Stream(TensorStream, Running<'a>),
+    /// Tokens written as they're generated.
+    Tokens(TokenStream<'a>),
+}
+
+/// What a forward pass holds while it runs: its turn in the inference queue,
+/// its quota slot and the timer of the forward phase. Outputs written as
+/// they're produced carry it until they've been written.
+struct Running<'a> {
+    _turn: Option<Turn<'a>>,
+    _quota: Option<QuotaSlot<'a>>,
+    timer: PhaseTimer<'a>,
+}
+
+/// Serves a single request within the server's request timeout, replying
+/// with an error frame if it fails or times out. Returns whether the
+/// connection can serve further requests.
+async fn serve_request<M, C, R, W>(
+    server: &Server<M, C>,
+    codec: &C,
+    peer: Option<IpAddr>,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<bool, Error>
+where
+    M: Sync + Send + 'static,
+    C: TensorCodec,
+    R: AsyncReadExt + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    let _timer = server.metrics.start_request();
+    let mut record = AccessRecord::new(peer);
+    let request = handle_request(server, codec, peer, reader, writer, &mut record);
+    let keep_open = match server.request_timeout {
+        None => request.await,
+        Some(request_timeout) => match timeout(request_timeout, request).await {
+            Ok(keep_open) => keep_open,
+            Err(_) => {
+                // part of the response may have been written, so the
+                // connection can't be reused
+                let e = Error::Msg(format!("request timed out after {request_timeout:?}"));
+                warn!(code = codes::TIMED_OUT, "request failed: {e}");
+                server.metrics.record_error(codes::TIMED_OUT);
+                server.report_error(&e);
+                record.outcome = Outcome::Failed(codes::TIMED_OUT);
+                let frame = ErrorFrame::new(codes::TIMED_OUT, e.to_string());
+                write_error_frame(&frame, writer).await.map(|()| false)
+            }
+        },
+    };
+    if keep_open.is_err() {
+        record.outcome = Outcome::Aborted;
+    }
+    server.log_access(&record);
+    keep_open
+}
+
+/// Serves a single request, replying with an error frame if it fails.
+/// Returns whether the connection can serve further requests.
+#[cfg_attr(feature = "trace", tracing::instrument(name = "request", skip_all))]
+async fn handle_request<M, C, R, W>(
+    server: &Server<M, C>,
+    codec: &C,
+    peer: Option<IpAddr>,
+    reader: &mut R,
+    writer: &mut W,
+    record: &mut AccessRecord,
+) -> Result<bool, Error>
+where
+    M: Sync + Send + 'static,
+    C: TensorCodec,
+    R: AsyncReadExt + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    let header = async {
+        let (prefix, envelope) = read_prologue(codec, reader, writer).await?;
+        let prefix = match server.forward {
+            // named inputs start with their own framing rather than a codec header
+            Forward::Named(_) => Ok(prefix),
+            _ => read_codec_header(server, codec, &prefix, reader).await,
+        };
+        Ok::<_, Error>((prefix, envelope))
+    };
+    let Some(prologue) = server.read_header(header).await else {
+        // the client is trickling the request and won't read the frame
+        // before it's done, so the connection can't be reused
+        let e = Error::Msg("timed out reading the request header".to_string());
+        warn!(code = codes::TIMED_OUT, "request failed: {e}");
+        server.metrics.record_error(codes::TIMED_OUT);
+        server.report_error(&e);
+        record.outcome = Outcome::Failed(codes::TIMED_OUT);
+        write_error_frame(&ErrorFrame::new(codes::TIMED_OUT, e.to_string()), writer).await?;
+        return Ok(false);
+    };
+    let (prefix, mut envelope) = prologue?;
+    record.request_id = envelope
+        .as_ref()
+        .map(|envelope| envelope.request_id.to_string());
+    let context = match &mut envelope {
+        Some(envelope) => RequestContext {
+            metadata: envelope.metadata.take(),
+            priority: envelope.priority,
+            peer,
+            api_key: envelope.token.take(),
+            model: envelope.model.clone(),
+            version: envelope.version.clone(),
+            session: envelope.session.clone(),
+            sampling: envelope.sampling.take().unwrap_or_default(),
+        },
+        None => RequestContext {
+            peer,
+            ..Default::default()
+        },
+    };
+
+    let result = match (server.authorize(context.api_key.as_deref()), prefix) {
+        (Ok(()), Ok(prefix)) => {
+            let mut buf_reader = prefix.as_slice().chain(reader);
+            forward(server, codec, &mut buf_reader, context, record).await
+        }
+        (Ok(()), Err(e)) | (Err(e), _) => Err(e),
+    };
+
+    // echo the envelope, carrying only metadata returned by the forward pass
+    // and the version of the model that ran
+    if let Some(envelope) = &mut envelope {
+        if let Some((_, version)) = &record.served {
+            envelope.version = version.clone();
+        }
+        envelope.metadata = match &result {
+            Ok((_, metadata)) => metadata.clone(),
+            Err(_) => None,
+        };
+        envelope.flags &= !FLAG_STREAMING;
+        if let Ok((Output::Stream(..) | Output::Tokens(_), _)) = &result {
+            envelope.flags |= FLAG_STREAMING;
+        }
+        write_envelope(envelope, writer).await?;
+    }
+    match result {
+        Ok((Output::Stream(stream, running), _)) => {
+            let timer = server.metrics.time(Phase::Encode);
+            let mut writer = CountingWriter::new(writer);
+            write_stream(server, codec, stream, running, &mut writer, record).await?;
+            server.metrics.add_response_bytes(writer.written());
+            record.bytes_out = writer.written();
+            record.set_phase(Phase::Encode, timer.elapsed());
+        }
+        Ok((Output::Tokens(tokens), _)) => {
+            let timer = server.metrics.time(Phase::Encode);
+            let mut writer = CountingWriter::new(writer);
+            write_tokens(server, codec, tokens, &mut writer, record).await?;
+            server.metrics.add_response_bytes(writer.written());
+            record.bytes_out = writer.written();
+            record.set_phase(Phase::Encode, timer.elapsed());
+        }
+        Ok((output, _)) => {
+            let timer = server.metrics.time(Phase::Encode);
+            let mut writer = CountingWriter::new(writer);
+            write_output(codec, &output, &mut writer).await?;
+            server.metrics.add_response_bytes(writer.written());
+            record.bytes_out = writer.written();
+            record.set_phase(Phase::Encode, timer.elapsed());
+        }
+        Err((code, e)) => {
+            warn!(code, "request failed: {e}");
+            server.metrics.record_error(code);
+            server.report_error(&e);
+            record.outcome = Outcome::Failed(code);
+            write_error_frame(&ErrorFrame::new(code, e.to_string()), writer).await?;
+            // the unread rest of a bad, unauthorized or oversized request
+            // can't be told apart from the next request
+            return Ok(!matches!(
+                code,
+                codes::BAD_REQUEST | codes::UNAUTHORIZED | codes::REQUEST_TOO_LARGE
+            ));
+        }
+    }
+    Ok(true)
+}
+
+/// Writes each tensor of a streamed output as soon as it's produced, then
+/// the end of the stream, or an error frame if producing one fails.
+async fn write_stream<M, C, D, W>(
+    server: &Server<M, C>,
+    codec: &D,
+    mut stream: TensorStream,
+    running: Running<'_>,
+    writer: &mut W,
+    record: &mut AccessRecord,
+) -> Result<(), Error>
+where
+    D: TensorCodec,
+    W: AsyncWrite + Unpin + Send,
+{
+    let failed = loop {
+        // the forward pass runs as the stream is advanced
+        let next = server
+            .executor
+            .run(move || Ok((stream.next(), stream)))
+            .await;
+        let x = match next {
+            Ok((Some(Ok(x)), rest)) => {
+                stream = rest;
+                x
+            }
+            Ok((None, _)) => break None,
+            Ok((Some(Err(e)), _)) | Err(e) => break Some(e),
+        };
+        if let Some(schema) = &server.output_schema {
+            if let Err(e) = check_output(schema, &Output::Tensor(x.clone())) {
+                break Some(e);
+            }
+        }
+        write_stream_item(codec, &x, writer).await?;
+        writer.flush().await?;
+    };
+    record.set_phase(Phase::Forward, running.timer.elapsed());
+    let ended = end_stream(server, failed, writer, record).await;
+    // the request holds its turn and quota slot until its stream is written
+    drop(running);
+    ended
+}
+
+/// Writes each chunk of tokens as soon as it's generated, then the end of
+/// the stream, or an error frame if generation fails.
+async fn write_tokens<M, C, D, W>(
+    server: &Server<M, C>,
+    codec: &D,
+    mut tokens: TokenStream<'_>,
+    writer: &mut W,
+    record: &mut AccessRecord,
+) -> Result<(), Error>
+where
+    D: TensorCodec,
+    W: AsyncWrite + Unpin + Send,
+{
+    while let Some(x) = tokens.tokens.recv().await {
+        write_stream_item(codec, &x, writer).await?;
+        writer.flush().await?;
+    }
+    let failed = match (&mut tokens.generation).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e),
+        Err(e) => Some(Error::wrap(e)),
+    };
+    record.set_phase(Phase::Forward, tokens.running.timer.elapsed());
+    let ended = end_stream(server, failed, writer, record).await;
+    // the request holds its turn and quota slot until its tokens are written
+    drop(tokens);
+    ended
+}
+
+/// Ends a streamed response, with an error frame if it `failed`.
+async fn end_stream<M, C, W>(
+    server: &Server<M, C>,
+    failed: Option<Error>,
+    writer: &mut W,
+    record: &mut AccessRecord,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    match failed {
+        None => write_stream_end(writer).await,
+        Some(e) => {
+            let code = codes::FORWARD_FAILED;
+            warn!(code, "request failed part way through its stream: {e}");
+            server.metrics.record_error(code);
+            server.report_error(&e);
+            record.outcome = Outcome::Failed(code);
+            write_error_frame(&ErrorFrame::new(code, e.to_string()), writer).await
+        }
+    }
+}
+
+/// Reads the hello and envelope that may precede a request, answering the
+/// hello. Returns the first bytes of the request, which are empty if it
+/// follows an envelope, and the envelope.
+async fn read_prologue<C, R, W>(
+    codec: &C,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<(Vec<u8>, Option<Envelope>), Error>
+where
+    C: TensorCodec,
+    R: AsyncReadExt + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    let mut prefix = read_prefix(reader).await?;
+    if prefix == HELLO_MAGIC {
+        let client = read_hello_body(reader).await?;
+        let hello = Hello::new(codec.name()).negotiate(&client);
+        write_hello(&hello, writer).await?;
+        prefix = read_prefix(reader).await?;
+    }
+    if prefix != ENVELOPE_MAGIC {
+        return Ok((prefix, None));
+    }
+    let envelope = read_envelope_body(reader).await?;
+    Ok((Vec::new(), Some(envelope)))
+}
+
+/// Reads the codec's header of a request starting with `prefix`, returning
+/// the first bytes of the request. Errors carry the code sent to the client.
+async fn read_codec_header<M, C, D, R>(
+    server: &Server<M, C>,
+    codec: &D,
+    prefix: &[u8],
+    reader: &mut R,
+) -> Result<Vec<u8>, (u16, Error)>
+where
+    D: TensorCodec,
+    R: AsyncReadExt + Unpin + Send,
+{
+    let mut rest = prefix;
+    let mut prefixed = RequestReader::new((&mut rest).chain(reader), server.max_request_bytes);
+    let header = codec.read_header(&mut prefixed).await;
+    let exceeded = prefixed.exceeded();
+    drop(prefixed);
+    match header {
+        Ok(header) => Ok([header.as_slice(), rest].concat()),
+        Err(e) => Err(decode_failed(e, exceeded)),
+    }
+}
+
+/// Reads the request and runs the forward pass on it. Errors carry the code
+/// sent to the client.
+async fn forward<'a, M, C, D, R>(
+    server: &'a Server<M, C>,
+    codec: &D,
+    reader: &mut R,
+    context: RequestContext,
+    record: &mut AccessRecord,
+) -> Result<(Output<'a>, Option<Value>), (u16, Error)>
+where
+    M: Sync + Send + 'static,
+    D: TensorCodec,
+    R: AsyncReadExt + Unpin + Send,
+{
+    let input = read_input(server, codec, reader, record).await?;
+    record.set_input(&input);
+    run_forward(server, input, context, record).await
+}
+
+/// Reads and decodes the request's tensors.
+#[cfg_attr(
+    feature = "trace",
+    tracing::instrument(name = "decode", skip_all, fields(bytes))
+)]
+async fn read_input<M, C, D, R>(
+    server: &Server<M, C>,
+    codec: &D,
+    reader: &mut R,
+    record: &mut AccessRecord,
+) -> Result<Input, (u16, Error)>
+where
+    D: TensorCodec,
+    R: AsyncReadExt + Unpin + Send,
+{
+    let timer = server.metrics.time(Phase::Decode);
+    let device = &server.device;
+    // tensors are no larger than the request they're read from
+    let max_payload_bytes = server.max_payload_bytes.min(server.max_request_bytes);
+    let mut reader = RequestReader::new(reader, server.max_request_bytes);
+
+    let input = match &server.forward {
+        Forward::Named(_) => read_named_with(codec, &mut reader, device, max_payload_bytes)
+            .await
+            .map(Input::Named),
+        _ => codec
+            .decode_with_limit(&mut reader, device, max_payload_bytes)
+            .await
+            .map(Input::Tensor),
+    };
+    server.metrics.add_request_bytes(reader.bytes_read());
+    record.bytes_in = reader.bytes_read();
+    record.set_phase(Phase::Decode, timer.elapsed());
+    #[cfg(feature = "trace")]
+    tracing::Span::current().record("bytes", reader.bytes_read());
+    input.map_err(|e| decode_failed(e, reader.exceeded()))
+}
+
+/// The code sent to the client for a request that couldn't be decoded,
+/// `exceeded` if reading it went over the request limit.
+fn decode_failed(e: Error, exceeded: bool) -> (u16, Error) {
+    match exceeded || LimitExceeded::is(&e) {
+        true => (codes::REQUEST_TOO_LARGE, e),
+        false => (codes::BAD_REQUEST, e),
+    }
+}
+
+/// Writes the output of a forward pass, named outputs in a stable order.
+#[cfg_attr(feature = "trace", tracing::instrument(name = "encode", skip_all))]
+async fn write_output<D, W>(codec: &D, output: &Output, writer: &mut W) -> Result<(), Error>
+where
+    D: TensorCodec,
+    W: AsyncWrite + Unpin + Send,
+{
+    match output {
+        Output::Tensor(x) => codec.encode(x, writer).await,
+        Output::Named(outputs) => {
+            let mut outputs: Vec<(&str, &Tensor)> =
+                outputs.iter().map(|(k, v)| (k.as_str(), v)).collect();
+            outputs.sort_by_key(|(name, _)| *name);
+            write_named_with(codec, &outputs, writer).await
+        }
+        Output::Stream(..) | Output::Tokens(_) => Err(Error::Msg(
+            "streamed outputs can only be written as a stream".to_string(),
+        )),
+    }
+}
+
+/// Runs the forward pass on a decoded request.
+#[cfg_attr(
+    feature = "trace",
+    tracing::instrument(name = "forward", skip_all, fields(priority = context.priority))
+)]
+async fn run_forward<'a, M, C>(
+    server: &'a Server<M, C>,
+    input: Input,
+    context: RequestContext,
+    record: &mut AccessRecord,
+) -> Result<(Output<'a>, Option<Value>), (u16, Error)>
+where
+    M: Sync + Send + 'static,
+{
+    let model = server.model.borrow().clone();
+    let forward_failed = |e| (codes::FORWARD_FAILED, e);
+    let invalid_input = |e| (codes::INVALID_INPUT, e);
+
+    if let (Some(limiter), Some(peer)) = (&server.rate_limiter, context.peer) {
+        if !limiter.check(peer) {
+            return Err((
+                codes::THROTTLED,
+                Error::Msg("rate limit exceeded".to_string()),
+            ));
+        }
+    }
+
+    let route = server.route(context.model.as_deref(), context.version.as_deref())?;
+    if let (Some(route), Some(model)) = (&route, &context.model) {
+        server
+            .metrics
+            .record_routed(model, route.version.as_deref());
+        record.served = Some((model.clone(), route.version.clone()));
+    }
+    let registered = route.and_then(|route| route.forward);
+    let served_version = match &record.served {
+        Some((_, version)) => version.as_deref(),
+        None => server.model_version.as_deref(),
+    };
+    // inputs are checked as the forward pass will see them
+    let input = match &registered {
+        None if !server.preprocess.is_empty() => {
+            let preprocess = server.preprocess.clone();
+            server
+                .executor
+                .run(move || preprocess.apply_input(input))
+                .await
+                .map_err(invalid_input)?
+        }
+        _ => input,
+    };
+    if let (Some(schema), None) = (&server.input_schema, &registered) {
+        check_input(schema, &input).map_err(invalid_input)?;
+    }
+    if server.check_finite {
+        match &input {
+            Input::Tensor(x) => check_finite(None, x).map_err(invalid_input)?,
+            Input::Named(inputs) => {
+                for (name, x) in inputs {
+                    check_finite(Some(name), x).map_err(invalid_input)?;
+                }
+            }
+        }
+    }
+    // the shadow model sees the input the served model does
+    let shadow = match &input {
+        Input::Tensor(x) => server
+            .shadow_for(context.model.as_deref(), served_version)
+            .map(|(target, net_forward)| Shadow {
+                model: target.model.clone(),
+                version: target.version.clone(),
+                request_id: record.request_id.clone(),
+                net_forward,
+                input: x.clone(),
+                in_flight: Arc::clone(&target.in_flight),
+            }),
+        Input::Named(_) => None,
+    };
+
+    let quota = match (&server.quotas, &context.api_key) {
+        (Some(quotas), Some(key)) => {
+            let elements = match &input {
+                Input::Tensor(x) => x.elem_count(),
+                Input::Named(inputs) => inputs.values().map(Tensor::elem_count).sum(),
+            };
+            quotas
+                .admit(key, elements as u64)
+                .map_err(|e| (codes::QUOTA_EXCEEDED, e))?
+        }
+        _ => None,
+    };
+
+    let turn = match &server.queue {
+        Some(queue) => {
+            let queued = server.metrics.queued();
+            let turn = queue.turn(context.priority, context.peer, server.shed_load);
+            #[cfg(feature = "trace")]
+            let turn = tracing::Instrument::instrument(turn, tracing::info_span!("queue_wait"));
+            let turn = turn.await;
+            record.set_phase(Phase::QueueWait, queued.elapsed());
+            Some(turn.ok_or_else(|| {
+                (
+                    codes::SERVER_BUSY,
+                    Error::Msg("server busy, the inference queue is full".to_string()),
+                )
+            })?)
+        }
+        None => None,
+    };
+
+    let (output, metadata) = {
+        // detached from the record by outputs written as they're produced
+        let timer = record.time(&server.metrics, Phase::Forward);
+        match (registered.clone(), &server.forward, input) {
+            (Some(net_forward), _, Input::Tensor(x)) => {
+                let x = server
+                    .executor
+                    .run(move || net_forward(x))
+                    .await
+                    .map_err(forward_failed)?;
+                Ok((Output::Tensor(x), None))
+            }
+            (None, Forward::Tensor(net_forward), Input::Tensor(x)) => {
+                let x = match &server.batcher {
+                    Some(batcher) => batcher.forward(x, &server.executor).await,
+                    None => {
+                        let net_forward = Arc::clone(net_forward);
+                        server.executor.run(move || net_forward(&model, x)).await
+                    }
+                }
+                .map_err(forward_failed)?;
+                Ok((Output::Tensor(x), None))
+            }
+            (None, Forward::Metadata(net_forward), Input::Tensor(x)) => {
+                let net_forward = Arc::clone(net_forward);
+                let (x, metadata) = server
+                    .executor
+                    .run(move || net_forward(&model, x, context.metadata))
+                    .await
+                    .map_err(forward_failed)?;
+                Ok((Output::Tensor(x), metadata))
+            }
+            (None, Forward::Async(net_forward), Input::Tensor(x)) => {
+                let x = net_forward(model, x).await.map_err(forward_failed)?;
+                Ok((Output::Tensor(x), None))
+            }
+            (None, Forward::Stream(net_forward), Input::Tensor(x)) => {
+                let net_forward = Arc::clone(net_forward);
+                let stream = server
+                    .executor
+                    .run(move || net_forward(model, x))
+                    .await
+                    .map_err(forward_failed)?;
+                let running = Running {
+                    _turn: turn,
+                    _quota: quota,
+                    timer: timer.detach(),
+                };
+                Ok((Output::Stream(stream, running), None))
+            }
+            (None, Forward::Generate(net_forward), Input::Tensor(x)) => {
+                let net_forward = Arc::clone(net_forward);
+                let (sender, tokens) = generate::channel();
+                let executor = server.executor.clone();
+                let sampling = context.sampling;
+                let generate = async move {
+                    let generate = move || net_forward(&model, x, &sampling, &sender);
+                    executor.run(generate).await
+                };
+                let running = Running {
+                    _turn: turn,
+                    _quota: quota,
+                    timer: timer.detach(),
+                };
+                let generation = tokio::spawn(generate);
+                let tokens = TokenStream {
+                    tokens,
+                    generation,
+                    running,
+                };
+                Ok((Output::Tokens(tokens), None))
+            }
+            (None, Forward::Session(net_forward), Input::Tensor(x)) => {
+                let net_forward = Arc::clone(net_forward);
+                // wait for the session's earlier requests before taking an
+                // executor thread
+                let mut state = match &context.session {
+                    Some(id) => server.sessions.state(id),
+                    None => Arc::default(),
+                }
+                .lock_owned()
+                .await;
+                let (x, memory_bytes) = server
+                    .executor
+                    .run(move || {
+                        let (x, memory_bytes) = net_forward(&model, x, &mut *state);
+                        Ok((x?, memory_bytes))
+                    })
+                    .await
+                    .map_err(forward_failed)?;
+                if let Some(id) = &context.session {
+                    server.sessions.update(id, memory_bytes);
+                }
+                Ok((Output::Tensor(x), None))
+            }
+            (None, Forward::Named(net_forward), Input::Named(inputs)) => {
+                let net_forward = Arc::clone(net_forward);
+                let outputs = server
+                    .executor
+                    .run(move || net_forward(&model, inputs))
+                    .await
+                    .map_err(forward_failed)?;
+                Ok((Output::Named(outputs), None))
+            }
+            (None, Forward::Named(_), Input::Tensor(_)) => Err((
+                codes::BAD_REQUEST,
+                Error::Msg("expected named tensors".to_string()),
+            )),
+            (_, _, Input::Named(_)) => Err((
+                codes::BAD_REQUEST,
+                Error::Msg("expected a single tensor".to_string()),
+            )),
+        }?
+    };
+    if let (Some(shadow), Output::Tensor(answer)) = (shadow, &output) {
+        let executor = server.executor.clone();
+        shadow.spawn(answer.clone(), executor, Arc::clone(&server.metrics));
+    }
+    let output = match &registered {
+        None if !server.postprocess.is_empty() => server
+            .postprocess
+            .apply_output(output, &server.executor)
+            .await
+            .map_err(forward_failed)?,
+        _ => output,
+    };
+    if let (Some(schema), None) = (&server.output_schema, &registered) {
+        check_output(schema, &output).map_err(forward_failed)?;
+    }
+    Ok((output, metadata))
+}
+
+/// Reads the four bytes used to detect optional protocol messages.
+async fn read_prefix<R: AsyncReadExt + Unpin>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let mut magic = vec![0u8; 4];
+    reader.read_exact(&mut magic).await?;
+    Ok(magic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::envelope::read_envelope;
+    use crate::io::error::read_error_frame;
+    use crate::io::stream::read_stream_item;
+    use crate::io::write_numpy;
+    use std::collections::BTreeMap;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+
+    fn identity(_: &(), x: Tensor) -> Result<Tensor, Error> {
+        Ok(x)
+    }
+
+    #[test]
+    fn test_route() {
+        let server = Server::new(Arc::new(()), identity);
+        assert!(server.route(Some("resnet"), None).unwrap().is_none());
+
+        let server = server
+            .with_model_name("mlp")
+            .register("resnet", Arc::new(()), identity);
+        assert!(server.route(None, None).unwrap().is_none());
+        let route = server.route(Some("mlp"), None).unwrap().unwrap();
+        assert!(route.forward.is_none());
+        let route = server.route(Some("resnet"), None).unwrap().unwrap();
+        assert!(route.forward.is_some());
+        let Err((code, _)) = server.route(Some("bert"), None) else {
+            panic!("expected no model named bert");
+        };
+        assert_eq!(code, codes::MODEL_NOT_FOUND);
+    }
+
+    #[test]
+    fn test_route_version() {
+        let server = Server::new(Arc::new(()), identity)
+            .with_model_name("mlp")
+            .with_model_version("1")
+            .register_version("mlp", "2", Arc::new(()), identity)
+            .register_version("mlp", "3", Arc::new(()), identity);
+        let version = |version| {
+            let route = server.route(Some("mlp"), version).unwrap().unwrap();
+            (route.forward.is_some(), route.version)
+        };
+        assert_eq!(version(None), (true, Some("3".to_string())));
+        assert_eq!(version(Some("latest")), (true, Some("3".to_string())));
+        assert_eq!(version(Some("2")), (true, Some("2".to_string())));
+        assert_eq!(version(Some("1")), (false, Some("1".to_string())));
+        assert!(server.route(Some("mlp"), Some("4")).is_err());
+        // unnamed requests still run the server's own model
+        assert!(server.route(None, Some("2")).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_route_split() {
+        let server = Server::new(Arc::new(()), identity)
+            .with_model_name("mlp")
+            .with_model_version("1")
+            .register_version("mlp", "2", Arc::new(()), identity)
+            .with_traffic_split("mlp", "1", "2", 50);
+        let version = |version| {
+            let route = server.route(Some("mlp"), version).unwrap().unwrap();
+            route.version.unwrap()
+        };
+        assert_eq!([version(None), version(None)], ["1", "2"]);
+        assert_eq!([version(Some("1")), version(Some("latest"))], ["1", "1"]);
+        assert_eq!(version(Some("latest")), "2");
+    }
+
+    #[test]
+    fn test_shadow_for() {
+        let server = Server::new(Arc::new(()), identity)
+            .with_model_name("mlp")
+            .with_model_version("1")
+            .register_version("mlp", "2", Arc::new(()), identity)
+            .with_shadow("mlp", "2");
+        let shadow = |model, version| {
+            let shadow = server.shadow_for(model, version);
+            shadow.map(|(target, _)| (target.model.as_str(), target.version.as_str()))
+        };
+        assert_eq!(shadow(None, Some("1")), Some(("mlp", "2")));
+        assert_eq!(shadow(Some("mlp"), Some("1")), Some(("mlp", "2")));
+        assert_eq!(shadow(Some("mlp"), Some("2")), None);
+        assert_eq!(shadow(Some("resnet"), None), None);
+    }
+
+    #[tokio::test]
+    async fn test_model_reloader() {
+        let server = Server::new(Arc::new(1), |_: &i32, x| Ok(x));
+        let reloader = server.model_reloader();
+        let running = reloader.current();
+        assert_eq!(*reloader.reload(Arc::new(2)), 1);
+        assert_eq!((*running, *server.model.borrow().clone()), (1, 2));
+
+        let failed = reloader.reload_with(|| Err(Error::Msg("no checkpoint".to_string())));
+        assert!(failed.await.is_err());
+        assert_eq!(*reloader.reload_with(|| Ok(3)).await.unwrap(), 2);
+        assert_eq!(*reloader.current(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_model_reloader_warmup() {
+        let forward = |model: &i32, x| match model {
+            0 => Err(Error::Msg("cold".to_string())),
+            _ => Ok(x),
+        };
+        let x = Tensor::new(&[1f32], &Device::Cpu).unwrap();
+        let server = Server::new(Arc::new(1), forward).with_warmup(vec![x]);
+        let reloader = server.model_reloader();
+        let e = reloader.reload_with(|| Ok(0)).await.unwrap_err();
+        assert!(e.to_string().contains("warmup failed"));
+        assert_eq!(*reloader.current(), 1);
+        assert_eq!(*reloader.reload_with(|| Ok(2)).await.unwrap(), 1);
+        assert_eq!(*reloader.current(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_streaming() {
+        // yields x and 2x, failing on the second if x is negative
+        let refine = |_: Arc<()>, x: Tensor| {
+            let steps = (1..=2).map(move |i| match (i, x.to_vec1::<f32>()?[0] < 0.) {
+                (2, true) => Err(Error::Msg("diverged".to_string())),
+                _ => x.affine(i as f64, 0.),
+            });
+            Ok(steps)
+        };
+        let handle = Server::streaming(Arc::new(()), refine)
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let mut socket = TcpStream::connect(handle.local_addr()).await.unwrap();
+        let x = Tensor::new(&[1f32], &Device::Cpu).unwrap();
+        write_envelope(&Envelope::new(1), &mut socket)
+            .await
+            .unwrap();
+        write_numpy(&x, &mut socket).await.unwrap();
+        let envelope = read_envelope(&mut socket).await.unwrap();
+        assert!(envelope.has_flag(FLAG_STREAMING));
+        for i in 1..=2 {
+            let y = read_stream_item(&NpyCodec, &mut socket, &Device::Cpu).await;
+            assert_eq!(y.unwrap().unwrap().to_vec1::<f32>().unwrap(), [i as f32]);
+        }
+        let end = read_stream_item(&NpyCodec, &mut socket, &Device::Cpu).await;
+        assert!(end.unwrap().is_none());
+
+        // the connection stays open after a stream fails part way
+        let x = Tensor::new(&[-1f32], &Device::Cpu).unwrap();
+        write_numpy(&x, &mut socket).await.unwrap();
+        let y = read_stream_item(&NpyCodec, &mut socket, &Device::Cpu).await;
+        assert_eq!(y.unwrap().unwrap().to_vec1::<f32>().unwrap(), [-1.]);
+        let e = read_stream_item(&NpyCodec, &mut socket, &Device::Cpu).await;
+        assert!(e.unwrap_err().to_string().contains("diverged"));
+        write_numpy(&x, &mut socket).await.unwrap();
+        let y = read_stream_item(&NpyCodec, &mut socket, &Device::Cpu).await;
+        assert!(y.unwrap().is_some());
+        handle.shutdown();
+        handle.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let handle = Server::new(Arc::new(()), identity)
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        // an idle connection doesn't hold up the shutdown
+        let _socket = TcpStream::connect(handle.local_addr()).await.unwrap();
+        handle.shutdown();
+        handle.join().await.unwrap();
+    }
+
+    #[test]
+    fn test_is_connection_error() {
+        let reset = std::io::Error::from(ErrorKind::ConnectionReset);
+        assert!(is_connection_error(&reset));
+        // EMFILE
+        let too_many_files = std::io::Error::from_raw_os_error(24);
+        assert!(!is_connection_error(&too_many_files));
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_listener() {
+        let (connect, listener) = tokio::sync::mpsc::channel(1);
+        let shutdown = CancellationToken::new();
+        let server =
+            tokio::spawn(Server::new(Arc::new(()), identity).serve(listener, shutdown.clone()));
+        let (mut client, stream) = tokio::io::duplex(1024);
+        connect.send(stream).await.unwrap();
+        client.write_all(b"not a numpy array").await.unwrap();
+        let frame = read_error_frame(&mut client).await.unwrap();
+        assert_eq!(frame.code, codes::BAD_REQUEST);
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bad_request() {
+        let handle = Server::new(Arc::new(()), identity)
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let mut socket = TcpStream::connect(handle.local_addr()).await.unwrap();
+        socket.write_all(b"not a numpy array").await.unwrap();
+        let frame = read_error_frame(&mut socket).await.unwrap();
+        assert_eq!(frame.code, codes::BAD_REQUEST);
+        // the connection is closed after a bad request
+        assert_eq!(socket.read(&mut [0u8; 1]).await.unwrap(), 0);
+        let stats = handle.stats();
+        assert_eq!((stats.connections, stats.requests), (1, 1));
+        assert_eq!(stats.errors, BTreeMap::from([(codes::BAD_REQUEST, 1)]));
+        handle.shutdown();
+        handle.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let handle = Server::new(Arc::new(()), identity)
+            .with_request_timeout(Duration::from_millis(50))
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let mut socket = TcpStream::connect(handle.local_addr()).await.unwrap();
+        // start a request and never finish it
+        socket.write_all(b"\x93NU").await.unwrap();
+        let frame = read_error_frame(&mut socket).await.unwrap();
+        assert_eq!(frame.code, codes::TIMED_OUT);
+        assert_eq!(socket.read(&mut [0u8; 1]).await.unwrap(), 0);
+        handle.shutdown();
+        handle.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_header_read_timeout() {
+        let handle = Server::new(Arc::new(()), identity)
+            .with_header_read_timeout(Duration::from_millis(50))
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let mut socket = TcpStream::connect(handle.local_addr()).await.unwrap();
+        socket.write_all(b"\x93N").await.unwrap();
+        let frame = read_error_frame(&mut socket).await.unwrap();
+        assert_eq!(frame.code, codes::TIMED_OUT);
+        assert_eq!(socket.read(&mut [0u8; 1]).await.unwrap(), 0);
+
+        // the deadline covers the npy header after the first bytes
+        let mut socket = TcpStream::connect(handle.local_addr()).await.unwrap();
+        socket
+            .write_all(b"\x93NUMPY\x01\x00\x76\x00{'descr': ")
+            .await
+            .unwrap();
+        let frame = read_error_frame(&mut socket).await.unwrap();
+        assert_eq!(frame.code, codes::TIMED_OUT);
+        assert_eq!(socket.read(&mut [0u8; 1]).await.unwrap(), 0);
+        handle.shutdown();
+        handle.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_too_large() {
+        let handle = Server::new(Arc::new(()), identity)
+            .with_max_request_bytes(16)
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let mut socket = TcpStream::connect(handle.local_addr()).await.unwrap();
+        // an npy header longer than the whole limit
+        socket
+            .write_all(b"\x93NUMPY\x01\x00\x76\x00{'descr': ")
+            .await
+            .unwrap();
+        let frame = read_error_frame(&mut socket).await.unwrap();
+        assert_eq!(frame.code, codes::REQUEST_TOO_LARGE);
+        assert_eq!(socket.read(&mut [0u8; 1]).await.unwrap(), 0);
+        handle.shutdown();
+        handle.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized() {
+        let handle = Server::new(Arc::new(()), identity)
+            .with_token_auth(|token| token == "secret")
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let mut socket = TcpStream::connect(handle.local_addr()).await.unwrap();
+        let envelope = Envelope {
+            token: Some("guess".to_string()),
+            ..Envelope::new(1)
+        };
+        write_envelope(&envelope, &mut socket).await.unwrap();
+        // the echoed envelope doesn't carry the token
+        assert_eq!(read_envelope(&mut socket).await.unwrap(), Envelope::new(1));
+        let frame = read_error_frame(&mut socket).await.unwrap();
+        assert_eq!(frame.code, codes::UNAUTHORIZED);
+        assert_eq!(socket.read(&mut [0u8; 1]).await.unwrap(), 0);
+        handle.shutdown();
+        handle.join().await.unwrap();
+    }
+}
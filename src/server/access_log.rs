@@ -0,0 +1,275 @@
+//! One structured log record per request, for auditing and troubleshooting
+//! without a metrics stack.
+//!
+//! With [`Server::with_access_log`](super::Server::with_access_log) each
+//! request is logged at the info level with the target `socket_nn::access`,
+//! so the records can be filtered or routed apart from other logs. A record
+//! holds the model's name and version if set, the client's address, the
+//! request ID, the input shapes, the status, the latency and the bytes of
+//! tensors read and written:
+//!
+//! ```text
+//! peer=127.0.0.1 request_id=7 shape="[1, 3]" status=ok latency_ms=1.204 bytes_in=140 bytes_out=132
+//! ```
+use std::fmt::Write as _;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use candle_core::Tensor;
+use serde_json::{json, Map, Value};
+
+use super::metrics::{error_name, Metrics, Phase, PhaseTimer};
+use super::Input;
+
+/// How access log records are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// A JSON object per record.
+    Json,
+    /// `key=value` pairs separated by spaces.
+    Logfmt,
+}
+
+/// How a request ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Outcome {
+    Ok,
+    /// Failed with an error frame or status carrying the code.
+    Failed(u16),
+    /// The connection failed before a response was sent.
+    Aborted,
+}
+
+/// What is logged about a request, filled in as it's served.
+pub(super) struct AccessRecord {
+    start: Instant,
+    peer: Option<IpAddr>,
+    pub(super) request_id: Option<String>,
+    /// The name and version of the model run, if the request named one.
+    pub(super) served: Option<(String, Option<String>)>,
+    shape: Option<Value>,
+    pub(super) outcome: Outcome,
+    pub(super) bytes_in: usize,
+    pub(super) bytes_out: usize,
+    phases: [Duration; Phase::ALL.len()],
+}
+
+impl AccessRecord {
+    pub(super) fn new(peer: Option<IpAddr>) -> Self {
+        Self {
+            start: Instant::now(),
+            peer,
+            request_id: None,
+            served: None,
+            shape: None,
+            outcome: Outcome::Ok,
+            bytes_in: 0,
+            bytes_out: 0,
+            phases: Default::default(),
+        }
+    }
+
+    /// How long the request has taken so far.
+    pub(super) fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Records the time spent in a phase of the request.
+    pub(super) fn set_phase(&mut self, phase: Phase, elapsed: Duration) {
+        self.phases[phase as usize] = elapsed;
+    }
+
+    /// Times a phase into the record and `metrics` until the guard is
+    /// dropped, however the phase ends.
+    pub(super) fn time<'a, 'm>(
+        &'a mut self,
+        metrics: &'m Metrics,
+        phase: Phase,
+    ) -> RecordPhase<'a, 'm> {
+        RecordPhase {
+            timer: Some(metrics.time(phase)),
+            record: self,
+            phase,
+        }
+    }
+
+    /// Records the shapes of the request's tensors.
+    pub(super) fn set_input(&mut self, input: &Input) {
+        let dims = |x: &Tensor| json!(x.dims());
+        self.shape = Some(match input {
+            Input::Tensor(x) => dims(x),
+            Input::Named(inputs) => Value::Object(
+                inputs
+                    .iter()
+                    .map(|(name, x)| (name.clone(), dims(x)))
+                    .collect::<Map<_, _>>(),
+            ),
+        });
+    }
+
+    fn status(&self) -> &'static str {
+        match self.outcome {
+            Outcome::Ok => "ok",
+            Outcome::Failed(code) => error_name(code),
+            Outcome::Aborted => "aborted",
+        }
+    }
+
+    /// The record as a single line in `format`, naming the model served if
+    /// it has a name or version, unless the request named another, with the
+    /// time spent in each phase if `phases` is set.
+    pub(super) fn render(
+        &self,
+        format: AccessLogFormat,
+        model: Option<&str>,
+        version: Option<&str>,
+        phases: bool,
+    ) -> String {
+        let (model, version) = match &self.served {
+            Some((model, version)) => (Some(model.as_str()), version.as_deref()),
+            None => (model, version),
+        };
+        let mut fields = Vec::new();
+        if let Some(model) = model {
+            fields.push(("model".to_string(), json!(model)));
+        }
+        if let Some(version) = version {
+            fields.push(("version".to_string(), json!(version)));
+        }
+        fields.extend([
+            (
+                "peer".to_string(),
+                json!(self.peer.map(|peer| peer.to_string())),
+            ),
+            ("request_id".to_string(), json!(self.request_id)),
+            ("shape".to_string(), json!(self.shape)),
+            ("status".to_string(), json!(self.status())),
+            ("latency_ms".to_string(), json!(millis(self.elapsed()))),
+            ("bytes_in".to_string(), json!(self.bytes_in)),
+            ("bytes_out".to_string(), json!(self.bytes_out)),
+        ]);
+        if phases {
+            for phase in Phase::ALL {
+                let elapsed = self.phases[phase as usize];
+                fields.push((format!("{}_ms", phase.name()), json!(millis(elapsed))));
+            }
+        }
+        match format {
+            AccessLogFormat::Json => Value::Object(fields.into_iter().collect()).to_string(),
+            AccessLogFormat::Logfmt => {
+                let mut line = String::new();
+                for (key, value) in fields {
+                    let value = match (key.as_str(), value) {
+                        (_, Value::Null) => continue,
+                        ("shape", shape) => shape_text(&shape),
+                        (_, Value::String(value)) => value,
+                        (_, value) => value.to_string(),
+                    };
+                    if !line.is_empty() {
+                        line.push(' ');
+                    }
+                    let _ = write!(line, "{key}={}", logfmt_value(&value));
+                }
+                line
+            }
+        }
+    }
+}
+
+/// Milliseconds rounded to the microsecond.
+fn millis(duration: Duration) -> f64 {
+    duration.as_micros() as f64 / 1e3
+}
+
+/// Times a phase of a request, see [`AccessRecord::time`].
+pub(super) struct RecordPhase<'a, 'm> {
+    record: &'a mut AccessRecord,
+    /// `None` once detached.
+    timer: Option<PhaseTimer<'m>>,
+    phase: Phase,
+}
+
+impl<'m> RecordPhase<'_, 'm> {
+    /// Stops timing the phase into the record, for phases outliving the
+    /// borrow of it. The metrics still record the phase once the returned
+    /// timer is dropped.
+    pub(super) fn detach(mut self) -> PhaseTimer<'m> {
+        self.timer.take().expect("a phase is only detached once")
+    }
+}
+
+impl Drop for RecordPhase<'_, '_> {
+    fn drop(&mut self) {
+        if let Some(timer) = &self.timer {
+            self.record.set_phase(self.phase, timer.elapsed());
+        }
+    }
+}
+
+/// Formats shapes as `[1, 3]` or `x=[1, 3] y=[2]` with names in order.
+fn shape_text(shape: &Value) -> String {
+    let dims = |dims: &Value| {
+        let dims = dims.as_array().map(Vec::as_slice).unwrap_or_default();
+        let dims = dims.iter().map(Value::to_string).collect::<Vec<_>>();
+        format!("[{}]", dims.join(", "))
+    };
+    match shape {
+        Value::Object(shapes) => {
+            let mut shapes = shapes
+                .iter()
+                .map(|(name, shape)| format!("{name}={}", dims(shape)))
+                .collect::<Vec<_>>();
+            shapes.sort();
+            shapes.join(" ")
+        }
+        shape => dims(shape),
+    }
+}
+
+/// Quotes a logfmt value holding spaces, quotes or `=`.
+fn logfmt_value(value: &str) -> String {
+    if value.is_empty() || value.contains([' ', '"', '=']) {
+        format!("{value:?}")
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::error::codes;
+
+    #[test]
+    fn test_render() {
+        let mut record = AccessRecord::new(Some(IpAddr::from([127, 0, 0, 1])));
+        record.request_id = Some("7".to_string());
+        record.shape = Some(json!({"y": [2], "x": [1, 3]}));
+        record.outcome = Outcome::Failed(codes::INVALID_INPUT);
+        record.bytes_in = 140;
+
+        let line = record.render(AccessLogFormat::Logfmt, Some("mlp"), None, false);
+        assert!(line.starts_with(
+            "model=mlp peer=127.0.0.1 request_id=7 shape=\"x=[1, 3] y=[2]\" status=invalid_input latency_ms="
+        ));
+        assert!(line.ends_with(" bytes_in=140 bytes_out=0"));
+
+        record.set_phase(Phase::Forward, Duration::from_micros(1500));
+        let line = record.render(AccessLogFormat::Logfmt, None, None, true);
+        assert!(line.contains(" decode_ms=0.0 queue_wait_ms=0.0 forward_ms=1.5 encode_ms=0.0"));
+
+        record.served = Some(("resnet".to_string(), None));
+        let line = record.render(AccessLogFormat::Logfmt, Some("mlp"), Some("1"), false);
+        assert!(line.starts_with("model=resnet peer="));
+
+        record.served = None;
+        let json = record.render(AccessLogFormat::Json, None, Some("2"), false);
+        let json: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["version"], "2");
+        assert!(json.get("model").is_none());
+        assert_eq!(json["peer"], "127.0.0.1");
+        assert_eq!(json["shape"]["x"], json!([1, 3]));
+        assert_eq!(json["status"], "invalid_input");
+        assert_eq!(json["bytes_out"], 0);
+    }
+}
@@ -0,0 +1,246 @@
+//! A local endpoint for managing a running server, see
+//! [`Server::with_admin_endpoint`](super::Server::with_admin_endpoint).
+//!
+//! Operators send one command per line and get one line back, `ok` or the
+//! result of the command, or `error: ` and why it failed, so it can be
+//! driven with `nc` or `socat`:
+//!
+//! - `stats` answers the server's [`ServerStats`](super::ServerStats) as
+//!   JSON.
+//! - `reload` loads the model again with the function given to
+//!   [`Server::with_admin_reload`](super::Server::with_admin_reload) and
+//!   serves it.
+//! - `drain` stops accepting connections and shuts the server down once
+//!   in-flight requests finish, as cancelling its shutdown token does.
+//! - `set-log-level <level>` passes `level` to the function given to
+//!   [`Server::with_admin_log_level`](super::Server::with_admin_log_level),
+//!   such as one reloading a `tracing_subscriber` filter.
+//!
+//! The endpoint is either a loopback `host:port` or, on Unix, a socket path
+//! starting with `unix:`, as it isn't authenticated.
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use candle_core::{Error, Result};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+use super::listener::Listener;
+use super::metrics::Metrics;
+use super::ModelReloader;
+
+/// Longest command line read, so a client can't make the server buffer
+/// without bound.
+const MAX_COMMAND_BYTES: u64 = 1024;
+
+/// Loads the model served again, see
+/// [`Server::with_admin_reload`](super::Server::with_admin_reload).
+pub(super) type LoadFn<M> = dyn Fn() -> Result<M> + Send + Sync;
+
+/// Changes the log level, see
+/// [`Server::with_admin_log_level`](super::Server::with_admin_log_level).
+pub(super) type SetLogLevelFn = dyn Fn(&str) -> Result<()> + Send + Sync;
+
+/// What admin commands act on.
+pub(super) struct Admin<M> {
+    pub(super) metrics: Arc<Metrics>,
+    pub(super) reloader: ModelReloader<M>,
+    pub(super) load: Option<Arc<LoadFn<M>>>,
+    pub(super) set_log_level: Option<Arc<SetLogLevelFn>>,
+    pub(super) shutdown: CancellationToken,
+}
+
+impl<M> Admin<M>
+where
+    M: Send + Sync + 'static,
+{
+    /// Runs the command `line`, returning its result.
+    async fn command(&self, line: &str) -> Result<String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let args = words.collect::<Vec<_>>();
+        match (command, &args[..]) {
+            ("stats", []) => serde_json::to_string(&self.metrics.stats()).map_err(Error::wrap),
+            ("reload", []) => {
+                let load = self
+                    .load
+                    .clone()
+                    .ok_or_else(|| Error::Msg("reloading isn't configured".to_string()))?;
+                self.reloader.reload_with(move || load()).await?;
+                Ok("ok".to_string())
+            }
+            ("drain", []) => {
+                info!("draining on admin request");
+                self.shutdown.cancel();
+                Ok("ok".to_string())
+            }
+            ("set-log-level", [level]) => {
+                let set_log_level = self.set_log_level.as_ref().ok_or_else(|| {
+                    Error::Msg("setting the log level isn't configured".to_string())
+                })?;
+                set_log_level(level)?;
+                info!(level, "log level set on admin request");
+                Ok("ok".to_string())
+            }
+            ("stats" | "reload" | "drain" | "set-log-level", _) => {
+                Err(Error::Msg(format!("wrong arguments for {command}")))
+            }
+            _ => Err(Error::Msg(format!("unknown command {command:?}"))),
+        }
+    }
+}
+
+/// Binds the admin endpoint `addr` and answers commands on it until
+/// `shutdown` is cancelled.
+pub(super) async fn start<M>(addr: &str, admin: Admin<M>) -> Result<()>
+where
+    M: Send + Sync + 'static,
+{
+    let shutdown = admin.shutdown.clone();
+    #[cfg(unix)]
+    if let Some(path) = addr.strip_prefix("unix:") {
+        // a socket left behind by a previous run
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        tokio::spawn(serve(listener, Arc::new(admin), shutdown));
+        return Ok(());
+    }
+    let addr: SocketAddr = addr.parse().map_err(Error::wrap)?;
+    if !addr.ip().is_loopback() {
+        return Err(Error::Msg(format!(
+            "the admin endpoint must be on a loopback address, got {addr}"
+        )));
+    }
+    let listener = TcpListener::bind(addr).await?;
+    tokio::spawn(serve(listener, Arc::new(admin), shutdown));
+    Ok(())
+}
+
+/// Answers commands on connections from `listener` until `shutdown` is
+/// cancelled.
+async fn serve<L, M>(mut listener: L, admin: Arc<Admin<M>>, shutdown: CancellationToken)
+where
+    L: Listener,
+    M: Send + Sync + 'static,
+{
+    loop {
+        let socket = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    debug!("failed to accept admin connection: {e}");
+                    continue;
+                }
+            },
+        };
+        let admin = Arc::clone(&admin);
+        tokio::spawn(async move {
+            if let Err(e) = answer(socket, &admin).await {
+                debug!("admin connection failed: {e}");
+            }
+        });
+    }
+}
+
+/// Answers each command on `socket` until it's closed.
+async fn answer<S, M>(socket: S, admin: &Admin<M>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    M: Send + Sync + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(socket);
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = (&mut reader)
+            .take(MAX_COMMAND_BYTES)
+            .read_line(&mut line)
+            .await?;
+        if read == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && read as u64 == MAX_COMMAND_BYTES {
+            writer.write_all(b"error: command too long\n").await?;
+            return Ok(());
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match admin.command(&line).await {
+            Ok(reply) => reply,
+            Err(e) => format!("error: {e}"),
+        };
+        writer.write_all(reply.as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use candle_core::Tensor;
+
+    /// Sends `command` and reads the reply.
+    async fn send<S>(client: &mut S, command: &str) -> String
+    where
+        S: AsyncBufReadExt + AsyncWrite + Unpin,
+    {
+        let command = format!("{command}\n");
+        client.write_all(command.as_bytes()).await.unwrap();
+        let mut reply = String::new();
+        client.read_line(&mut reply).await.unwrap();
+        reply.trim_end().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        let server = Server::new(Arc::new(1), |_: &i32, x: Tensor| Ok(x));
+        let levels = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let set = Arc::clone(&levels);
+        let admin = Admin {
+            metrics: Arc::default(),
+            reloader: server.model_reloader(),
+            load: Some(Arc::new(|| Ok(2))),
+            set_log_level: Some(Arc::new(move |level: &str| {
+                set.lock().unwrap().push(level.to_string());
+                Ok(())
+            })),
+            shutdown: CancellationToken::new(),
+        };
+        let (client, socket) = tokio::io::duplex(1024);
+        tokio::spawn(async move { answer(socket, &admin).await });
+        let mut client = BufReader::new(client);
+
+        let stats = send(&mut client, "stats").await;
+        assert!(serde_json::from_str::<serde_json::Value>(&stats).is_ok());
+        assert_eq!(send(&mut client, "reload").await, "ok");
+        assert_eq!(*server.model_reloader().current(), 2);
+        assert_eq!(send(&mut client, "set-log-level debug").await, "ok");
+        assert_eq!(*levels.lock().unwrap(), ["debug"]);
+        let reply = send(&mut client, "set-log-level").await;
+        assert_eq!(reply, "error: wrong arguments for set-log-level");
+        let reply = send(&mut client, "restart").await;
+        assert_eq!(reply, "error: unknown command \"restart\"");
+        assert_eq!(send(&mut client, "drain").await, "ok");
+    }
+
+    #[tokio::test]
+    async fn test_rejects_remote_addr() {
+        let server = Server::new(Arc::new(1), |_: &i32, x: Tensor| Ok(x));
+        let admin = Admin {
+            metrics: Arc::default(),
+            reloader: server.model_reloader(),
+            load: None,
+            set_log_level: None,
+            shutdown: CancellationToken::new(),
+        };
+        assert!(start("0.0.0.0:0", admin).await.is_err());
+    }
+}
@@ -0,0 +1,289 @@
+//! Dynamic batching of single tensor requests.
+//!
+//! Requests are queued and, once `max_batch_size` rows are waiting or the
+//! first request has waited `max_wait`, requests with the same dtype and
+//! trailing dimensions are concatenated along their first dimension and run
+//! through one forward pass. The output is split back along the first
+//! dimension into one response per request, so the forward pass must keep
+//! the batch dimension and treat its rows independently.
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use candle_core::{DType, Error, Result, Tensor};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout_at, Instant};
+
+use super::executor::Executor;
+use super::{ForwardFn, SharedModel};
+
+/// Limits on the batches formed from queued requests, see
+/// [`Server::with_max_batch_size`](super::Server::with_max_batch_size) and
+/// [`Server::with_max_batch_wait`](super::Server::with_max_batch_wait).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchConfig {
+    /// The most rows run in one forward pass. A single request larger than
+    /// this is run on its own.
+    pub max_batch_size: usize,
+    /// The longest a request waits for others to join its batch.
+    pub max_wait: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 32,
+            max_wait: Duration::from_millis(2),
+        }
+    }
+}
+
+/// A request waiting to be batched.
+struct Pending {
+    input: Tensor,
+    respond: oneshot::Sender<Result<Tensor>>,
+}
+
+/// Queues requests for a task that runs them in batches. The task is
+/// started with the first request and stops when the batcher is dropped.
+pub(super) struct Batcher<M> {
+    model: SharedModel<M>,
+    net_forward: Arc<ForwardFn<M>>,
+    config: BatchConfig,
+    queue: OnceLock<mpsc::Sender<Pending>>,
+}
+
+impl<M> Batcher<M>
+where
+    M: Sync + Send + 'static,
+{
+    pub(super) fn new(
+        model: SharedModel<M>,
+        net_forward: Arc<ForwardFn<M>>,
+        config: BatchConfig,
+    ) -> Self {
+        Self {
+            model,
+            net_forward,
+            config,
+            queue: OnceLock::new(),
+        }
+    }
+
+    /// Replaces the limits, which only takes effect before the first request.
+    pub(super) fn set_config(&mut self, config: BatchConfig) {
+        self.config = config;
+    }
+
+    /// Runs the forward pass on `input` as part of a batch. Batches run on
+    /// the executor passed with the first request.
+    pub(super) async fn forward(&self, input: Tensor, executor: &Executor) -> Result<Tensor> {
+        let queue = self.queue.get_or_init(|| {
+            let (queue, requests) = mpsc::channel(self.config.max_batch_size.max(1));
+            tokio::spawn(run_batches(
+                Arc::clone(&self.model),
+                Arc::clone(&self.net_forward),
+                self.config,
+                executor.clone(),
+                requests,
+            ));
+            queue
+        });
+        let (respond, response) = oneshot::channel();
+        let stopped = || Error::Msg("batcher stopped".to_string());
+        queue
+            .send(Pending { input, respond })
+            .await
+            .map_err(|_| stopped())?;
+        response.await.map_err(|_| stopped())?
+    }
+}
+
+/// Collects queued requests into batches until every sender is dropped.
+async fn run_batches<M>(
+    model: SharedModel<M>,
+    net_forward: Arc<ForwardFn<M>>,
+    config: BatchConfig,
+    executor: Executor,
+    mut requests: mpsc::Receiver<Pending>,
+) where
+    M: Sync + Send + 'static,
+{
+    // a request that didn't fit in the last batch starts the next one
+    let mut held = None;
+    loop {
+        let first = match held.take() {
+            Some(request) => request,
+            None => match requests.recv().await {
+                Some(request) => request,
+                None => break,
+            },
+        };
+        let deadline = Instant::now() + config.max_wait;
+        let mut rows = batch_rows(&first.input);
+        let mut pending = vec![first];
+        while rows < config.max_batch_size {
+            match timeout_at(deadline, requests.recv()).await {
+                Ok(Some(request)) if rows + batch_rows(&request.input) > config.max_batch_size => {
+                    held = Some(request);
+                    break;
+                }
+                Ok(Some(request)) => {
+                    rows += batch_rows(&request.input);
+                    pending.push(request);
+                }
+                // the window closed or the batcher was dropped
+                _ => break,
+            }
+        }
+        // if the forward pass panics the dropped senders fail the requests
+        // the batch runs on the model current when it's formed
+        let model = model.borrow().clone();
+        let net_forward = Arc::clone(&net_forward);
+        let _ = executor
+            .run(move || {
+                for batch in group_compatible(pending) {
+                    run_batch(&*model, &*net_forward, batch);
+                }
+                Ok(())
+            })
+            .await;
+    }
+}
+
+/// The number of rows a request adds to a batch. Scalars can't be batched
+/// and count as one row.
+fn batch_rows(x: &Tensor) -> usize {
+    x.dims().first().copied().unwrap_or(1)
+}
+
+/// What requests must share to be concatenated along their first dimension.
+#[derive(PartialEq, Eq)]
+struct BatchKey {
+    dtype: DType,
+    trailing_dims: Option<Vec<usize>>,
+}
+
+impl BatchKey {
+    fn of(x: &Tensor) -> Self {
+        let dims = x.dims();
+        Self {
+            dtype: x.dtype(),
+            trailing_dims: (!dims.is_empty()).then(|| dims[1..].to_vec()),
+        }
+    }
+}
+
+/// Splits requests into groups that can be concatenated, keeping the order
+/// in which they arrived. Scalars are always run on their own.
+fn group_compatible(pending: Vec<Pending>) -> Vec<Vec<Pending>> {
+    let mut groups: Vec<(BatchKey, Vec<Pending>)> = Vec::new();
+    for request in pending {
+        let key = BatchKey::of(&request.input);
+        let group = match groups
+            .iter_mut()
+            .find(|(k, _)| key.trailing_dims.is_some() && *k == key)
+        {
+            Some((_, group)) => group,
+            None => {
+                groups.push((key, Vec::new()));
+                &mut groups.last_mut().unwrap().1
+            }
+        };
+        group.push(request);
+    }
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+/// Runs one forward pass over the batch and sends each request its rows of
+/// the output.
+fn run_batch<M>(model: &M, net_forward: &ForwardFn<M>, mut batch: Vec<Pending>) {
+    if batch.len() == 1 {
+        let request = batch.pop().unwrap();
+        let _ = request.respond.send(net_forward(model, request.input));
+        return;
+    }
+
+    let rows = batch
+        .iter()
+        .map(|r| batch_rows(&r.input))
+        .collect::<Vec<_>>();
+    let total = rows.iter().sum::<usize>();
+    let inputs = batch.iter().map(|r| &r.input).collect::<Vec<_>>();
+    let output = Tensor::cat(&inputs, 0)
+        .and_then(|x| net_forward(model, x))
+        .and_then(|y| match y.dims().first() {
+            Some(&n) if n == total => Ok(y),
+            _ => Err(Error::Msg(format!(
+                "batched forward pass returned shape {:?} for {total} rows",
+                y.dims()
+            ))),
+        });
+
+    match output {
+        Ok(y) => {
+            let mut offset = 0;
+            for (request, n) in batch.into_iter().zip(rows) {
+                let _ = request.respond.send(y.narrow(0, offset, n));
+                offset += n;
+            }
+        }
+        Err(e) => {
+            // errors aren't cloneable so each request gets the message
+            let message = e.to_string();
+            for request in batch {
+                let _ = request.respond.send(Err(Error::Msg(message.clone())));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[tokio::test]
+    async fn test_batches_and_scatters() -> Result<()> {
+        let model = Arc::new(tokio::sync::watch::channel(Arc::new(())).0);
+        let net_forward: Arc<ForwardFn<()>> = Arc::new(|_: &(), x: Tensor| {
+            if x.dim(0)? > 3 {
+                return Err(Error::Msg(format!("batch of {} rows", x.dim(0)?)));
+            }
+            x.affine(2.0, 0.0)
+        });
+        let config = BatchConfig {
+            max_batch_size: 3,
+            max_wait: Duration::from_secs(1),
+        };
+        let batcher = Batcher::new(model, net_forward, config);
+
+        let a = Tensor::new(&[[1f32, 2.0]], &Device::Cpu)?;
+        let b = Tensor::new(&[[3f32, 4.0], [5.0, 6.0]], &Device::Cpu)?;
+        let executor = Executor::default();
+        let (ya, yb) = tokio::join!(batcher.forward(a, &executor), batcher.forward(b, &executor));
+        assert_eq!(ya?.to_vec2::<f32>()?, vec![vec![2.0, 4.0]]);
+        assert_eq!(
+            yb?.to_vec2::<f32>()?,
+            vec![vec![6.0, 8.0], vec![10.0, 12.0]]
+        );
+
+        // a request that would overflow the batch runs in the next one
+        let b = Tensor::new(&[[3f32, 4.0], [5.0, 6.0]], &Device::Cpu)?;
+        let c = Tensor::new(&[[7f32, 8.0], [9.0, 10.0]], &Device::Cpu)?;
+        let (yb, yc) = tokio::join!(batcher.forward(b, &executor), batcher.forward(c, &executor));
+        assert_eq!(
+            yb?.to_vec2::<f32>()?,
+            vec![vec![6.0, 8.0], vec![10.0, 12.0]]
+        );
+        assert_eq!(
+            yc?.to_vec2::<f32>()?,
+            vec![vec![14.0, 16.0], vec![18.0, 20.0]]
+        );
+
+        // a single request larger than a batch runs on its own
+        let d = Tensor::zeros((4, 2), DType::F32, &Device::Cpu)?;
+        let err = batcher.forward(d, &executor).await.unwrap_err();
+        assert!(err.to_string().contains("batch of 4 rows"), "{err}");
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+//! A live terminal dashboard of a server's request rate, latency
+//! percentiles, queue depth and errors, for load tests and demos.
+//!
+//! [`run`] draws the statistics of a server started with
+//! [`Server::bind`](super::Server::bind) in the same process, and
+//! [`run_remote`] polls `GET /stats` on the metrics endpoint of a server
+//! elsewhere, see [`Server::with_metrics_endpoint`](super::Server::with_metrics_endpoint).
+//! Both redraw every second on the terminal's alternate screen until
+//! Ctrl-C. Rates and percentiles are over the last refresh, so they follow
+//! the load as it changes.
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::future::{pending, Future};
+use std::io::Write as _;
+use std::time::Duration;
+
+use candle_core::{Error, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{interval, timeout, Instant, MissedTickBehavior};
+
+use super::metrics::{error_name, ServerStats};
+use super::ServerHandle;
+
+/// How often the dashboard is redrawn.
+const REFRESH: Duration = Duration::from_secs(1);
+/// Longest a remote server may take to answer for its statistics.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(2);
+/// How many refreshes of the request rate are charted.
+const HISTORY: usize = 60;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Draws the statistics of `handle`'s server until Ctrl-C or the server
+/// shuts down.
+pub async fn run(handle: &ServerHandle) -> Result<()> {
+    let shutdown = handle.shutdown.clone();
+    draw_until(|| async { Ok(handle.stats()) }, async move {
+        shutdown.cancelled().await
+    })
+    .await
+}
+
+/// Draws the statistics served as `GET /stats` on the metrics endpoint at
+/// `addr`, such as `"127.0.0.1:9090"`, until Ctrl-C.
+pub async fn run_remote(addr: &str) -> Result<()> {
+    draw_until(|| fetch_stats(addr), pending()).await
+}
+
+async fn draw_until<F, Fut>(mut stats: F, stop: impl Future<Output = ()>) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<ServerStats>>,
+{
+    let screen = Screen::enter()?;
+    let mut dashboard = Dashboard::default();
+    let mut ticks = interval(REFRESH);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    tokio::pin!(stop);
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = &mut stop => break,
+            _ = ticks.tick() => {}
+        }
+        let frame = match stats().await {
+            Ok(stats) => dashboard.update(stats, Instant::now()),
+            Err(e) => format!("socket-nn\n\nno statistics: {e}\n"),
+        };
+        screen.draw(&frame)?;
+    }
+    Ok(())
+}
+
+/// Fetches the statistics of the server with its metrics endpoint at `addr`.
+async fn fetch_stats(addr: &str) -> Result<ServerStats> {
+    let fetch = async {
+        let mut socket = TcpStream::connect(addr).await?;
+        let request = format!("GET /stats HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n");
+        socket.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await?;
+        Ok::<_, Error>(response)
+    };
+    let response = timeout(FETCH_TIMEOUT, fetch)
+        .await
+        .map_err(|_| Error::Msg(format!("timed out fetching statistics from {addr}")))??;
+    match response.split_once("\r\n\r\n") {
+        Some((head, body)) if head.starts_with("HTTP/1.1 200") => {
+            serde_json::from_str(body).map_err(Error::wrap)
+        }
+        _ => Err(Error::Msg(format!("no statistics served at {addr}"))),
+    }
+}
+
+/// The terminal's alternate screen, left when dropped.
+struct Screen;
+
+impl Screen {
+    fn enter() -> Result<Screen> {
+        let mut stdout = std::io::stdout();
+        // alternate screen, cursor hidden
+        stdout.write_all(b"\x1b[?1049h\x1b[?25l")?;
+        stdout.flush()?;
+        Ok(Screen)
+    }
+
+    fn draw(&self, frame: &str) -> Result<()> {
+        let mut stdout = std::io::stdout().lock();
+        // home, then clear each line's remains and below the frame
+        stdout.write_all(b"\x1b[H")?;
+        for line in frame.lines() {
+            write!(stdout, "{line}\x1b[K\r\n")?;
+        }
+        stdout.write_all(b"\x1b[J")?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(b"\x1b[?25h\x1b[?1049l");
+        let _ = stdout.flush();
+    }
+}
+
+/// The previous statistics and recent request rates, to draw frames from.
+#[derive(Default)]
+struct Dashboard {
+    previous: Option<(ServerStats, Instant)>,
+    rates: VecDeque<f64>,
+}
+
+impl Dashboard {
+    /// Takes `stats` fetched at `now` and returns the frame to draw.
+    fn update(&mut self, stats: ServerStats, now: Instant) -> String {
+        let empty = ServerStats::default();
+        let (previous, seconds) = match &self.previous {
+            Some((previous, at)) => (previous, now.duration_since(*at).as_secs_f64()),
+            None => (&empty, 0.0),
+        };
+        let requests = stats.requests.saturating_sub(previous.requests);
+        let errors = stats
+            .errors
+            .iter()
+            .map(|(code, count)| {
+                let earlier = previous.errors.get(code).copied().unwrap_or_default();
+                (*code, count.saturating_sub(earlier))
+            })
+            .collect::<Vec<_>>();
+        let latency = stats.latency.since(&previous.latency);
+
+        let rate = if seconds > 0.0 {
+            requests as f64 / seconds
+        } else {
+            0.0
+        };
+        if self.previous.is_some() {
+            self.rates.push_back(rate);
+            if self.rates.len() > HISTORY {
+                self.rates.pop_front();
+            }
+        }
+
+        let mut frame = String::new();
+        let _ = writeln!(frame, "socket-nn  up {}", uptime(stats.uptime_secs));
+        let _ = writeln!(frame);
+        let _ = writeln!(
+            frame,
+            "requests    {:>8.1}/s  {} total",
+            rate, stats.requests
+        );
+        let _ = writeln!(frame, "            {}", sparkline(&self.rates));
+        let percentiles = [("p50", 0.5), ("p90", 0.9), ("p99", 0.99)]
+            .map(|(name, q)| format!("{name} {}", latency_text(latency.percentile(q))));
+        let _ = writeln!(frame, "latency     {}", percentiles.join("  "));
+        let _ = writeln!(
+            frame,
+            "in flight   {:>8}    queued {}",
+            stats.in_flight, stats.queue_depth
+        );
+        let _ = writeln!(
+            frame,
+            "connections {:>8}    open {}",
+            stats.connections, stats.open_connections
+        );
+        let failed: u64 = errors.iter().map(|(_, count)| count).sum();
+        let error_rate = if requests > 0 {
+            100.0 * failed as f64 / requests as f64
+        } else {
+            0.0
+        };
+        let mut line = format!("errors      {error_rate:>7.1}%");
+        for (code, count) in errors.iter().filter(|(_, count)| *count > 0) {
+            let _ = write!(line, "  {} {count}", error_name(*code));
+        }
+        let _ = writeln!(frame, "{line}");
+        let _ = writeln!(frame);
+        let _ = writeln!(frame, "Ctrl-C to quit");
+
+        self.previous = Some((stats, now));
+        frame
+    }
+}
+
+/// Formats seconds as `1d 02:03:04`, without the days if none.
+fn uptime(secs: u64) -> String {
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    let time = format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60);
+    match days {
+        0 => time,
+        days => format!("{days}d {time}"),
+    }
+}
+
+fn latency_text(latency: Option<Duration>) -> String {
+    match latency {
+        Some(latency) => format!("{:.1}ms", latency.as_secs_f64() * 1e3),
+        None => "-".to_string(),
+    }
+}
+
+/// Charts `values` scaled to the largest, one character each.
+fn sparkline(values: &VecDeque<f64>) -> String {
+    let max = values.iter().copied().fold(0.0, f64::max);
+    values
+        .iter()
+        .map(|&value| {
+            let level = if max > 0.0 { value / max } else { 0.0 };
+            SPARKS[(level * (SPARKS.len() - 1) as f64).round() as usize]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::io::error::codes;
+    use crate::server::metrics::LatencyHistogram;
+
+    #[test]
+    fn test_update() {
+        let mut dashboard = Dashboard::default();
+        let start = Instant::now();
+        let frame = dashboard.update(ServerStats::default(), start);
+        assert!(frame.contains("latency     p50 -  p90 -  p99 -"));
+
+        let stats = ServerStats {
+            uptime_secs: 3723,
+            requests: 20,
+            in_flight: 3,
+            queue_depth: 1,
+            errors: BTreeMap::from([(codes::TIMED_OUT, 2)]),
+            latency: LatencyHistogram {
+                counts: vec![10, 10],
+            },
+            ..Default::default()
+        };
+        let frame = dashboard.update(stats, start + Duration::from_secs(2));
+        assert!(frame.starts_with("socket-nn  up 01:02:03\n"));
+        assert!(frame.contains("requests        10.0/s  20 total\n            █\n"));
+        assert!(frame.contains("latency     p50 5.0ms  p90 9.0ms  p99 9.9ms\n"));
+        assert!(frame.contains("in flight          3    queued 1\n"));
+        assert!(frame.contains("errors         10.0%  timed_out 2\n"));
+    }
+}
@@ -0,0 +1,86 @@
+//! Where forward passes run, off the tasks serving IO.
+//!
+//! By default forward passes run on tokio's blocking thread pool. With the
+//! `pool` feature they can instead run on a dedicated rayon pool with a fixed
+//! number of threads, optionally pinned to cores, so CPU-bound models get
+//! predictable latency under concurrent load.
+#[cfg(feature = "pool")]
+use std::sync::Arc;
+
+use candle_core::{Error, Result};
+use tokio::task::spawn_blocking;
+
+/// Runs forward passes. A panic in the forward pass fails the request.
+#[derive(Clone, Default)]
+pub(super) enum Executor {
+    #[default]
+    Blocking,
+    #[cfg(feature = "pool")]
+    Pool(Arc<rayon::ThreadPool>),
+}
+
+impl Executor {
+    /// A dedicated pool of `threads` threads, the i-th pinned to the i-th
+    /// core if `pin_cores` is set.
+    #[cfg(feature = "pool")]
+    pub(super) fn pool(threads: usize, pin_cores: bool) -> Result<Self> {
+        let cores = match pin_cores {
+            true => Some(core_affinity::get_core_ids().ok_or_else(|| {
+                Error::Msg("core pinning isn't supported on this platform".to_string())
+            })?),
+            false => None,
+        };
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("socket-nn-inference-{i}"))
+            .start_handler(move |i| {
+                if let Some(cores) = &cores {
+                    core_affinity::set_for_current(cores[i % cores.len()]);
+                }
+            })
+            // the dropped response fails the request instead of aborting
+            .panic_handler(|_| {})
+            .build()
+            .map_err(Error::wrap)?;
+        Ok(Executor::Pool(Arc::new(pool)))
+    }
+
+    pub(super) async fn run<T, F>(&self, net_forward: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        match self {
+            Executor::Blocking => spawn_blocking(net_forward).await.map_err(Error::wrap)?,
+            #[cfg(feature = "pool")]
+            Executor::Pool(pool) => {
+                let (respond, response) = tokio::sync::oneshot::channel();
+                pool.spawn(move || {
+                    let _ = respond.send(net_forward());
+                });
+                response
+                    .await
+                    .map_err(|_| Error::Msg("forward pass panicked".to_string()))?
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "pool"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pool() -> Result<()> {
+        let executor = Executor::pool(2, false)?;
+        let name = executor
+            .run(|| Ok(std::thread::current().name().map(String::from)))
+            .await?;
+        assert!(name.unwrap().starts_with("socket-nn-inference-"));
+        assert!(executor
+            .run(|| -> Result<()> { panic!("boom") })
+            .await
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! Streaming tokens from autoregressive generation as they're produced, see
+//! [`Server::generate`](super::Server::generate).
+//!
+//! The forward pass sends each token, or chunk of tokens, through a
+//! [`TokenSender`] and the server writes and flushes it to the client while
+//! generation carries on, so clients see the first token as soon as it's
+//! generated rather than once the whole sequence is. Tokens are written as
+//! a streamed response, see [`crate::io::stream`]:
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use candle_core::{Result, Tensor};
+//! # use socket_nn::io::envelope::SamplingParams;
+//! # use socket_nn::server::Server;
+//! # use socket_nn::server::generate::TokenSender;
+//! # struct Lm;
+//! # impl Lm { fn next_token(&self, ids: &[u32], sampling: &SamplingParams) -> Result<u32> { Ok(0) } }
+//! fn generate(
+//!     lm: &Lm,
+//!     prompt: Tensor,
+//!     sampling: &SamplingParams,
+//!     tokens: &TokenSender,
+//! ) -> Result<()> {
+//!     let mut ids = prompt.to_vec1::<u32>()?;
+//!     for _ in 0..sampling.max_tokens.unwrap_or(256) {
+//!         let next = lm.next_token(&ids, sampling)?;
+//!         tokens.send_ids(&[next])?;
+//!         ids.push(next);
+//!     }
+//!     Ok(())
+//! }
+//! # async fn serve() -> Result<()> {
+//! Server::generate(Arc::new(Lm), generate).run("127.0.0.1:8080").await
+//! # }
+//! ```
+use candle_core::{Device, Error, Result, Tensor};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use super::Running;
+
+/// How many chunks may wait to be written before generation waits for the
+/// client to catch up.
+const TOKEN_BUFFER: usize = 32;
+
+/// Sends the tokens a forward pass generates to the client.
+pub struct TokenSender {
+    /// `None` when the tokens are discarded, such as during warmup.
+    tokens: Option<mpsc::Sender<Tensor>>,
+}
+
+impl TokenSender {
+    /// Sends `tokens` to the client, written as soon as the ones before it
+    /// are. Fails once the client has gone, so generation can stop early.
+    /// Must not be called from async code.
+    pub fn send(&self, tokens: Tensor) -> Result<()> {
+        match &self.tokens {
+            Some(sender) => sender
+                .blocking_send(tokens)
+                .map_err(|_| Error::Msg("the client stopped reading tokens".to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends token IDs as a 1-D `u32` tensor, see [`TokenSender::send`].
+    pub fn send_ids(&self, ids: &[u32]) -> Result<()> {
+        self.send(Tensor::new(ids, &Device::Cpu)?)
+    }
+
+    /// A sender discarding the tokens sent.
+    pub(super) fn discard() -> TokenSender {
+        TokenSender { tokens: None }
+    }
+}
+
+/// The tokens of a request being generated. Generation is stopped if the
+/// stream is dropped before it finishes, such as when the request times out.
+pub(super) struct TokenStream<'a> {
+    pub(super) tokens: mpsc::Receiver<Tensor>,
+    /// Generation, finished once every token has been sent.
+    pub(super) generation: JoinHandle<Result<()>>,
+    /// Held until the tokens have been written.
+    pub(super) running: Running<'a>,
+}
+
+impl Drop for TokenStream<'_> {
+    fn drop(&mut self) {
+        self.generation.abort();
+    }
+}
+
+/// A sender of tokens and the receiver they're written from.
+pub(super) fn channel() -> (TokenSender, mpsc::Receiver<Tensor>) {
+    let (sender, receiver) = mpsc::channel(TOKEN_BUFFER);
+    let sender = TokenSender {
+        tokens: Some(sender),
+    };
+    (sender, receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::codec::NpyCodec;
+    use crate::io::envelope::{read_envelope, write_envelope, Envelope, SamplingParams};
+    use crate::io::stream::read_stream_item;
+    use crate::io::write_numpy;
+    use crate::server::Server;
+    use std::sync::mpsc::Receiver;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_generate() {
+        // counts up from the prompt, failing past 10
+        let count = |_: &(), prompt: Tensor, sampling: &SamplingParams, tokens: &TokenSender| {
+            let start = prompt.to_vec1::<u32>()?[0];
+            for id in start..start + sampling.max_tokens.unwrap_or(3) {
+                if id > 10 {
+                    return Err(Error::Msg("too long".to_string()));
+                }
+                tokens.send_ids(&[id])?;
+            }
+            Ok(())
+        };
+        let handle = Server::generate(Arc::new(()), count)
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let mut socket = TcpStream::connect(handle.local_addr()).await.unwrap();
+        let prompt = Tensor::new(&[1u32], &Device::Cpu).unwrap();
+        write_numpy(&prompt, &mut socket).await.unwrap();
+        for id in 1..4u32 {
+            let tokens = read_stream_item(&NpyCodec, &mut socket, &Device::Cpu).await;
+            assert_eq!(tokens.unwrap().unwrap().to_vec1::<u32>().unwrap(), [id]);
+        }
+        let end = read_stream_item(&NpyCodec, &mut socket, &Device::Cpu).await;
+        assert!(end.unwrap().is_none());
+
+        // the envelope limits the tokens generated
+        let envelope = Envelope {
+            sampling: Some(SamplingParams {
+                max_tokens: Some(1),
+                ..Default::default()
+            }),
+            ..Envelope::new(1)
+        };
+        write_envelope(&envelope, &mut socket).await.unwrap();
+        write_numpy(&prompt, &mut socket).await.unwrap();
+        read_envelope(&mut socket).await.unwrap();
+        let tokens = read_stream_item(&NpyCodec, &mut socket, &Device::Cpu).await;
+        assert_eq!(tokens.unwrap().unwrap().to_vec1::<u32>().unwrap(), [1]);
+        let end = read_stream_item(&NpyCodec, &mut socket, &Device::Cpu).await;
+        assert!(end.unwrap().is_none());
+
+        let prompt = Tensor::new(&[10u32], &Device::Cpu).unwrap();
+        write_numpy(&prompt, &mut socket).await.unwrap();
+        let tokens = read_stream_item(&NpyCodec, &mut socket, &Device::Cpu).await;
+        assert_eq!(tokens.unwrap().unwrap().to_vec1::<u32>().unwrap(), [10]);
+        let e = read_stream_item(&NpyCodec, &mut socket, &Device::Cpu).await;
+        assert!(e.unwrap_err().to_string().contains("too long"));
+        handle.shutdown();
+        handle.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_generate_holds_queue_turn() {
+        // sends the prompt back then waits to be released
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let echo = |released: &Mutex<Receiver<()>>,
+                    prompt: Tensor,
+                    _: &SamplingParams,
+                    tokens: &TokenSender| {
+            tokens.send(prompt)?;
+            released.lock().unwrap().recv().map_err(Error::wrap)
+        };
+        let handle = Server::generate(Arc::new(Mutex::new(released)), echo)
+            .with_inference_queue(1, 4)
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let mut first = TcpStream::connect(handle.local_addr()).await.unwrap();
+        write_numpy(&Tensor::new(&[1u32], &Device::Cpu).unwrap(), &mut first)
+            .await
+            .unwrap();
+        let tokens = read_stream_item(&NpyCodec, &mut first, &Device::Cpu).await;
+        assert_eq!(tokens.unwrap().unwrap().to_vec1::<u32>().unwrap(), [1]);
+
+        // the second request waits for the first to finish generating
+        let mut second = TcpStream::connect(handle.local_addr()).await.unwrap();
+        write_numpy(&Tensor::new(&[2u32], &Device::Cpu).unwrap(), &mut second)
+            .await
+            .unwrap();
+        let waiting = read_stream_item(&NpyCodec, &mut second, &Device::Cpu);
+        assert!(timeout(Duration::from_millis(100), waiting).await.is_err());
+
+        release.send(()).unwrap();
+        let end = read_stream_item(&NpyCodec, &mut first, &Device::Cpu).await;
+        assert!(end.unwrap().is_none());
+        let tokens = read_stream_item(&NpyCodec, &mut second, &Device::Cpu).await;
+        assert_eq!(tokens.unwrap().unwrap().to_vec1::<u32>().unwrap(), [2]);
+        release.send(()).unwrap();
+        let end = read_stream_item(&NpyCodec, &mut second, &Device::Cpu).await;
+        assert!(end.unwrap().is_none());
+        handle.shutdown();
+        handle.join().await.unwrap();
+    }
+}
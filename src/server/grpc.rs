@@ -0,0 +1,443 @@
+//! gRPC inference service, enabled with the `grpc` feature.
+//!
+//! Implements `ModelInfer` of the KServe v2 `inference.GRPCInferenceService`,
+//! so standard v2 clients can call the server. Only the fields needed to
+//! describe dense tensors are decoded. A server whose forward pass takes a
+//! single tensor expects exactly one input and returns one output named
+//! `output0`, a server of named tensors uses the input and output names.
+//! Outputs are always sent in `raw_output_contents`.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use candle_core::{DType, Device, Error, Shape, Tensor};
+use prost::Message;
+use tonic::codegen::{empty_body, http, Body, BoxFuture, Service, StdError};
+use tonic::server::{Grpc, NamedService, UnaryService};
+use tonic::Status;
+use tracing::warn;
+
+use super::access_log::{AccessRecord, Outcome};
+use super::metrics::Phase;
+use super::{run_forward, Forward, Input, Output, RequestContext, Server};
+use crate::io::error::codes;
+use crate::io::{tensor_from_le_bytes, tensor_to_le_bytes};
+
+const SERVICE_NAME: &str = "inference.GRPCInferenceService";
+/// Name of the output of a forward pass returning a single tensor.
+const OUTPUT_NAME: &str = "output0";
+
+/// Subset of the v2 `ModelInferRequest` message.
+#[derive(Clone, PartialEq, Message)]
+pub struct ModelInferRequest {
+    #[prost(string, tag = "1")]
+    pub model_name: String,
+    #[prost(string, tag = "2")]
+    pub model_version: String,
+    #[prost(string, tag = "3")]
+    pub id: String,
+    #[prost(message, repeated, tag = "5")]
+    pub inputs: Vec<InferInputTensor>,
+    #[prost(bytes = "vec", repeated, tag = "7")]
+    pub raw_input_contents: Vec<Vec<u8>>,
+}
+
+/// Subset of the v2 `ModelInferRequest.InferInputTensor` message.
+#[derive(Clone, PartialEq, Message)]
+pub struct InferInputTensor {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub datatype: String,
+    #[prost(int64, repeated, tag = "3")]
+    pub shape: Vec<i64>,
+    #[prost(message, optional, tag = "5")]
+    pub contents: Option<InferTensorContents>,
+}
+
+/// Subset of the v2 `InferTensorContents` message.
+#[derive(Clone, PartialEq, Message)]
+pub struct InferTensorContents {
+    #[prost(bool, repeated, tag = "1")]
+    pub bool_contents: Vec<bool>,
+    #[prost(int64, repeated, tag = "3")]
+    pub int64_contents: Vec<i64>,
+    #[prost(uint32, repeated, tag = "4")]
+    pub uint_contents: Vec<u32>,
+    #[prost(float, repeated, tag = "6")]
+    pub fp32_contents: Vec<f32>,
+    #[prost(double, repeated, tag = "7")]
+    pub fp64_contents: Vec<f64>,
+}
+
+/// Subset of the v2 `ModelInferResponse` message.
+#[derive(Clone, PartialEq, Message)]
+pub struct ModelInferResponse {
+    #[prost(string, tag = "1")]
+    pub model_name: String,
+    #[prost(string, tag = "2")]
+    pub model_version: String,
+    #[prost(string, tag = "3")]
+    pub id: String,
+    #[prost(message, repeated, tag = "5")]
+    pub outputs: Vec<InferOutputTensor>,
+    #[prost(bytes = "vec", repeated, tag = "6")]
+    pub raw_output_contents: Vec<Vec<u8>>,
+}
+
+/// Subset of the v2 `ModelInferResponse.InferOutputTensor` message.
+#[derive(Clone, PartialEq, Message)]
+pub struct InferOutputTensor {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub datatype: String,
+    #[prost(int64, repeated, tag = "3")]
+    pub shape: Vec<i64>,
+}
+
+/// The inference service running the forward pass of a server, to be added
+/// to a `tonic` server, see [`Server::into_grpc_service`].
+pub struct GrpcInferenceService<M, C> {
+    server: Arc<Server<M, C>>,
+}
+
+impl<M, C> GrpcInferenceService<M, C> {
+    pub(super) fn new(server: Server<M, C>) -> GrpcInferenceService<M, C> {
+        GrpcInferenceService {
+            server: Arc::new(server),
+        }
+    }
+}
+
+impl<M, C> Clone for GrpcInferenceService<M, C> {
+    fn clone(&self) -> Self {
+        GrpcInferenceService {
+            server: Arc::clone(&self.server),
+        }
+    }
+}
+
+impl<M, C> NamedService for GrpcInferenceService<M, C> {
+    const NAME: &'static str = SERVICE_NAME;
+}
+
+impl<M, C, B> Service<http::Request<B>> for GrpcInferenceService<M, C>
+where
+    M: Sync + Send + 'static,
+    C: Sync + Send + 'static,
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        if request.uri().path() != format!("/{SERVICE_NAME}/ModelInfer") {
+            return Box::pin(async move {
+                // grpc-status 12 is UNIMPLEMENTED
+                Ok(http::Response::builder()
+                    .status(200)
+                    .header("grpc-status", "12")
+                    .header("content-type", "application/grpc")
+                    .body(empty_body())
+                    .unwrap())
+            });
+        }
+        let service = ModelInfer(Arc::clone(&self.server));
+        let max_request_bytes = self.server.max_request_bytes;
+        Box::pin(async move {
+            let mut grpc = Grpc::new(tonic::codec::ProstCodec::default());
+            // otherwise tonic's default limit applies
+            if max_request_bytes != usize::MAX {
+                grpc = grpc.max_decoding_message_size(max_request_bytes);
+            }
+            Ok(grpc.unary(service, request).await)
+        })
+    }
+}
+
+/// The `ModelInfer` method.
+struct ModelInfer<M, C>(Arc<Server<M, C>>);
+
+impl<M, C> UnaryService<ModelInferRequest> for ModelInfer<M, C>
+where
+    M: Sync + Send + 'static,
+    C: Sync + Send + 'static,
+{
+    type Response = ModelInferResponse;
+    type Future = BoxFuture<tonic::Response<ModelInferResponse>, Status>;
+
+    fn call(&mut self, request: tonic::Request<ModelInferRequest>) -> Self::Future {
+        let server = Arc::clone(&self.0);
+        let peer = request.remote_addr().map(|addr| addr.ip());
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|token| token.to_str().ok())
+            .map(|token| token.strip_prefix("Bearer ").unwrap_or(token).to_string());
+        Box::pin(async move {
+            model_infer(&server, peer, token, request.into_inner())
+                .await
+                .map(tonic::Response::new)
+        })
+    }
+}
+
+#[cfg_attr(
+    feature = "trace",
+    tracing::instrument(name = "request", skip_all, fields(peer = ?peer))
+)]
+async fn model_infer<M, C>(
+    server: &Server<M, C>,
+    peer: Option<IpAddr>,
+    token: Option<String>,
+    request: ModelInferRequest,
+) -> Result<ModelInferResponse, Status>
+where
+    M: Sync + Send + 'static,
+{
+    let _timer = server.metrics.start_request();
+    let mut record = AccessRecord::new(peer);
+    record.request_id = Some(request.id.clone()).filter(|id| !id.is_empty());
+    let input = server.authorize(token.as_deref()).and_then(|()| {
+        decode_inputs(server, &request, &mut record).map_err(|e| (codes::BAD_REQUEST, e))
+    });
+    let result = match input {
+        Ok(input) => {
+            record.set_input(&input);
+            run_forward(
+                server,
+                input,
+                RequestContext {
+                    peer,
+                    api_key: token,
+                    model: Some(request.model_name.clone()).filter(|name| !name.is_empty()),
+                    version: Some(request.model_version.clone()).filter(|v| !v.is_empty()),
+                    ..Default::default()
+                },
+                &mut record,
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    let outputs = match result {
+        Ok((Output::Tensor(x), _)) => vec![(OUTPUT_NAME.to_string(), x)],
+        Ok((Output::Named(outputs), _)) => {
+            // write outputs in a stable order
+            let mut outputs = outputs.into_iter().collect::<Vec<_>>();
+            outputs.sort_by(|(a, _), (b, _)| a.cmp(b));
+            outputs
+        }
+        Ok((Output::Stream(..) | Output::Tokens(_), _)) => {
+            let e = Error::Msg("streamed outputs aren't served over gRPC".to_string());
+            server.report_error(&e);
+            record.outcome = Outcome::Failed(codes::FORWARD_FAILED);
+            server.log_access(&record);
+            return Err(Status::unimplemented(e.to_string()));
+        }
+        Err((code, e)) => {
+            warn!(code, "request failed: {e}");
+            server.metrics.record_error(code);
+            server.report_error(&e);
+            record.outcome = Outcome::Failed(code);
+            server.log_access(&record);
+            return Err(match code {
+                codes::BAD_REQUEST | codes::INVALID_INPUT => {
+                    Status::invalid_argument(e.to_string())
+                }
+                codes::SERVER_BUSY | codes::THROTTLED | codes::QUOTA_EXCEEDED => {
+                    Status::resource_exhausted(e.to_string())
+                }
+                codes::UNAUTHORIZED => Status::unauthenticated(e.to_string()),
+                codes::MODEL_NOT_FOUND => Status::not_found(e.to_string()),
+                _ => Status::internal(e.to_string()),
+            });
+        }
+    };
+
+    let mut response = ModelInferResponse {
+        model_name: request.model_name,
+        // the version that ran, which a traffic split or "latest" resolves
+        model_version: match record.served.as_ref() {
+            Some((_, Some(version))) => version.clone(),
+            _ => request.model_version,
+        },
+        id: request.id,
+        ..Default::default()
+    };
+    let timer = server.metrics.time(Phase::Encode);
+    #[cfg(feature = "trace")]
+    let _span = tracing::info_span!("encode").entered();
+    for (name, tensor) in outputs {
+        let raw = match tensor_to_le_bytes(&tensor) {
+            Ok(raw) => raw,
+            Err(e) => {
+                record.outcome = Outcome::Failed(codes::FORWARD_FAILED);
+                server.log_access(&record);
+                return Err(Status::internal(e.to_string()));
+            }
+        };
+        response.outputs.push(InferOutputTensor {
+            name,
+            datatype: datatype_name(tensor.dtype()).to_string(),
+            shape: tensor.dims().iter().map(|&d| d as i64).collect(),
+        });
+        server.metrics.add_response_bytes(raw.len());
+        record.bytes_out += raw.len();
+        response.raw_output_contents.push(raw);
+    }
+    record.set_phase(Phase::Encode, timer.elapsed());
+    server.log_access(&record);
+    Ok(response)
+}
+
+/// Converts the request tensors to the input of the server's forward pass.
+#[cfg_attr(feature = "trace", tracing::instrument(name = "decode", skip_all))]
+fn decode_inputs<M, C>(
+    server: &Server<M, C>,
+    request: &ModelInferRequest,
+    record: &mut AccessRecord,
+) -> Result<Input, Error> {
+    if !request.raw_input_contents.is_empty()
+        && request.raw_input_contents.len() != request.inputs.len()
+    {
+        return Err(Error::Msg(format!(
+            "expected raw contents for {} inputs, got {}",
+            request.inputs.len(),
+            request.raw_input_contents.len()
+        )));
+    }
+    let timer = server.metrics.time(Phase::Decode);
+    let mut nbytes = 0;
+    let mut tensors = Vec::with_capacity(request.inputs.len());
+    for (i, input) in request.inputs.iter().enumerate() {
+        let raw = request.raw_input_contents.get(i).map(Vec::as_slice);
+        let tensor = input_to_tensor(input, raw)?.to_device(&server.device)?;
+        nbytes += tensor.elem_count() * tensor.dtype().size_in_bytes();
+        if nbytes > server.max_payload_bytes {
+            return Err(Error::Msg(format!(
+                "inputs exceed the limit of {} bytes",
+                server.max_payload_bytes
+            )));
+        }
+        tensors.push((input.name.clone(), tensor));
+    }
+    server.metrics.add_request_bytes(nbytes);
+    record.bytes_in = nbytes;
+    record.set_phase(Phase::Decode, timer.elapsed());
+
+    // models registered by name take a single tensor
+    let registered = server.models.contains_key(&request.model_name);
+    if let (Forward::Named(_), false) = (&server.forward, registered) {
+        let mut inputs = HashMap::with_capacity(tensors.len());
+        for (name, tensor) in tensors {
+            if inputs.insert(name.clone(), tensor).is_some() {
+                return Err(Error::Msg(format!("duplicate input {name}")));
+            }
+        }
+        return Ok(Input::Named(inputs));
+    }
+    match <[_; 1]>::try_from(tensors) {
+        Ok([(_, tensor)]) => Ok(Input::Tensor(tensor)),
+        Err(tensors) => Err(Error::Msg(format!(
+            "expected a single input, got {}",
+            tensors.len()
+        ))),
+    }
+}
+
+fn input_to_tensor(input: &InferInputTensor, raw: Option<&[u8]>) -> Result<Tensor, Error> {
+    let dims = input
+        .shape
+        .iter()
+        .map(|&d| usize::try_from(d).map_err(|_| Error::Msg(format!("negative dim {d}"))))
+        .collect::<Result<Vec<_>, _>>()?;
+    let shape = Shape::from(dims);
+    let dtype = dtype_from_datatype(&input.datatype)?;
+    if let Some(raw) = raw {
+        return tensor_from_le_bytes(raw, dtype, shape);
+    }
+
+    // otherwise the values are stored in the typed field for the data type
+    let contents = input.contents.clone().unwrap_or_default();
+    let device = &Device::Cpu;
+    match input.datatype.as_str() {
+        "BOOL" => {
+            let data = contents
+                .bool_contents
+                .iter()
+                .map(|&v| v as u8)
+                .collect::<Vec<_>>();
+            Tensor::from_vec(data, shape, device)
+        }
+        "UINT8" => {
+            let data = contents
+                .uint_contents
+                .iter()
+                .map(|&v| v as u8)
+                .collect::<Vec<_>>();
+            Tensor::from_vec(data, shape, device)
+        }
+        "UINT32" => Tensor::from_vec(contents.uint_contents, shape, device),
+        "INT64" => Tensor::from_vec(contents.int64_contents, shape, device),
+        "FP32" => Tensor::from_vec(contents.fp32_contents, shape, device),
+        "FP64" => Tensor::from_vec(contents.fp64_contents, shape, device),
+        datatype => Err(Error::Msg(format!(
+            "{datatype} inputs must be sent in raw_input_contents"
+        ))),
+    }
+}
+
+fn datatype_name(dtype: DType) -> &'static str {
+    match dtype {
+        DType::U8 => "UINT8",
+        DType::U32 => "UINT32",
+        DType::I64 => "INT64",
+        DType::BF16 => "BF16",
+        DType::F16 => "FP16",
+        DType::F32 => "FP32",
+        DType::F64 => "FP64",
+    }
+}
+
+fn dtype_from_datatype(datatype: &str) -> Result<DType, Error> {
+    match datatype {
+        "BOOL" | "UINT8" => Ok(DType::U8),
+        "UINT32" => Ok(DType::U32),
+        "INT64" => Ok(DType::I64),
+        "BF16" => Ok(DType::BF16),
+        "FP16" => Ok(DType::F16),
+        "FP32" => Ok(DType::F32),
+        "FP64" => Ok(DType::F64),
+        datatype => Err(Error::Msg(format!("unsupported datatype {datatype}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_datatype_roundtrip() {
+        for dtype in [
+            DType::U8,
+            DType::U32,
+            DType::I64,
+            DType::BF16,
+            DType::F16,
+            DType::F32,
+            DType::F64,
+        ] {
+            assert_eq!(dtype_from_datatype(datatype_name(dtype)).unwrap(), dtype);
+        }
+        assert!(dtype_from_datatype("INT8").is_err());
+    }
+}
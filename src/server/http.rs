@@ -0,0 +1,357 @@
+//! HTTP transport, enabled with the `http` feature.
+//!
+//! Requests are sent as `POST /infer` with the tensor as the body, or as
+//! `POST /models/{name}/infer` to run a model registered with
+//! [`Server::register`](super::Server::register) and
+//! `POST /models/{name}/versions/{version}/infer` to pin its version. Bodies
+//! with a `Content-Type` of `application/json` are read and answered with
+//! [`JsonCodec`], any other body with the server's codec such as
+//! `application/x-npy` for the default numpy format. Failed requests are
+//! answered with a 400 or 500 status, 401 if they lack a valid bearer token,
+//! 404 if they name no model served, 413 if they're too large, 429 if they
+//! exceed the rate limit or their quota or 503 if they time out or the
+//! server is busy, and the error message as plain text. An `X-Request-Id`
+//! header is logged as the request's ID in the
+//! [`access_log`](super::access_log). Responses from a model named in the
+//! path carry the version that ran as an `X-Model-Version` header.
+//! Keep-alive connections are closed once no request has been in flight for
+//! the server's idle timeout.
+use std::convert::Infallible;
+use std::future::pending;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use candle_core::Error;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{sleep, sleep_until, timeout, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use super::access_log::{AccessRecord, Outcome};
+use super::metrics::Phase;
+use super::{forward, write_output, RequestContext, Server};
+use crate::io::codec::TensorCodec;
+use crate::io::error::codes;
+use crate::io::json::JsonCodec;
+
+const NPY_CONTENT_TYPE: &str = "application/x-npy";
+const JSON_CONTENT_TYPE: &str = "application/json";
+/// Response header naming the version of the model that ran.
+const MODEL_VERSION: &str = "x-model-version";
+/// Header carrying the ID logged with the request.
+const REQUEST_ID: &str = "x-request-id";
+
+/// Serves HTTP requests on a connection until the client closes it or the
+/// server shuts down.
+pub(super) async fn handle_connection<M, C, S>(
+    stream: S,
+    peer: Option<IpAddr>,
+    server: Arc<Server<M, C>>,
+    shutdown: &CancellationToken,
+) -> Result<(), Error>
+where
+    M: Sync + Send + 'static,
+    C: TensorCodec + Clone,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let idle_timeout = server.idle_timeout;
+    let header_read_timeout = server.header_read_timeout;
+    let activity = Arc::new(Activity::new());
+    let service = {
+        let activity = Arc::clone(&activity);
+        service_fn(move |request| {
+            let in_flight = Activity::start(&activity);
+            let response = infer(Arc::clone(&server), peer, request);
+            async move {
+                let response = response.await;
+                drop(in_flight);
+                response
+            }
+        })
+    };
+    let mut http = Http::new();
+    http.http1_only(true);
+    if let Some(header_read_timeout) = header_read_timeout {
+        http.http1_header_read_timeout(header_read_timeout);
+    }
+    let connection = http.serve_connection(stream, service);
+    tokio::pin!(connection);
+    let idle = async {
+        match idle_timeout {
+            Some(idle_timeout) => activity.idle(idle_timeout).await,
+            None => pending().await,
+        }
+    };
+    tokio::select! {
+        result = connection.as_mut() => return result.map_err(Error::wrap),
+        _ = shutdown.cancelled() => {}
+        _ = idle => debug!("closing idle connection"),
+    }
+    // finish the in-flight request
+    connection.as_mut().graceful_shutdown();
+    connection.await.map_err(Error::wrap)
+}
+
+/// The requests in flight on a connection and when the last one finished.
+struct Activity {
+    in_flight: AtomicUsize,
+    last: Mutex<Instant>,
+}
+
+/// Marks a request in flight until dropped.
+struct InFlight(Arc<Activity>);
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            in_flight: AtomicUsize::new(0),
+            last: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn start(activity: &Arc<Activity>) -> InFlight {
+        activity.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(Arc::clone(activity))
+    }
+
+    /// Completes once no request has been in flight for `idle_timeout`.
+    async fn idle(&self, idle_timeout: Duration) {
+        loop {
+            if self.in_flight.load(Ordering::SeqCst) > 0 {
+                sleep(idle_timeout).await;
+                continue;
+            }
+            let deadline = *self.last.lock().unwrap() + idle_timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            sleep_until(deadline).await;
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        *self.0.last.lock().unwrap() = Instant::now();
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Answers a single HTTP request.
+async fn infer<M, C>(
+    server: Arc<Server<M, C>>,
+    peer: Option<IpAddr>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible>
+where
+    M: Sync + Send + 'static,
+    C: TensorCodec + Clone,
+{
+    let path = request.uri().path();
+    let route = path
+        .strip_prefix("/models/")
+        .and_then(|path| path.strip_suffix("/infer"))
+        .map(|path| match path.split_once("/versions/") {
+            Some((model, version)) => (model, Some(version)),
+            None => (path, None),
+        })
+        .filter(|(model, version)| {
+            let valid = |name: &str| !name.is_empty() && !name.contains('/');
+            valid(model) && version.iter().all(|version| valid(version))
+        })
+        .map(|(model, version)| (model.to_string(), version.map(str::to_string)));
+    if path != "/infer" && route.is_none() {
+        return Ok(text_response(
+            StatusCode::NOT_FOUND,
+            "not found".to_string(),
+        ));
+    }
+    if request.method() != Method::POST {
+        return Ok(text_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "expected POST".to_string(),
+        ));
+    }
+    let _timer = server.metrics.start_request();
+    let mut record = AccessRecord::new(peer);
+    record.request_id = request
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|request_id| request_id.to_str().ok())
+        .map(str::to_string);
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|token| token.to_str().ok())
+        .and_then(|token| token.strip_prefix("Bearer "))
+        .map(str::to_string);
+    if let Err((code, e)) = server.authorize(token.as_deref()) {
+        warn!(code, "request failed: {e}");
+        server.metrics.record_error(code);
+        server.report_error(&e);
+        record.outcome = Outcome::Failed(code);
+        server.log_access(&record);
+        let mut response = text_response(StatusCode::UNAUTHORIZED, e.to_string());
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        return Ok(response);
+    }
+    let json = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with(JSON_CONTENT_TYPE));
+
+    let codec = server.codec.clone();
+    let context = RequestContext {
+        peer,
+        api_key: token,
+        model: route.as_ref().map(|(model, _)| model.clone()),
+        version: route.and_then(|(_, version)| version),
+        ..Default::default()
+    };
+    let response = async {
+        if json {
+            respond(
+                &server,
+                &JsonCodec,
+                context,
+                request.into_body(),
+                JSON_CONTENT_TYPE,
+                &mut record,
+            )
+            .await
+        } else {
+            respond(
+                &server,
+                &codec,
+                context,
+                request.into_body(),
+                NPY_CONTENT_TYPE,
+                &mut record,
+            )
+            .await
+        }
+    };
+    let response = match server.request_timeout {
+        None => response.await,
+        Some(request_timeout) => match timeout(request_timeout, response).await {
+            Ok(response) => response,
+            Err(_) => {
+                let e = Error::Msg(format!("request timed out after {request_timeout:?}"));
+                warn!(code = codes::TIMED_OUT, "request failed: {e}");
+                server.metrics.record_error(codes::TIMED_OUT);
+                server.report_error(&e);
+                record.outcome = Outcome::Failed(codes::TIMED_OUT);
+                text_response(StatusCode::SERVICE_UNAVAILABLE, e.to_string())
+            }
+        },
+    };
+    server.log_access(&record);
+    Ok(response)
+}
+
+/// Runs the forward pass on a request body decoded with `codec`.
+async fn respond<M, C, D>(
+    server: &Server<M, C>,
+    codec: &D,
+    context: RequestContext,
+    body: Body,
+    content_type: &'static str,
+    record: &mut AccessRecord,
+) -> Response<Body>
+where
+    M: Sync + Send + 'static,
+    D: TensorCodec,
+{
+    let result = match read_body(body, server.max_request_bytes).await {
+        Ok(body) => forward(server, codec, &mut body.as_ref(), context, record).await,
+        Err(e) => Err(e),
+    };
+    let mut bytes = Vec::new();
+    let written = match result {
+        Ok((output, _)) => {
+            let timer = server.metrics.time(Phase::Encode);
+            let written = write_output(codec, &output, &mut bytes).await;
+            record.set_phase(Phase::Encode, timer.elapsed());
+            written
+        }
+        Err((code, e)) => {
+            warn!(code, "request failed: {e}");
+            server.metrics.record_error(code);
+            server.report_error(&e);
+            record.outcome = Outcome::Failed(code);
+            let status = match code {
+                codes::BAD_REQUEST | codes::INVALID_INPUT => StatusCode::BAD_REQUEST,
+                codes::MODEL_NOT_FOUND => StatusCode::NOT_FOUND,
+                codes::REQUEST_TOO_LARGE => StatusCode::PAYLOAD_TOO_LARGE,
+                codes::SERVER_BUSY => StatusCode::SERVICE_UNAVAILABLE,
+                codes::THROTTLED | codes::QUOTA_EXCEEDED => StatusCode::TOO_MANY_REQUESTS,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            return text_response(status, e.to_string());
+        }
+    };
+    if let Err(e) = written {
+        server.report_error(&e);
+        record.outcome = Outcome::Failed(codes::FORWARD_FAILED);
+        return text_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    }
+
+    server.metrics.add_response_bytes(bytes.len());
+    record.bytes_out = bytes.len();
+    let mut response = Response::new(Body::from(bytes));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    let version = record
+        .served
+        .as_ref()
+        .and_then(|(_, version)| version.as_deref());
+    if let Some(version) = version.and_then(|version| HeaderValue::from_str(version).ok()) {
+        response.headers_mut().insert(MODEL_VERSION, version);
+    }
+    response
+}
+
+/// Reads the whole body, rejecting bodies larger than `max_bytes` without
+/// reading past the limit.
+async fn read_body(mut body: Body, max_bytes: usize) -> Result<Vec<u8>, (u16, Error)> {
+    let too_large = || {
+        (
+            codes::REQUEST_TOO_LARGE,
+            Error::Msg(format!("request exceeds the limit of {max_bytes} bytes")),
+        )
+    };
+    // the lower bound is the content length when one is sent
+    if body.size_hint().lower() > max_bytes as u64 {
+        return Err(too_large());
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| (codes::BAD_REQUEST, Error::wrap(e)))?;
+        if chunk.len() > max_bytes - bytes.len() {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+fn text_response(status: StatusCode, message: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(message));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response
+}
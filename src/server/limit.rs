@@ -0,0 +1,206 @@
+//! Limits on what a single client or request may use.
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+use std::time::Instant;
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Buckets are pruned once more clients than this have been seen.
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// A token bucket per client, refilled at `rate` tokens per second up to
+/// `burst` tokens. Each request takes one token.
+pub(super) struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub(super) fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: burst as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the client's bucket, returning whether it had one.
+    pub(super) fn check(&self, client: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            // full buckets are the same as no bucket
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        if self.refill(bucket, now) < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        bucket.tokens
+    }
+}
+
+/// Caps the connections each client holds open at once.
+pub(super) struct ConnectionLimiter {
+    max_per_peer: usize,
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// One of a client's open connections, counted until dropped.
+pub(super) struct ConnectionSlot<'a> {
+    limiter: &'a ConnectionLimiter,
+    peer: IpAddr,
+}
+
+impl ConnectionLimiter {
+    pub(super) fn new(max_per_peer: usize) -> Self {
+        Self {
+            max_per_peer,
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a new connection from `peer`, or returns `None` if it already
+    /// holds the most connections allowed.
+    pub(super) fn acquire(&self, peer: IpAddr) -> Option<ConnectionSlot<'_>> {
+        let mut open = self.open.lock().unwrap();
+        if open.get(&peer).copied().unwrap_or(0) >= self.max_per_peer {
+            return None;
+        }
+        *open.entry(peer).or_insert(0) += 1;
+        Some(ConnectionSlot {
+            limiter: self,
+            peer,
+        })
+    }
+}
+
+impl Drop for ConnectionSlot<'_> {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.peer) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.peer);
+            }
+        }
+    }
+}
+
+/// Reads at most `max_bytes` from a request, failing reads past the limit.
+pub(super) struct RequestReader<R> {
+    inner: R,
+    remaining: usize,
+    max_bytes: usize,
+    exceeded: bool,
+}
+
+impl<R> RequestReader<R> {
+    pub(super) fn new(inner: R, max_bytes: usize) -> Self {
+        Self {
+            inner,
+            remaining: max_bytes,
+            max_bytes,
+            exceeded: false,
+        }
+    }
+
+    /// How many bytes have been read.
+    pub(super) fn bytes_read(&self) -> usize {
+        self.max_bytes - self.remaining
+    }
+
+    /// Whether a read failed because the request is over the limit.
+    pub(super) fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for RequestReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if this.remaining == 0 {
+            this.exceeded = true;
+            return Poll::Ready(Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("request exceeds the limit of {} bytes", this.max_bytes),
+            )));
+        }
+        let len = buf.remaining().min(this.remaining);
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(len));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        buf.advance(read);
+        this.remaining -= read;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = RateLimiter::new(0.001, 2);
+        let a = IpAddr::from([10, 0, 0, 1]);
+        let b = IpAddr::from([10, 0, 0, 2]);
+        assert!(limiter.check(a));
+        assert!(limiter.check(a));
+        assert!(!limiter.check(a));
+        assert!(limiter.check(b));
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let limiter = ConnectionLimiter::new(1);
+        let a = IpAddr::from([10, 0, 0, 1]);
+        let slot = limiter.acquire(a);
+        assert!(slot.is_some());
+        assert!(limiter.acquire(a).is_none());
+        assert!(limiter.acquire(IpAddr::from([10, 0, 0, 2])).is_some());
+        drop(slot);
+        assert!(limiter.acquire(a).is_some());
+        assert!(limiter.open.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_request_reader() {
+        let mut reader = RequestReader::new(&b"abcdef"[..], 4);
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"abcd");
+        assert!(!reader.exceeded());
+        assert!(reader.read_u8().await.is_err());
+        assert!(reader.exceeded());
+    }
+}
@@ -0,0 +1,72 @@
+//! Sources of connections served by a [`Server`](super::Server).
+//!
+//! Every transport goes through the same connection handling, so anything
+//! that yields `AsyncRead + AsyncWrite` streams can be served with
+//! [`Server::serve`](super::Server::serve).
+use std::fmt::Debug;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio::net::{unix, UnixListener, UnixStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+/// Accepts incoming connections.
+pub trait Listener: Send + 'static {
+    /// A connection to a client.
+    type Io: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+    /// The address of a client, used when logging.
+    type Addr: Debug + Send + 'static;
+
+    /// Wait for the next connection.
+    fn accept(&mut self) -> impl Future<Output = io::Result<(Self::Io, Self::Addr)>> + Send;
+
+    /// The IP address of a client, used to schedule and limit requests per
+    /// client. Clients without one aren't told apart.
+    fn peer_ip(_addr: &Self::Addr) -> Option<IpAddr> {
+        None
+    }
+}
+
+impl Listener for TcpListener {
+    type Io = TcpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        TcpListener::accept(self).await
+    }
+
+    fn peer_ip(addr: &SocketAddr) -> Option<IpAddr> {
+        Some(addr.ip())
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Io = UnixStream;
+    type Addr = unix::SocketAddr;
+
+    async fn accept(&mut self) -> io::Result<(UnixStream, unix::SocketAddr)> {
+        UnixListener::accept(self).await
+    }
+}
+
+/// Streams sent over a channel, such as in-memory duplexes in tests. Once
+/// every sender is dropped no further connections arrive.
+impl<S> Listener for mpsc::Receiver<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Io = S;
+    type Addr = ();
+
+    async fn accept(&mut self) -> io::Result<(S, ())> {
+        match self.recv().await {
+            Some(stream) => Ok((stream, ())),
+            None => std::future::pending().await,
+        }
+    }
+}
@@ -0,0 +1,729 @@
+//! Request metrics, exported in the Prometheus text format.
+//!
+//! With [`Server::with_metrics_endpoint`](super::Server::with_metrics_endpoint)
+//! the metrics are served as `GET /metrics` on their own port, so they can be
+//! scraped without going through the inference protocol. The same port
+//! answers `GET /stats` with a [`ServerStats`] snapshot as JSON for quick
+//! health checks, which [`ServerHandle::stats`](super::ServerHandle::stats)
+//! also returns.
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use candle_core::Error;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+use super::shadow;
+use super::statsd::Statsd;
+use crate::io::error::codes;
+
+/// Upper bounds of the latency histogram buckets in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// Longest a scrape may take to send its request.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most bytes of a scrape request read.
+const MAX_SCRAPE_BYTES: u64 = 8 * 1024;
+
+/// Counters shared by every connection of a server.
+#[derive(Default)]
+pub(super) struct Metrics {
+    started: OnceLock<Instant>,
+    connections: AtomicU64,
+    open_connections: AtomicUsize,
+    requests: AtomicU64,
+    errors: Mutex<BTreeMap<u16, u64>>,
+    /// Requests naming a model by the model and version run.
+    routed: Mutex<BTreeMap<(String, String), u64>>,
+    shadows: [AtomicU64; shadow::Outcome::NAMES.len()],
+    in_flight: AtomicUsize,
+    queued: AtomicUsize,
+    request_bytes: AtomicU64,
+    response_bytes: AtomicU64,
+    latency: Histogram,
+    phases: [Histogram; Phase::ALL.len()],
+    statsd: OnceLock<Statsd>,
+}
+
+/// A snapshot of what a server has served.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerStats {
+    /// Seconds since the server started serving.
+    pub uptime_secs: u64,
+    /// Connections accepted.
+    pub connections: u64,
+    /// Connections open now.
+    pub open_connections: usize,
+    /// Requests served.
+    pub requests: u64,
+    /// Requests being served now.
+    pub in_flight: usize,
+    /// Failed requests by error code, see [`codes`].
+    pub errors: BTreeMap<u16, u64>,
+    /// Requests waiting in the inference queue.
+    pub queue_depth: usize,
+    /// Requests served by latency.
+    pub latency: LatencyHistogram,
+}
+
+/// Counts of requests by latency, in buckets up to 5ms, 10ms, 25ms, 50ms,
+/// 100ms, 250ms, 500ms, 1s, 2.5s, 5s, 10s and beyond.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencyHistogram {
+    /// Requests in each bucket, not cumulative.
+    pub counts: Vec<u64>,
+}
+
+impl LatencyHistogram {
+    /// The requests counted since `earlier`, a snapshot of the same server.
+    pub fn since(&self, earlier: &LatencyHistogram) -> LatencyHistogram {
+        let counts = self.counts.iter().enumerate();
+        let counts = counts.map(|(i, count)| {
+            count.saturating_sub(earlier.counts.get(i).copied().unwrap_or_default())
+        });
+        LatencyHistogram {
+            counts: counts.collect(),
+        }
+    }
+
+    /// Estimates the latency below which a fraction `q` of the requests
+    /// fall, interpolating within buckets, or `None` if there are none.
+    /// Latencies beyond the last bucket are estimated as its bound.
+    pub fn percentile(&self, q: f64) -> Option<Duration> {
+        let total: u64 = self.counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = (q.clamp(0.0, 1.0) * total as f64).max(1.0);
+        let mut cumulative = 0;
+        let mut lower = 0.0;
+        for (i, &count) in self.counts.iter().enumerate() {
+            let Some(&upper) = LATENCY_BUCKETS.get(i) else {
+                break;
+            };
+            if count > 0 && (cumulative + count) as f64 >= rank {
+                let fraction = (rank - cumulative as f64) / count as f64;
+                return Some(micros(lower + (upper - lower) * fraction));
+            }
+            cumulative += count;
+            lower = upper;
+        }
+        Some(micros(lower))
+    }
+}
+
+/// Seconds rounded to the microsecond.
+fn micros(seconds: f64) -> Duration {
+    Duration::from_micros((seconds * 1e6).round() as u64)
+}
+
+/// Counts a connection open until dropped.
+pub(super) struct OpenConnection<'a>(&'a Metrics);
+
+/// A part of serving a request, timed separately to tell whether slowness
+/// comes from the network, the queue or the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Phase {
+    /// Reading and decoding the request's tensors.
+    Decode,
+    /// Waiting for a turn in the inference queue.
+    QueueWait,
+    /// Running the forward pass, including waiting for a batch.
+    Forward,
+    /// Encoding and writing the response.
+    Encode,
+}
+
+impl Phase {
+    pub(super) const ALL: [Phase; 4] = [
+        Phase::Decode,
+        Phase::QueueWait,
+        Phase::Forward,
+        Phase::Encode,
+    ];
+
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            Phase::Decode => "decode",
+            Phase::QueueWait => "queue_wait",
+            Phase::Forward => "forward",
+            Phase::Encode => "encode",
+        }
+    }
+}
+
+/// Records the duration of a phase when dropped.
+pub(super) struct PhaseTimer<'a> {
+    metrics: &'a Metrics,
+    phase: Phase,
+    start: Instant,
+}
+
+/// Counts a request in flight until dropped, then records its latency.
+pub(super) struct RequestTimer<'a> {
+    metrics: &'a Metrics,
+    start: Instant,
+}
+
+/// Counts a request waiting for its turn in the inference queue and times
+/// the wait.
+pub(super) struct Queued<'a>(PhaseTimer<'a>);
+
+impl Metrics {
+    pub(super) fn start_request(&self) -> RequestTimer<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        RequestTimer {
+            metrics: self,
+            start: Instant::now(),
+        }
+    }
+
+    /// Starts the uptime, unless already started.
+    pub(super) fn start(&self) {
+        self.started.get_or_init(Instant::now);
+    }
+
+    /// Sends metrics to `statsd` as well, unless already sending them.
+    pub(super) fn set_statsd(&self, statsd: Statsd) {
+        let _ = self.statsd.set(statsd);
+    }
+
+    pub(super) fn statsd(&self) -> Option<&Statsd> {
+        self.statsd.get()
+    }
+
+    pub(super) fn open_connection(&self) -> OpenConnection<'_> {
+        if let Some(statsd) = self.statsd() {
+            statsd.count("connections", 1, None);
+        }
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(self)
+    }
+
+    pub(super) fn queued(&self) -> Queued<'_> {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        Queued(self.time(Phase::QueueWait))
+    }
+
+    pub(super) fn time(&self, phase: Phase) -> PhaseTimer<'_> {
+        PhaseTimer {
+            metrics: self,
+            phase,
+            start: Instant::now(),
+        }
+    }
+
+    pub(super) fn record_error(&self, code: u16) {
+        if let Some(statsd) = self.statsd() {
+            statsd.count("errors", 1, Some(("error", error_name(code))));
+        }
+        *self.errors.lock().unwrap().entry(code).or_default() += 1;
+    }
+
+    /// Counts a request naming a model run on `version` of `model`.
+    pub(super) fn record_routed(&self, model: &str, version: Option<&str>) {
+        let key = (model.to_string(), version.unwrap_or_default().to_string());
+        *self.routed.lock().unwrap().entry(key).or_default() += 1;
+    }
+
+    pub(super) fn record_shadow(&self, outcome: shadow::Outcome) {
+        let i = shadow::Outcome::NAMES
+            .iter()
+            .position(|&name| name == outcome.name())
+            .unwrap_or_default();
+        self.shadows[i].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn add_request_bytes(&self, bytes: usize) {
+        if let Some(statsd) = self.statsd() {
+            statsd.count("request_bytes", bytes as u64, None);
+        }
+        self.request_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn add_response_bytes(&self, bytes: usize) {
+        if let Some(statsd) = self.statsd() {
+            statsd.count("response_bytes", bytes as u64, None);
+        }
+        self.response_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn stats(&self) -> ServerStats {
+        let uptime = self.started.get().map(Instant::elapsed).unwrap_or_default();
+        ServerStats {
+            uptime_secs: uptime.as_secs(),
+            connections: self.connections.load(Ordering::Relaxed),
+            open_connections: self.open_connections.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            errors: self.errors.lock().unwrap().clone(),
+            queue_depth: self.queued.load(Ordering::Relaxed),
+            latency: self.latency.snapshot(),
+        }
+    }
+
+    /// The metrics in the Prometheus text format, with `labels` on every
+    /// series. `labels` is empty or ends in a comma.
+    pub(super) fn render(&self, labels: &str) -> String {
+        let mut out = String::new();
+        counter(
+            &mut out,
+            "socket_nn_connections_total",
+            "Connections accepted.",
+            labels,
+            self.connections.load(Ordering::Relaxed),
+        );
+        gauge(
+            &mut out,
+            "socket_nn_connections_open",
+            "Connections open.",
+            labels,
+            self.open_connections.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "socket_nn_requests_total",
+            "Requests served.",
+            labels,
+            self.requests.load(Ordering::Relaxed),
+        );
+        header(
+            &mut out,
+            "socket_nn_errors_total",
+            "Failed requests by error.",
+            "counter",
+        );
+        for (code, count) in self.errors.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "socket_nn_errors_total{{{labels}error=\"{}\"}} {count}",
+                error_name(*code)
+            );
+        }
+        header(
+            &mut out,
+            "socket_nn_model_requests_total",
+            "Requests naming a model by the model and version run.",
+            "counter",
+        );
+        // the model run replaces the labels of the server's own model
+        for ((model, version), count) in self.routed.lock().unwrap().iter() {
+            let labels = model_labels(Some(model), Some(version));
+            let _ = writeln!(
+                out,
+                "socket_nn_model_requests_total{} {count}",
+                braces(&labels)
+            );
+        }
+        header(
+            &mut out,
+            "socket_nn_shadow_requests_total",
+            "Requests for the shadow model by how its output compared, or skipped.",
+            "counter",
+        );
+        for (outcome, count) in shadow::Outcome::NAMES.iter().zip(&self.shadows) {
+            let _ = writeln!(
+                out,
+                "socket_nn_shadow_requests_total{{{labels}outcome=\"{outcome}\"}} {}",
+                count.load(Ordering::Relaxed)
+            );
+        }
+        gauge(
+            &mut out,
+            "socket_nn_requests_in_flight",
+            "Requests being served.",
+            labels,
+            self.in_flight.load(Ordering::Relaxed),
+        );
+        gauge(
+            &mut out,
+            "socket_nn_queue_depth",
+            "Requests waiting in the inference queue.",
+            labels,
+            self.queued.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "socket_nn_request_bytes_total",
+            "Bytes of request tensors read.",
+            labels,
+            self.request_bytes.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "socket_nn_response_bytes_total",
+            "Bytes of response tensors written.",
+            labels,
+            self.response_bytes.load(Ordering::Relaxed),
+        );
+        let name = "socket_nn_request_duration_seconds";
+        header(&mut out, name, "Time to serve a request.", "histogram");
+        self.latency.render(&mut out, name, labels);
+        let name = "socket_nn_phase_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "Time spent in each phase of a request.",
+            "histogram",
+        );
+        for (phase, histogram) in Phase::ALL.iter().zip(&self.phases) {
+            let labels = format!("{labels}phase=\"{}\",", phase.name());
+            histogram.render(&mut out, name, &labels);
+        }
+        out
+    }
+}
+
+impl Drop for RequestTimer<'_> {
+    fn drop(&mut self) {
+        let metrics = self.metrics;
+        let elapsed = self.start.elapsed();
+        metrics.requests.fetch_add(1, Ordering::Relaxed);
+        metrics.in_flight.fetch_sub(1, Ordering::Relaxed);
+        metrics.latency.observe(elapsed);
+        if let Some(statsd) = metrics.statsd() {
+            statsd.count("requests", 1, None);
+            statsd.timing("request_duration", elapsed, None);
+        }
+    }
+}
+
+impl PhaseTimer<'_> {
+    /// How long the phase has taken so far.
+    pub(super) fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+impl Queued<'_> {
+    /// How long the request has waited so far.
+    pub(super) fn elapsed(&self) -> Duration {
+        self.0.elapsed()
+    }
+}
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        self.0.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for PhaseTimer<'_> {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        self.metrics.phases[self.phase as usize].observe(elapsed);
+        if let Some(statsd) = self.metrics.statsd() {
+            let tag = ("phase", self.phase.name());
+            statsd.timing("phase_duration", elapsed, Some(tag));
+        }
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A histogram of durations over [`LATENCY_BUCKETS`].
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&le| seconds <= le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// The counts in each bucket, then beyond the last.
+    fn snapshot(&self) -> LatencyHistogram {
+        let mut counts = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        let count = self.count.load(Ordering::Relaxed);
+        counts.push(count.saturating_sub(counts.iter().sum()));
+        LatencyHistogram { counts }
+    }
+
+    /// Writes the series of the histogram, where `labels` is empty or ends
+    /// in a comma.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        // buckets are cumulative
+        let mut cumulative = 0;
+        for (le, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{{labels}le=\"{le}\"}} {cumulative}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_bucket{{{labels}le=\"+Inf\"}} {count}");
+        let labels = braces(labels);
+        let _ = writeln!(out, "{name}_sum{labels} {sum}");
+        let _ = writeln!(out, "{name}_count{labels} {count}");
+    }
+}
+
+/// The labels naming the model served, empty if it has no name.
+pub(super) fn model_labels(name: Option<&str>, version: Option<&str>) -> String {
+    let mut labels = String::new();
+    if let Some(name) = name {
+        let _ = write!(labels, "model=\"{}\",", escape(name));
+    }
+    if let Some(version) = version {
+        let _ = write!(labels, "version=\"{}\",", escape(version));
+    }
+    labels
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Wraps labels ending in a comma in braces, or nothing if there are none.
+fn braces(labels: &str) -> String {
+    match labels.strip_suffix(',') {
+        Some(labels) => format!("{{{labels}}}"),
+        None => String::new(),
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn counter(out: &mut String, name: &str, help: &str, labels: &str, value: u64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{name}{} {value}", braces(labels));
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, labels: &str, value: usize) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{name}{} {value}", braces(labels));
+}
+
+/// The label value of an error code.
+pub(super) fn error_name(code: u16) -> &'static str {
+    match code {
+        codes::BAD_REQUEST => "bad_request",
+        codes::FORWARD_FAILED => "forward_failed",
+        codes::TIMED_OUT => "timed_out",
+        codes::SERVER_BUSY => "server_busy",
+        codes::UNAUTHORIZED => "unauthorized",
+        codes::THROTTLED => "throttled",
+        codes::REQUEST_TOO_LARGE => "request_too_large",
+        codes::INVALID_INPUT => "invalid_input",
+        codes::QUOTA_EXCEEDED => "quota_exceeded",
+        codes::MODEL_NOT_FOUND => "model_not_found",
+        _ => "other",
+    }
+}
+
+/// Counts the bytes written through it.
+pub(super) struct CountingWriter<'a, W> {
+    inner: &'a mut W,
+    written: usize,
+}
+
+impl<'a, W> CountingWriter<'a, W> {
+    pub(super) fn new(inner: &'a mut W) -> Self {
+        Self { inner, written: 0 }
+    }
+
+    pub(super) fn written(&self) -> usize {
+        self.written
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<'_, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut *this.inner).poll_write(cx, buf))?;
+        this.written += written;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Answers scrapes of `GET /metrics` and `GET /stats` on `listener` until
+/// `shutdown` is cancelled.
+pub(super) async fn serve(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    labels: String,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let socket = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    debug!("failed to accept metrics scrape: {e}");
+                    continue;
+                }
+            },
+        };
+        let metrics = Arc::clone(&metrics);
+        let labels = labels.clone();
+        tokio::spawn(async move {
+            if let Err(e) = scrape(socket, &metrics, &labels).await {
+                debug!("metrics scrape failed: {e}");
+            }
+        });
+    }
+}
+
+/// Answers one HTTP request and closes the connection.
+async fn scrape(socket: TcpStream, metrics: &Metrics, labels: &str) -> Result<(), Error> {
+    let mut socket = BufReader::new(socket.take(MAX_SCRAPE_BYTES));
+    let mut request_line = String::new();
+    let read = async {
+        socket.read_line(&mut request_line).await?;
+        // skip the headers
+        let mut header = String::new();
+        while socket.read_line(&mut header).await? > 2 {
+            header.clear();
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    timeout(SCRAPE_TIMEOUT, read)
+        .await
+        .map_err(|_| Error::Msg("timed out reading the scrape".to_string()))??;
+
+    let (status, content_type, body) = match request_line.split(' ').take(2).collect::<Vec<_>>()[..]
+    {
+        ["GET", "/metrics"] => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            metrics.render(labels),
+        ),
+        ["GET", "/stats"] => (
+            "200 OK",
+            "application/json",
+            serde_json::to_string(&metrics.stats()).map_err(Error::wrap)?,
+        ),
+        _ => (
+            "404 Not Found",
+            "text/plain; charset=utf-8",
+            "not found".to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let socket = socket.get_mut().get_mut();
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_scrape() {
+        let metrics = Arc::new(Metrics::default());
+        drop(metrics.start_request());
+        drop(metrics.time(Phase::Forward));
+        metrics.record_error(codes::BAD_REQUEST);
+        metrics.record_routed("resnet", Some("2"));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let labels = model_labels(Some("mlp"), None);
+        let server = tokio::spawn(serve(listener, metrics, labels, shutdown.clone()));
+
+        let response = get(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("\nsocket_nn_requests_total{model=\"mlp\"} 1\n"));
+        assert!(response.contains("socket_nn_errors_total{model=\"mlp\",error=\"bad_request\"} 1"));
+        assert!(response.contains("socket_nn_request_duration_seconds_count{model=\"mlp\"} 1"));
+        assert!(response
+            .contains("socket_nn_phase_duration_seconds_count{model=\"mlp\",phase=\"forward\"} 1"));
+        assert!(
+            response.contains("socket_nn_model_requests_total{model=\"resnet\",version=\"2\"} 1")
+        );
+
+        let response = get(addr, "/stats").await;
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let stats: ServerStats = serde_json::from_str(body).unwrap();
+        assert_eq!(stats.requests, 1);
+        assert_eq!(stats.errors, BTreeMap::from([(codes::BAD_REQUEST, 1)]));
+
+        assert!(get(addr, "/").await.starts_with("HTTP/1.1 404"));
+
+        shutdown.cancel();
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_percentile() {
+        let latency = Histogram::default();
+        for millis in [1, 2, 3, 4, 6, 7, 8, 9, 20, 20_000] {
+            latency.observe(Duration::from_millis(millis));
+        }
+        let latency = latency.snapshot();
+        assert_eq!(latency.counts[..3], [4, 4, 1]);
+        assert_eq!(latency.counts[LATENCY_BUCKETS.len()], 1);
+        assert_eq!(latency.percentile(0.4), Some(Duration::from_millis(5)));
+        assert_eq!(latency.percentile(0.6), Some(Duration::from_micros(7500)));
+        assert_eq!(latency.percentile(1.0), Some(Duration::from_secs(10)));
+
+        let earlier = LatencyHistogram { counts: vec![4, 4] };
+        let later = latency.since(&earlier);
+        assert_eq!(later.percentile(0.5), Some(Duration::from_millis(25)));
+        assert_eq!(LatencyHistogram::default().percentile(0.5), None);
+    }
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        socket.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        response
+    }
+}
@@ -0,0 +1,52 @@
+//! Export of the server's request spans over OTLP, enabled with the `otlp`
+//! feature.
+//!
+//! Each request is exported as a `request` span with `decode`, `queue_wait`,
+//! `forward` and `encode` children, under the `connection` span of the
+//! connection it was read from, so inference latency shows up in a tracing
+//! backend alongside the services calling the server. Add the layer to the
+//! application's subscriber from within the Tokio runtime:
+//!
+//! ```no_run
+//! # use tracing_subscriber::prelude::*;
+//! # async fn run() -> candle_core::Result<()> {
+//! let layer = socket_nn::server::otlp::layer("http://localhost:4317", "mlp")?;
+//! tracing_subscriber::registry().with(layer).init();
+//! // serve until shutdown, then send the spans still buffered
+//! socket_nn::server::otlp::shutdown();
+//! # Ok(())
+//! # }
+//! ```
+use candle_core::{Error, Result};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::{self, Tracer};
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// A layer exporting spans in batches to the OTLP gRPC collector at
+/// `endpoint`, such as `"http://localhost:4317"`, as the service
+/// `service_name`. Must be called within the Tokio runtime.
+pub fn layer<S>(endpoint: &str, service_name: &str) -> Result<OpenTelemetryLayer<S, Tracer>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(endpoint);
+    let resource = Resource::new([KeyValue::new("service.name", service_name.to_string())]);
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace::config().with_resource(resource))
+        .install_batch(runtime::Tokio)
+        .map_err(Error::wrap)?;
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Sends the spans still buffered and stops exporting.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
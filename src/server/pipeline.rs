@@ -0,0 +1,192 @@
+//! Stages run on tensors before and after the forward pass, so preparing
+//! inputs and outputs can live on the server without bloating the forward
+//! pass, see [`Server::with_preprocess`](super::Server::with_preprocess) and
+//! [`Server::with_postprocess`](super::Server::with_postprocess).
+//!
+//! Stages run in the order they're added, on the server's executor, and on
+//! each tensor of named and streamed requests and responses, but not on
+//! generated tokens or the tensors of models registered by name. Inputs are
+//! checked against the input schema after preprocessing and outputs against
+//! the output schema after postprocessing, so the schemas describe what the
+//! forward pass takes and what the client gets. A failing preprocessing
+//! stage fails the request with an invalid input error, and a failing
+//! postprocessing stage with a forward pass error.
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use candle_core::{DType, Result, Tensor};
+//! # use socket_nn::server::Server;
+//! # use socket_nn::server::pipeline::{cast, normalize, softmax};
+//! # fn forward(model: &(), x: Tensor) -> Result<Tensor> { Ok(x) }
+//! # async fn serve() -> Result<()> {
+//! Server::new(Arc::new(()), forward)
+//!     .with_preprocess(cast(DType::F32))
+//!     .with_preprocess(normalize(127.5, 127.5))
+//!     .with_postprocess(softmax(1))
+//!     .run("127.0.0.1:8080")
+//!     .await
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use candle_core::{DType, Error, Result, Tensor};
+
+use super::executor::Executor;
+use super::{Input, Output};
+
+/// A stage run on a tensor before or after the forward pass.
+pub type StageFn = dyn Fn(Tensor) -> Result<Tensor> + Send + Sync;
+
+/// Stages run one after another.
+#[derive(Clone, Default)]
+pub(super) struct Pipeline {
+    stages: Vec<Arc<StageFn>>,
+}
+
+impl Pipeline {
+    pub(super) fn push(&mut self, stage: Arc<StageFn>) {
+        self.stages.push(stage);
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Runs every stage on `x` in order.
+    fn apply(&self, x: Tensor) -> Result<Tensor> {
+        self.stages.iter().try_fold(x, |x, stage| stage(x))
+    }
+
+    fn apply_named(&self, tensors: HashMap<String, Tensor>) -> Result<HashMap<String, Tensor>> {
+        tensors
+            .into_iter()
+            .map(|(name, x)| Ok((name, self.apply(x)?)))
+            .collect()
+    }
+
+    pub(super) fn apply_input(&self, input: Input) -> Result<Input> {
+        Ok(match input {
+            Input::Tensor(x) => Input::Tensor(self.apply(x)?),
+            Input::Named(inputs) => Input::Named(self.apply_named(inputs)?),
+        })
+    }
+
+    /// Runs every stage on `output` on `executor`. Streamed tensors are run
+    /// on as they're produced.
+    pub(super) async fn apply_output<'a>(
+        &self,
+        output: Output<'a>,
+        executor: &Executor,
+    ) -> Result<Output<'a>> {
+        let pipeline = self.clone();
+        Ok(match output {
+            Output::Tensor(x) => Output::Tensor(executor.run(move || pipeline.apply(x)).await?),
+            Output::Named(outputs) => {
+                Output::Named(executor.run(move || pipeline.apply_named(outputs)).await?)
+            }
+            Output::Stream(stream, running) => {
+                let stream = Box::new(stream.map(move |x| pipeline.apply(x?)));
+                Output::Stream(stream, running)
+            }
+            Output::Tokens(tokens) => Output::Tokens(tokens),
+        })
+    }
+}
+
+/// A stage subtracting `mean` and dividing by `std`.
+pub fn normalize(mean: f64, std: f64) -> impl Fn(Tensor) -> Result<Tensor> + Send + Sync {
+    move |x| x.affine(1. / std, -mean / std)
+}
+
+/// A stage converting tensors to `dtype`.
+pub fn cast(dtype: DType) -> impl Fn(Tensor) -> Result<Tensor> + Send + Sync {
+    move |x| x.to_dtype(dtype)
+}
+
+/// A stage clipping values to between `min` and `max`.
+pub fn clip(min: f64, max: f64) -> impl Fn(Tensor) -> Result<Tensor> + Send + Sync {
+    move |x| x.clamp(min, max)
+}
+
+/// A stage taking the softmax along `dim`.
+pub fn softmax(dim: usize) -> impl Fn(Tensor) -> Result<Tensor> + Send + Sync {
+    move |x| {
+        if dim >= x.rank() {
+            return Err(Error::Msg(format!(
+                "softmax along dimension {dim} of a tensor of rank {}",
+                x.rank()
+            )));
+        }
+        // subtracting the max keeps the exponentials from overflowing
+        let exp = x.broadcast_sub(&x.max_keepdim(dim)?)?.exp()?;
+        exp.broadcast_div(&exp.sum_keepdim(dim)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{read_numpy, write_numpy};
+    use crate::server::validate::{Schema, TensorSchema};
+    use crate::server::Server;
+    use candle_core::Device;
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn test_pipeline() {
+        let mut pipeline = Pipeline::default();
+        pipeline.push(Arc::new(normalize(1., 2.)));
+        pipeline.push(Arc::new(clip(-0.25, 1.)));
+        let x = Tensor::new(&[0f32, 2., 5.], &Device::Cpu).unwrap();
+        let y = pipeline.apply(x).unwrap();
+        assert_eq!(y.to_vec1::<f32>().unwrap(), [-0.25, 0.5, 1.]);
+
+        let x = Tensor::new(&[[0f32, 0.], [1., 1.]], &Device::Cpu).unwrap();
+        let y = softmax(1)(x).unwrap();
+        assert_eq!(y.to_vec2::<f32>().unwrap(), [[0.5, 0.5], [0.5, 0.5]]);
+        let x = Tensor::new(&[1u8], &Device::Cpu).unwrap();
+        assert_eq!(cast(DType::F32)(x).unwrap().dtype(), DType::F32);
+        assert!(softmax(1)(Tensor::new(&[1f32], &Device::Cpu).unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stages() {
+        let double = |_: &(), x: Tensor| x.affine(2., 0.);
+        let handle = Server::new(Arc::new(()), double)
+            .with_preprocess(cast(DType::F32))
+            .with_preprocess(normalize(1., 1.))
+            .with_postprocess(clip(0., 3.))
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let mut socket = TcpStream::connect(handle.local_addr()).await.unwrap();
+        let x = Tensor::new(&[1u8, 2, 4], &Device::Cpu).unwrap();
+        write_numpy(&x, &mut socket).await.unwrap();
+        let y = read_numpy(&mut socket).await.unwrap();
+        assert_eq!(y.to_vec1::<f32>().unwrap(), [0., 2., 3.]);
+        handle.shutdown();
+        handle.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_input_schema_checks_preprocessed_input() {
+        let schema = Schema::Tensor(TensorSchema {
+            dtype: Some(DType::F32),
+            shape: None,
+        });
+        let handle = Server::new(Arc::new(()), |_: &(), x: Tensor| Ok(x))
+            .with_preprocess(cast(DType::F32))
+            .with_input_schema(schema)
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let mut socket = TcpStream::connect(handle.local_addr()).await.unwrap();
+        let x = Tensor::new(&[1u8, 2], &Device::Cpu).unwrap();
+        write_numpy(&x, &mut socket).await.unwrap();
+        let y = read_numpy(&mut socket).await.unwrap();
+        assert_eq!(y.to_vec1::<f32>().unwrap(), [1., 2.]);
+        handle.shutdown();
+        handle.join().await.unwrap();
+    }
+}
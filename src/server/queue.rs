@@ -0,0 +1,244 @@
+//! A bounded queue between connections and the executor.
+//!
+//! At most `max_concurrency` forward passes run at once and at most
+//! `capacity` more requests wait for their turn. When the queue is full a
+//! request either waits for a place, which stops its connection from reading
+//! further requests, or is shed with a [`SERVER_BUSY`] error frame.
+//!
+//! Waiting requests take their turn in order of priority, highest first.
+//! Within a priority, clients take turns in rounds so a client sending many
+//! requests can't starve the others: a client's requests are placed in
+//! successive rounds, and a round's requests run in order of arrival.
+//! Requests from clients without an IP address aren't told apart.
+//!
+//! [`SERVER_BUSY`]: crate::io::error::codes::SERVER_BUSY
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use tokio::sync::{oneshot, Semaphore, SemaphorePermit};
+
+pub(super) struct InferenceQueue {
+    /// Places for requests waiting or running.
+    places: Semaphore,
+    turns: Mutex<Turns>,
+}
+
+/// Turns to run a forward pass, handed to waiting requests as they free up.
+struct Turns {
+    free: usize,
+    waiting: BinaryHeap<Waiter>,
+    arrivals: u64,
+    /// The round of the request that last took a turn.
+    round: u64,
+    /// The last round each waiting client has a request in.
+    client_rounds: HashMap<IpAddr, u64>,
+}
+
+struct Waiter {
+    priority: u8,
+    round: u64,
+    arrival: u64,
+    wake: oneshot::Sender<()>,
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // the heap pops the highest priority, then the earliest round and
+        // arrival
+        self.priority
+            .cmp(&other.priority)
+            .then(other.round.cmp(&self.round))
+            .then(other.arrival.cmp(&self.arrival))
+    }
+}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+/// Held while a request runs its forward pass.
+pub(super) struct Turn<'a> {
+    queue: &'a InferenceQueue,
+    _place: SemaphorePermit<'a>,
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        self.queue.release();
+    }
+}
+
+/// Gives back a turn handed to a request that stopped waiting for it.
+struct Waiting<'a> {
+    queue: &'a InferenceQueue,
+    woken: oneshot::Receiver<()>,
+    done: bool,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.woken.close();
+            if self.woken.try_recv().is_ok() {
+                self.queue.release();
+            }
+        }
+    }
+}
+
+impl InferenceQueue {
+    pub(super) fn new(max_concurrency: usize, capacity: usize) -> Self {
+        Self {
+            places: Semaphore::new(max_concurrency + capacity),
+            turns: Mutex::new(Turns {
+                free: max_concurrency,
+                waiting: BinaryHeap::new(),
+                arrivals: 0,
+                round: 0,
+                client_rounds: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Waits for the request's turn to run, or returns `None` if the queue
+    /// is full and `shed` is set.
+    pub(super) async fn turn(
+        &self,
+        priority: u8,
+        client: Option<IpAddr>,
+        shed: bool,
+    ) -> Option<Turn<'_>> {
+        let place = match shed {
+            true => self.places.try_acquire().ok()?,
+            false => self.places.acquire().await.expect("queue is never closed"),
+        };
+        let woken = {
+            let mut turns = self.turns.lock().unwrap();
+            if turns.free > 0 {
+                turns.free -= 1;
+                None
+            } else {
+                let (wake, woken) = oneshot::channel();
+                let arrival = turns.arrivals;
+                turns.arrivals += 1;
+                let mut round = turns.round;
+                if let Some(client) = client {
+                    round = match turns.client_rounds.get(&client) {
+                        Some(&last) if last >= round => last + 1,
+                        _ => round,
+                    };
+                    turns.client_rounds.insert(client, round);
+                }
+                turns.waiting.push(Waiter {
+                    priority,
+                    round,
+                    arrival,
+                    wake,
+                });
+                Some(woken)
+            }
+        };
+        if let Some(woken) = woken {
+            let mut waiting = Waiting {
+                queue: self,
+                woken,
+                done: false,
+            };
+            // waiters are only dropped once their receiver is gone
+            let _ = (&mut waiting.woken).await;
+            waiting.done = true;
+        }
+        Some(Turn {
+            queue: self,
+            _place: place,
+        })
+    }
+
+    /// Hands a freed turn to the next waiting request.
+    fn release(&self) {
+        let mut turns = self.turns.lock().unwrap();
+        while let Some(waiter) = turns.waiting.pop() {
+            turns.round = turns.round.max(waiter.round);
+            let round = turns.round;
+            // clients with no requests after this round start again in
+            // the next round
+            turns.client_rounds.retain(|_, last| *last >= round);
+            if waiter.wake.send(()).is_ok() {
+                return;
+            }
+        }
+        turns.free += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_shed_when_full() {
+        let queue = InferenceQueue::new(1, 0);
+        let turn = queue.turn(0, None, true).await;
+        assert!(turn.is_some());
+        assert!(queue.turn(0, None, true).await.is_none());
+        drop(turn);
+        assert!(queue.turn(0, None, true).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_priority_order() {
+        let queue = Arc::new(InferenceQueue::new(1, 2));
+        let turn = queue.turn(0, None, false).await;
+
+        let (order, mut finished) = tokio::sync::mpsc::unbounded_channel();
+        for priority in [1, 5] {
+            let queue = Arc::clone(&queue);
+            let order = order.clone();
+            tokio::spawn(async move {
+                let _turn = queue.turn(priority, None, false).await;
+                order.send(priority).unwrap();
+            });
+            // let the request join the queue
+            tokio::task::yield_now().await;
+        }
+        drop(turn);
+        assert_eq!(finished.recv().await, Some(5));
+        assert_eq!(finished.recv().await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_clients_take_turns() {
+        let queue = Arc::new(InferenceQueue::new(1, 4));
+        let turn = queue.turn(0, None, false).await;
+
+        let (order, mut finished) = tokio::sync::mpsc::unbounded_channel();
+        let a = IpAddr::from([10, 0, 0, 1]);
+        let b = IpAddr::from([10, 0, 0, 2]);
+        for client in [a, a, a, b] {
+            let queue = Arc::clone(&queue);
+            let order = order.clone();
+            tokio::spawn(async move {
+                let _turn = queue.turn(0, Some(client), false).await;
+                order.send(client).unwrap();
+            });
+            tokio::task::yield_now().await;
+        }
+        drop(turn);
+        for client in [a, b, a, a] {
+            assert_eq!(finished.recv().await, Some(client));
+        }
+    }
+}
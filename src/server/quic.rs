@@ -0,0 +1,146 @@
+//! QUIC transport, enabled with the `quic` feature.
+//!
+//! Each bidirectional stream carries one request, encoded as on a plain
+//! connection, and its response, so many requests can be in flight on one
+//! connection without blocking each other. The client finishes its side of
+//! the stream after the request and the server finishes its side after the
+//! response. The server's idle timeout is the connection's QUIC idle timeout,
+//! so connections without requests are closed by the transport.
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use candle_core::Error;
+use quinn::{Connecting, Endpoint, IdleTimeout, RecvStream, SendStream, TransportConfig, VarInt};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use super::tls::{load_certs, load_key};
+use super::{serve_request, Server};
+use crate::io::codec::TensorCodec;
+
+/// Bind a QUIC endpoint to `addr` with the PEM encoded certificate chain and
+/// private key, closing connections idle for `idle_timeout` if given.
+pub(super) fn bind(
+    addr: SocketAddr,
+    cert_path: &Path,
+    key_path: &Path,
+    idle_timeout: Option<Duration>,
+) -> Result<Endpoint, Error> {
+    let mut config =
+        quinn::ServerConfig::with_single_cert(load_certs(cert_path)?, load_key(key_path)?)
+            .map_err(Error::wrap)?;
+    if let Some(idle_timeout) = idle_timeout {
+        let mut transport = TransportConfig::default();
+        transport.max_idle_timeout(Some(
+            IdleTimeout::try_from(idle_timeout).map_err(Error::wrap)?,
+        ));
+        config.transport_config(Arc::new(transport));
+    }
+    Ok(Endpoint::server(config, addr)?)
+}
+
+/// Serve connections on the endpoint until `shutdown` is cancelled, then let
+/// in-flight requests finish.
+pub(super) async fn serve<M, C>(
+    server: Arc<Server<M, C>>,
+    endpoint: Endpoint,
+    shutdown: CancellationToken,
+) -> Result<(), Error>
+where
+    M: Sync + Send + 'static,
+    C: TensorCodec + Clone,
+{
+    let mut connections = JoinSet::new();
+    loop {
+        let connecting = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            // reap finished connections
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            connecting = endpoint.accept() => match connecting {
+                Some(connecting) => connecting,
+                None => break,
+            },
+        };
+        let server = Arc::clone(&server);
+        let shutdown = shutdown.clone();
+        connections.spawn(async move {
+            let peer = connecting.remote_address();
+            let Some(_slot) = server.connection_slot(Some(peer.ip())) else {
+                debug!(%peer, "refusing connection over the per-peer limit");
+                return;
+            };
+            let _open = server.metrics.open_connection();
+            if let Err(e) = handle_connection(connecting, &server, &shutdown).await {
+                warn!(%peer, "connection failed: {e}");
+                server.report_error(&e);
+            }
+        });
+    }
+    while connections.join_next().await.is_some() {}
+    endpoint.wait_idle().await;
+    Ok(())
+}
+
+/// Serves the streams opened on a connection until the client closes it or
+/// the server shuts down.
+#[cfg_attr(
+    feature = "trace",
+    tracing::instrument(name = "connection", skip_all, fields(peer = %connecting.remote_address()))
+)]
+async fn handle_connection<M, C>(
+    connecting: Connecting,
+    server: &Arc<Server<M, C>>,
+    shutdown: &CancellationToken,
+) -> Result<(), Error>
+where
+    M: Sync + Send + 'static,
+    C: TensorCodec + Clone,
+{
+    let connection = connecting.await.map_err(Error::wrap)?;
+    let peer = connection.remote_address().ip();
+    let mut streams = JoinSet::new();
+    loop {
+        let (send, recv) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            Some(_) = streams.join_next(), if !streams.is_empty() => continue,
+            stream = connection.accept_bi() => match stream {
+                Ok(stream) => stream,
+                // the client closed the connection
+                Err(_) => break,
+            },
+        };
+        let server = Arc::clone(server);
+        let stream = async move {
+            if let Err(e) = handle_stream(send, recv, peer, &server).await {
+                warn!("stream failed: {e}");
+                server.report_error(&e);
+            }
+        };
+        #[cfg(feature = "trace")]
+        let stream = tracing::Instrument::in_current_span(stream);
+        streams.spawn(stream);
+    }
+    while streams.join_next().await.is_some() {}
+    connection.close(VarInt::from_u32(0), b"");
+    Ok(())
+}
+
+/// Serves the request sent on a stream.
+async fn handle_stream<M, C>(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    peer: IpAddr,
+    server: &Server<M, C>,
+) -> Result<(), Error>
+where
+    M: Sync + Send + 'static,
+    C: TensorCodec + Clone,
+{
+    // codecs may hold per-stream state such as negotiated compression
+    let codec = server.codec.clone();
+    serve_request(server, &codec, Some(peer), &mut recv, &mut send).await?;
+    send.finish().await.map_err(Error::wrap)
+}
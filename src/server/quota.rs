@@ -0,0 +1,220 @@
+//! Quotas on what each API key may use, for servers shared between tenants.
+//!
+//! A request's API key is its token, sent in the envelope, as an HTTP bearer
+//! token or as gRPC `authorization` metadata. Unless the server also checks
+//! tokens with [`Server::with_token_auth`](super::Server::with_token_auth)
+//! any client can send any key. Requests with a key that has no quota, or
+//! without a key, aren't limited or counted.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use candle_core::{Error, Result};
+
+/// How long the requests per minute are counted over.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Limits on one API key. The default doesn't limit the key but still counts
+/// its usage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// The most requests started in a minute.
+    pub requests_per_minute: Option<u32>,
+    /// The most requests running at once.
+    pub max_concurrent: Option<usize>,
+    /// The most tensor elements read over the server's lifetime.
+    pub max_elements: Option<u64>,
+}
+
+/// What an API key has used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaUsage {
+    /// Requests admitted.
+    pub requests: u64,
+    /// Requests rejected for exceeding the quota.
+    pub rejected: u64,
+    /// Requests running now.
+    pub in_flight: usize,
+    /// Tensor elements read in admitted requests.
+    pub elements: u64,
+}
+
+/// Quotas by API key, shared between the server and the code querying usage.
+///
+/// ```
+/// # use socket_nn::server::quota::{Quota, Quotas};
+/// let quotas = Quotas::new().with_quota(
+///     "team-a",
+///     Quota {
+///         requests_per_minute: Some(600),
+///         ..Default::default()
+///     },
+/// );
+/// // pass a clone to `Server::with_quotas` and keep this one to query usage
+/// assert_eq!(quotas.usage("team-a").unwrap().requests, 0);
+/// ```
+#[derive(Clone, Default)]
+pub struct Quotas {
+    tenants: Arc<Mutex<HashMap<String, Tenant>>>,
+}
+
+struct Tenant {
+    quota: Quota,
+    usage: QuotaUsage,
+    window_start: Instant,
+    window_requests: u32,
+}
+
+/// Held while a request counted against a quota runs.
+pub(super) struct QuotaSlot<'a> {
+    quotas: &'a Quotas,
+    key: String,
+}
+
+impl Quotas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit requests sent with `key`, replacing any quota it had. The key
+    /// keeps its usage and requests counted in the current minute.
+    pub fn with_quota(self, key: impl Into<String>, quota: Quota) -> Self {
+        let mut tenants = self.tenants.lock().unwrap();
+        tenants
+            .entry(key.into())
+            .and_modify(|tenant| tenant.quota = quota)
+            .or_insert_with(|| Tenant {
+                quota,
+                usage: QuotaUsage::default(),
+                window_start: Instant::now(),
+                window_requests: 0,
+            });
+        drop(tenants);
+        self
+    }
+
+    /// What `key` has used, or `None` if it has no quota.
+    pub fn usage(&self, key: &str) -> Option<QuotaUsage> {
+        let tenants = self.tenants.lock().unwrap();
+        tenants.get(key).map(|tenant| tenant.usage)
+    }
+
+    /// What every key with a quota has used.
+    pub fn all_usage(&self) -> HashMap<String, QuotaUsage> {
+        let tenants = self.tenants.lock().unwrap();
+        tenants
+            .iter()
+            .map(|(key, tenant)| (key.clone(), tenant.usage))
+            .collect()
+    }
+
+    /// Counts a request of `elements` tensor elements against the key's
+    /// quota, failing if it would exceed it. Returns `None` if the key has no
+    /// quota.
+    pub(super) fn admit(&self, key: &str, elements: u64) -> Result<Option<QuotaSlot<'_>>> {
+        let mut tenants = self.tenants.lock().unwrap();
+        let Some(tenant) = tenants.get_mut(key) else {
+            return Ok(None);
+        };
+        let now = Instant::now();
+        if now.duration_since(tenant.window_start) >= WINDOW {
+            tenant.window_start = now;
+            tenant.window_requests = 0;
+        }
+        let quota = tenant.quota;
+        let usage = &mut tenant.usage;
+        let exceeded = if quota
+            .requests_per_minute
+            .is_some_and(|max| tenant.window_requests >= max)
+        {
+            Some("requests per minute")
+        } else if quota
+            .max_concurrent
+            .is_some_and(|max| usage.in_flight >= max)
+        {
+            Some("concurrent requests")
+        } else if quota
+            .max_elements
+            .is_some_and(|max| usage.elements.saturating_add(elements) > max)
+        {
+            Some("total elements")
+        } else {
+            None
+        };
+        if let Some(exceeded) = exceeded {
+            usage.rejected += 1;
+            return Err(Error::Msg(format!("quota of {exceeded} exceeded")));
+        }
+        tenant.window_requests += 1;
+        usage.requests += 1;
+        usage.in_flight += 1;
+        usage.elements += elements;
+        Ok(Some(QuotaSlot {
+            quotas: self,
+            key: key.to_string(),
+        }))
+    }
+}
+
+impl Drop for QuotaSlot<'_> {
+    fn drop(&mut self) {
+        let mut tenants = self.quotas.tenants.lock().unwrap();
+        if let Some(tenant) = tenants.get_mut(&self.key) {
+            tenant.usage.in_flight = tenant.usage.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quotas() {
+        let quotas = Quotas::new().with_quota(
+            "a",
+            Quota {
+                max_concurrent: Some(1),
+                max_elements: Some(10),
+                ..Default::default()
+            },
+        );
+        let slot = quotas.admit("a", 4).unwrap();
+        assert!(slot.is_some());
+        assert!(quotas.admit("a", 4).is_err());
+        drop(slot);
+        assert!(quotas.admit("a", 6).unwrap().is_some());
+        assert!(quotas.admit("a", 1).is_err());
+        // keys without a quota aren't limited
+        assert!(quotas.admit("b", 100).unwrap().is_none());
+        assert_eq!(
+            quotas.usage("a"),
+            Some(QuotaUsage {
+                requests: 2,
+                rejected: 2,
+                in_flight: 0,
+                elements: 10,
+            })
+        );
+    }
+
+    #[test]
+    fn test_replace_quota() {
+        let quotas = Quotas::new().with_quota("a", Quota::default());
+        let slot = quotas.admit("a", 4).unwrap();
+        // replacing the quota through a clone, as on a running server, keeps
+        // the key's usage
+        quotas.clone().with_quota(
+            "a",
+            Quota {
+                max_concurrent: Some(1),
+                ..Default::default()
+            },
+        );
+        assert_eq!(quotas.usage("a").unwrap().in_flight, 1);
+        assert!(quotas.admit("a", 1).is_err());
+        drop(slot);
+        assert_eq!(quotas.usage("a").unwrap().in_flight, 0);
+        assert!(quotas.admit("a", 1).unwrap().is_some());
+    }
+}
@@ -0,0 +1,322 @@
+//! Reloading the model from weights polled from a URL, enabled with the
+//! `remote` feature.
+//!
+//! [`ModelReloader::poll_url`] fetches an `http://` or `https://` URL, such
+//! as a presigned S3 URL, on an interval and, when the weights there
+//! changed, loads them with the given function and serves the model loaded.
+//! Changes are detected with the `ETag` or `Last-Modified` header if the
+//! server sends one, and otherwise by comparing the bytes fetched. Loading
+//! also validates the weights: if it fails they're skipped with a warning
+//! and the model served is unchanged, as it is if the fetch fails. With the
+//! `verify` feature, [`ModelReloader::poll_url_verified`] also checks the
+//! weights against the checksum and signature sidecars served next to them
+//! before loading them, see [`verify`](super::verify).
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use std::time::Duration;
+//! # use candle_core::{Result, Tensor};
+//! # use socket_nn::server::Server;
+//! # fn forward(model: &Vec<u8>, x: Tensor) -> Result<Tensor> { Ok(x) }
+//! # async fn serve() -> Result<()> {
+//! let server = Server::new(Arc::new(vec![]), forward);
+//! let _poller = server.model_reloader().poll_url(
+//!     "https://models.example.com/mlp.safetensors",
+//!     Duration::from_secs(60),
+//!     |weights: &[u8]| Ok(weights.to_vec()),
+//! )?;
+//! server.run("127.0.0.1:8080").await
+//! # }
+//! ```
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use candle_core::{Error, Result};
+use hyper::body::Bytes;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use hyper::{Body, Client, Request, StatusCode, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, timeout, MissedTickBehavior};
+use tracing::{debug, info, warn};
+
+#[cfg(feature = "verify")]
+use super::verify::{WeightsVerifier, SHA256_EXTENSION, SIGNATURE_EXTENSION};
+use super::ModelReloader;
+
+/// Longest a fetch of the weights may take.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Polls a URL for weights until dropped, see [`ModelReloader::poll_url`].
+pub struct UrlPoller {
+    task: JoinHandle<()>,
+}
+
+impl Drop for UrlPoller {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl<M> ModelReloader<M>
+where
+    M: Send + Sync + 'static,
+{
+    /// Fetches the weights at `url` every `period`, starting now, and loads
+    /// them with `load` and serves them when they've changed, see
+    /// [`remote`](self). Must be called within the Tokio runtime.
+    pub fn poll_url<F>(&self, url: &str, period: Duration, load: F) -> Result<UrlPoller>
+    where
+        F: Fn(&[u8]) -> Result<M> + Send + Sync + 'static,
+    {
+        let (client, url) = client(url)?;
+        let task = tokio::spawn(poll(
+            self.clone(),
+            client,
+            url,
+            period,
+            Arc::new(load),
+            #[cfg(feature = "verify")]
+            None,
+        ));
+        Ok(UrlPoller { task })
+    }
+
+    /// Like [`poll_url`](Self::poll_url), but only loads weights `verifier`
+    /// accepts. Their digest is fetched from the URL with `.sha256` appended
+    /// to its path and, if `verifier` requires one, their signature from the
+    /// URL with `.sig` appended. Weights failing verification are fetched
+    /// again on the next poll, in case their sidecars weren't uploaded yet.
+    #[cfg(feature = "verify")]
+    pub fn poll_url_verified<F>(
+        &self,
+        url: &str,
+        period: Duration,
+        verifier: WeightsVerifier,
+        load: F,
+    ) -> Result<UrlPoller>
+    where
+        F: Fn(&[u8]) -> Result<M> + Send + Sync + 'static,
+    {
+        let (client, url) = client(url)?;
+        let task = tokio::spawn(poll(
+            self.clone(),
+            client,
+            url,
+            period,
+            Arc::new(load),
+            Some(verifier),
+        ));
+        Ok(UrlPoller { task })
+    }
+}
+
+/// A client for fetching `url`, and `url` parsed.
+fn client(url: &str) -> Result<(Client<HttpsConnector<HttpConnector>>, Uri)> {
+    let url = url.parse::<Uri>().map_err(Error::wrap)?;
+    if !matches!(url.scheme_str(), Some("http" | "https")) {
+        return Err(Error::Msg(format!(
+            "expected an http or https URL, got {url}"
+        )));
+    }
+    let connector = HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Ok((Client::builder().build(connector), url))
+}
+
+/// What identifies the weights last loaded.
+#[derive(Default)]
+struct Version {
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    hash: Option<u64>,
+}
+
+async fn poll<M, F>(
+    reloader: ModelReloader<M>,
+    client: Client<HttpsConnector<HttpConnector>>,
+    url: Uri,
+    period: Duration,
+    load: Arc<F>,
+    #[cfg(feature = "verify")] verifier: Option<WeightsVerifier>,
+) where
+    M: Send + Sync + 'static,
+    F: Fn(&[u8]) -> Result<M> + Send + Sync + 'static,
+{
+    let mut ticks = interval(period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut version = Version::default();
+    loop {
+        ticks.tick().await;
+        let fetched = match timeout(FETCH_TIMEOUT, fetch(&client, &url, &version)).await {
+            Ok(Ok(fetched)) => fetched,
+            Ok(Err(e)) => {
+                warn!(%url, "fetching weights failed: {e}");
+                continue;
+            }
+            Err(_) => {
+                warn!(%url, "fetching weights timed out after {FETCH_TIMEOUT:?}");
+                continue;
+            }
+        };
+        let Some((weights, mut fetched)) = fetched else {
+            debug!(%url, "weights unchanged");
+            continue;
+        };
+        let mut hasher = DefaultHasher::new();
+        weights.hash(&mut hasher);
+        fetched.hash = Some(hasher.finish());
+        if fetched.hash == version.hash {
+            debug!(%url, "weights unchanged");
+            version = fetched;
+            continue;
+        }
+        #[cfg(feature = "verify")]
+        if let Some(verifier) = &verifier {
+            if let Err(e) = verify(&client, &url, verifier, &weights).await {
+                warn!(%url, "skipped weights: {e}");
+                continue;
+            }
+        }
+        let load = Arc::clone(&load);
+        match reloader.reload_with(move || load(&weights)).await {
+            Ok(_) => info!(%url, "loaded weights"),
+            Err(e) => warn!(%url, "skipped weights: {e}"),
+        }
+        // weights that failed to load aren't retried until they change
+        version = fetched;
+    }
+}
+
+/// Fetches the weights at `url` unless they're still `version`, returning
+/// them and their new version.
+async fn fetch(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    url: &Uri,
+    version: &Version,
+) -> Result<Option<(Bytes, Version)>> {
+    let mut request = Request::get(url.clone());
+    if let Some(etag) = &version.etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    } else if let Some(last_modified) = &version.last_modified {
+        request = request.header(IF_MODIFIED_SINCE, last_modified);
+    }
+    let request = request.body(Body::empty()).map_err(Error::wrap)?;
+    let response = client.request(request).await.map_err(Error::wrap)?;
+    match response.status() {
+        StatusCode::NOT_MODIFIED => return Ok(None),
+        StatusCode::OK => {}
+        status => return Err(Error::Msg(format!("unexpected status {status}"))),
+    }
+    let fetched = Version {
+        etag: response.headers().get(ETAG).cloned(),
+        last_modified: response.headers().get(LAST_MODIFIED).cloned(),
+        hash: None,
+    };
+    let weights = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(Error::wrap)?;
+    Ok(Some((weights, fetched)))
+}
+
+/// Verifies `weights` fetched from `url` against the sidecars next to them.
+#[cfg(feature = "verify")]
+async fn verify(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    url: &Uri,
+    verifier: &WeightsVerifier,
+    weights: &[u8],
+) -> Result<()> {
+    let sha256 = fetch_sidecar(client, url, SHA256_EXTENSION).await?;
+    let sha256 = std::str::from_utf8(&sha256).map_err(Error::wrap)?;
+    let signature = match verifier.requires_signature() {
+        true => Some(fetch_sidecar(client, url, SIGNATURE_EXTENSION).await?),
+        false => None,
+    };
+    verifier.verify(weights, sha256, signature.as_deref())
+}
+
+/// Fetches the sidecar of `url` with the extension `extension`.
+#[cfg(feature = "verify")]
+async fn fetch_sidecar(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    url: &Uri,
+    extension: &str,
+) -> Result<Bytes> {
+    let mut path_and_query = format!("{}.{extension}", url.path());
+    if let Some(query) = url.query() {
+        path_and_query = format!("{path_and_query}?{query}");
+    }
+    let mut parts = url.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().map_err(Error::wrap)?);
+    let sidecar = Uri::from_parts(parts).map_err(Error::wrap)?;
+    let response = client.get(sidecar.clone()).await.map_err(Error::wrap)?;
+    if response.status() != StatusCode::OK {
+        return Err(Error::Msg(format!(
+            "unexpected status {} fetching {sidecar}",
+            response.status()
+        )));
+    }
+    hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(Error::wrap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use candle_core::Tensor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_poll_url() {
+        // serves "1" then "2", answering requests for an unchanged ETag with 304
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/weights", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let served = Arc::clone(&requests);
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let len = socket.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..len]).to_lowercase();
+                let weights = if served.fetch_add(1, Ordering::SeqCst) < 2 {
+                    "1"
+                } else {
+                    "2"
+                };
+                let response = if request.contains(&format!("if-none-match: \"{weights}\"")) {
+                    "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_string()
+                } else {
+                    format!("HTTP/1.1 200 OK\r\nETag: \"{weights}\"\r\nContent-Length: 1\r\nConnection: close\r\n\r\n{weights}")
+                };
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let server = Server::new(Arc::new(0), |_: &u32, x: Tensor| Ok(x));
+        let reloader = server.model_reloader();
+        let _poller = reloader
+            .poll_url(&url, Duration::from_millis(50), |weights| {
+                let weights = std::str::from_utf8(weights).map_err(Error::wrap)?;
+                weights.parse().map_err(Error::wrap)
+            })
+            .unwrap();
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        while *reloader.current() != 2 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(*reloader.current(), 2);
+        assert!(requests.load(Ordering::SeqCst) >= 3);
+    }
+}
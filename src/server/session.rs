@@ -0,0 +1,258 @@
+//! State kept on the server between requests of a session, such as a
+//! transformer's KV cache for incremental decoding, see
+//! [`Server::stateful`](super::Server::stateful).
+//!
+//! A request names its session with the session ID in its envelope, see
+//! [`crate::io::envelope`]. The first request of a session runs on the
+//! state's default and each later one on the state the one before left
+//! behind. Requests of one session run one at a time, in the order they
+//! start. Requests without a session run on a fresh state that isn't kept.
+//!
+//! Sessions unused for longer than their TTL are dropped, and once there
+//! are more sessions than the session limit, or they together hold more
+//! than the memory bound, as measured by [`SessionState::memory_bytes`],
+//! those used least recently are dropped until they fit. A request naming a
+//! dropped session starts it over.
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex as AsyncMutex;
+
+use tracing::{debug, warn};
+
+/// How long a session is kept after its last request by default.
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(10 * 60);
+/// How many bytes of session state are kept by default.
+pub const DEFAULT_MAX_SESSION_BYTES: usize = 1 << 30;
+/// How many sessions are kept by default.
+pub const DEFAULT_MAX_SESSIONS: usize = 10_000;
+
+/// State kept for a session between its requests.
+pub trait SessionState: Default + Send + 'static {
+    /// Bytes the state holds, counted against the server's memory bound.
+    fn memory_bytes(&self) -> usize;
+}
+
+/// A session's state, type erased so servers aren't generic over it. `None`
+/// until the session's first request.
+pub(super) type State = Option<Box<dyn Any + Send>>;
+
+/// The sessions of a server.
+pub(super) struct Sessions {
+    pub(super) ttl: Duration,
+    pub(super) max_bytes: usize,
+    pub(super) max_sessions: usize,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+struct Session {
+    /// Locked by a request of the session until its forward pass is done, so
+    /// the requests after it wait without holding an executor thread.
+    state: Arc<AsyncMutex<State>>,
+    last_used: Instant,
+    memory_bytes: usize,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Sessions {
+            ttl: DEFAULT_SESSION_TTL,
+            max_bytes: DEFAULT_MAX_SESSION_BYTES,
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            sessions: Mutex::default(),
+        }
+    }
+}
+
+impl Sessions {
+    /// The state of the session `id`, started if it isn't kept, dropping
+    /// sessions that expired and those used least recently to start it
+    /// within the session limit.
+    pub(super) fn state(&self, id: &str) -> Arc<AsyncMutex<State>> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|id, session| {
+            let expired = now.duration_since(session.last_used) > self.ttl;
+            if expired {
+                debug!(session = id, "session expired");
+            }
+            !expired
+        });
+        if !sessions.contains_key(id) {
+            while sessions.len() >= self.max_sessions {
+                let Some(oldest) = least_recently_used(&sessions) else {
+                    break;
+                };
+                debug!(
+                    session = oldest,
+                    "dropped a session to stay within the session limit"
+                );
+                sessions.remove(&oldest);
+            }
+        }
+        let session = sessions.entry(id.to_string()).or_insert_with(|| Session {
+            state: Arc::default(),
+            last_used: now,
+            memory_bytes: 0,
+        });
+        session.last_used = now;
+        Arc::clone(&session.state)
+    }
+
+    /// Records that the session `id` now holds `memory_bytes`, dropping the
+    /// sessions used least recently while they hold more than the bound.
+    pub(super) fn update(&self, id: &str, memory_bytes: usize) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(id) {
+            session.memory_bytes = memory_bytes;
+        }
+        let mut total: usize = sessions.values().map(|session| session.memory_bytes).sum();
+        while total > self.max_bytes {
+            let Some(oldest) = least_recently_used(&sessions) else {
+                break;
+            };
+            let bytes = sessions[&oldest].memory_bytes;
+            if oldest == id {
+                warn!(
+                    session = id,
+                    memory_bytes, "dropped a session over the memory bound"
+                );
+            } else {
+                debug!(
+                    session = oldest,
+                    "dropped a session to stay within the memory bound"
+                );
+            }
+            sessions.remove(&oldest);
+            total -= bytes;
+        }
+    }
+
+    /// How many sessions are kept.
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+}
+
+/// The ID of the session used least recently.
+fn least_recently_used(sessions: &HashMap<String, Session>) -> Option<String> {
+    sessions
+        .iter()
+        .min_by_key(|(_, session)| session.last_used)
+        .map(|(id, _)| id.clone())
+}
+
+/// Runs `run` on the state `S` held in `state`, starting it if needed,
+/// returning its result and the bytes the state holds afterwards.
+pub(super) fn with_state<S, T>(state: &mut State, run: impl FnOnce(&mut S) -> T) -> (T, usize)
+where
+    S: SessionState,
+{
+    // taken out while running, as a forward pass that panics may leave it
+    // half updated and the session is then started over
+    let mut taken = state.take().unwrap_or_else(|| Box::new(S::default()));
+    let session = taken
+        .downcast_mut::<S>()
+        .expect("a server's sessions all hold the same state type");
+    let result = run(session);
+    let memory_bytes = session.memory_bytes();
+    *state = Some(taken);
+    (result, memory_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::envelope::{read_envelope, write_envelope, Envelope};
+    use crate::io::{read_numpy, write_numpy};
+    use crate::server::Server;
+    use candle_core::{Device, Tensor};
+    use tokio::net::TcpStream;
+
+    #[derive(Default)]
+    struct Cache(Vec<u32>);
+
+    impl SessionState for Cache {
+        fn memory_bytes(&self) -> usize {
+            self.0.len() * 4
+        }
+    }
+
+    #[test]
+    fn test_sessions() {
+        let sessions = Sessions {
+            max_bytes: 8,
+            ..Default::default()
+        };
+        let push = |id, token| {
+            let state = sessions.state(id);
+            let mut state = state.try_lock().unwrap();
+            let (len, bytes) = with_state(&mut *state, |cache: &mut Cache| {
+                cache.0.push(token);
+                cache.0.len()
+            });
+            sessions.update(id, bytes);
+            len
+        };
+        assert_eq!(push("a", 1), 1);
+        assert_eq!(push("a", 2), 2);
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(push("b", 1), 1);
+        // "a" was used least recently and dropped to fit "b"
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(push("a", 3), 1);
+
+        let sessions = Sessions {
+            ttl: Duration::ZERO,
+            ..Default::default()
+        };
+        sessions.state("a");
+        std::thread::sleep(Duration::from_millis(1));
+        sessions.state("b");
+        assert_eq!(sessions.len(), 1);
+
+        // sessions holding nothing still count towards the session limit
+        let sessions = Sessions {
+            max_sessions: 2,
+            ..Default::default()
+        };
+        for id in ["a", "b", "a", "c"] {
+            sessions.state(id);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(sessions.len(), 2);
+        let kept = sessions.sessions.lock().unwrap();
+        assert!(kept.contains_key("a") && kept.contains_key("c"));
+    }
+
+    #[tokio::test]
+    async fn test_stateful() {
+        // answers with how many tokens the session has seen
+        let count = |_: &(), x: Tensor, cache: &mut Cache| {
+            cache.0.extend(x.to_vec1::<u32>()?);
+            Tensor::new(&[cache.0.len() as u32], &Device::Cpu)
+        };
+        let handle = Server::stateful(Arc::new(()), count)
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let mut socket = TcpStream::connect(handle.local_addr()).await.unwrap();
+        let x = Tensor::new(&[1u32, 2], &Device::Cpu).unwrap();
+        for (session, seen) in [(Some("a"), 2u32), (Some("a"), 4), (Some("b"), 2), (None, 2)] {
+            let envelope = Envelope {
+                session: session.map(str::to_string),
+                ..Envelope::new(1)
+            };
+            write_envelope(&envelope, &mut socket).await.unwrap();
+            write_numpy(&x, &mut socket).await.unwrap();
+            assert_eq!(read_envelope(&mut socket).await.unwrap(), envelope);
+            let y = read_numpy(&mut socket).await.unwrap();
+            assert_eq!(y.to_vec1::<u32>().unwrap(), [seen]);
+        }
+        handle.shutdown();
+        handle.join().await.unwrap();
+    }
+}
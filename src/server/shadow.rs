@@ -0,0 +1,180 @@
+//! Mirroring live requests to a candidate model, see
+//! [`Server::with_shadow`](super::Server::with_shadow).
+//!
+//! Once the served model has answered a request, and while the answer is
+//! written, its input runs again on the candidate in the background. The
+//! candidate's output is compared with the answer and logged at the info
+//! level with the target `socket_nn::shadow`:
+//!
+//! ```text
+//! model=mlp version=2 request_id=7 outcome=ok max_abs_diff=0.0003
+//! ```
+//!
+//! The outcome is `ok` if the outputs have the same shape, `shape_mismatch`
+//! if not and `failed` if the candidate failed, and is counted in the
+//! [`metrics`](super::metrics). The candidate's output is never returned.
+//!
+//! At most [`MAX_SHADOWS_IN_FLIGHT`] mirrored requests run at once so the
+//! candidate can't pile up work under load, requests arriving beyond that
+//! aren't mirrored and are counted as `skipped`.
+use std::sync::Arc;
+
+use candle_core::{DType, Result, Tensor};
+use tokio::sync::Semaphore;
+use tracing::{debug, info, warn};
+
+use super::executor::Executor;
+use super::metrics::Metrics;
+use super::RegisteredForwardFn;
+
+/// Most mirrored requests running on the candidate at once.
+pub(super) const MAX_SHADOWS_IN_FLIGHT: usize = 16;
+
+/// How a candidate's output compared with the answer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Outcome {
+    /// The outputs have the same shape and differ by at most this much.
+    Ok(f64),
+    ShapeMismatch,
+    Failed,
+    /// The request wasn't mirrored as too many mirrors were in flight.
+    Skipped,
+}
+
+impl Outcome {
+    pub(super) const NAMES: [&'static str; 4] = ["ok", "shape_mismatch", "failed", "skipped"];
+
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            Outcome::Ok(_) => "ok",
+            Outcome::ShapeMismatch => "shape_mismatch",
+            Outcome::Failed => "failed",
+            Outcome::Skipped => "skipped",
+        }
+    }
+}
+
+/// The candidate version requests for a model are mirrored to.
+pub(super) struct ShadowTarget {
+    pub(super) model: String,
+    pub(super) version: String,
+    pub(super) in_flight: Arc<Semaphore>,
+}
+
+impl ShadowTarget {
+    pub(super) fn new(model: &str, version: &str) -> ShadowTarget {
+        ShadowTarget {
+            model: model.to_string(),
+            version: version.to_string(),
+            in_flight: Arc::new(Semaphore::new(MAX_SHADOWS_IN_FLIGHT)),
+        }
+    }
+}
+
+/// A request mirrored to the candidate.
+pub(super) struct Shadow {
+    pub(super) model: String,
+    pub(super) version: String,
+    pub(super) request_id: Option<String>,
+    pub(super) net_forward: Arc<RegisteredForwardFn>,
+    pub(super) input: Tensor,
+    pub(super) in_flight: Arc<Semaphore>,
+}
+
+impl Shadow {
+    /// Runs the candidate on the request's input in the background and
+    /// compares its output with `answer`, unless too many mirrors are in
+    /// flight.
+    pub(super) fn spawn(self, answer: Tensor, executor: Executor, metrics: Arc<Metrics>) {
+        let Ok(permit) = Arc::clone(&self.in_flight).try_acquire_owned() else {
+            debug!(
+                target: "socket_nn::shadow",
+                model = self.model.as_str(),
+                "too many mirrors in flight, skipped"
+            );
+            metrics.record_shadow(Outcome::Skipped);
+            return;
+        };
+        tokio::spawn(async move {
+            let _permit = permit;
+            let Shadow {
+                model,
+                version,
+                request_id,
+                net_forward,
+                input,
+                ..
+            } = self;
+            let outcome = match executor.run(move || net_forward(input)).await {
+                Ok(output) => compare(&answer, &output).unwrap_or(Outcome::Failed),
+                Err(e) => {
+                    warn!(target: "socket_nn::shadow", model, version, "candidate failed: {e}");
+                    Outcome::Failed
+                }
+            };
+            metrics.record_shadow(outcome);
+            let max_abs_diff = match outcome {
+                Outcome::Ok(diff) => Some(diff),
+                _ => None,
+            };
+            info!(
+                target: "socket_nn::shadow",
+                model,
+                version,
+                request_id,
+                outcome = outcome.name(),
+                max_abs_diff,
+            );
+        });
+    }
+}
+
+/// Compares the candidate's `output` with the `answer`.
+fn compare(answer: &Tensor, output: &Tensor) -> Result<Outcome> {
+    if answer.dims() != output.dims() {
+        return Ok(Outcome::ShapeMismatch);
+    }
+    if answer.elem_count() == 0 {
+        return Ok(Outcome::Ok(0.0));
+    }
+    let answer = answer.to_dtype(DType::F64)?;
+    let output = output.to_dtype(DType::F64)?.to_device(answer.device())?;
+    let diff = answer.sub(&output)?.abs()?.flatten_all()?.max(0)?;
+    Ok(Outcome::Ok(diff.to_scalar::<f64>()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[test]
+    fn test_compare() -> Result<()> {
+        let answer = Tensor::new(&[[1f32, 2.0], [3.0, 4.0]], &Device::Cpu)?;
+        let output = Tensor::new(&[[1f32, 2.5], [3.0, 3.0]], &Device::Cpu)?;
+        assert_eq!(compare(&answer, &output)?, Outcome::Ok(1.0));
+        assert_eq!(
+            compare(&answer, &output.flatten_all()?)?,
+            Outcome::ShapeMismatch
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_skip_when_too_many_in_flight() {
+        let metrics = Arc::new(Metrics::default());
+        let shadow = Shadow {
+            model: "mlp".to_string(),
+            version: "2".to_string(),
+            request_id: None,
+            net_forward: Arc::new(|_| panic!("the candidate must not run")),
+            input: Tensor::new(&[1f32], &Device::Cpu).unwrap(),
+            in_flight: Arc::new(Semaphore::new(0)),
+        };
+        let answer = Tensor::new(&[1f32], &Device::Cpu).unwrap();
+        shadow.spawn(answer, Executor::default(), Arc::clone(&metrics));
+        assert!(metrics
+            .render("")
+            .contains("socket_nn_shadow_requests_total{outcome=\"skipped\"} 1"));
+    }
+}
@@ -0,0 +1,55 @@
+//! Splitting the traffic for a model between two of its versions, see
+//! [`Server::with_traffic_split`](super::Server::with_traffic_split).
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Sends a share of the requests for a model that don't pin a version to a
+/// candidate version and the rest to a control version.
+pub(super) struct TrafficSplit {
+    control: String,
+    candidate: String,
+    percent: u64,
+    requests: AtomicU64,
+}
+
+impl TrafficSplit {
+    /// Sends `percent` of requests, at most 100, to `candidate`.
+    pub(super) fn new(control: &str, candidate: &str, percent: u8) -> Self {
+        Self {
+            control: control.to_string(),
+            candidate: candidate.to_string(),
+            percent: u64::from(percent.min(100)),
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    /// The version the next request runs. The candidate's requests are
+    /// spread evenly rather than drawn at random, so every hundred requests
+    /// hold exactly its share.
+    pub(super) fn pick(&self) -> &str {
+        let n = self.requests.fetch_add(1, Ordering::Relaxed) % 100;
+        // whether the candidate's share of the first n + 1 requests grew
+        if (n + 1) * self.percent / 100 > n * self.percent / 100 {
+            &self.candidate
+        } else {
+            &self.control
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick() {
+        let split = TrafficSplit::new("1", "2", 25);
+        let picks = (0..8).map(|_| split.pick()).collect::<Vec<_>>();
+        assert_eq!(picks, ["1", "1", "1", "2", "1", "1", "1", "2"]);
+
+        let split = TrafficSplit::new("1", "2", 10);
+        let candidate = (0..1000).filter(|_| split.pick() == "2").count();
+        assert_eq!(candidate, 100);
+        let split = TrafficSplit::new("1", "2", 0);
+        assert!((0..100).all(|_| split.pick() == "1"));
+    }
+}
@@ -0,0 +1,175 @@
+//! Export of the server's metrics to statsd over UDP, for deployments without
+//! Prometheus.
+//!
+//! Counters and timings are sent as they happen, gauges every
+//! [`GAUGE_INTERVAL`]. Names are those of the Prometheus metrics without the
+//! `socket_nn_` prefix and `_total` suffix, under the configured prefix.
+//! Plain statsd has no tags, so errors and phases are named like
+//! `errors.bad_request` and `phase_duration.decode`; DogStatsD sends them,
+//! and the model's name and version, as tags. Packets that can't be sent
+//! are dropped.
+use std::fmt::{Display, Write as _};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+use candle_core::{Error, Result};
+use tokio::net::lookup_host;
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+
+use super::metrics::Metrics;
+
+/// How often gauges are sent.
+const GAUGE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Where and how to send metrics.
+pub(super) struct StatsdConfig {
+    pub(super) addr: String,
+    pub(super) prefix: String,
+    pub(super) dogstatsd: bool,
+}
+
+/// A connected statsd client.
+pub(super) struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    /// The tags sent with every metric, `None` for plain statsd.
+    tags: Option<String>,
+}
+
+impl Statsd {
+    /// Resolves the statsd address and connects to it, tagging metrics with
+    /// the model's name and version if sending to DogStatsD.
+    pub(super) async fn connect(
+        config: &StatsdConfig,
+        model: Option<&str>,
+        version: Option<&str>,
+    ) -> Result<Statsd> {
+        let addr = lookup_host(&config.addr)
+            .await?
+            .next()
+            .ok_or_else(|| Error::Msg(format!("no address for statsd at {}", config.addr)))?;
+        let local = match addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        // metrics are sent from request handlers, which mustn't block
+        socket.set_nonblocking(true)?;
+
+        let tags = config.dogstatsd.then(|| {
+            let tags = [("model", model), ("version", version)];
+            let tags = tags
+                .iter()
+                .filter_map(|(key, value)| value.map(|value| format!("{key}:{value}")));
+            tags.collect::<Vec<_>>().join(",")
+        });
+        let prefix = match config.prefix.as_str() {
+            "" => String::new(),
+            prefix => format!("{}.", prefix.trim_end_matches('.')),
+        };
+        Ok(Statsd {
+            socket,
+            prefix,
+            tags,
+        })
+    }
+
+    pub(super) fn count(&self, name: &str, value: u64, tag: Option<(&str, &str)>) {
+        self.send(name, value, "c", tag);
+    }
+
+    pub(super) fn timing(&self, name: &str, duration: Duration, tag: Option<(&str, &str)>) {
+        let millis = duration.as_micros() as f64 / 1e3;
+        self.send(name, millis, "ms", tag);
+    }
+
+    fn gauge(&self, name: &str, value: usize) {
+        self.send(name, value, "g", None);
+    }
+
+    /// Sends one metric, with `tag` as a tag for DogStatsD or appended to
+    /// the name for plain statsd.
+    fn send(&self, name: &str, value: impl Display, kind: &str, tag: Option<(&str, &str)>) {
+        let mut packet = format!("{}{name}", self.prefix);
+        match (&self.tags, tag) {
+            (Some(tags), tag) => {
+                let _ = write!(packet, ":{value}|{kind}");
+                let tag = tag.map(|(key, value)| format!("{key}:{value}"));
+                let tags = [Some(tags.as_str()), tag.as_deref()];
+                let tags = tags
+                    .into_iter()
+                    .flatten()
+                    .filter(|tags| !tags.is_empty())
+                    .collect::<Vec<_>>();
+                if !tags.is_empty() {
+                    let _ = write!(packet, "|#{}", tags.join(","));
+                }
+            }
+            (None, Some((_, tag))) => {
+                let _ = write!(packet, ".{tag}:{value}|{kind}");
+            }
+            (None, None) => {
+                let _ = write!(packet, ":{value}|{kind}");
+            }
+        }
+        let _ = self.socket.send(packet.as_bytes());
+    }
+}
+
+/// Sends the gauges every [`GAUGE_INTERVAL`] until `shutdown` is cancelled.
+pub(super) async fn send_gauges(metrics: Arc<Metrics>, shutdown: CancellationToken) {
+    let mut ticks = interval(GAUGE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = ticks.tick() => {}
+        }
+        let Some(statsd) = metrics.statsd() else {
+            break;
+        };
+        let stats = metrics.stats();
+        statsd.gauge("connections_open", stats.open_connections);
+        statsd.gauge("requests_in_flight", stats.in_flight);
+        statsd.gauge("queue_depth", stats.queue_depth);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn receive(receiver: &tokio::net::UdpSocket) -> String {
+        let mut buf = [0u8; 512];
+        let len = receiver.recv(&mut buf).await.unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_statsd() {
+        let receiver = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut config = StatsdConfig {
+            addr: receiver.local_addr().unwrap().to_string(),
+            prefix: "nn".to_string(),
+            dogstatsd: false,
+        };
+
+        let statsd = Statsd::connect(&config, Some("mlp"), None).await.unwrap();
+        statsd.count("errors", 1, Some(("error", "bad_request")));
+        assert_eq!(receive(&receiver).await, "nn.errors.bad_request:1|c");
+        statsd.timing("request_duration", Duration::from_micros(1500), None);
+        assert_eq!(receive(&receiver).await, "nn.request_duration:1.5|ms");
+
+        config.dogstatsd = true;
+        let statsd = Statsd::connect(&config, Some("mlp"), None).await.unwrap();
+        statsd.count("errors", 1, Some(("error", "bad_request")));
+        assert_eq!(
+            receive(&receiver).await,
+            "nn.errors:1|c|#model:mlp,error:bad_request"
+        );
+        statsd.count("requests", 1, None);
+        assert_eq!(receive(&receiver).await, "nn.requests:1|c|#model:mlp");
+    }
+}
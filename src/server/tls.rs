@@ -0,0 +1,51 @@
+//! TLS termination, enabled with the `tls` feature. The certificate loading
+//! is shared with the `quic` feature.
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+use candle_core::{Error, Result};
+use rustls::{Certificate, PrivateKey};
+use rustls_pemfile::Item;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
+
+/// Build an acceptor from a PEM encoded certificate chain and private key.
+#[cfg(feature = "tls")]
+pub(crate) fn load_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor> {
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert_path)?, load_key(key_path)?)
+        .map_err(Error::wrap)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Read a PEM encoded certificate chain.
+pub(crate) fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(Error::Msg(format!("no certificates in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+/// Read the first RSA, PKCS8 or EC private key in the file.
+pub(crate) fn load_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key))
+            }
+            _ => {}
+        }
+    }
+    Err(Error::Msg(format!("no private key in {}", path.display())))
+}
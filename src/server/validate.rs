@@ -0,0 +1,225 @@
+//! Checks on decoded requests before they reach the forward pass.
+use std::collections::HashMap;
+use std::fmt;
+
+use candle_core::{DType, Error, Result, Tensor};
+
+use super::{Input, Output};
+
+/// The inputs a model expects or the outputs it returns, see
+/// [`Server::with_input_schema`](super::Server::with_input_schema) and
+/// [`Server::with_output_schema`](super::Server::with_output_schema).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schema {
+    /// A single tensor.
+    Tensor(TensorSchema),
+    /// Named tensors, all of which must be sent and no others.
+    Named(HashMap<String, TensorSchema>),
+}
+
+/// The dtype and shape expected of a tensor. The default accepts any tensor.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TensorSchema {
+    /// The dtype the tensor must have, any dtype if `None`.
+    pub dtype: Option<DType>,
+    /// The dimensions the tensor must have, where `None` matches any size.
+    /// A pattern of only `None` checks the rank. Any shape if `None`.
+    pub shape: Option<Vec<Option<usize>>>,
+}
+
+impl TensorSchema {
+    /// Fails with a description of the mismatch if `x` doesn't match.
+    pub fn check(&self, x: &Tensor) -> Result<()> {
+        if let Some(dtype) = self.dtype {
+            if x.dtype() != dtype {
+                return Err(Error::Msg(format!(
+                    "expected dtype {}, got {}",
+                    dtype.as_str(),
+                    x.dtype().as_str()
+                )));
+            }
+        }
+        if let Some(shape) = &self.shape {
+            let matches = shape.len() == x.rank()
+                && shape
+                    .iter()
+                    .zip(x.dims())
+                    .all(|(expected, &d)| expected.is_none() || *expected == Some(d));
+            if !matches {
+                return Err(Error::Msg(format!(
+                    "expected shape {}, got {:?}",
+                    ShapePattern(shape),
+                    x.dims()
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats a shape pattern with `?` for any size.
+struct ShapePattern<'a>(&'a [Option<usize>]);
+
+impl fmt::Display for ShapePattern<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[")?;
+        for (i, d) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match d {
+                Some(d) => write!(f, "{d}")?,
+                None => write!(f, "?")?,
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+/// Fails if the input doesn't match the schema. Inputs of the wrong kind are
+/// left for the forward pass to reject.
+pub(super) fn check_input(schema: &Schema, input: &Input) -> Result<()> {
+    let checked = match input {
+        Input::Tensor(x) => check_tensor(schema, "input", x),
+        Input::Named(inputs) => check_named(schema, "input", inputs),
+    };
+    checked.unwrap_or(Ok(()))
+}
+
+/// Fails if the output of the forward pass doesn't match the schema.
+pub(super) fn check_output(schema: &Schema, output: &Output) -> Result<()> {
+    let checked = match output {
+        Output::Tensor(x) => check_tensor(schema, "output", x),
+        Output::Named(outputs) => check_named(schema, "output", outputs),
+        // each tensor is checked as it's written
+        Output::Stream(..) => return Ok(()),
+        // generated tokens aren't checked
+        Output::Tokens(_) => return Ok(()),
+    };
+    checked.unwrap_or_else(|| {
+        Err(Error::Msg(match schema {
+            Schema::Tensor(_) => "expected a single output tensor".to_string(),
+            Schema::Named(_) => "expected named output tensors".to_string(),
+        }))
+    })
+}
+
+/// Checks a single tensor, or returns `None` if the schema is of named
+/// tensors.
+fn check_tensor(schema: &Schema, kind: &str, x: &Tensor) -> Option<Result<()>> {
+    let Schema::Tensor(schema) = schema else {
+        return None;
+    };
+    Some(
+        schema
+            .check(x)
+            .map_err(|e| Error::Msg(format!("invalid {kind}: {e}"))),
+    )
+}
+
+/// Checks named tensors, or returns `None` if the schema is of a single
+/// tensor.
+fn check_named(
+    schema: &Schema,
+    kind: &str,
+    tensors: &HashMap<String, Tensor>,
+) -> Option<Result<()>> {
+    let Schema::Named(schemas) = schema else {
+        return None;
+    };
+    let checked = || {
+        for (name, schema) in schemas {
+            let x = tensors
+                .get(name)
+                .ok_or_else(|| Error::Msg(format!("missing {kind} {name}")))?;
+            schema
+                .check(x)
+                .map_err(|e| Error::Msg(format!("invalid {kind} {name}: {e}")))?;
+        }
+        match tensors.keys().find(|name| !schemas.contains_key(*name)) {
+            Some(name) => Err(Error::Msg(format!("unexpected {kind} {name}"))),
+            None => Ok(()),
+        }
+    };
+    Some(checked())
+}
+
+/// Fails if a floating point tensor holds a NaN or infinite value. `name`
+/// names the input in the error.
+pub(super) fn check_finite(name: Option<&str>, x: &Tensor) -> Result<()> {
+    if !matches!(
+        x.dtype(),
+        DType::BF16 | DType::F16 | DType::F32 | DType::F64
+    ) {
+        return Ok(());
+    }
+    // x - x is zero where x is finite and NaN elsewhere
+    let sum = x
+        .sub(x)?
+        .sum_all()?
+        .to_dtype(DType::F64)?
+        .to_scalar::<f64>()?;
+    if sum == 0.0 {
+        return Ok(());
+    }
+    Err(Error::Msg(match name {
+        Some(name) => format!("input {name} holds NaN or infinite values"),
+        None => "input holds NaN or infinite values".to_string(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[test]
+    fn test_tensor_schema() -> Result<()> {
+        let schema = TensorSchema {
+            dtype: Some(DType::F32),
+            shape: Some(vec![None, Some(3)]),
+        };
+        assert!(schema
+            .check(&Tensor::zeros((2, 3), DType::F32, &Device::Cpu)?)
+            .is_ok());
+        assert!(schema
+            .check(&Tensor::zeros((2, 4), DType::F32, &Device::Cpu)?)
+            .is_err());
+        assert!(schema
+            .check(&Tensor::zeros(3, DType::F32, &Device::Cpu)?)
+            .is_err());
+        let err = schema
+            .check(&Tensor::zeros((2, 3), DType::F16, &Device::Cpu)?)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "expected dtype f32, got f16");
+        assert_eq!(ShapePattern(&[None, Some(3)]).to_string(), "[?, 3]");
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_output() -> Result<()> {
+        let schema = Schema::Named(HashMap::from([(
+            "logits".to_string(),
+            TensorSchema::default(),
+        )]));
+        let x = Tensor::zeros(2, DType::F32, &Device::Cpu)?;
+        let outputs = HashMap::from([("probs".to_string(), x.clone())]);
+        let err = check_output(&schema, &Output::Named(outputs)).unwrap_err();
+        assert_eq!(err.to_string(), "missing output logits");
+        assert!(check_output(&schema, &Output::Tensor(x)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_finite() -> Result<()> {
+        let x = Tensor::new(&[1f32, -2.0], &Device::Cpu)?;
+        assert!(check_finite(None, &x).is_ok());
+        for bad in [f32::NAN, f32::INFINITY] {
+            let x = Tensor::new(&[1f32, bad], &Device::Cpu)?;
+            assert!(check_finite(Some("x"), &x).is_err());
+        }
+        let x = Tensor::new(&[1u32, 2], &Device::Cpu)?;
+        assert!(check_finite(None, &x).is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+//! Verifying weights before they're loaded, enabled with the `verify`
+//! feature.
+//!
+//! A [`WeightsVerifier`] accepts weights whose SHA-256 digest matches the
+//! one expected and, if it has a public key, that carry a valid ed25519
+//! signature of their bytes, rejecting corrupt or tampered checkpoints with
+//! an error. Checkpoints are verified against sidecar files next to them:
+//! `weights.sha256` holding the digest in hex, as written by `sha256sum`,
+//! and `weights.sig` holding the 64 byte signature, raw or in hex. Wrap a
+//! loader with [`verified_file`] to verify checkpoints loaded at start up or
+//! by [`ModelReloader::watch_dir`](super::ModelReloader::watch_dir), and see
+//! [`ModelReloader::poll_url_verified`](super::ModelReloader::poll_url_verified)
+//! for weights fetched from a URL.
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use candle_core::{Result, Tensor};
+//! # use socket_nn::server::Server;
+//! # use socket_nn::server::verify::{verified_file, WeightsVerifier};
+//! # fn forward(model: &Vec<u8>, x: Tensor) -> Result<Tensor> { Ok(x) }
+//! # async fn serve(public_key: [u8; 32]) -> Result<()> {
+//! let verifier = WeightsVerifier::new().with_public_key(&public_key)?;
+//! let load = verified_file(verifier, |weights: &[u8]| Ok(weights.to_vec()));
+//! let server = Server::new(Arc::new(load("checkpoints/v1".as_ref())?), forward);
+//! let _watcher = server.model_reloader().watch_dir("checkpoints", load)?;
+//! server.run("127.0.0.1:8080").await
+//! # }
+//! ```
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use candle_core::{Error, Result};
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+/// Extension of the sidecar holding a checkpoint's SHA-256 digest.
+pub const SHA256_EXTENSION: &str = "sha256";
+/// Extension of the sidecar holding a checkpoint's signature.
+pub const SIGNATURE_EXTENSION: &str = "sig";
+
+/// Checks the digest and signature of weights, see [`verify`](self).
+#[derive(Debug, Clone, Default)]
+pub struct WeightsVerifier {
+    key: Option<VerifyingKey>,
+}
+
+impl WeightsVerifier {
+    /// A verifier checking only the SHA-256 digest of weights.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also require weights to be signed by the private key of the ed25519
+    /// public `key`.
+    pub fn with_public_key(mut self, key: &[u8; 32]) -> Result<Self> {
+        self.key = Some(VerifyingKey::from_bytes(key).map_err(Error::wrap)?);
+        Ok(self)
+    }
+
+    /// Whether signatures are required.
+    pub fn requires_signature(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// Checks that `weights` have the SHA-256 digest `sha256`, in hex as
+    /// written by `sha256sum`, and, if required, that `signature` is a valid
+    /// signature of them, raw or in hex.
+    pub fn verify(&self, weights: &[u8], sha256: &str, signature: Option<&[u8]>) -> Result<()> {
+        let expected = sha256
+            .split_whitespace()
+            .next()
+            .map(str::to_ascii_lowercase)
+            .unwrap_or_default();
+        let digest = hex(&Sha256::digest(weights));
+        if digest != expected {
+            return Err(Error::Msg(format!(
+                "checksum mismatch, expected sha256 {expected} but got {digest}"
+            )));
+        }
+        let Some(key) = &self.key else {
+            return Ok(());
+        };
+        let signature = signature.ok_or_else(|| Error::Msg("missing signature".to_string()))?;
+        let signature = match signature.len() {
+            Signature::BYTE_SIZE => signature.to_vec(),
+            _ => unhex(std::str::from_utf8(signature).map_err(Error::wrap)?.trim())?,
+        };
+        let signature = Signature::from_slice(&signature).map_err(Error::wrap)?;
+        key.verify_strict(weights, &signature)
+            .map_err(|_| Error::Msg("invalid signature".to_string()))
+    }
+
+    /// Reads the checkpoint at `path` and verifies it against its sidecars.
+    pub fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
+        let weights = std::fs::read(path)?;
+        let sidecar = sidecar_path(path, SHA256_EXTENSION);
+        let sha256 = std::fs::read_to_string(&sidecar)
+            .map_err(|e| Error::Msg(format!("no checksum at {}: {e}", sidecar.display())))?;
+        let signature = match self.key {
+            Some(_) => {
+                let sidecar = sidecar_path(path, SIGNATURE_EXTENSION);
+                Some(std::fs::read(&sidecar).map_err(|e| {
+                    Error::Msg(format!("no signature at {}: {e}", sidecar.display()))
+                })?)
+            }
+            None => None,
+        };
+        self.verify(&weights, &sha256, signature.as_deref())
+            .map_err(|e| Error::Msg(format!("{}: {e}", path.display())))?;
+        Ok(weights)
+    }
+}
+
+/// A loader reading checkpoints from files, verifying them with `verifier`
+/// and loading the bytes verified with `load`.
+pub fn verified_file<M, F>(verifier: WeightsVerifier, load: F) -> impl Fn(&Path) -> Result<M>
+where
+    F: Fn(&[u8]) -> Result<M>,
+{
+    move |path| load(&verifier.read_file(path)?)
+}
+
+/// The path of the sidecar of `path` with the extension `extension`.
+pub(super) fn sidecar_path(path: &Path, extension: &str) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".");
+    sidecar.push(extension);
+    PathBuf::from(sidecar)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+fn unhex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(Error::Msg("invalid hex".to_string()));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(Error::wrap))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn test_verify() {
+        let verifier = WeightsVerifier::new();
+        verifier.verify(b"abc", ABC_SHA256, None).unwrap();
+        let sha256sum = format!("{}  weights\n", ABC_SHA256.to_uppercase());
+        verifier.verify(b"abc", &sha256sum, None).unwrap();
+        let e = verifier.verify(b"abd", ABC_SHA256, None).unwrap_err();
+        assert!(e.to_string().contains("checksum mismatch"));
+
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let verifier = verifier
+            .with_public_key(key.verifying_key().as_bytes())
+            .unwrap();
+        let signature = key.sign(b"abc").to_bytes();
+        verifier
+            .verify(b"abc", ABC_SHA256, Some(&signature))
+            .unwrap();
+        verifier
+            .verify(b"abc", ABC_SHA256, Some(hex(&signature).as_bytes()))
+            .unwrap();
+        assert!(verifier.verify(b"abc", ABC_SHA256, None).is_err());
+        let forged = key.sign(b"abd").to_bytes();
+        let e = verifier
+            .verify(b"abc", ABC_SHA256, Some(&forged))
+            .unwrap_err();
+        assert_eq!(e.to_string(), "invalid signature");
+    }
+}
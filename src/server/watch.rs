@@ -0,0 +1,208 @@
+//! Reloading the model from checkpoints dropped into a directory, enabled
+//! with the `watch` feature.
+//!
+//! [`ModelReloader::watch_dir`] watches a directory and, once a file in it
+//! has been created or changed and left alone for [`SETTLE_TIME`], loads it
+//! with the given function and serves the model loaded. Loading also
+//! validates the checkpoint: if it fails the file is skipped with a warning
+//! and the model served is unchanged. Hidden files, such as a `.partial`
+//! file being copied in before an atomic rename, are ignored. With the
+//! `verify` feature, a checksum or signature sidecar changing counts as its
+//! checkpoint changing, so a checkpoint whose sidecars are written after it
+//! is still loaded once they are, see [`verify`](super::verify):
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use candle_core::{Result, Tensor};
+//! # use socket_nn::server::Server;
+//! # fn forward(model: &Vec<f32>, x: Tensor) -> Result<Tensor> { Ok(x) }
+//! # fn load_checkpoint(path: &std::path::Path) -> Result<Vec<f32>> { Ok(vec![]) }
+//! # async fn serve() -> Result<()> {
+//! let server = Server::new(Arc::new(load_checkpoint("v1".as_ref())?), forward);
+//! let _watcher = server.model_reloader().watch_dir("checkpoints", load_checkpoint)?;
+//! server.run("127.0.0.1:8080").await
+//! # }
+//! ```
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use candle_core::{Error, Result};
+use notify::event::{EventKind, ModifyKind};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Instant};
+use tracing::{info, warn};
+
+use super::ModelReloader;
+
+/// How long a file must go unchanged before it's loaded, so checkpoints
+/// still being written aren't.
+pub const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// Watches a directory for checkpoints until dropped, see
+/// [`ModelReloader::watch_dir`].
+pub struct DirWatcher {
+    _watcher: RecommendedWatcher,
+    task: JoinHandle<()>,
+}
+
+impl Drop for DirWatcher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl<M> ModelReloader<M>
+where
+    M: Send + Sync + 'static,
+{
+    /// Loads each checkpoint created or changed in `dir` with `load` and
+    /// serves it, see [`watch`](self). Files already in `dir` aren't loaded.
+    /// Must be called within the Tokio runtime.
+    pub fn watch_dir<F>(&self, dir: impl AsRef<Path>, load: F) -> Result<DirWatcher>
+    where
+        F: Fn(&Path) -> Result<M> + Send + Sync + 'static,
+    {
+        let (events, changed) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<_>| {
+            // the receiver is only gone once the watcher is being dropped
+            let _ = events.send(event);
+        })
+        .map_err(Error::wrap)?;
+        watcher
+            .watch(dir.as_ref(), RecursiveMode::NonRecursive)
+            .map_err(Error::wrap)?;
+        info!(dir = %dir.as_ref().display(), "watching for checkpoints");
+        let task = tokio::spawn(reload_changed(self.clone(), Arc::new(load), changed));
+        Ok(DirWatcher {
+            _watcher: watcher,
+            task,
+        })
+    }
+}
+
+/// Loads files once they've settled after changing, in the order they last
+/// changed.
+async fn reload_changed<M, F>(
+    reloader: ModelReloader<M>,
+    load: Arc<F>,
+    mut changed: mpsc::UnboundedReceiver<notify::Result<notify::Event>>,
+) where
+    M: Send + Sync + 'static,
+    F: Fn(&Path) -> Result<M> + Send + Sync + 'static,
+{
+    // the files changed and when they last did
+    let mut pending = BTreeMap::<PathBuf, Instant>::new();
+    loop {
+        let settled = pending.values().min().map(|&changed| changed + SETTLE_TIME);
+        tokio::select! {
+            event = changed.recv() => {
+                let event = match event {
+                    Some(Ok(event)) => event,
+                    Some(Err(e)) => {
+                        warn!("watching for checkpoints failed: {e}");
+                        continue;
+                    }
+                    None => break,
+                };
+                if is_write(&event.kind) {
+                    for path in event.paths.into_iter().filter(|path| is_checkpoint(path)) {
+                        #[cfg(feature = "verify")]
+                        let path = checkpoint_of(path);
+                        pending.insert(path, Instant::now());
+                    }
+                }
+            }
+            _ = sleep_until(settled.unwrap_or_else(Instant::now)), if settled.is_some() => {
+                let now = Instant::now();
+                let mut ready = pending
+                    .iter()
+                    .filter(|(_, &changed)| changed + SETTLE_TIME <= now)
+                    .map(|(path, &changed)| (changed, path.clone()))
+                    .collect::<Vec<_>>();
+                ready.sort();
+                for (_, path) in ready {
+                    pending.remove(&path);
+                    reload(&reloader, &load, path).await;
+                }
+            }
+        }
+    }
+}
+
+async fn reload<M, F>(reloader: &ModelReloader<M>, load: &Arc<F>, path: PathBuf)
+where
+    M: Send + Sync + 'static,
+    F: Fn(&Path) -> Result<M> + Send + Sync + 'static,
+{
+    // a file removed since it changed was renamed away or deleted
+    if !path.is_file() {
+        return;
+    }
+    let load = Arc::clone(load);
+    let checkpoint = path.clone();
+    match reloader.reload_with(move || load(&checkpoint)).await {
+        Ok(_) => info!(path = %path.display(), "loaded checkpoint"),
+        Err(e) => warn!(path = %path.display(), "skipped checkpoint: {e}"),
+    }
+}
+
+/// Whether an event may leave a new checkpoint behind.
+fn is_write(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Create(_)
+            | EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Name(_) | ModifyKind::Any)
+    )
+}
+
+/// Whether a file may be a checkpoint rather than hidden.
+fn is_checkpoint(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| !name.starts_with('.'))
+}
+
+/// The checkpoint a sidecar belongs to, or `path` itself if it isn't one.
+#[cfg(feature = "verify")]
+fn checkpoint_of(path: PathBuf) -> PathBuf {
+    use super::verify::{SHA256_EXTENSION, SIGNATURE_EXTENSION};
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some(SHA256_EXTENSION | SIGNATURE_EXTENSION) => path.with_extension(""),
+        _ => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use candle_core::Tensor;
+
+    #[tokio::test]
+    async fn test_watch_dir() {
+        let dir = std::env::temp_dir().join(format!("socket-nn-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let server = Server::new(Arc::new(0), |_: &u32, x: Tensor| Ok(x));
+        let reloader = server.model_reloader();
+        let _watcher = reloader
+            .watch_dir(&dir, |path| {
+                let weights = std::fs::read_to_string(path)?;
+                weights.trim().parse().map_err(Error::wrap)
+            })
+            .unwrap();
+
+        std::fs::write(dir.join(".1.partial"), "1").unwrap();
+        std::fs::write(dir.join("broken"), "not weights").unwrap();
+        std::fs::write(dir.join("2"), "2").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while *reloader.current() != 2 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(*reloader.current(), 2);
+    }
+}
@@ -0,0 +1,90 @@
+//! WebSocket transport, enabled with the `websocket` feature.
+//!
+//! Each binary message holds one request, encoded as on a plain connection,
+//! and is answered with one binary message holding the response.
+use std::net::IpAddr;
+
+use candle_core::Error;
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+use super::{serve_request, Server};
+use crate::io::codec::TensorCodec;
+
+/// Serves requests sent as WebSocket messages until the client closes the
+/// connection, it idles, it reaches the request limit or the server shuts
+/// down.
+pub(super) async fn handle_connection<M, C, S>(
+    stream: S,
+    peer: Option<IpAddr>,
+    server: &Server<M, C>,
+    shutdown: &CancellationToken,
+) -> Result<(), Error>
+where
+    M: Sync + Send + 'static,
+    C: TensorCodec + Clone,
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    // tungstenite closes the connection when a message is over the limit
+    let config = (server.max_request_bytes != usize::MAX).then(|| WebSocketConfig {
+        max_message_size: Some(server.max_request_bytes),
+        ..Default::default()
+    });
+    let mut socket = server
+        .read_header(tokio_tungstenite::accept_async_with_config(stream, config))
+        .await
+        .ok_or_else(|| Error::Msg("timed out in the WebSocket handshake".to_string()))?
+        .map_err(Error::wrap)?;
+    // codecs may hold per-connection state such as negotiated compression
+    let codec = server.codec.clone();
+
+    let mut served = 0;
+    while server.max_requests_per_connection != Some(served) {
+        let next = async {
+            match server.idle_timeout {
+                Some(idle_timeout) => timeout(idle_timeout, socket.next()).await.ok(),
+                None => Some(socket.next().await),
+            }
+        };
+        let message = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            message = next => match message {
+                Some(message) => message,
+                None => {
+                    debug!("closing idle connection");
+                    break;
+                }
+            },
+        };
+        let request = match message {
+            Some(message) => message.map_err(Error::wrap)?,
+            None => break,
+        };
+        let request = match request {
+            Message::Binary(request) => request,
+            Message::Close(_) => break,
+            // pings are answered by tungstenite
+            _ => continue,
+        };
+
+        served += 1;
+        let mut response = Vec::new();
+        let keep_open =
+            serve_request(server, &codec, peer, &mut request.as_slice(), &mut response).await?;
+        socket
+            .send(Message::Binary(response))
+            .await
+            .map_err(Error::wrap)?;
+        if !keep_open {
+            break;
+        }
+    }
+    // the client may already be gone
+    let _ = socket.close(None).await;
+    Ok(())
+}
@@ -0,0 +1,58 @@
+//! ZeroMQ transport, enabled with the `zmq` feature.
+//!
+//! The server binds a REP socket and answers each message with one message.
+//! The frames of a request are joined into one request, encoded as on a plain
+//! connection, and the response is sent as a single frame. Being a REP socket,
+//! requests are served one at a time.
+use candle_core::Error;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+use zeromq::{RepSocket, Socket, SocketRecv, SocketSend, ZmqMessage};
+
+use super::{serve_request, Server};
+use crate::io::codec::TensorCodec;
+use crate::io::error::{codes, write_error_frame, ErrorFrame};
+
+/// Serve requests on a REP socket bound to `endpoint` until `shutdown` is
+/// cancelled.
+pub(super) async fn serve<M, C>(
+    server: &Server<M, C>,
+    endpoint: &str,
+    shutdown: CancellationToken,
+) -> Result<(), Error>
+where
+    M: Sync + Send + 'static,
+    C: TensorCodec + Clone,
+{
+    let mut socket = RepSocket::new();
+    socket.bind(endpoint).await.map_err(Error::wrap)?;
+    let codec = server.codec.clone();
+
+    loop {
+        let message = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            message = socket.recv() => message.map_err(Error::wrap)?,
+        };
+        let request = message.into_vec().concat();
+
+        let mut response = Vec::new();
+        if let Err(e) =
+            serve_request(server, &codec, None, &mut request.as_slice(), &mut response).await
+        {
+            // a REP socket must answer every request
+            warn!("request failed: {e}");
+            server.report_error(&e);
+            response.clear();
+            write_error_frame(
+                &ErrorFrame::new(codes::BAD_REQUEST, e.to_string()),
+                &mut response,
+            )
+            .await?;
+        }
+        socket
+            .send(ZmqMessage::from(response))
+            .await
+            .map_err(Error::wrap)?;
+    }
+    Ok(())
+}